@@ -0,0 +1,54 @@
+/* An event log on banyan, for C and C++ programs. See src/cabi.rs for the details.
+ *
+ * Build with `cargo build --release --lib`, and link with -lbanyan_camp from target/release. The
+ * static library also needs -lssl -lcrypto -lpthread -ldl -lm.
+ * The functions that return int return 0 when they worked and -1 when not, and
+ * banyan_last_error has the message of the last failure on the calling thread. A panic is
+ * such a failure, and never unwinds into the caller. Keep this in line with src/cabi.rs, a test
+ * there checks that the functions match. */
+#ifndef BANYAN_CAMP_H
+#define BANYAN_CAMP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct BanyanLog BanyanLog;
+
+/* Called with the context, the offset and the bytes of a value. Returning anything but 0 stops
+ * the iteration */
+typedef int (*BanyanLogCallback)(void *ctx, uint64_t offset, const uint8_t *value, size_t len);
+
+/* The message of the last failure on this thread, or NULL. Valid until the next failure */
+const char *banyan_last_error(void);
+
+/* Open the log in a directory, and create it if it is not there. NULL on failure */
+BanyanLog *banyan_log_open(const char *dir);
+
+/* Close a log. Values appended after the last snapshot are lost */
+void banyan_log_close(BanyanLog *log);
+
+/* Append count values, where value i is lens[i] bytes at values[i], which may be NULL if
+ * lens[i] is 0 */
+int banyan_log_append(BanyanLog *log, const uint8_t *const *values, const size_t *lens,
+                      size_t count);
+
+/* The number of values in the log, which is the offset of the next one, or UINT64_MAX if it
+ * panicked */
+uint64_t banyan_log_count(const BanyanLog *log);
+
+/* Make the values appended so far durable, and write the cid of the root as a nul terminated
+ * string to cid, or an empty string if the log is empty. 64 bytes are always enough */
+int banyan_log_snapshot(BanyanLog *log, char *cid, size_t cid_len);
+
+/* Call callback for every value from offset from on, in order */
+int banyan_log_iter(const BanyanLog *log, uint64_t from, BanyanLogCallback callback, void *ctx);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
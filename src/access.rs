@@ -0,0 +1,313 @@
+//! Who may read and append to which streams of the server
+//!
+//! Without an access file, the [server](crate::server) answers everyone, which is fine on
+//! localhost. With one, every request needs a bearer token from the file, and the token needs the
+//! permission for the stream of the request:
+//!
+//! ```toml
+//! # a dashboard that follows two streams
+//! [[token]]
+//! token = "3q2-dashboard-4f1c7e"
+//! read = ["events", "alerts"]
+//!
+//! # an ingest job, which can also read everything
+//! [[token]]
+//! token = "9x7-ingest-b02d5a"
+//! read = ["*"]
+//! append = ["events"]
+//!
+//! # an application that is a tenant, see crate::tenants
+//! [[token]]
+//! token = "5k2-acme-c81e09"
+//! tenant = "acme"
+//! read = ["*"]
+//! append = ["events"]
+//! ```
+//!
+//! The streams of a token with a tenant are the streams of the tenant, so the last one may read
+//! every stream `acme/<stream>` and append to `acme/events`, and nothing outside of the tenant.
+//! A root can be of any stream, so the routes of the server by root are only for tokens without a
+//! tenant that may read `*`.
+//!
+//! Reading covers sync and tail requests, appending covers appends, and `*` is every stream. The
+//! metrics and the health are for every token in the file. A client sends its token in an
+//! `Authorization: Bearer <token>` header, or, since a WebSocket of a browser can not have
+//! headers, as the `access_token` parameter of the query. A request without a token of the file is
+//! answered with a 401, one whose token does not have the permission with a 403.
+//!
+//! The tail and sync-pull commands send the token in `BANYAN_SERVER_TOKEN`, if it is set.
+use std::{fmt, fs, path::Path};
+
+use toml_edit::{Document, Item};
+
+use crate::tenants;
+
+/// What a request does to a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Append,
+}
+
+/// Why a request is not allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// no token, or one that is not in the file
+    Unauthorized,
+    /// a token without the permission
+    Forbidden,
+}
+
+#[derive(Clone)]
+struct Grant {
+    token: String,
+    /// the tenant whose streams the names are
+    tenant: Option<String>,
+    read: Vec<String>,
+    append: Vec<String>,
+}
+
+impl fmt::Debug for Grant {
+    // no secrets in logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Grant")
+            .field("tenant", &self.tenant)
+            .field("read", &self.read)
+            .field("append", &self.append)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Grant {
+    fn allows(&self, permission: Permission, stream: &str) -> bool {
+        let streams = match permission {
+            Permission::Read => &self.read,
+            Permission::Append => &self.append,
+        };
+        // a stream of another tenant, or of none, is never one of the streams
+        let stream = match &self.tenant {
+            Some(tenant) => match stream.split_once('/') {
+                Some((of, stream)) if of == tenant => stream,
+                _ => return false,
+            },
+            None => stream,
+        };
+        streams.iter().any(|name| name == "*" || name == stream)
+    }
+}
+
+/// Whether two tokens are the same, in a time that only depends on their length, so the time of
+/// an answer does not tell how much of a guess was right
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The tokens of an access file, see the module docs
+#[derive(Debug, Clone)]
+pub struct Access {
+    grants: Vec<Grant>,
+}
+
+impl Access {
+    /// Load the tokens from an access file
+    pub fn load(file: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(file)
+            .map_err(|cause| anyhow::anyhow!("can not read {}: {}", file.display(), cause))?;
+        Self::parse(&text).map_err(|cause| anyhow::anyhow!("{}: {}", file.display(), cause))
+    }
+
+    /// Parse the tokens from the text of an access file
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let doc = text.parse::<Document>()?;
+        if let Some((key, _)) = doc.iter().find(|(key, _)| *key != "token") {
+            anyhow::bail!("unknown section {}", key);
+        }
+        let Some(tables) = doc.get("token").and_then(Item::as_array_of_tables) else {
+            anyhow::bail!("no [[token]] sections");
+        };
+        let mut grants = Vec::<Grant>::new();
+        for table in tables.iter() {
+            if let Some((key, _)) = table
+                .iter()
+                .find(|(key, _)| !["token", "tenant", "read", "append"].contains(key))
+            {
+                anyhow::bail!("a token has an unknown field {}", key);
+            }
+            let token = table
+                .get("token")
+                .and_then(Item::as_str)
+                .filter(|token| !token.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("a [[token]] without a token"))?;
+            anyhow::ensure!(
+                grants.iter().all(|grant| grant.token != token),
+                "a token is in the file twice"
+            );
+            let tenant = match table.get("tenant") {
+                Some(item) => {
+                    let tenant = item
+                        .as_str()
+                        .ok_or_else(|| anyhow::anyhow!("a tenant must be a string"))?;
+                    tenants::check_name("tenant", tenant)?;
+                    Some(tenant.to_string())
+                }
+                None => None,
+            };
+            let streams = |key: &str| -> anyhow::Result<Vec<String>> {
+                let Some(item) = table.get(key) else {
+                    return Ok(Vec::new());
+                };
+                let array = item
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("{} must be a list of streams", key))?;
+                array
+                    .iter()
+                    .map(|name| {
+                        name.as_str()
+                            .map(str::to_string)
+                            .ok_or_else(|| anyhow::anyhow!("{} must be a list of streams", key))
+                    })
+                    .collect()
+            };
+            grants.push(Grant {
+                token: token.to_string(),
+                tenant,
+                read: streams("read")?,
+                append: streams("append")?,
+            });
+        }
+        Ok(Self { grants })
+    }
+
+    /// Check that a token may do `need`, a permission on a stream, or that it is in the file for
+    /// nothing in particular, like the metrics
+    pub fn check(
+        &self,
+        token: Option<&str>,
+        need: Option<(Permission, &str)>,
+    ) -> Result<(), Denied> {
+        let token = token.ok_or(Denied::Unauthorized)?;
+        // every token is compared, so the time does not tell which one matched
+        let grant = self
+            .grants
+            .iter()
+            .fold(None, |found, grant| match same(&grant.token, token) {
+                true => Some(grant),
+                false => found,
+            })
+            .ok_or(Denied::Unauthorized)?;
+        match need {
+            Some((permission, stream)) if !grant.allows(permission, stream) => {
+                Err(Denied::Forbidden)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The token for requests to a server, from `BANYAN_SERVER_TOKEN`
+pub fn token_from_env() -> Option<String> {
+    std::env::var("BANYAN_SERVER_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+[[token]]
+token = "reader"
+read = ["events"]
+
+[[token]]
+token = "writer"
+read = ["*"]
+append = ["events"]
+
+[[token]]
+token = "acme"
+tenant = "acme"
+read = ["*"]
+append = ["events"]
+"#;
+
+    #[test]
+    fn permissions() {
+        let access = Access::parse(FILE).unwrap();
+        let read = |stream| Some((Permission::Read, stream));
+        let append = |stream| Some((Permission::Append, stream));
+        assert_eq!(access.check(Some("reader"), read("events")), Ok(()));
+        assert_eq!(
+            access.check(Some("reader"), read("other")),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            access.check(Some("reader"), append("events")),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(access.check(Some("writer"), read("other")), Ok(()));
+        assert_eq!(access.check(Some("writer"), append("events")), Ok(()));
+        assert_eq!(
+            access.check(Some("writer"), append("other")),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(access.check(Some("reader"), None), Ok(()));
+        assert_eq!(
+            access.check(Some("readers"), None),
+            Err(Denied::Unauthorized)
+        );
+        assert_eq!(
+            access.check(None, read("events")),
+            Err(Denied::Unauthorized)
+        );
+        assert!(!format!("{:?}", access).contains("reader\""));
+    }
+
+    #[test]
+    fn tenants() {
+        let access = Access::parse(FILE).unwrap();
+        let check = |permission, stream| access.check(Some("acme"), Some((permission, stream)));
+        assert_eq!(check(Permission::Read, "acme/events"), Ok(()));
+        assert_eq!(check(Permission::Read, "acme/alerts"), Ok(()));
+        assert_eq!(check(Permission::Append, "acme/events"), Ok(()));
+        assert_eq!(
+            check(Permission::Append, "acme/alerts"),
+            Err(Denied::Forbidden)
+        );
+        // the streams of another tenant, or of none, are out of reach
+        for stream in ["globex/events", "events", "acme", "*"] {
+            assert_eq!(check(Permission::Read, stream), Err(Denied::Forbidden));
+        }
+        // a token without a tenant names streams in full
+        assert_eq!(
+            access.check(Some("writer"), Some((Permission::Append, "acme/events"))),
+            Err(Denied::Forbidden)
+        );
+        assert_eq!(
+            access.check(Some("writer"), Some((Permission::Read, "acme/events"))),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn invalid_files() {
+        for text in [
+            "",
+            "[other]",
+            "[[token]]\nread = [\"a\"]",
+            "[[token]]\ntoken = \"\"",
+            "[[token]]\ntoken = \"a\"\nwrite = [\"a\"]",
+            "[[token]]\ntoken = \"a\"\nread = \"a\"",
+            "[[token]]\ntoken = \"a\"\n[[token]]\ntoken = \"a\"",
+            "[[token]]\ntoken = \"a\"\ntenant = \"a/b\"",
+            "[[token]]\ntoken = \"a\"\ntenant = 1",
+        ] {
+            assert!(Access::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Tumbling window aggregates over a query
+//!
+//! Events come out of a filtered iteration in offset order, which for a log of events is also
+//! time order. So a window is complete as soon as the first event after it comes in, and
+//! [Tumbling] emits the aggregates of each window right then, instead of collecting the whole
+//! result first. Within a window, events can be grouped, e.g. by device.
+//!
+//! An event that is older than the current window can not be added anymore, and is an error.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Instant,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::DagCbor;
+
+use crate::columnar::{self, ColumnarTT, TimeRangeQuery};
+
+/// Count, sum, min and max of the values in a window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+pub struct Stats {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Stats {
+    pub fn new(value: u64) -> Self {
+        Self {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    pub fn add(&mut self, value: u64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum as f64 / self.count as f64
+    }
+}
+
+/// The aggregate of one group in one window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bucket<G> {
+    /// the start of the window
+    pub start: u64,
+    pub group: G,
+    pub stats: Stats,
+}
+
+/// Aggregates of `(time, group, value)` items per window of `width` and group
+pub struct Tumbling<I, G> {
+    inner: I,
+    width: u64,
+    /// the start of the current window
+    start: Option<u64>,
+    /// the groups of the current window
+    groups: BTreeMap<G, Stats>,
+    /// the buckets of the last complete window
+    done: VecDeque<Bucket<G>>,
+}
+
+impl<I, G: Ord> Tumbling<I, G> {
+    pub fn new(inner: I, width: u64) -> Self {
+        assert!(width > 0, "windows need a width");
+        Self {
+            inner,
+            width,
+            start: None,
+            groups: BTreeMap::new(),
+            done: VecDeque::new(),
+        }
+    }
+
+    /// Move the groups of the current window to the complete buckets
+    fn close(&mut self, start: u64) {
+        let groups = std::mem::take(&mut self.groups);
+        self.done
+            .extend(groups.into_iter().map(|(group, stats)| Bucket {
+                start,
+                group,
+                stats,
+            }));
+    }
+}
+
+impl<I, G> Iterator for Tumbling<I, G>
+where
+    I: Iterator<Item = anyhow::Result<(u64, G, u64)>>,
+    G: Ord,
+{
+    type Item = anyhow::Result<Bucket<G>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(bucket) = self.done.pop_front() {
+                return Some(Ok(bucket));
+            }
+            let (time, group, value) = match self.inner.next() {
+                Some(Ok(item)) => item,
+                Some(Err(cause)) => return Some(Err(cause)),
+                None => {
+                    // the last window is complete when the events end
+                    let start = self.start.take()?;
+                    self.close(start);
+                    continue;
+                }
+            };
+            let start = time - time % self.width;
+            match self.start {
+                Some(current) if start < current => {
+                    return Some(Err(anyhow::anyhow!(
+                        "event at {} is before the window at {}",
+                        time,
+                        current
+                    )))
+                }
+                Some(current) if start > current => self.close(current),
+                _ => {}
+            }
+            self.start = Some(start);
+            match self.groups.get_mut(&group) {
+                Some(stats) => stats.add(value),
+                None => {
+                    self.groups.insert(group, Stats::new(value));
+                }
+            }
+        }
+    }
+}
+
+/// Print the aggregates of the events of a columnar tree in kubo as each window completes
+pub fn print_aggregate<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    window: u64,
+    per_device: bool,
+    query: TimeRangeQuery,
+) -> anyhow::Result<()> {
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let events = forest.iter_filtered(&tree, query).map(move |item| {
+        item.map(|(_, key, value)| {
+            let group = if per_device { Some(key.device) } else { None };
+            (key.time, group, value)
+        })
+    });
+    println!("start\tdevice\tcount\tsum\tavg\tmin\tmax");
+    for bucket in Tumbling::new(events, window) {
+        let Bucket {
+            start,
+            group,
+            stats,
+        } = bucket?;
+        let group = group.map(|x| x.to_string()).unwrap_or_else(|| "*".into());
+        println!(
+            "{}\t{}\t{}\t{}\t{:.2}\t{}\t{}",
+            start,
+            group,
+            stats.count,
+            stats.sum,
+            stats.avg(),
+            stats.min,
+            stats.max
+        );
+    }
+    Ok(())
+}
+
+/// Hourly aggregates over all events, and per device
+pub fn aggregate_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let hour = 60 * 60 * 1000;
+    println!("Example: hourly aggregates of {} events", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, columnar::events(n))?;
+    let tree = builder.snapshot();
+    let all = TimeRangeQuery {
+        min: 0,
+        max: u64::MAX,
+    };
+
+    let t0 = Instant::now();
+    let events = txn
+        .iter_filtered(&tree, all.clone())
+        .map(|item| item.map(|(_, key, value)| (key.time, (), value)));
+    let hourly = Tumbling::new(events, hour).collect::<anyhow::Result<Vec<_>>>()?;
+    println!("start\tcount\tavg");
+    for bucket in &hourly {
+        println!(
+            "{}\t{}\t{:.1}",
+            bucket.start,
+            bucket.stats.count,
+            bucket.stats.avg()
+        );
+    }
+    let count = hourly.iter().map(|b| b.stats.count).sum::<u64>();
+    let sum = hourly.iter().map(|b| b.stats.sum).sum::<u64>();
+    anyhow::ensure!(count == n && sum == n * (n - 1) / 2, "events are missing");
+
+    let events = txn
+        .iter_filtered(&tree, all)
+        .map(|item| item.map(|(_, key, value)| (key.time, key.device, value)));
+    let mut buckets = 0;
+    let mut count = 0;
+    for bucket in Tumbling::new(events, hour) {
+        let bucket = bucket?;
+        buckets += 1;
+        count += bucket.stats.count;
+    }
+    anyhow::ensure!(count == n, "events are missing per device");
+    println!(
+        "{} buckets for {} hours per device, {}s",
+        buckets,
+        hourly.len(),
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
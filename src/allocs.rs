@@ -0,0 +1,52 @@
+//! Counting heap allocations
+//!
+//! With the `count-allocs` feature, the allocator of the binary is the system allocator with a
+//! counter, so an example can tell how many allocations a piece of code does, which is a better
+//! measure of allocator pressure than the time alone. The counter is for the whole process, so the
+//! count of a piece of code is only exact while no other thread allocates. Without the feature,
+//! the allocator is the system allocator, nothing is counted, and [count] has no count.
+#[cfg(feature = "count-allocs")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "count-allocs")]
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "count-allocs")]
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "count-allocs")]
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The number of allocations and reallocations so far, if they are counted
+pub fn allocations() -> Option<u64> {
+    cfg!(feature = "count-allocs").then(|| ALLOCATIONS.load(Ordering::Relaxed))
+}
+
+/// Run a function, and return its result with the number of allocations it did, if they are
+/// counted
+pub fn count<R>(f: impl FnOnce() -> R) -> (R, Option<u64>) {
+    let before = allocations();
+    let result = f();
+    let count = allocations()
+        .zip(before)
+        .map(|(after, before)| after - before);
+    (result, count)
+}
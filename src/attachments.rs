@@ -0,0 +1,125 @@
+//! Deduplicated attachments with content defined chunking
+//!
+//! Blocks are content addressed, so two payloads that have a chunk in common store it only once.
+//! With fixed size chunks this only works as long as nothing is inserted or removed, since that
+//! shifts all chunk boundaries after it. FastCDC finds chunk boundaries from the content itself,
+//! so after an edit, the boundaries sync up again and only the chunks around the edit are new.
+//!
+//! The chunker reads from any [Read], so a payload never has to be in memory as a whole.
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io::Read,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use fastcdc::v2020::StreamCDC;
+use libipld::{cbor::DagCborCodec, codec::Codec};
+
+use crate::blobs::{self, chunk_links, iter_payloads, put_blob, BlobRef};
+
+/// Minimum, average and maximum chunk size for content defined chunking
+const MIN_CHUNK: usize = 1 << 14;
+const AVG_CHUNK: usize = 1 << 16;
+const MAX_CHUNK: usize = blobs::CHUNK_SIZE;
+
+/// Read the payload from the reader, store it in content defined chunks and return a reference
+pub fn put_stream(
+    store: &mut impl BlockWriter<Sha256Digest>,
+    reader: impl Read,
+) -> anyhow::Result<BlobRef> {
+    let mut links = Vec::new();
+    let mut len = 0u64;
+    for chunk in StreamCDC::new(reader, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK) {
+        let chunk = chunk?;
+        len += chunk.length as u64;
+        links.push(store.put(chunk.data)?);
+    }
+    let manifest = store.put(DagCborCodec.encode(&links)?)?;
+    Ok(BlobRef { manifest, len })
+}
+
+/// Total size of the payloads, and of their distinct chunks
+fn stored_bytes(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    blobs: &[BlobRef],
+) -> anyhow::Result<(u64, u64)> {
+    let mut chunks = BTreeMap::new();
+    for blob in blobs {
+        for link in chunk_links(store, blob)? {
+            if let Entry::Vacant(entry) = chunks.entry(link) {
+                entry.insert(store.get(&link)?.len() as u64);
+            }
+        }
+    }
+    let total = blobs.iter().map(|blob| blob.len).sum();
+    Ok((total, chunks.values().sum()))
+}
+
+#[derive(Debug, Clone)]
+struct AttachmentTT;
+
+impl TreeTypes for AttachmentTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Attachments for camp....";
+}
+
+/// Attach successive versions of a binary state to events, with fixed size and with content
+/// defined chunks, and compare how much is stored
+pub fn attachments_example(
+    mut store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 20usize;
+    println!(
+        "Example: {} events with a 4 MiB snapshot attached, with small edits in between",
+        n
+    );
+    // every version inserts a few bytes somewhere in the previous one
+    let mut state = blobs::noise(4 << 20, 1);
+    let mut versions = Vec::new();
+    for i in 0..n {
+        let at = (i * 1_234_567) % state.len();
+        let edit = blobs::noise(100, i as u64 + 2);
+        state.splice(at..at, edit);
+        versions.push(state.clone());
+    }
+
+    let mut fixed = Vec::new();
+    let mut cdc = Vec::new();
+    for data in &versions {
+        fixed.push(put_blob(&mut store, data, AVG_CHUNK)?);
+        cdc.push(put_stream(&mut store, data.as_slice())?);
+    }
+    let forest = Forest::<AttachmentTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder =
+        StreamBuilder::<AttachmentTT, BlobRef>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, cdc.iter().map(|blob| ((), *blob)))?;
+    let tree = builder.snapshot();
+    for item in iter_payloads(&txn, &tree) {
+        let (i, _, data) = item?;
+        anyhow::ensure!(data == versions[i as usize], "attachment {} changed", i);
+    }
+
+    println!("chunking\tpayload\tstored\tdedup ratio");
+    for (name, blobs) in [("fixed", &fixed), ("fastcdc", &cdc)] {
+        let (total, stored) = stored_bytes(&store, blobs)?;
+        println!(
+            "{}\t{}\t{}\t{:.1}",
+            name,
+            total,
+            stored,
+            total as f64 / stored as f64
+        );
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Events that arrive late
+//!
+//! A tree is ordered by offset, and events can only be appended. When the events should be in key
+//! order, like by time for range queries, an event that arrives after newer ones can not go into
+//! its place. [Backfill] appends it to a small side tree of late events instead, so nothing is
+//! lost, and reads merge both trees, with the late events sorted in memory. Once there are enough
+//! late events, [Backfill::compact] merges them into a new main tree, in key order, and starts
+//! an empty side tree.
+//!
+//! Compaction rewrites the whole main tree, so it should run rarely, e.g. when the late events
+//! make reads noticeably slower.
+use std::time::Instant;
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey, TimeRangeQuery},
+    extsort,
+    merge::{Event, MergeByKey},
+};
+
+/// A main tree in key order, and the events that came too late for it
+pub struct Backfill {
+    config: Config,
+    main: StreamBuilder<ColumnarTT, u64>,
+    late: StreamBuilder<ColumnarTT, u64>,
+    /// the last key of the main tree
+    last: Option<EventKey>,
+}
+
+impl Backfill {
+    pub fn new(config: Config) -> Self {
+        Self {
+            main: StreamBuilder::new(config.clone(), Secrets::default()),
+            late: StreamBuilder::new(config.clone(), Secrets::default()),
+            config,
+            last: None,
+        }
+    }
+
+    /// The number of late events since the last compaction
+    pub fn late(&self) -> u64 {
+        self.late.count()
+    }
+
+    /// Append the events to the main tree, or to the late events if they are older than it
+    pub fn append<R, W>(
+        &mut self,
+        txn: &mut Transaction<ColumnarTT, R, W>,
+        events: impl IntoIterator<Item = Event>,
+    ) -> anyhow::Result<()>
+    where
+        R: ReadOnlyStore<Sha256Digest>,
+        W: BlockWriter<Sha256Digest>,
+    {
+        let mut main = Vec::new();
+        let mut late = Vec::new();
+        let mut last = self.last;
+        for (key, value) in events {
+            if last.map(|last| key < last).unwrap_or_default() {
+                late.push((key, value));
+            } else {
+                last = Some(key);
+                main.push((key, value));
+            }
+        }
+        txn.extend(&mut self.main, main)?;
+        // only once the events are in the main tree, so a failed append can be tried again
+        self.last = last;
+        txn.extend(&mut self.late, late)?;
+        Ok(())
+    }
+
+    /// The late events matching the query, in key order
+    fn late_sorted<R: ReadOnlyStore<Sha256Digest>>(
+        &self,
+        forest: &Forest<ColumnarTT, R>,
+        query: TimeRangeQuery,
+    ) -> anyhow::Result<Vec<Event>> {
+        let mut late = forest
+            .iter_filtered(&self.late.snapshot(), query)
+            .map(|item| item.map(|(_, key, value)| (key, value)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        late.sort_by_key(|(key, _)| *key);
+        Ok(late)
+    }
+
+    /// The events of both trees matching the query, in key order
+    pub fn iter_filtered<R: ReadOnlyStore<Sha256Digest>>(
+        &self,
+        forest: &Forest<ColumnarTT, R>,
+        query: TimeRangeQuery,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Event>>> {
+        let late = self.late_sorted(forest, query.clone())?;
+        let main = forest
+            .iter_filtered(&self.main.snapshot(), query)
+            .map(|item| item.map(|(_, key, value)| (key, value)));
+        Ok(MergeByKey::new(main, late.into_iter().map(Ok)))
+    }
+
+    /// Rebuild the main tree with the late events in their place, and clear the late events
+    pub fn compact<R, W>(&mut self, txn: &mut Transaction<ColumnarTT, R, W>) -> anyhow::Result<()>
+    where
+        R: ReadOnlyStore<Sha256Digest>,
+        W: BlockWriter<Sha256Digest>,
+    {
+        let all = TimeRangeQuery {
+            min: 0,
+            max: u64::MAX,
+        };
+        // a second handle to read from while the transaction writes, so the main tree is
+        // streamed, and only the late events are in memory
+        let forest = Forest::clone(txn);
+        let merged = self.iter_filtered(&forest, all)?;
+        let mut main = StreamBuilder::new(self.config.clone(), Secrets::default());
+        extsort::extend(txn, &mut main, merged)?;
+        self.main = main;
+        self.late = StreamBuilder::new(self.config.clone(), Secrets::default());
+        Ok(())
+    }
+}
+
+/// Events where every 50th one arrives a few hundred events late, queried before and after
+/// compaction
+pub fn backfill_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: {} events with some of them arriving late", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut sorted = columnar::events(n);
+    // the order of arrival
+    let mut arrivals = sorted.clone();
+    sorted.sort_by_key(|(key, _)| *key);
+    for i in (0..arrivals.len() - 300).step_by(50) {
+        let late = arrivals.remove(i);
+        arrivals.insert(i + 300, late);
+    }
+
+    let mut backfill = Backfill::new(config.clone());
+    for chunk in arrivals.chunks(1000) {
+        backfill.append(&mut txn, chunk.iter().cloned())?;
+    }
+    let late = backfill.late();
+    let query = TimeRangeQuery {
+        min: sorted[(n / 3) as usize].0.time,
+        max: sorted[(n / 2) as usize].0.time,
+    };
+    let expected = sorted
+        .iter()
+        .filter(|(key, _)| query.min <= key.time && key.time <= query.max)
+        .cloned()
+        .collect::<Vec<_>>();
+    let before = backfill
+        .iter_filtered(&txn, query.clone())?
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        before == expected,
+        "late events are missing before compaction"
+    );
+
+    let t0 = Instant::now();
+    backfill.compact(&mut txn)?;
+    let compaction = t0.elapsed();
+    anyhow::ensure!(backfill.late() == 0, "compaction left late events");
+    let after = txn
+        .iter_filtered(&backfill.main.snapshot(), query)
+        .map(|item| item.map(|(_, key, value)| (key, value)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        after == expected,
+        "late events are missing after compaction"
+    );
+    println!(
+        "{} late events, {} matching, compacted in {}s",
+        late,
+        after.len(),
+        compaction.as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
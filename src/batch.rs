@@ -0,0 +1,282 @@
+//! Batched block fetches for filtered queries
+//!
+//! The traversal loads the children of a branch one after the other. When a query matches several
+//! children of a branch we already know which blocks it will need, so we can fetch them with
+//! concurrent requests. kubo handles concurrent `block/get` calls fine, and since [IpfsStore] gets
+//! are blocking http requests, a few scoped threads are all we need. The reader of a channel and
+//! the `select` of the server fetch this way from kubo.
+//!
+//! [IpfsStore]: banyan_utils::ipfs::IpfsStore
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    chacha20::XNonce,
+    index::{BranchIndex, Index, LeafIndex},
+    query::Query,
+    store::{BranchCache, MemStore, ReadOnlyStore, ZstdDagCborSeq},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    prefetch::LatencyStore,
+};
+
+/// The number of concurrent gets of the queries that read from kubo
+pub const PARALLELISM: usize = 16;
+
+/// Fetch a number of blocks with at most `parallelism` concurrent gets, in the order of the links
+pub fn get_many<L, S>(store: &S, links: &[L], parallelism: usize) -> anyhow::Result<Vec<Box<[u8]>>>
+where
+    L: Sync,
+    S: ReadOnlyStore<L>,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..links.len()).map(|_| None).collect::<Vec<_>>());
+    thread::scope(|s| {
+        for _ in 0..parallelism.clamp(1, links.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= links.len() {
+                    break;
+                }
+                let block = store.get(&links[i]);
+                results.lock().unwrap()[i] = Some(block);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.expect("every link was fetched"))
+        .collect()
+}
+
+/// A store that serves blocks fetched ahead of time by a [BatchQuery]
+pub struct BatchStore<L, S> {
+    inner: S,
+    fetched: Arc<Mutex<HashMap<L, Box<[u8]>>>>,
+}
+
+impl<L, S: Clone> Clone for BatchStore<L, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            fetched: self.fetched.clone(),
+        }
+    }
+}
+
+impl<L, S> BatchStore<L, S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            fetched: Default::default(),
+        }
+    }
+}
+
+impl<L, S> ReadOnlyStore<L> for BatchStore<L, S>
+where
+    L: Hash + Eq + Send + Sync + 'static,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        // every block is only needed once per traversal
+        if let Some(data) = self.fetched.lock().unwrap().remove(link) {
+            return Ok(data);
+        }
+        self.inner.get(link)
+    }
+}
+
+/// A query wrapper that fetches the children of a branch with a single [get_many] if more than one
+/// of them matches
+///
+/// Branches in the branch cache are neither fetched nor left in the store, since the traversal
+/// takes them from the cache and would never collect them.
+pub struct BatchQuery<Q, T: TreeTypes, S> {
+    inner: Q,
+    store: BatchStore<T::Link, S>,
+    cache: BranchCache<T>,
+    secrets: Secrets,
+    parallelism: usize,
+}
+
+impl<Q, T: TreeTypes, S> BatchQuery<Q, T, S> {
+    /// The store and the cache must be the ones of the forest, so the traversal finds the fetched
+    /// blocks
+    pub fn new(
+        inner: Q,
+        store: BatchStore<T::Link, S>,
+        cache: BranchCache<T>,
+        secrets: Secrets,
+        parallelism: usize,
+    ) -> Self {
+        Self {
+            inner,
+            store,
+            cache,
+            secrets,
+            parallelism,
+        }
+    }
+}
+
+impl<Q: fmt::Debug, T: TreeTypes, S> fmt::Debug for BatchQuery<Q, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BatchQuery")
+            .field("inner", &self.inner)
+            .field("parallelism", &self.parallelism)
+            .finish()
+    }
+}
+
+impl<Q: Clone, T: TreeTypes, S: Clone> Clone for BatchQuery<Q, T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            store: self.store.clone(),
+            cache: self.cache.clone(),
+            secrets: self.secrets.clone(),
+            parallelism: self.parallelism,
+        }
+    }
+}
+
+impl<Q, T, S> BatchQuery<Q, T, S>
+where
+    T: TreeTypes,
+    S: ReadOnlyStore<T::Link>,
+{
+    /// Fetch the branch and the matching children that are not cached, and leave them for the
+    /// traversal
+    fn fetch_children(&self, link: &T::Link, res: &[bool]) -> anyhow::Result<()> {
+        let (children, data) = match self.cache.get(link) {
+            Some(branch) => (branch.children.to_vec(), None),
+            None => {
+                let data = self.store.get(link)?;
+                let nonce = <&XNonce>::from(T::NONCE);
+                let (seq, _) = ZstdDagCborSeq::decrypt(&data, self.secrets.index_key(), nonce)?;
+                (seq.items::<Index<T>>()?, Some(data))
+            }
+        };
+        let links = children
+            .iter()
+            .zip(res)
+            .filter(|(_, matches)| **matches)
+            .filter_map(|(child, _)| *child.link())
+            // only branches are cached
+            .filter(|link| self.cache.get(link).is_none())
+            .collect::<Vec<_>>();
+        let blocks = get_many(&self.store.inner, &links, self.parallelism)?;
+        let mut fetched = self.store.fetched.lock().unwrap();
+        fetched.extend(data.map(|data| (*link, data)));
+        fetched.extend(links.into_iter().zip(blocks));
+        Ok(())
+    }
+
+    /// The number of fetched blocks the traversal has not taken yet
+    pub fn pending(&self) -> usize {
+        self.store.fetched.lock().unwrap().len()
+    }
+}
+
+impl<T, Q, S> Query<T> for BatchQuery<Q, T, S>
+where
+    T: TreeTypes,
+    Q: Query<T>,
+    S: ReadOnlyStore<T::Link>,
+{
+    fn containing(&self, offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        self.inner.containing(offset, index, res)
+    }
+
+    fn intersecting(&self, offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        self.inner.intersecting(offset, index, res);
+        if let Some(link) = &index.link {
+            if res.iter().filter(|x| **x).count() > 1 {
+                // if this fails, the traversal will fail on the same block and report the error
+                let _ = self.fetch_children(link, res);
+            }
+        }
+    }
+}
+
+/// Run a query, returning the number of matches and the elapsed time
+fn query<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<ColumnarTT, R>,
+    tree: &Tree<ColumnarTT, u64>,
+    query: impl Query<ColumnarTT> + Clone,
+) -> anyhow::Result<(u64, f64)> {
+    let t0 = Instant::now();
+    let mut n = 0;
+    for item in forest.iter_filtered(tree, query) {
+        item?;
+        n += 1;
+    }
+    Ok((n, t0.elapsed().as_secs_f64()))
+}
+
+/// Query the middle half of `n` events through a store with the given latency, once with plain
+/// and once with batched fetches, and compare the times
+pub fn report(
+    config: &Config,
+    n: u64,
+    parallelism: usize,
+    latency: Duration,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(n >= 4, "need at least 4 events");
+    let xs = columnar::events(n);
+    let range = TimeRangeQuery {
+        min: xs[xs.len() / 4].0.time,
+        max: xs[xs.len() * 3 / 4].0.time,
+    };
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, xs)?;
+    let tree = builder.snapshot();
+    let slow = LatencyStore::new(store, latency);
+    println!(
+        "querying half of {} events, {}ms per get",
+        n,
+        latency.as_millis()
+    );
+
+    let forest = Forest::new(slow.clone(), BranchCache::default());
+    let (plain_n, plain_dt) = query(&forest, &tree, range.clone())?;
+    println!("plain\t{:.3}s", plain_dt);
+
+    let store = BatchStore::new(slow);
+    let cache = BranchCache::default();
+    let forest = Forest::new(store.clone(), cache.clone());
+    let secrets = tree.secrets().cloned().unwrap_or_default();
+    let batched = BatchQuery::new(range, store, cache, secrets, parallelism);
+    let (n, dt) = query(&forest, &tree, batched.clone())?;
+    println!("batched\t{:.3}s ({} concurrent gets)", dt, parallelism);
+    anyhow::ensure!(n == plain_n, "batched query results differ");
+    // the second time the branches are cached, and nothing is left behind
+    let (n, dt) = query(&forest, &tree, batched.clone())?;
+    println!("cached\t{:.3}s", dt);
+    anyhow::ensure!(n == plain_n, "cached query results differ");
+    anyhow::ensure!(
+        batched.pending() == 0,
+        "{} fetched blocks were never read",
+        batched.pending()
+    );
+    Ok(())
+}
@@ -0,0 +1,157 @@
+//! Large payloads outside of the tree
+//!
+//! A leaf is compressed and encrypted as a whole, and has to be loaded as a whole to get a single
+//! value out of it. So payloads of a few megabytes do not belong in a leaf. Instead we split the
+//! payload into chunks, store each chunk as a raw block, and store a manifest block with the links
+//! to all chunks. The value in the tree is just the link to the manifest and the length.
+//!
+//! The chunks are not encrypted, and since the values of the tree are, nothing outside of banyan
+//! can see that the tree refers to the manifests. So they have to be pinned separately.
+use std::time::Instant;
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+/// Default size of a chunk
+pub const CHUNK_SIZE: usize = 1 << 18;
+
+/// The value stored in the tree for a payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+pub struct BlobRef {
+    /// the manifest with the links to all chunks
+    pub manifest: Sha256Digest,
+    /// total length of the payload in bytes
+    pub len: u64,
+}
+
+/// Store the payload in chunks of the given size and return a reference to it
+pub fn put_blob(
+    store: &mut impl BlockWriter<Sha256Digest>,
+    data: &[u8],
+    chunk_size: usize,
+) -> anyhow::Result<BlobRef> {
+    put_chunks(store, data.chunks(chunk_size.max(1)), data.len())
+}
+
+/// Store the chunks as raw blocks, and a manifest with their links
+pub fn put_chunks<'a>(
+    store: &mut impl BlockWriter<Sha256Digest>,
+    chunks: impl IntoIterator<Item = &'a [u8]>,
+    len: usize,
+) -> anyhow::Result<BlobRef> {
+    let links = chunks
+        .into_iter()
+        .map(|chunk| store.put(chunk.to_vec()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let manifest = store.put(DagCborCodec.encode(&links)?)?;
+    Ok(BlobRef {
+        manifest,
+        len: len as u64,
+    })
+}
+
+/// The links of all chunks of a payload
+pub fn chunk_links(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    blob: &BlobRef,
+) -> anyhow::Result<Vec<Sha256Digest>> {
+    DagCborCodec.decode(&store.get(&blob.manifest)?)
+}
+
+/// Load all chunks of a payload and put them back together
+pub fn get_blob(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    blob: &BlobRef,
+) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(blob.len as usize);
+    for link in chunk_links(store, blob)? {
+        data.extend_from_slice(&store.get(&link)?);
+    }
+    anyhow::ensure!(
+        data.len() as u64 == blob.len,
+        "payload has the wrong length"
+    );
+    Ok(data)
+}
+
+/// Iterate over the tree like `iter_from`, but with the payloads instead of the references
+pub fn iter_payloads<'a, T, R>(
+    forest: &'a Forest<T, R>,
+    tree: &'a Tree<T, BlobRef>,
+) -> impl Iterator<Item = anyhow::Result<(u64, T::Key, Vec<u8>)>> + 'a
+where
+    T: TreeTypes<Link = Sha256Digest>,
+    R: ReadOnlyStore<Sha256Digest>,
+{
+    forest.iter_from(tree).map(move |item| {
+        let (i, k, blob) = item?;
+        Ok((i, k, get_blob(forest.store(), &blob)?))
+    })
+}
+
+#[derive(Debug, Clone)]
+struct BlobTT;
+
+impl TreeTypes for BlobTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Large payloads camp.....";
+}
+
+/// Some bytes that do not compress (xorshift)
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed | 1;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
+
+/// Store events with payloads of up to a few megabytes and read them back
+pub fn blobs_example(
+    mut store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 50usize;
+    println!("Example: {} events with payloads of up to 2 MiB", n);
+    let payloads = (0..n)
+        .map(|i| noise((i * 40503) % (2 << 20), i as u64))
+        .collect::<Vec<_>>();
+    let t0 = Instant::now();
+    let refs = payloads
+        .iter()
+        .map(|data| put_blob(&mut store, data, CHUNK_SIZE))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let forest = Forest::<BlobTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<BlobTT, BlobRef>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, refs.into_iter().map(|r| ((), r)))?;
+    let tree = builder.snapshot();
+    println!("{:#?} {}s", tree, t0.elapsed().as_secs_f64());
+
+    let t0 = Instant::now();
+    let mut bytes = 0;
+    for item in iter_payloads(&txn, &tree) {
+        let (i, _, data) = item?;
+        anyhow::ensure!(data == payloads[i as usize], "payload {} changed", i);
+        bytes += data.len();
+    }
+    println!(
+        "{} payload bytes read back {}s",
+        bytes,
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,460 @@
+//! Scanning values without building them
+//!
+//! A scan through the forest decodes every leaf into a `Vec` of owned values, so a tree of
+//! strings costs an allocation per event, and another one per leaf, just to look at each value
+//! once. [scan] decompresses each leaf into a buffer that it reuses for the whole scan, and hands
+//! the function a [RawValue] for each event, a slice of that buffer with the dag-cbor of the
+//! value. A string or a number can be read from it in place, and anything else decoded from the
+//! slice. What is left is an allocation or two per block, for the data and for the decryption,
+//! and a `Vec` of children per branch.
+//!
+//! [scan_filtered] does the same for the events that match a query. The traversal of the forest
+//! allocates a `Vec<bool>` for the matching children of every branch and the matching events of
+//! every leaf. A scan only looks at one node per depth of the tree at a time, so it keeps one
+//! such buffer per depth, and clears and reuses it for every node at that depth, which is what a
+//! bump allocator that is reset per node would do, without the allocator.
+//!
+//! The slice is only valid during the call, so a value that is needed later has to be decoded or
+//! copied. The tree is walked without the branch cache of the forest.
+use std::{
+    convert::TryFrom,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    chacha20::XNonce,
+    index::{CompactSeq, Index},
+    query::{AllQuery, Query},
+    store::{BlockWriter, BranchCache, ReadOnlyStore, ZstdDagCborSeq},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec};
+
+use crate::{
+    allocs,
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    error::Error,
+    projection::load_children,
+};
+
+/// The dag-cbor of one value, borrowed from the decompressed leaf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawValue<'a>(&'a [u8]);
+
+/// The major type, the argument and the length of the head of a cbor item
+fn head(data: &[u8]) -> anyhow::Result<(u8, u64, usize)> {
+    let first = *data
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("cbor item is cut off"))?;
+    let (major, info) = (first >> 5, first & 0x1f);
+    let len = match info {
+        0..=23 => return Ok((major, info as u64, 1)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        // dag-cbor has no indefinite lengths
+        _ => anyhow::bail!("invalid cbor head {:#x}", first),
+    };
+    let bytes = data
+        .get(1..1 + len)
+        .ok_or_else(|| anyhow::anyhow!("cbor item is cut off"))?;
+    let arg = bytes.iter().fold(0u64, |arg, byte| arg << 8 | *byte as u64);
+    Ok((major, arg, 1 + len))
+}
+
+/// The length of the cbor item at the start of the data
+fn item_len(data: &[u8]) -> anyhow::Result<usize> {
+    let (major, arg, mut len) = head(data)?;
+    let items = match major {
+        // numbers, simple values and floats are just the head
+        0 | 1 | 7 => 0,
+        // bytes and strings
+        2 | 3 => {
+            len = usize::try_from(arg)?
+                .checked_add(len)
+                .ok_or_else(|| anyhow::anyhow!("cbor string of {} bytes", arg))?;
+            0
+        }
+        4 => arg,
+        5 => arg
+            .checked_mul(2)
+            .ok_or_else(|| anyhow::anyhow!("cbor map of {} entries", arg))?,
+        // a tag is followed by one item
+        _ => 1,
+    };
+    for _ in 0..items {
+        len += item_len(data.get(len..).unwrap_or_default())?;
+    }
+    anyhow::ensure!(len <= data.len(), "cbor item is cut off");
+    Ok(len)
+}
+
+impl<'a> RawValue<'a> {
+    pub fn bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match head(self.0).ok()? {
+            (0, arg, _) => Some(arg),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match head(self.0).ok()? {
+            (3, arg, len) => std::str::from_utf8(self.0.get(len..len + arg as usize)?).ok(),
+            _ => None,
+        }
+    }
+}
+
+fn decode_error(link: &impl ToString, cause: impl ToString) -> anyhow::Error {
+    Error::Decode {
+        link: link.to_string(),
+        reason: cause.to_string(),
+    }
+    .into()
+}
+
+/// The largest leaf to decompress, like banyan
+const MAX_LEAF: usize = 16 << 20;
+
+/// A decompressor with a buffer that only grows
+struct Decompressor {
+    zstd: zstd::block::Decompressor,
+    buffer: Vec<u8>,
+}
+
+impl Decompressor {
+    fn new() -> Self {
+        Self {
+            zstd: zstd::block::Decompressor::new(),
+            buffer: Vec::with_capacity(1 << 20),
+        }
+    }
+
+    fn decompress(&mut self, compressed: &[u8]) -> anyhow::Result<&[u8]> {
+        loop {
+            self.buffer.clear();
+            match self.zstd.decompress_to_buffer(compressed, &mut self.buffer) {
+                Ok(_) => return Ok(&self.buffer),
+                // most likely too small, and if not it fails again when it is large enough
+                Err(_) if self.buffer.capacity() < MAX_LEAF => {
+                    self.buffer.reserve(self.buffer.capacity() * 2)
+                }
+                Err(cause) => return Err(cause.into()),
+            }
+        }
+    }
+}
+
+/// The state of a scan, with the buffers it reuses
+struct Scan<'a, T: TreeTypes, R, Q> {
+    store: &'a R,
+    secrets: Secrets,
+    query: &'a Q,
+    decompressor: Decompressor,
+    /// the matching children or events of the node at each depth, see the module docs
+    masks: Vec<Vec<bool>>,
+    _types: PhantomData<T>,
+}
+
+impl<'a, T, R, Q> Scan<'a, T, R, Q>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    Q: Query<T>,
+{
+    /// The mask for a node at this depth, with all of its `n` children or events in it
+    fn mask(&mut self, depth: usize, n: usize) -> Vec<bool> {
+        if self.masks.len() <= depth {
+            self.masks.resize_with(depth + 1, Vec::new);
+        }
+        let mut mask = std::mem::take(&mut self.masks[depth]);
+        mask.clear();
+        mask.resize(n, true);
+        mask
+    }
+
+    fn visit<F>(
+        &mut self,
+        depth: usize,
+        offset: u64,
+        index: &Index<T>,
+        f: &mut F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(u64, T::Key, RawValue) -> anyhow::Result<()>,
+    {
+        match index {
+            Index::Branch(branch) => {
+                let mut mask = self.mask(depth, branch.summaries.count() as usize);
+                self.query.intersecting(offset, branch, &mut mask);
+                // a purged branch has no events to scan
+                if let (Some(link), true) = (&branch.link, mask.contains(&true)) {
+                    let mut offset = offset;
+                    for (child, matches) in load_children::<T>(self.store, &self.secrets, link)?
+                        .iter()
+                        .zip(mask.iter())
+                    {
+                        if *matches {
+                            self.visit(depth + 1, offset, child, f)?;
+                        }
+                        offset += child.count();
+                    }
+                }
+                self.masks[depth] = mask;
+            }
+            Index::Leaf(leaf) => {
+                let mut mask = self.mask(depth, leaf.keys.count() as usize);
+                self.query.containing(offset, leaf, &mut mask);
+                if let (Some(link), true) = (&leaf.link, mask.contains(&true)) {
+                    let data = self.store.get(link)?;
+                    let nonce = <&XNonce>::from(T::NONCE);
+                    let (seq, _) = ZstdDagCborSeq::decrypt(&data, self.secrets.value_key(), nonce)
+                        .map_err(|cause| decode_error(link, cause))?;
+                    let mut values = self
+                        .decompressor
+                        .decompress(seq.compressed())
+                        .map_err(|cause| decode_error(link, cause))?;
+                    for (i, matches) in mask.iter().enumerate() {
+                        let len = item_len(values).map_err(|cause| decode_error(link, cause))?;
+                        if *matches {
+                            let key = leaf.keys.get(i).expect("i < count");
+                            f(offset + i as u64, key, RawValue(&values[..len]))?;
+                        }
+                        values = &values[len..];
+                    }
+                    anyhow::ensure!(
+                        values.is_empty(),
+                        decode_error(link, "the leaf has more values than keys")
+                    );
+                }
+                self.masks[depth] = mask;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Call the function with the offset, the key and the raw value of every event of the tree, see
+/// the module docs
+pub fn scan<T, R, V, F>(store: &R, tree: &Tree<T, V>, f: F) -> anyhow::Result<()>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    F: FnMut(u64, T::Key, RawValue) -> anyhow::Result<()>,
+{
+    scan_filtered(store, tree, &AllQuery, f)
+}
+
+/// Like [scan], but only for the events that match the query
+pub fn scan_filtered<T, R, V, Q, F>(
+    store: &R,
+    tree: &Tree<T, V>,
+    query: &Q,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    Q: Query<T>,
+    F: FnMut(u64, T::Key, RawValue) -> anyhow::Result<()>,
+{
+    let mut scan = Scan {
+        store,
+        secrets: tree.secrets().cloned().unwrap_or_default(),
+        query,
+        decompressor: Decompressor::new(),
+        masks: Vec::new(),
+        _types: PhantomData,
+    };
+    match tree.index() {
+        Some(index) => scan.visit(0, 0, index, &mut f),
+        None => Ok(()),
+    }
+}
+
+/// Print a line of the table of a scan, with `-` for allocations that are not counted
+fn print_scan(name: &str, allocs: Option<u64>, events: u64, time: Duration) {
+    let (allocs, per_event) = match allocs {
+        Some(allocs) => (
+            allocs.to_string(),
+            format!("{:.3}", allocs as f64 / events as f64),
+        ),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    println!(
+        "{}\t{}\t{}\t{:.3}s",
+        name,
+        allocs,
+        per_event,
+        time.as_secs_f64()
+    );
+}
+
+/// Scan a tree of a million strings through the forest and with raw values, and count the
+/// allocations of both with the `count-allocs` feature
+pub fn borrowed_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    println!("Example: scanning {} strings without decoding them", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, String>::new(config.clone(), Secrets::default());
+    let events = columnar::events(n)
+        .into_iter()
+        .map(|(key, value)| (key, format!("reading {:x}", value)))
+        .collect::<Vec<_>>();
+    let expected = (n, events.iter().map(|(_, value)| value.len() as u64).sum());
+    txn.extend(&mut builder, events)?;
+    let tree = builder.snapshot();
+
+    println!("scan\tallocations\tper event\ttime");
+    let t0 = Instant::now();
+    let (owned, owned_allocs) = allocs::count(|| forest_scan(&txn, &tree));
+    let owned_time = t0.elapsed();
+    let t0 = Instant::now();
+    let (borrowed, borrowed_allocs) = allocs::count(|| {
+        let mut res = (0u64, 0u64);
+        scan(&store, &tree, |offset, _, value| {
+            if offset == 0 {
+                let decoded: String = DagCborCodec.decode(value.bytes())?;
+                anyhow::ensure!(Some(decoded.as_str()) == value.as_str(), "{:?}", value);
+            }
+            let value = value
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("{:?} is not a string", value))?;
+            res.0 += 1;
+            res.1 += value.len() as u64;
+            Ok(())
+        })?;
+        anyhow::Ok(res)
+    });
+    let borrowed_time = t0.elapsed();
+    print_scan("forest", owned_allocs, n, owned_time);
+    print_scan("borrowed", borrowed_allocs, n, borrowed_time);
+    anyhow::ensure!(
+        owned? == expected && borrowed? == expected,
+        "the scans saw other values"
+    );
+    if let (Some(borrowed), Some(owned)) = (borrowed_allocs, owned_allocs) {
+        anyhow::ensure!(
+            borrowed * 10 < owned,
+            "the borrowed scan allocates too much"
+        );
+    }
+    println!();
+    Ok(())
+}
+
+/// The number of events and the total length of the strings, from a scan through the forest
+fn forest_scan<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<ColumnarTT, R>,
+    tree: &Tree<ColumnarTT, String>,
+) -> anyhow::Result<(u64, u64)> {
+    let mut res = (0u64, 0u64);
+    for item in forest.iter_filtered(tree, AllQuery) {
+        let (_, _, value) = item?;
+        res.0 += 1;
+        res.1 += value.len() as u64;
+    }
+    Ok(res)
+}
+
+/// Sum the values in a time range of a million events, through the forest and with
+/// [scan_filtered], and count the allocations of both with the `count-allocs` feature
+pub fn filtered_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    let events = columnar::events(n);
+    let (first, last) = (events[0].0.time, events[events.len() - 1].0.time);
+    let query = TimeRangeQuery {
+        min: first + (last - first) / 10 * 4,
+        max: first + (last - first) / 10 * 5,
+    };
+    println!(
+        "Example: summing the values in a tenth of the time range of {} events",
+        n
+    );
+    // small leaves, so there are many branches and leaves to filter
+    let config = &Config {
+        target_leaf_size: 1 << 12,
+        max_leaf_count: 1 << 10,
+        ..config.clone()
+    };
+    config.validate()?;
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let expected = events
+        .iter()
+        .filter(|(key, _)| key.time >= query.min && key.time <= query.max)
+        .fold((0u64, 0u64), |(count, sum), (_, value)| {
+            (count + 1, sum + value)
+        });
+    txn.extend(&mut builder, events)?;
+    let tree = builder.snapshot();
+
+    println!("scan\tallocations\tper event\ttime");
+    let t0 = Instant::now();
+    let (forest, forest_allocs) = allocs::count(|| {
+        let mut res = (0u64, 0u64);
+        for item in txn.iter_filtered(&tree, query.clone()) {
+            let (_, _, value) = item?;
+            res = (res.0 + 1, res.1 + value);
+        }
+        anyhow::Ok(res)
+    });
+    let forest_time = t0.elapsed();
+    let t0 = Instant::now();
+    let (scratch, scratch_allocs) = allocs::count(|| {
+        let mut res = (0u64, 0u64);
+        scan_filtered(&store, &tree, &query, |_, _, value| {
+            let value = value
+                .as_u64()
+                .ok_or_else(|| anyhow::anyhow!("{:?} is not a number", value))?;
+            res = (res.0 + 1, res.1 + value);
+            Ok(())
+        })?;
+        anyhow::Ok(res)
+    });
+    let scratch_time = t0.elapsed();
+    print_scan("forest", forest_allocs, expected.0, forest_time);
+    print_scan("scratch", scratch_allocs, expected.0, scratch_time);
+    anyhow::ensure!(
+        forest? == expected && scratch? == expected,
+        "the scans saw other events"
+    );
+    if let (Some(scratch), Some(forest)) = (scratch_allocs, forest_allocs) {
+        anyhow::ensure!(
+            scratch < forest,
+            "the scan with scratch buffers allocates more"
+        );
+    }
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths_that_overflow() {
+        // a map and a string with u64::MAX entries and bytes
+        for major in [0xbb, 0x7b] {
+            let mut data = vec![major];
+            data.extend_from_slice(&u64::MAX.to_be_bytes());
+            assert!(item_len(&data).is_err());
+        }
+    }
+}
@@ -0,0 +1,452 @@
+//! Exactly-once ingestion from a message stream into a tree
+//!
+//! A [Source] delivers records until they are acked, and again after a crash if they are not. So
+//! the [Bridge] acks a batch only after it is in a [Wal], with the sequence number of the newest
+//! record in it. That alone is at least once: a crash between the log and the acks delivers the
+//! batch a second time. So the bridge skips every record it delivers again that is not newer than
+//! what is in the log.
+//!
+//! Every few batches, the bridge persists a snapshot of everything in the log, and empties it. The
+//! snapshot is a [Head] block with the root and the newest sequence number as the id of the batch,
+//! appended with [append_batch]. Its link is swapped in a [RootStore] under the name of the
+//! bridge, so a second bridge on the same consumer fails instead of writing over the first. The
+//! store is synced before the swap, and the log is emptied after it. On startup, the bridge
+//! appends the batches in the log that are newer than the snapshot, so an acked record is never
+//! only in blocks that a crash can lose.
+//!
+//! A record is a json object like `{"device": 7, "value": 42}`, with an optional `time` in unix
+//! milliseconds, which defaults to the time the source stored the record. A record that is not
+//! like that can never go into the tree, so it is rejected and not delivered again.
+//!
+//! The only source of the `bridge` command is a NATS JetStream pull consumer, see [nats]. There is
+//! no Kafka client without a lot of dependencies, and no way to try one here.
+//!
+//! [nats]: crate::nats
+use std::{collections::VecDeque, path::Path, time::Duration};
+
+use banyan::{
+    store::{BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use serde_json::Value;
+
+use crate::{
+    columnar::{ColumnarTT, EventKey},
+    fs_store::DurableStore,
+    idempotent::{self, append_batch, Head},
+    roots::{ManifestFile, RootStore},
+    wal::{self, Wal},
+    webhooks::{self, Notifier},
+};
+
+/// A record from a source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// the position in the stream, which only grows
+    pub sequence: u64,
+    /// when the source stored it, in unix milliseconds
+    pub time: u64,
+    pub payload: Vec<u8>,
+    /// where to ack it
+    pub ack: String,
+}
+
+/// Where records come from
+pub trait Source {
+    /// Up to `n` records that were not acked, waiting up to `wait` for them
+    fn fetch(&mut self, n: usize, wait: Duration) -> anyhow::Result<Vec<Record>>;
+
+    /// The record is in the log, and should not be delivered again
+    fn ack(&mut self, record: &Record) -> anyhow::Result<()>;
+
+    /// The record can not be used, and should not be delivered again
+    fn reject(&mut self, record: &Record) -> anyhow::Result<()>;
+}
+
+/// What the bridge did so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BridgeStats {
+    pub records: u64,
+    pub appended: u64,
+    /// records that were delivered again after they were persisted
+    pub duplicates: u64,
+    pub rejected: u64,
+    pub snapshots: u64,
+}
+
+/// The event in a record
+fn event(record: &Record) -> anyhow::Result<(EventKey, u64)> {
+    let value: Value = serde_json::from_slice(&record.payload)?;
+    let field = |name: &str| value[name].as_u64();
+    let device = field("device")
+        .and_then(|device| u32::try_from(device).ok())
+        .ok_or_else(|| anyhow::anyhow!("no device in {}", value))?;
+    let time = field("time").unwrap_or(record.time);
+    let value = field("value").ok_or_else(|| anyhow::anyhow!("no value in {}", value))?;
+    Ok((EventKey { time, device }, value))
+}
+
+/// Appends records from a source to a tree, see the module docs
+pub struct Bridge<S, M> {
+    name: String,
+    txn: Transaction<ColumnarTT, S, S>,
+    store: S,
+    manifest: M,
+    config: Config,
+    secrets: Secrets,
+    /// the batch of the head is the sequence of the newest record in the tree
+    head: Head,
+    wal: Wal,
+    /// the events in the log, which are not in the head yet
+    pending: Vec<(EventKey, u64)>,
+    /// the sequence of the newest record in the log, or in the head
+    sequence: u64,
+    stats: BridgeStats,
+}
+
+impl<S, M> Bridge<S, M>
+where
+    S: ReadOnlyStore<Sha256Digest> + DurableStore<Sha256Digest>,
+    M: RootStore<Sha256Digest>,
+{
+    /// Continue from the head in the manifest and the batches in the log, or start a new tree
+    pub fn open(
+        name: &str,
+        store: S,
+        manifest: M,
+        wal: &Path,
+        config: &Config,
+        secrets: &Secrets,
+    ) -> anyhow::Result<Self> {
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let txn = Transaction::new(forest, store.clone());
+        let head = Self::load_head(&store, &manifest, name)?;
+        let (wal, entries) = Wal::open(wal)?;
+        let mut pending = Vec::new();
+        let mut sequence = head.batch;
+        // a crash after the snapshot and before the checkpoint leaves batches that are in it
+        for entry in entries {
+            let (newest, events) = wal::decode_batch(&entry)?;
+            if newest > sequence {
+                pending.extend(events);
+                sequence = newest;
+            }
+        }
+        Ok(Self {
+            name: name.to_string(),
+            txn,
+            store,
+            manifest,
+            config: config.clone(),
+            secrets: secrets.clone(),
+            head,
+            wal,
+            pending,
+            sequence,
+            stats: BridgeStats::default(),
+        })
+    }
+
+    fn load_head(store: &S, manifest: &M, name: &str) -> anyhow::Result<Head> {
+        let head = idempotent::head(store, manifest, name)?.map(|(_, head)| head);
+        Ok(head.unwrap_or(Head {
+            tree: None,
+            batch: 0,
+        }))
+    }
+
+    pub fn head(&self) -> &Head {
+        &self.head
+    }
+
+    /// The sequence of the newest record in the log or in the head
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn stats(&self) -> &BridgeStats {
+        &self.stats
+    }
+
+    pub fn tree(&self) -> anyhow::Result<Tree<ColumnarTT, u64>> {
+        match self.head.tree {
+            Some(root) => self.txn.load_tree(self.secrets.clone(), root),
+            None => Ok(Tree::default()),
+        }
+    }
+
+    /// Append the new events of the records to the log, without acking. Returns the records to
+    /// reject
+    pub fn append<'a>(&mut self, records: &'a [Record]) -> anyhow::Result<Vec<&'a Record>> {
+        let mut events = Vec::new();
+        let mut rejected = Vec::new();
+        let mut sequence = self.sequence;
+        for record in records {
+            self.stats.records += 1;
+            if record.sequence <= sequence {
+                self.stats.duplicates += 1;
+                continue;
+            }
+            sequence = record.sequence;
+            match event(record) {
+                Ok(event) => events.push(event),
+                Err(cause) => {
+                    tracing::warn!("rejecting record {}: {}", record.sequence, cause);
+                    self.stats.rejected += 1;
+                    rejected.push(record);
+                }
+            }
+        }
+        if sequence == self.sequence {
+            return Ok(rejected);
+        }
+        self.wal.append(&wal::encode_batch(sequence, &events))?;
+        self.stats.appended += events.len() as u64;
+        self.pending.extend(events);
+        self.sequence = sequence;
+        Ok(rejected)
+    }
+
+    /// Persist a snapshot of the events in the log, and empty it. Returns false if there was
+    /// nothing new
+    pub fn snapshot(&mut self) -> anyhow::Result<bool> {
+        if self.sequence == self.head.batch {
+            return Ok(false);
+        }
+        let mut writer = self.store.clone();
+        let sequence = self.sequence;
+        anyhow::ensure!(
+            append_batch(
+                &mut self.txn,
+                &mut writer,
+                &self.manifest,
+                &self.name,
+                &self.config,
+                &self.secrets,
+                sequence,
+                std::mem::take(&mut self.pending),
+            )?,
+            "{} is already after sequence {}, is another bridge running?",
+            self.name,
+            sequence
+        );
+        self.head = Self::load_head(&self.store, &self.manifest, &self.name)?;
+        self.wal.checkpoint()?;
+        self.stats.snapshots += 1;
+        Ok(true)
+    }
+
+    /// Fetch a batch, append it, and ack it once it is in the log. Returns the number of records
+    /// fetched
+    pub fn step(
+        &mut self,
+        source: &mut impl Source,
+        batch: usize,
+        wait: Duration,
+    ) -> anyhow::Result<usize> {
+        let records = source.fetch(batch, wait)?;
+        let rejected = self.append(&records)?;
+        for record in &records {
+            match rejected.contains(&record) {
+                true => source.reject(record)?,
+                false => source.ack(record)?,
+            }
+        }
+        Ok(records.len())
+    }
+}
+
+/// Persist a snapshot if there is something new, print it and tell the notifier
+fn snapshot<S, M>(bridge: &mut Bridge<S, M>, notifier: Option<&Notifier>) -> anyhow::Result<()>
+where
+    S: ReadOnlyStore<Sha256Digest> + DurableStore<Sha256Digest>,
+    M: RootStore<Sha256Digest>,
+{
+    if !bridge.snapshot()? {
+        return Ok(());
+    }
+    if let Some(notifier) = notifier {
+        notifier.notify(webhooks::record(&bridge.name, &bridge.tree()?));
+    }
+    let Head { tree, batch } = bridge.head();
+    let root = tree.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+    println!("{}\t{}\t{}", batch, bridge.stats().appended, root);
+    Ok(())
+}
+
+/// Run a bridge from a source into a tree in a store, until `count` records are fetched. It
+/// persists a snapshot every `snapshot_every` batches, when there is nothing to fetch, and at the
+/// end, and tells the notifier about every snapshot
+#[allow(clippy::too_many_arguments)]
+pub fn run<S: ReadOnlyStore<Sha256Digest> + DurableStore<Sha256Digest>>(
+    name: &str,
+    source: &mut impl Source,
+    store: S,
+    manifest: &Path,
+    wal: &Path,
+    config: &Config,
+    secrets: &Secrets,
+    batch: usize,
+    snapshot_every: usize,
+    count: Option<u64>,
+    notifier: Option<Notifier>,
+) -> anyhow::Result<()> {
+    let manifest = ManifestFile::new(manifest);
+    let mut bridge = Bridge::open(name, store, manifest, wal, config, secrets)?;
+    eprintln!(
+        "{} continues after sequence {} at {:?}, {} events in the log",
+        name,
+        bridge.head().batch,
+        bridge.head().tree,
+        bridge.pending.len(),
+    );
+    let wait = Duration::from_secs(5);
+    let mut batches = 0;
+    while count.is_none_or(|count| bridge.stats().records < count) {
+        match bridge.step(source, batch, wait)? {
+            0 => batches = snapshot_every,
+            _ => batches += 1,
+        }
+        if batches >= snapshot_every {
+            snapshot(&mut bridge, notifier.as_ref())?;
+            batches = 0;
+        }
+    }
+    snapshot(&mut bridge, notifier.as_ref())?;
+    let stats = bridge.stats();
+    eprintln!(
+        "{} records, {} appended, {} duplicates, {} rejected, {} snapshots",
+        stats.records, stats.appended, stats.duplicates, stats.rejected, stats.snapshots
+    );
+    Ok(())
+}
+
+/// A source in memory that delivers everything that was not acked, again after a crash
+#[derive(Debug, Default)]
+struct MemorySource {
+    records: VecDeque<Record>,
+    /// delivered and not acked yet
+    inflight: Vec<Record>,
+}
+
+impl MemorySource {
+    /// The consumer reconnects, and gets everything that was not acked again
+    fn crash(&mut self) {
+        for record in self.inflight.drain(..).rev() {
+            self.records.push_front(record);
+        }
+    }
+
+    fn done(&mut self, record: &Record) -> anyhow::Result<()> {
+        let before = self.inflight.len();
+        self.inflight.retain(|x| x.ack != record.ack);
+        anyhow::ensure!(
+            self.inflight.len() < before,
+            "{} was not delivered",
+            record.ack
+        );
+        Ok(())
+    }
+}
+
+impl Source for MemorySource {
+    fn fetch(&mut self, n: usize, _wait: Duration) -> anyhow::Result<Vec<Record>> {
+        let records = (0..n)
+            .map_while(|_| self.records.pop_front())
+            .collect::<Vec<_>>();
+        self.inflight.extend(records.iter().cloned());
+        Ok(records)
+    }
+
+    fn ack(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.done(record)
+    }
+
+    fn reject(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.done(record)
+    }
+}
+
+/// Bridge records into a tree, crash after a batch is in the log before the acks, and recover
+pub fn bridge_example(config: &Config) -> anyhow::Result<()> {
+    let n = 1000u64;
+    let batch = 100;
+    println!(
+        "Example: bridging {} records in batches of {}, with a crash",
+        n, batch
+    );
+    let t0 = 1_600_000_000_000u64;
+    let mut source = MemorySource::default();
+    for sequence in 1..=n {
+        let payload = match sequence {
+            // someone published garbage
+            500 => b"not json".to_vec(),
+            _ => format!(r#"{{"device": {}, "value": {}}}"#, sequence % 10, sequence).into_bytes(),
+        };
+        source.records.push_back(Record {
+            sequence,
+            time: t0 + sequence,
+            payload,
+            ack: format!("ack.{}", sequence),
+        });
+    }
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let temp = std::env::temp_dir().join(format!("banyan-bridge-{}", std::process::id()));
+    let (manifest, wal) = (temp.with_extension("manifest"), temp.with_extension("wal"));
+    let secrets = Secrets::default();
+    let wait = Duration::ZERO;
+    let open = || {
+        let manifest = ManifestFile::new(&manifest);
+        Bridge::open("example", store.clone(), manifest, &wal, config, &secrets)
+    };
+    let mut bridge = open()?;
+    for _ in 0..2 {
+        bridge.step(&mut source, batch, wait)?;
+    }
+    bridge.snapshot()?;
+    bridge.step(&mut source, batch, wait)?;
+    // the next batch is in the log, but the process dies before it acks
+    let records = source.fetch(batch, wait)?;
+    bridge.append(&records)?;
+    drop(bridge);
+    source.crash();
+
+    let mut bridge = open()?;
+    anyhow::ensure!(
+        bridge.head().batch == 200 && bridge.sequence() == 400,
+        "recovered at {} with {} in the log",
+        bridge.head().batch,
+        bridge.sequence()
+    );
+    while bridge.step(&mut source, batch, wait)? > 0 {}
+    bridge.snapshot()?;
+    anyhow::ensure!(source.inflight.is_empty(), "records were not acked");
+    let stats = *bridge.stats();
+    anyhow::ensure!(
+        stats.duplicates == batch as u64 && stats.rejected == 1,
+        "{:?}",
+        stats
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store, BranchCache::new(1 << 20));
+    let root = bridge.head().tree.expect("not empty");
+    let tree = forest.load_tree::<u64>(secrets, root)?;
+    let values = forest
+        .iter_from(&tree)
+        .map(|item| item.map(|(_, _, value)| value))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        values.into_iter().eq((1..=n).filter(|x| *x != 500)),
+        "events are missing or twice in the tree"
+    );
+    println!(
+        "{} events in the tree, {} duplicates skipped after the crash, {} rejected",
+        tree.count(),
+        stats.duplicates,
+        stats.rejected
+    );
+    std::fs::remove_file(&manifest)?;
+    std::fs::remove_file(&wal)?;
+    println!();
+    Ok(())
+}
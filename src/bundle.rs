@@ -0,0 +1,171 @@
+//! A tree as a single, self describing file
+//!
+//! A CAR file with a tree is only useful to someone who knows the tree types, and which of the
+//! roots is the tree. A bundle is a CAR file with a single root, a [BundleManifest] block with the
+//! name, the tree types, the size and the root of the tree, and a link to its [TreeSchema]. Since
+//! the manifest links to both, [write_car] includes them with all blocks of the tree.
+//!
+//! [open_bundle] finds the driver for the tree, checks that its schema is the one in the bundle,
+//! and returns the store with the blocks, which can be queried like any other store. The bundle
+//! has no secrets, so it only works for trees with the default secrets, like all in this crate.
+use std::{fs, path::Path};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+use crate::{
+    car::{write_car, CarStore},
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    drivers::Registry,
+    error::Error,
+    overlay::OverlayStore,
+    schema::TreeSchema,
+};
+
+/// The root block of a bundle
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct BundleManifest {
+    pub name: String,
+    /// the name of the driver for the tree types
+    pub types: String,
+    pub count: u64,
+    pub tree: Sha256Digest,
+    pub schema: Sha256Digest,
+}
+
+/// Write the tree with its manifest and schema to a bundle file
+pub fn write_bundle<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    name: &str,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<BundleManifest> {
+    let registry = Registry::builtin();
+    let (driver, stats) = registry.detect(store, root, &Secrets::default())?;
+    // the manifest and schema are new blocks, the store might be read-only
+    let mut blocks = OverlayStore::new(
+        store.clone(),
+        MemStore::new(usize::MAX, Sha256Digest::digest),
+    );
+    let schema = blocks.put(DagCborCodec.encode(&driver.schema())?)?;
+    let manifest = BundleManifest {
+        name: name.to_string(),
+        types: driver.name().to_string(),
+        count: stats.count,
+        tree: root,
+        schema,
+    };
+    let link = blocks.put(DagCborCodec.encode(&manifest)?)?;
+    write_car(&blocks, &[link], path)?;
+    Ok(manifest)
+}
+
+/// The blocks and manifest of a bundle, after checking that the tree matches its schema
+pub fn open_bundle(path: impl AsRef<Path>) -> anyhow::Result<(CarStore, BundleManifest)> {
+    let store = CarStore::open(path)?;
+    let link = match store.roots() {
+        [link] => *link,
+        roots => anyhow::bail!("a bundle has one root, not {}", roots.len()),
+    };
+    let manifest: BundleManifest = DagCborCodec.decode(&store.get(&link)?)?;
+    let schema: TreeSchema = DagCborCodec.decode(&store.get(&manifest.schema)?)?;
+    let registry = Registry::builtin();
+    let (driver, stats) = registry.detect(&store, manifest.tree, &Secrets::default())?;
+    if driver.schema() != schema {
+        return Err(Error::SchemaMismatch {
+            name: manifest.name,
+            expected: schema,
+            actual: driver.schema(),
+        }
+        .into());
+    }
+    anyhow::ensure!(
+        driver.name() == manifest.types && stats.count == manifest.count,
+        "bundle {} says {} events of {}, but has {} of {}",
+        manifest.name,
+        manifest.count,
+        manifest.types,
+        stats.count,
+        driver.name()
+    );
+    Ok((store, manifest))
+}
+
+/// Bundle a tree in kubo into a file
+pub fn print_bundle<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    name: &str,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let manifest = write_bundle(store, root, name, path)?;
+    println!(
+        "{} events of {} as {} in {}, {} bytes",
+        manifest.count,
+        manifest.types,
+        manifest.name,
+        path.display(),
+        fs::metadata(path)?.len()
+    );
+    Ok(())
+}
+
+/// Open a bundle, check it and print its manifest and schema
+pub fn print_open_bundle(config: &Config, path: &Path) -> anyhow::Result<()> {
+    let (store, manifest) = open_bundle(path)?;
+    let schema: TreeSchema = DagCborCodec.decode(&store.get(&manifest.schema)?)?;
+    println!("name\t{}", manifest.name);
+    println!("types\t{}", manifest.types);
+    println!("count\t{}", manifest.count);
+    println!("tree\t{}", manifest.tree);
+    println!("key\t{}", schema.key);
+    println!("value\t{}", schema.value);
+    let registry = Registry::builtin();
+    let (driver, _) = registry.detect(&store, manifest.tree, &Secrets::default())?;
+    driver.verify(&store, config, manifest.tree)?;
+    println!("{} blocks, all events are valid", store.blocks());
+    Ok(())
+}
+
+/// Bundle a tree into a file, open the file like someone who only got it, and query it
+pub fn bundle_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: {} events in a bundle file", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let events = columnar::events(n);
+    let query = TimeRangeQuery {
+        min: events[1000].0.time,
+        max: events[1999].0.time,
+    };
+    txn.extend(&mut builder, events)?;
+    let root = builder.link().expect("not empty");
+
+    let path = std::env::temp_dir().join(format!("banyan-bundle-{}.car", std::process::id()));
+    let written = write_bundle(&store, root, "events", &path)?;
+    let (bundle, manifest) = open_bundle(&path)?;
+    anyhow::ensure!(manifest == written, "manifest changed");
+    let forest = Forest::<ColumnarTT, _>::new(bundle.clone(), BranchCache::new(1024));
+    let tree = forest.load_tree::<u64>(Secrets::default(), manifest.tree)?;
+    let matches = forest.iter_filtered(&tree, query).count();
+    anyhow::ensure!(matches >= 1000, "events are missing in the bundle");
+    println!(
+        "{} of {} with {} blocks in {} bytes, {} events in the query",
+        manifest.name,
+        manifest.types,
+        bundle.blocks(),
+        fs::metadata(&path)?.len(),
+        matches
+    );
+    fs::remove_file(&path)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,479 @@
+//! An event log for C and C++ programs
+//!
+//! The library target of the crate is this module alone, built as a shared and a static library,
+//! so a C or C++ program can keep an event log on banyan: open a log in a directory, append
+//! batches of values, snapshot it, and iterate over the values with a callback. The functions are
+//! declared in `include/banyan_camp.h`, which is kept by hand, and a test checks that it declares
+//! every function here with the same signature.
+//!
+//! A log is a directory with a file per block, named by its cid, and a `root` file with the cid of
+//! the last snapshot. Opening a log continues after its last snapshot, so values appended after
+//! it are lost. Values are opaque bytes, stored as dag-cbor byte strings, and the keys are unit,
+//! so values are selected by offset.
+//!
+//! The functions return 0 when they worked and -1 when not, and [banyan_last_error] has the
+//! message of the last failure on the calling thread. A panic never unwinds into the caller, it is
+//! a failure like any other.
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    fs::{self, File},
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    ptr, slice,
+    str::FromStr,
+    sync::Arc,
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Ipld;
+
+#[derive(Debug, Clone)]
+struct CabiTT;
+
+impl TreeTypes for CabiTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Event log for C callers.";
+}
+
+/// Write a file so that it is either all there or not at all, even after a crash
+fn write_durable(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The blocks of a log, a file per block
+#[derive(Debug, Clone)]
+struct DirStore(Arc<PathBuf>);
+
+impl ReadOnlyStore<Sha256Digest> for DirStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        Ok(fs::read(self.0.join(link.to_string()))?.into())
+    }
+}
+
+impl BlockWriter<Sha256Digest> for DirStore {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+        let link = Sha256Digest::digest(&data);
+        let path = self.0.join(link.to_string());
+        if !path.exists() {
+            write_durable(&path, &data)?;
+        }
+        Ok(link)
+    }
+}
+
+/// An open log, see the module docs
+pub struct BanyanLog {
+    dir: PathBuf,
+    txn: Transaction<CabiTT, DirStore, DirStore>,
+    builder: StreamBuilder<CabiTT, Ipld>,
+}
+
+impl BanyanLog {
+    fn open(dir: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let store = DirStore(Arc::new(dir.clone()));
+        let forest = Forest::new(store.clone(), BranchCache::new(1 << 20));
+        let builder = match fs::read_to_string(dir.join("root")) {
+            Ok(root) => forest.load_stream_builder(
+                Secrets::default(),
+                Config::debug_fast(),
+                Sha256Digest::from_str(root.trim())?,
+            )?,
+            Err(cause) if cause.kind() == std::io::ErrorKind::NotFound => {
+                StreamBuilder::new(Config::debug_fast(), Secrets::default())
+            }
+            Err(cause) => return Err(cause.into()),
+        };
+        Ok(Self {
+            dir,
+            txn: Transaction::new(forest, store),
+            builder,
+        })
+    }
+
+    /// Write the root, after the blocks and their directory are on disk
+    fn snapshot(&mut self) -> anyhow::Result<Option<Sha256Digest>> {
+        let Some(root) = self.builder.snapshot().link() else {
+            return Ok(None);
+        };
+        File::open(&self.dir)?.sync_all()?;
+        write_durable(&self.dir.join("root"), root.to_string().as_bytes())?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(Some(root))
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run a function, and turn a panic into an error, so it does not unwind across the C boundary
+fn catch<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|x| x.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(anyhow::anyhow!("panicked: {}", message))
+    })
+}
+
+/// Run a function, and turn its result or its panic into a return code
+fn code(f: impl FnOnce() -> anyhow::Result<()>) -> c_int {
+    match catch(f) {
+        Ok(()) => 0,
+        Err(cause) => {
+            let message =
+                CString::new(format!("{:#}", cause).replace('\0', " ")).expect("no nul bytes");
+            // the message of a panic in a borrow of the last error is lost
+            let _ = panic::catch_unwind(|| LAST_ERROR.with(|e| *e.borrow_mut() = Some(message)));
+            -1
+        }
+    }
+}
+
+/// The message of the last failure on this thread, or null if there was none. It stays valid
+/// until the next failure on the same thread
+#[no_mangle]
+pub extern "C" fn banyan_last_error() -> *const c_char {
+    catch(|| Ok(LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))))
+        .unwrap_or(ptr::null())
+}
+
+/// Open the log in a directory, and create it if it is not there. Returns null on failure
+///
+/// # Safety
+///
+/// `dir` has to be a nul terminated string
+#[no_mangle]
+pub unsafe extern "C" fn banyan_log_open(dir: *const c_char) -> *mut BanyanLog {
+    let mut log = ptr::null_mut();
+    code(|| {
+        let dir = CStr::from_ptr(dir).to_str()?;
+        log = Box::into_raw(Box::new(BanyanLog::open(dir.into())?));
+        Ok(())
+    });
+    log
+}
+
+/// Close a log. Values appended after the last snapshot are lost
+///
+/// # Safety
+///
+/// `log` has to come from [banyan_log_open], and is not valid afterwards
+#[no_mangle]
+pub unsafe extern "C" fn banyan_log_close(log: *mut BanyanLog) {
+    if !log.is_null() {
+        // nothing to tell the caller if dropping panics
+        let _ = catch(|| {
+            drop(Box::from_raw(log));
+            Ok(())
+        });
+    }
+}
+
+/// Append `count` values, where value `i` is `lens[i]` bytes at `values[i]`
+///
+/// # Safety
+///
+/// `log` has to be open, and `values` and `lens` have to point to `count` elements each. A value
+/// of length 0 may be null
+#[no_mangle]
+pub unsafe extern "C" fn banyan_log_append(
+    log: *mut BanyanLog,
+    values: *const *const u8,
+    lens: *const usize,
+    count: usize,
+) -> c_int {
+    let log = &mut *log;
+    code(|| {
+        if count == 0 {
+            return Ok(());
+        }
+        let values = slice::from_raw_parts(values, count);
+        let lens = slice::from_raw_parts(lens, count);
+        let values = values
+            .iter()
+            .zip(lens)
+            .map(|(value, len)| {
+                // an empty value may be null, which from_raw_parts does not allow
+                let bytes = match *len {
+                    0 => Vec::new(),
+                    len => slice::from_raw_parts(*value, len).to_vec(),
+                };
+                ((), Ipld::Bytes(bytes))
+            })
+            .collect::<Vec<_>>();
+        log.txn.extend(&mut log.builder, values)
+    })
+}
+
+/// The number of values in the log, which is the offset of the next one, or `u64::MAX` if it
+/// panicked
+///
+/// # Safety
+///
+/// `log` has to be open
+#[no_mangle]
+pub unsafe extern "C" fn banyan_log_count(log: *const BanyanLog) -> u64 {
+    catch(|| Ok((*log).builder.snapshot().count())).unwrap_or(u64::MAX)
+}
+
+/// Make the values appended so far durable, and write the cid of the root as a nul terminated
+/// string to `cid`, or an empty string if the log is empty. Fails if `cid_len` is too short,
+/// 64 bytes are always enough
+///
+/// # Safety
+///
+/// `log` has to be open, and `cid` has to point to `cid_len` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn banyan_log_snapshot(
+    log: *mut BanyanLog,
+    cid: *mut c_char,
+    cid_len: usize,
+) -> c_int {
+    let log = &mut *log;
+    code(|| {
+        let text = log
+            .snapshot()?
+            .map(|root| root.to_string())
+            .unwrap_or_default();
+        anyhow::ensure!(
+            text.len() < cid_len,
+            "the cid needs {} bytes, not {}",
+            text.len() + 1,
+            cid_len
+        );
+        ptr::copy_nonoverlapping(text.as_ptr(), cid as *mut u8, text.len());
+        *cid.add(text.len()) = 0;
+        Ok(())
+    })
+}
+
+/// The callback of [banyan_log_iter], with the context, the offset and the bytes of a value.
+/// Returning anything but 0 stops the iteration
+pub type BanyanLogCallback =
+    unsafe extern "C" fn(ctx: *mut c_void, offset: u64, value: *const u8, len: usize) -> c_int;
+
+/// Call `callback` for every value from offset `from` on, in order, including the values that
+/// are not in a snapshot yet
+///
+/// # Safety
+///
+/// `log` has to be open, and `callback` has to be safe to call with `ctx`
+#[no_mangle]
+pub unsafe extern "C" fn banyan_log_iter(
+    log: *const BanyanLog,
+    from: u64,
+    callback: BanyanLogCallback,
+    ctx: *mut c_void,
+) -> c_int {
+    let log = &*log;
+    code(|| {
+        let tree = log.builder.snapshot();
+        for item in log.txn.iter_filtered(&tree, OffsetRangeQuery::from(from..)) {
+            let (offset, _, value) = item?;
+            let Ipld::Bytes(value) = value else {
+                anyhow::bail!("value {} is not bytes", offset);
+            };
+            if callback(ctx, offset, value.as_ptr(), value.len()) != 0 {
+                break;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn collect(
+        ctx: *mut c_void,
+        offset: u64,
+        value: *const u8,
+        len: usize,
+    ) -> c_int {
+        let values = &mut *(ctx as *mut Vec<(u64, Vec<u8>)>);
+        values.push((offset, slice::from_raw_parts(value, len).to_vec()));
+        0
+    }
+
+    /// The C type of a Rust type of a signature here
+    fn c_type(rust: &str) -> String {
+        let pointer = |inner: String, qualifier: &str| match inner.ends_with('*') {
+            true => format!("{}{}*", inner, qualifier),
+            false => format!("{}{} *", qualifier, inner),
+        };
+        if let Some(inner) = rust.strip_prefix("*const ") {
+            return pointer(c_type(inner), "const ").replace("const const", "const");
+        }
+        if let Some(inner) = rust.strip_prefix("*mut ") {
+            return pointer(c_type(inner), "");
+        }
+        match rust {
+            "c_char" => "char",
+            "c_int" => "int",
+            "c_void" => "void",
+            "u8" => "uint8_t",
+            "u64" => "uint64_t",
+            "usize" => "size_t",
+            "BanyanLog" | "BanyanLogCallback" => rust,
+            _ => panic!("no C type for {}", rust),
+        }
+        .to_string()
+    }
+
+    /// A C declaration of a name with a type, like `const char *dir`
+    fn c_declaration(c_type: &str, name: &str) -> String {
+        match c_type.ends_with('*') {
+            true => format!("{}{}", c_type, name),
+            false => format!("{} {}", c_type, name),
+        }
+    }
+
+    /// The C parameters of a Rust parameter list
+    fn c_parameters(rust: &str) -> String {
+        let parameters = rust
+            .split(',')
+            .map(str::trim)
+            .filter(|parameter| !parameter.is_empty())
+            .map(|parameter| {
+                let (name, rust) = parameter.split_once(": ").unwrap();
+                c_declaration(&c_type(rust), name)
+            })
+            .collect::<Vec<_>>();
+        match parameters.is_empty() {
+            true => "void".to_string(),
+            false => parameters.join(", "),
+        }
+    }
+
+    #[test]
+    fn header_matches() {
+        let normalize = |text: &str| {
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            text.replace("( ", "(").replace(" )", ")")
+        };
+        // the functions, without these tests
+        let source = include_str!("cabi.rs")
+            .split("#[cfg(test)]")
+            .next()
+            .unwrap();
+        let source = normalize(source);
+        let header = normalize(include_str!("../include/banyan_camp.h"));
+        let mut functions = 0;
+        for item in source.split("#[no_mangle] pub ").skip(1) {
+            let item = item.trim_start_matches("unsafe ");
+            let item = item.strip_prefix("extern \"C\" fn ").unwrap();
+            let (name, rest) = item.split_once('(').unwrap();
+            let (parameters, rest) = rest.split_once(')').unwrap();
+            let result = rest
+                .split_once('{')
+                .unwrap()
+                .0
+                .trim()
+                .strip_prefix("-> ")
+                .map_or("void".to_string(), c_type);
+            let declaration = format!(
+                "{}({});",
+                c_declaration(&result, name),
+                c_parameters(parameters)
+            );
+            assert!(
+                header.contains(&declaration),
+                "{} is not in the header",
+                declaration
+            );
+            functions += 1;
+        }
+        let declared = header.split("banyan_").skip(1).filter(|rest| {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_');
+            rest[end.unwrap_or(rest.len())..].starts_with('(')
+        });
+        assert!(functions > 0);
+        assert_eq!(
+            declared.count(),
+            functions,
+            "the header has functions that are not here"
+        );
+
+        let callback = source
+            .split_once("pub type BanyanLogCallback = unsafe extern \"C\" fn(")
+            .unwrap()
+            .1;
+        let (parameters, result) = callback.split_once(") -> ").unwrap();
+        let result = c_type(result.split_once(';').unwrap().0);
+        let typedef = format!(
+            "typedef {} (*BanyanLogCallback)({});",
+            result,
+            c_parameters(parameters)
+        );
+        assert!(
+            header.contains(&typedef),
+            "{} is not in the header",
+            typedef
+        );
+    }
+
+    #[test]
+    fn panic_is_a_failure() {
+        assert_eq!(code(|| panic!("boom")), -1);
+        let message = unsafe { CStr::from_ptr(banyan_last_error()) };
+        assert_eq!(message.to_str().unwrap(), "panicked: boom");
+    }
+
+    #[test]
+    fn append_snapshot_reopen_iter() {
+        let dir = std::env::temp_dir().join(format!("banyan-cabi-{}", std::process::id()));
+        let path = CString::new(dir.to_str().unwrap()).unwrap();
+        let values: [&[u8]; 3] = [b"a", b"bc", b""];
+        let mut ptrs = values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
+        // an empty value from C may be null
+        ptrs[2] = ptr::null();
+        let lens = values.iter().map(|v| v.len()).collect::<Vec<_>>();
+        let mut cid = [0 as c_char; 64];
+        unsafe {
+            let log = banyan_log_open(path.as_ptr());
+            assert!(!log.is_null());
+            assert_eq!(banyan_log_append(log, ptrs.as_ptr(), lens.as_ptr(), 3), 0);
+            assert_eq!(banyan_log_snapshot(log, cid.as_mut_ptr(), 4), -1);
+            assert!(!banyan_last_error().is_null());
+            assert_eq!(banyan_log_snapshot(log, cid.as_mut_ptr(), cid.len()), 0);
+            // not in the snapshot, so gone after the log is opened again
+            assert_eq!(banyan_log_append(log, ptrs.as_ptr(), lens.as_ptr(), 1), 0);
+            assert_eq!(banyan_log_count(log), 4);
+            banyan_log_close(log);
+
+            let log = banyan_log_open(path.as_ptr());
+            assert!(!log.is_null());
+            assert_eq!(banyan_log_count(log), 3);
+            let mut got = Vec::<(u64, Vec<u8>)>::new();
+            let ctx = &mut got as *mut _ as *mut c_void;
+            assert_eq!(banyan_log_iter(log, 1, collect, ctx), 0);
+            banyan_log_close(log);
+            assert_eq!(got, vec![(1, b"bc".to_vec()), (2, Vec::new())]);
+        }
+        let root = fs::read_to_string(dir.join("root")).unwrap();
+        let cid = unsafe { CStr::from_ptr(cid.as_ptr()) };
+        assert_eq!(root, cid.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
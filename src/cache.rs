@@ -0,0 +1,211 @@
+//! Branch cache statistics
+//!
+//! [BranchCache] has no hooks for hits or misses, so we infer them from the outside: a query
+//! wrapper records the links of all branches the traversal looks at, and a store wrapper counts
+//! how often each link is fetched. Every branch fetch is a miss, and a branch that is fetched more
+//! than once was evicted in between (or was too large to be cached in the first place).
+//!
+//! The traversal does not depend on the cache, so running the same queries with a disabled cache
+//! gives the total number of branch lookups, and the hits are the lookups that were not misses.
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use banyan::{
+    index::{BranchIndex, LeafIndex},
+    query::Query,
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::columnar::{self, ColumnarTT, TimeRangeQuery};
+
+#[derive(Debug)]
+struct State<L> {
+    /// number of fetches from the store, per link
+    gets: HashMap<L, u64>,
+    /// links of all branches the traversal looked at
+    branches: HashSet<L>,
+}
+
+/// Fetch counts, shared between a [StatsStore] and any number of [StatsQuery]s
+#[derive(Debug)]
+pub struct CacheStats<L>(Arc<Mutex<State<L>>>);
+
+impl<L> Clone for CacheStats<L> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<L: Hash + Eq + Copy> CacheStats<L> {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(State {
+            gets: HashMap::new(),
+            branches: HashSet::new(),
+        })))
+    }
+
+    /// wrap a store so gets are counted
+    pub fn store<S>(&self, inner: S) -> StatsStore<S, L> {
+        StatsStore {
+            inner,
+            stats: self.clone(),
+        }
+    }
+
+    /// wrap a query so visited branches are recorded
+    pub fn query<Q>(&self, inner: Q) -> StatsQuery<Q, L> {
+        StatsQuery {
+            inner,
+            stats: self.clone(),
+        }
+    }
+
+    /// branch fetches from the store, which are all cache misses
+    pub fn misses(&self) -> u64 {
+        let state = self.0.lock().unwrap();
+        state
+            .gets
+            .iter()
+            .filter(|(link, _)| state.branches.contains(link))
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// branch fetches of a branch that was already fetched before
+    pub fn evictions(&self) -> u64 {
+        let state = self.0.lock().unwrap();
+        state
+            .gets
+            .iter()
+            .filter(|(link, _)| state.branches.contains(link))
+            .map(|(_, n)| n - 1)
+            .sum()
+    }
+
+    /// leaf fetches from the store, which are never cached
+    pub fn leaves(&self) -> u64 {
+        let state = self.0.lock().unwrap();
+        state
+            .gets
+            .iter()
+            .filter(|(link, _)| !state.branches.contains(link))
+            .map(|(_, n)| n)
+            .sum()
+    }
+}
+
+/// A store wrapper that counts fetches per link
+#[derive(Debug, Clone)]
+pub struct StatsStore<S, L> {
+    inner: S,
+    stats: CacheStats<L>,
+}
+
+impl<L, S> ReadOnlyStore<L> for StatsStore<S, L>
+where
+    L: Hash + Eq + Copy + Send + Sync + 'static,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let data = self.inner.get(link)?;
+        *self.stats.0.lock().unwrap().gets.entry(*link).or_default() += 1;
+        Ok(data)
+    }
+}
+
+impl<L, S> BlockWriter<L> for StatsStore<S, L>
+where
+    L: Send + Sync + 'static,
+    S: BlockWriter<L>,
+{
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.inner.put(data)
+    }
+}
+
+/// A query wrapper that records every branch the traversal looks at
+#[derive(Debug, Clone)]
+pub struct StatsQuery<Q, L> {
+    inner: Q,
+    stats: CacheStats<L>,
+}
+
+impl<T, Q> Query<T> for StatsQuery<Q, T::Link>
+where
+    T: TreeTypes,
+    Q: Query<T>,
+{
+    fn containing(&self, offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        self.inner.containing(offset, index, res)
+    }
+
+    fn intersecting(&self, offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        if let Some(link) = index.link {
+            self.stats.0.lock().unwrap().branches.insert(link);
+        }
+        self.inner.intersecting(offset, index, res)
+    }
+}
+
+/// Run the same filtered queries over `n` events with each of the cache sizes and print a table
+/// of cache hits, misses and evictions
+pub fn report(config: &Config, n: u64, queries: u64, sizes: &[usize]) -> anyhow::Result<()> {
+    anyhow::ensure!(n >= 1000, "need at least 1000 events");
+    let xs = columnar::events(n);
+    // a few time windows, each 1000 events wide, that the queries cycle through
+    let windows = (0..8u64)
+        .map(|i| {
+            let start = (i * (n - 1000) / 8) as usize;
+            TimeRangeQuery {
+                min: xs[start].0.time,
+                max: xs[start + 999].0.time,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // build the tree once, it can be read with any forest on the same store
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, xs)?;
+    let tree = builder.snapshot();
+
+    // the same queries with each cache size, starting with a disabled cache to count the lookups
+    let run = |size: usize| -> anyhow::Result<(CacheStats<Sha256Digest>, f64)> {
+        let stats = CacheStats::new();
+        let forest =
+            Forest::<ColumnarTT, _>::new(stats.store(store.clone()), BranchCache::new(size));
+        let t0 = Instant::now();
+        for i in 0..queries {
+            let query = windows[(i % windows.len() as u64) as usize].clone();
+            for item in forest.iter_filtered(&tree, stats.query(query)) {
+                item?;
+            }
+        }
+        Ok((stats, t0.elapsed().as_secs_f64()))
+    };
+    let lookups = run(0)?.0.misses();
+    println!("cache\tseconds\tlookups\thits\tmisses\tevicted\tleaves");
+    for &size in sizes {
+        let (stats, dt) = run(size)?;
+        let misses = stats.misses();
+        println!(
+            "{}\t{:.3}\t{}\t{}\t{}\t{}\t{}",
+            size,
+            dt,
+            lookups,
+            lookups - misses,
+            misses,
+            stats.evictions(),
+            stats.leaves()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! Stopping a query that takes too long
+//!
+//! A query runs until the iterator is done, and a get on a gateway that stopped answering can
+//! block for as long as the connection stays open. A [Cancel] token can be cancelled from another
+//! thread, and can have a deadline. The [CancellableStore] checks it before every get, so a
+//! traversal stops at the next block it needs, with a [Cancelled] error from the iterator.
+//!
+//! With a deadline, each get also only waits until the deadline. The get itself can not be
+//! interrupted, so it runs on another thread, and its result is dropped when it arrives too late.
+//! The threads are reused, so a get only costs a thread when all threads of the store are busy
+//! with gets that ran out of time.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{prefetch::LatencyStore, snapshots::LogTT};
+
+/// Why a get was not done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancelled {
+    /// the token was cancelled
+    ByToken,
+    /// the deadline has passed
+    Deadline,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ByToken => write!(f, "query was cancelled"),
+            Self::Deadline => write!(f, "query deadline exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// A cancellation token, shared by all clones
+#[derive(Debug, Clone, Default)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that is cancelled once the timeout has passed, or when cancelled before
+    pub fn timeout(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            ..Self::default()
+        }
+    }
+
+    /// Cancel everything that uses this token or a clone of it
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// An error if the token was cancelled or the deadline has passed
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(Cancelled::ByToken);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Cancelled::Deadline),
+            _ => Ok(()),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A thread that runs a get, and then the gets it is sent, until its sender is dropped
+fn getter(first: Job) -> mpsc::Sender<Job> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+        first();
+        for job in receiver {
+            job();
+        }
+    });
+    sender
+}
+
+/// A store wrapper that stops getting blocks once its token is cancelled
+#[derive(Clone)]
+pub struct CancellableStore<S> {
+    inner: S,
+    cancel: Cancel,
+    /// the threads that are not running a get, shared by all clones
+    idle: Arc<Mutex<Vec<mpsc::Sender<Job>>>>,
+}
+
+impl<S> CancellableStore<S> {
+    pub fn new(inner: S, cancel: Cancel) -> Self {
+        Self {
+            inner,
+            cancel,
+            idle: Default::default(),
+        }
+    }
+}
+
+impl<S: Clone> CancellableStore<S> {
+    /// The same store with another token, sharing the threads of this one, like for a server
+    /// with a deadline per request
+    pub fn with_cancel(&self, cancel: Cancel) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cancel,
+            idle: self.idle.clone(),
+        }
+    }
+}
+
+impl<L, S> ReadOnlyStore<L> for CancellableStore<S>
+where
+    L: Clone + Send + 'static,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.cancel.check()?;
+        let Some(deadline) = self.cancel.deadline else {
+            return self.inner.get(link);
+        };
+        let (tx, rx) = mpsc::channel();
+        let inner = self.inner.clone();
+        let link = link.clone();
+        let job: Job = Box::new(move || {
+            // nobody is waiting any more if this fails
+            tx.send(inner.get(&link)).ok();
+        });
+        let idle = self.idle.lock().unwrap().pop();
+        let getter = match idle {
+            Some(idle) => match idle.send(job) {
+                Ok(()) => idle,
+                // the thread is gone, after a get that panicked
+                Err(mpsc::SendError(job)) => getter(job),
+            },
+            None => getter(job),
+        };
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(res) => {
+                self.idle.lock().unwrap().push(getter);
+                res
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // the getter is busy with the get, and ends when it is done, since its sender
+                // is dropped here
+                Err(Cancelled::Deadline.into())
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("the get panicked"),
+        }
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for CancellableStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.inner.put(data)
+    }
+}
+
+/// Wrap the store in a [CancellableStore] if there is a timeout
+pub fn with_timeout<S: ReadOnlyStore<Sha256Digest>>(
+    store: S,
+    timeout: Option<Duration>,
+) -> CancellableStore<S> {
+    let cancel = timeout.map(Cancel::timeout).unwrap_or_default();
+    CancellableStore::new(store, cancel)
+}
+
+/// A slow scan with a deadline, one that is cancelled from another thread, and a get that hangs
+pub fn cancel_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: cancelling scans of {} events on a slow store", n);
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, so a scan needs many gets
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config, Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+
+    // count the events until the scan stops, and return how it stopped
+    let scan = |store: CancellableStore<LatencyStore<_>>| {
+        let t0 = Instant::now();
+        let forest = Forest::<LogTT, _>::new(store, BranchCache::new(0));
+        let mut count = 0;
+        for item in forest.iter_from(&tree) {
+            if let Err(cause) = item {
+                let cancelled = cause.downcast_ref::<Cancelled>().copied();
+                return (count, cancelled, t0.elapsed());
+            }
+            count += 1;
+        }
+        (count, None, t0.elapsed())
+    };
+
+    // a scan well within its deadline runs all of its gets on one thread
+    let fast = with_timeout(
+        LatencyStore::new(store.clone(), Duration::ZERO),
+        Some(Duration::from_secs(60)),
+    );
+    let (count, cancelled, _) = scan(fast.clone());
+    anyhow::ensure!(count == n && cancelled.is_none(), "the scan did not finish");
+    let threads = fast.idle.lock().unwrap().len();
+    anyhow::ensure!(threads == 1, "the gets ran on {} threads", threads);
+
+    let slow = LatencyStore::new(store.clone(), Duration::from_millis(2));
+    let (count, cancelled, elapsed) =
+        scan(with_timeout(slow.clone(), Some(Duration::from_millis(100))));
+    anyhow::ensure!(cancelled == Some(Cancelled::Deadline), "no deadline");
+    anyhow::ensure!(count < n, "the scan finished before the deadline");
+    println!(
+        "deadline 100ms: {} events in {:.3}s",
+        count,
+        elapsed.as_secs_f64()
+    );
+
+    let cancel = Cancel::new();
+    let other = cancel.clone();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        other.cancel();
+    });
+    let (count, cancelled, elapsed) = scan(CancellableStore::new(slow, cancel));
+    canceller.join().expect("no panic");
+    anyhow::ensure!(cancelled == Some(Cancelled::ByToken), "not cancelled");
+    println!(
+        "cancelled after 50ms: {} events in {:.3}s",
+        count,
+        elapsed.as_secs_f64()
+    );
+
+    // a gateway that does not answer for a minute
+    let stuck = LatencyStore::new(store, Duration::from_secs(60));
+    let (count, cancelled, elapsed) = scan(with_timeout(stuck, Some(Duration::from_millis(100))));
+    anyhow::ensure!(cancelled == Some(Cancelled::Deadline), "no deadline");
+    anyhow::ensure!(elapsed < Duration::from_secs(1), "waited for the stuck get");
+    println!(
+        "stuck get, deadline 100ms: {} events in {:.3}s",
+        count,
+        elapsed.as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! Trees as CAR files
+//!
+//! A CAR (content addressable archive) file is a header with the roots, followed by the blocks,
+//! each as a varint length, the cid and the data. [write_car] exports everything reachable from
+//! some roots, and [CarStore] serves the blocks of a CAR file without loading it into memory.
+//!
+//! See <https://ipld.io/specs/transport/car/carv1/>. Only version 1 is supported.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use banyan::store::ReadOnlyStore;
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, DagCbor, Ipld};
+
+use crate::{error::Error, probe::ProbingStore};
+
+/// The longest section a CAR from a stream may have, a block of 4 MiB, more than kubo accepts, with
+/// room for its cid. The length comes from the other side, so the buffer for a section is
+/// only allocated for lengths up to this
+pub const MAX_SECTION: u64 = (4 << 20) + 128;
+
+#[derive(Debug, Clone, DagCbor)]
+struct CarHeader {
+    roots: Vec<Cid>,
+    version: u64,
+}
+
+fn write_section(w: &mut impl Write, data: &[u8]) -> anyhow::Result<()> {
+    let mut buf = unsigned_varint::encode::u64_buffer();
+    w.write_all(unsigned_varint::encode::u64(data.len() as u64, &mut buf))?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Read a section length, or `None` at the end of the file
+fn read_len(r: &mut impl Read) -> anyhow::Result<Option<u64>> {
+    let mut first = [0u8];
+    if r.read(&mut first)? == 0 {
+        return Ok(None);
+    }
+    let mut buf = unsigned_varint::encode::u64_buffer();
+    buf[0] = first[0];
+    let mut n = 1;
+    while buf[n - 1] & 0x80 != 0 {
+        anyhow::ensure!(n < buf.len(), "length is too long");
+        r.read_exact(&mut buf[n..n + 1])?;
+        n += 1;
+    }
+    let (len, _) = unsigned_varint::decode::u64(&buf[..n])?;
+    Ok(Some(len))
+}
+
+fn write_header(w: &mut impl Write, roots: &[Sha256Digest]) -> anyhow::Result<()> {
+    let header = CarHeader {
+        roots: roots.iter().map(|root| Cid::from(*root)).collect(),
+        version: 1,
+    };
+    write_section(w, &DagCborCodec.encode(&header)?)
+}
+
+/// The buffer for a section of a length that was read, if it is not too long
+fn section_buffer(len: u64) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        len <= MAX_SECTION,
+        "a CAR section of {} bytes is longer than {}",
+        len,
+        MAX_SECTION
+    );
+    Ok(vec![0u8; len as usize])
+}
+
+fn read_header(r: &mut impl Read) -> anyhow::Result<Vec<Sha256Digest>> {
+    let len = read_len(r)?.ok_or_else(|| anyhow::anyhow!("empty CAR file"))?;
+    let mut header = section_buffer(len)?;
+    r.read_exact(&mut header)?;
+    let header: CarHeader = DagCborCodec.decode(&header)?;
+    anyhow::ensure!(
+        header.version == 1,
+        "unsupported CAR version {}",
+        header.version
+    );
+    header
+        .roots
+        .into_iter()
+        .map(Sha256Digest::try_from)
+        .collect()
+}
+
+/// Create a CAR file with just the header
+fn create(roots: &[Sha256Digest], path: impl AsRef<Path>) -> anyhow::Result<BufWriter<File>> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_header(&mut w, roots)?;
+    Ok(w)
+}
+
+fn write_block(w: &mut impl Write, link: Sha256Digest, data: &[u8]) -> anyhow::Result<()> {
+    let mut section = Cid::from(link).to_bytes();
+    section.extend_from_slice(data);
+    write_section(w, &section)
+}
+
+/// Write all blocks reachable from the roots to a CAR file, and return the number of blocks
+pub fn write_car(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    roots: &[Sha256Digest],
+    path: impl AsRef<Path>,
+) -> anyhow::Result<u64> {
+    let mut w = create(roots, path)?;
+    let mut seen = HashSet::new();
+    let mut todo = roots.to_vec();
+    while let Some(link) = todo.pop() {
+        if !seen.insert(link) {
+            continue;
+        }
+        let data = store.get(&link)?;
+        // blocks that are not dag-cbor, like payload chunks, have no links
+        if let Ok(ipld) = DagCborCodec.decode::<Ipld>(&data) {
+            let mut links = BTreeSet::<Cid>::new();
+            ipld.references(&mut links);
+            for cid in links {
+                todo.push(Sha256Digest::try_from(cid)?);
+            }
+        }
+        write_block(&mut w, link, &data)?;
+    }
+    w.flush()?;
+    Ok(seen.len() as u64)
+}
+
+/// Write just the given blocks to a CAR file, without following their links. A reader of the
+/// roots gets an error for every block that is not in the file
+pub fn write_car_blocks(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    roots: &[Sha256Digest],
+    blocks: &[Sha256Digest],
+    path: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let mut w = create(roots, path)?;
+    for link in blocks {
+        write_block(&mut w, *link, &store.get(link)?)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write the given blocks as a CAR to a stream, like [write_car_blocks] does to a file
+pub fn write_car_stream(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    roots: &[Sha256Digest],
+    blocks: &[Sha256Digest],
+    w: &mut impl Write,
+) -> anyhow::Result<()> {
+    write_header(w, roots)?;
+    for link in blocks {
+        write_block(w, *link, &store.get(link)?)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// The blocks of a CAR, with their data
+pub type Blocks = Vec<(Sha256Digest, Vec<u8>)>;
+
+/// Read a CAR from a stream, and return its roots and all its blocks. The blocks are not checked
+/// against their cids
+pub fn read_car_stream(r: &mut impl Read) -> anyhow::Result<(Vec<Sha256Digest>, Blocks)> {
+    let roots = read_header(r)?;
+    let mut blocks = Vec::new();
+    while let Some(len) = read_len(r)? {
+        let mut section = section_buffer(len)?;
+        r.read_exact(&mut section)?;
+        let mut cursor = Cursor::new(&section);
+        let cid = Cid::read_bytes(&mut cursor)?;
+        let data = section[cursor.position() as usize..].to_vec();
+        blocks.push((Sha256Digest::try_from(cid)?, data));
+    }
+    Ok((roots, blocks))
+}
+
+/// A read-only store with the blocks of a CAR file
+///
+/// The file is scanned once on open to find where each block is. Blocks are read when needed.
+#[derive(Debug, Clone)]
+pub struct CarStore {
+    file: Arc<Mutex<File>>,
+    /// offset and length of the data of each block
+    index: Arc<HashMap<Sha256Digest, (u64, usize)>>,
+    roots: Vec<Sha256Digest>,
+}
+
+impl CarStore {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let roots = read_header(&mut r)?;
+        let mut index = HashMap::new();
+        while let Some(len) = read_len(&mut r)? {
+            let start = r.stream_position()?;
+            // the cid is at most a few dozen bytes, so read ahead a bit and parse it from there
+            let mut prefix = vec![0u8; len.min(128) as usize];
+            r.read_exact(&mut prefix)?;
+            let mut cursor = Cursor::new(&prefix);
+            let cid = Cid::read_bytes(&mut cursor)?;
+            let offset = start + cursor.position();
+            index.insert(
+                Sha256Digest::try_from(cid)?,
+                (offset, (start + len - offset) as usize),
+            );
+            r.seek(SeekFrom::Start(start + len))?;
+        }
+        Ok(Self {
+            file: Arc::new(Mutex::new(r.into_inner())),
+            index: Arc::new(index),
+            roots,
+        })
+    }
+
+    /// The roots in the header
+    pub fn roots(&self) -> &[Sha256Digest] {
+        &self.roots
+    }
+
+    /// The number of blocks in the file
+    pub fn blocks(&self) -> u64 {
+        self.index.len() as u64
+    }
+}
+
+impl ReadOnlyStore<Sha256Digest> for CarStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        let (offset, len) = *self.index.get(link).ok_or_else(|| Error::missing(*link))?;
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; len];
+        file.read_exact(&mut data)?;
+        Ok(data.into())
+    }
+}
+
+impl ProbingStore<Sha256Digest> for CarStore {
+    fn size(&self, link: &Sha256Digest) -> anyhow::Result<Option<u64>> {
+        Ok(self.index.get(link).map(|(_, len)| *len as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn section_too_long() {
+        let mut car = Vec::new();
+        write_header(&mut car, &[]).unwrap();
+        let mut buf = unsigned_varint::encode::u64_buffer();
+        car.extend_from_slice(unsigned_varint::encode::u64(u64::MAX >> 1, &mut buf));
+        let err = read_car_stream(&mut car.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{}", err);
+    }
+}
@@ -0,0 +1,276 @@
+//! A columnar key sequence for composite keys
+//!
+//! [VecSeq](banyan::index::VecSeq) stores keys row-wise, so a leaf index with a `(time, device)`
+//! key is a cbor array of small arrays. Storing each component in its own column, delta and varint
+//! encoded, gives zstd much less to do, and the keys live in the branches that are closest to the
+//! root and therefore read most often.
+use std::{
+    io::{Read, Seek, Write},
+    iter::FromIterator,
+    time::Instant,
+};
+
+use banyan::{
+    index::{BranchIndex, CompactSeq, LeafIndex, Summarizable, VecSeq},
+    query::Query,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Decode, Encode},
+    DagCbor,
+};
+
+use crate::{delta, progress::CountingStore};
+
+/// A composite key of a timestamp and the device that produced the event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, DagCbor)]
+pub struct EventKey {
+    pub time: u64,
+    pub device: u32,
+}
+
+/// Inclusive ranges for both key components
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct EventSummary {
+    pub min_time: u64,
+    pub max_time: u64,
+    pub min_device: u32,
+    pub max_device: u32,
+}
+
+impl EventSummary {
+    fn combine(iter: impl IntoIterator<Item = EventSummary>) -> Self {
+        let mut iter = iter.into_iter();
+        let first = iter
+            .next()
+            .expect("summarize is only called on non-empty sequences");
+        iter.fold(first, |a, b| EventSummary {
+            min_time: a.min_time.min(b.min_time),
+            max_time: a.max_time.max(b.max_time),
+            min_device: a.min_device.min(b.min_device),
+            max_device: a.max_device.max(b.max_device),
+        })
+    }
+}
+
+impl From<EventKey> for EventSummary {
+    fn from(key: EventKey) -> Self {
+        Self {
+            min_time: key.time,
+            max_time: key.time,
+            min_device: key.device,
+            max_device: key.device,
+        }
+    }
+}
+
+/// A sequence of [EventKey]s, stored as one delta encoded column per component
+#[derive(Debug, Clone, Default)]
+pub struct ColumnarSeq {
+    time: Vec<u64>,
+    device: Vec<u32>,
+}
+
+impl Encode<DagCborCodec> for ColumnarSeq {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+        // a cbor array of two byte strings
+        let time = delta::pack(self.time.iter().cloned()).into_boxed_slice();
+        let device = delta::pack(self.device.iter().map(|x| *x as u64)).into_boxed_slice();
+        (time, device).encode(c, w)
+    }
+}
+
+impl Decode<DagCborCodec> for ColumnarSeq {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+        let (time, device) = <(Box<[u8]>, Box<[u8]>)>::decode(c, r)?;
+        let time = delta::unpack(&time)?;
+        let device = delta::unpack(&device)?
+            .into_iter()
+            .map(u32::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::ensure!(time.len() == device.len(), "columns differ in length");
+        Ok(Self { time, device })
+    }
+}
+
+impl CompactSeq for ColumnarSeq {
+    type Item = EventKey;
+    fn get(&self, index: usize) -> Option<EventKey> {
+        Some(EventKey {
+            time: *self.time.get(index)?,
+            device: *self.device.get(index)?,
+        })
+    }
+    fn len(&self) -> usize {
+        self.time.len()
+    }
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.time.capacity() * std::mem::size_of::<u64>()
+            + self.device.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+impl FromIterator<EventKey> for ColumnarSeq {
+    fn from_iter<I: IntoIterator<Item = EventKey>>(iter: I) -> Self {
+        let mut res = Self::default();
+        for key in iter {
+            res.time.push(key.time);
+            res.device.push(key.device);
+        }
+        res
+    }
+}
+
+impl Summarizable<EventSummary> for ColumnarSeq {
+    fn summarize(&self) -> EventSummary {
+        EventSummary::combine(self.to_vec().into_iter().map(EventSummary::from))
+    }
+}
+
+impl Summarizable<EventSummary> for VecSeq<EventKey> {
+    fn summarize(&self) -> EventSummary {
+        EventSummary::combine(self.as_ref().iter().cloned().map(EventSummary::from))
+    }
+}
+
+impl Summarizable<EventSummary> for VecSeq<EventSummary> {
+    fn summarize(&self) -> EventSummary {
+        EventSummary::combine(self.as_ref().iter().cloned())
+    }
+}
+
+/// Tree types with row-wise keys, for comparison
+#[derive(Debug, Clone)]
+pub struct RowTT;
+
+impl TreeTypes for RowTT {
+    type Key = EventKey;
+    type Summary = EventSummary;
+    type KeySeq = VecSeq<EventKey>;
+    type SummarySeq = VecSeq<EventSummary>;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Row-wise keys for camp..";
+}
+
+/// Tree types with columnar keys
+#[derive(Debug, Clone)]
+pub struct ColumnarTT;
+
+impl TreeTypes for ColumnarTT {
+    type Key = EventKey;
+    type Summary = EventSummary;
+    type KeySeq = ColumnarSeq;
+    type SummarySeq = VecSeq<EventSummary>;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Columnar keys for camp..";
+}
+
+/// Query for events in an inclusive time range, for both row-wise and columnar keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeRangeQuery {
+    /// inclusive
+    pub min: u64,
+    /// inclusive
+    pub max: u64,
+}
+
+impl<T: TreeTypes<Key = EventKey, Summary = EventSummary>> Query<T> for TimeRangeQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        for (i, key) in index.keys().enumerate() {
+            res[i] = res[i] && key.time >= self.min && key.time <= self.max;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        for (i, summary) in index.summaries().enumerate() {
+            res[i] = res[i] && !(summary.min_time > self.max || summary.max_time < self.min);
+        }
+    }
+}
+
+/// Some events from a few devices, with irregular time steps (xorshift for the noise)
+pub fn events(n: u64) -> Vec<(EventKey, u64)> {
+    let mut time = 1_600_000_000_000u64;
+    let mut rng = 0x2545f4914f6cdd1du64;
+    (0..n)
+        .map(|i| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            time += rng >> 54;
+            let device = (rng % 100) as u32;
+            (EventKey { time, device }, i)
+        })
+        .collect()
+}
+
+/// Build a tree from the events and print its size, returning the key bytes and the number of
+/// matches for a time range query
+fn build<T>(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+    xs: Vec<(EventKey, u64)>,
+    query: TimeRangeQuery,
+) -> anyhow::Result<(u64, u64)>
+where
+    T: TreeTypes<Key = EventKey, Summary = EventSummary, Link = Sha256Digest>,
+{
+    let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, CountingStore::new(store));
+    let mut builder = StreamBuilder::<T, u64>::new(config.clone(), Secrets::default());
+    let t0 = Instant::now();
+    txn.extend(&mut builder, xs)?;
+    let tree = builder.snapshot();
+    let dt = t0.elapsed().as_secs_f64();
+    let key_bytes = tree.index().map(|x| x.key_bytes()).unwrap_or_default();
+    let mut matches = 0;
+    for item in txn.iter_filtered(&tree, query) {
+        item?;
+        matches += 1;
+    }
+    println!(
+        "{}s, {} blocks, {} bytes stored, {} key bytes",
+        dt,
+        txn.writer().counters().blocks(),
+        txn.writer().counters().bytes(),
+        key_bytes
+    );
+    Ok((key_bytes, matches))
+}
+
+/// Build the same events with row-wise and with columnar keys and compare the index sizes
+pub fn columnar_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    println!(
+        "Example: {} events with a (time, device) key, row-wise vs. columnar keys",
+        n
+    );
+    let xs = events(n);
+    let query = TimeRangeQuery {
+        min: xs[1000].0.time,
+        max: xs[2000].0.time,
+    };
+    print!("row-wise: ");
+    let (row_bytes, row_matches) =
+        build::<RowTT>(store.clone(), config, xs.clone(), query.clone())?;
+    print!("columnar: ");
+    let (col_bytes, col_matches) = build::<ColumnarTT>(store, config, xs, query)?;
+    // both trees must find exactly the same events
+    anyhow::ensure!(row_matches == col_matches, "query results differ");
+    println!(
+        "key bytes: row-wise {}, columnar {} ({:.1}x smaller), {} query matches",
+        row_bytes,
+        col_bytes,
+        row_bytes as f64 / col_bytes as f64,
+        col_matches
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,281 @@
+//! Packing the tree while an ingest loop is idle
+//!
+//! [Transaction::extend] packs the tree on every call, which rewrites the unsealed nodes on the
+//! right edge of the tree each time, including the leaf the last batch ended in. For many small
+//! batches it writes a lot less to append with [Transaction::extend_unpacked], which only writes
+//! the new events and a branch that joins them to the tree. But then the tree degenerates into a
+//! list of small trees, with a small unsealed leaf at the end of each batch, and queries get slower
+//! the longer it goes on.
+//!
+//! A [Compactor] looks at the [Shape] of the tree now and then, and packs it when the tail that
+//! a pack would rewrite gets too long or too many of its leaves are small. It only does so when
+//! the loop has time to spare before its next batch. The checks are jittered, so writers that
+//! started together do not all compact at the same time.
+//!
+//! Everything happens on the thread of the loop, between batches, since the loop owns the
+//! [StreamBuilder]. That is as much in the background as it gets without a lock around it.
+use std::time::{Duration, Instant};
+
+use banyan::{
+    index::Index,
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{columnar::ColumnarTT, progress::CountingStore};
+
+/// When to check, and when to pack
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// the average time between checks
+    pub interval: Duration,
+    /// pack when a pack would rewrite more events than this
+    pub max_tail: u64,
+    /// pack when more than this fraction of the leaves in the tail are unsealed
+    pub max_small_leaves: f64,
+    /// but only with at least this many leaves in the tail, since right after a pack, the last
+    /// leaf is usually the only one and unsealed
+    pub min_leaves: u64,
+    /// only pack when there is at least this much time before the next batch
+    pub min_idle: Duration,
+}
+
+impl Policy {
+    /// Check about every `interval`, with thresholds for a tree that gets many small batches
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            max_tail: 100000,
+            max_small_leaves: 0.25,
+            min_leaves: 16,
+            min_idle: Duration::from_millis(20),
+        }
+    }
+}
+
+/// What a pack would do to the tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Shape {
+    pub events: u64,
+    /// the events that are not in a sealed root, which a pack would write again
+    pub tail: u64,
+    /// the leaves of the tail
+    pub leaves: u64,
+    /// the leaves of the tail that are not sealed, because a batch ended in them
+    pub small_leaves: u64,
+    pub level: i32,
+}
+
+impl Shape {
+    pub fn small_leaf_ratio(&self) -> f64 {
+        if self.leaves == 0 {
+            0.0
+        } else {
+            self.small_leaves as f64 / self.leaves as f64
+        }
+    }
+}
+
+/// The compaction work done so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub checks: u64,
+    pub packs: u64,
+    /// checks that found the tree in need of a pack, but no time for it
+    pub deferred: u64,
+    /// the events the packs wrote again
+    pub rewritten: u64,
+    /// the time the checks and packs took
+    pub busy: Duration,
+}
+
+/// Look at the shape of the tree. This reads the branches of the tail, not the whole tree
+pub fn shape<T, R, W, V>(
+    txn: &Transaction<T, R, W>,
+    builder: &StreamBuilder<T, V>,
+) -> anyhow::Result<Shape>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    V: BanyanValue,
+{
+    let packed = txn
+        .roots(builder)?
+        .iter()
+        .map(|root| root.count())
+        .sum::<u64>();
+    let tree = builder.snapshot();
+    let mut shape = Shape {
+        events: tree.count(),
+        tail: tree.count() - packed,
+        level: builder.level(),
+        ..Shape::default()
+    };
+    if shape.tail > 0 {
+        for index in txn.iter_index(&tree, OffsetRangeQuery::from(packed..)) {
+            if let Index::Leaf(leaf) = index? {
+                shape.leaves += 1;
+                if !leaf.sealed {
+                    shape.small_leaves += 1;
+                }
+            }
+        }
+    }
+    Ok(shape)
+}
+
+/// Decides when to pack, and keeps track of what it did
+#[derive(Debug, Clone)]
+pub struct Compactor {
+    policy: Policy,
+    next: Instant,
+    rng: u64,
+    metrics: Metrics,
+}
+
+impl Compactor {
+    pub fn new(policy: Policy, seed: u64) -> Self {
+        let mut this = Self {
+            policy,
+            next: Instant::now(),
+            rng: seed | 1,
+            metrics: Metrics::default(),
+        };
+        this.next = Instant::now() + this.jittered();
+        this
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The interval, times a random factor between 0.5 and 1.5
+    fn jittered(&mut self) -> Duration {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let factor = 0.5 + (self.rng % 1000) as f64 / 1000.0;
+        self.policy.interval.mul_f64(factor)
+    }
+
+    /// Whether the shape is bad enough for a pack
+    pub fn needs_pack(&self, shape: &Shape) -> bool {
+        shape.tail > self.policy.max_tail
+            || (shape.leaves >= self.policy.min_leaves
+                && shape.small_leaf_ratio() > self.policy.max_small_leaves)
+    }
+
+    /// Check the tree if a check is due, and pack it if it needs it and there is time until
+    /// `deadline`, when the next batch is due. Returns the shape before a pack
+    pub fn idle<T, R, W, V>(
+        &mut self,
+        txn: &mut Transaction<T, R, W>,
+        builder: &mut StreamBuilder<T, V>,
+        deadline: Instant,
+    ) -> anyhow::Result<Option<Shape>>
+    where
+        T: TreeTypes,
+        R: ReadOnlyStore<T::Link>,
+        W: BlockWriter<T::Link>,
+        V: BanyanValue,
+    {
+        let t0 = Instant::now();
+        if t0 < self.next || deadline.saturating_duration_since(t0) < self.policy.min_idle {
+            return Ok(None);
+        }
+        self.next = t0 + self.jittered();
+        self.metrics.checks += 1;
+        let shape = shape(txn, builder)?;
+        let packed = if !self.needs_pack(&shape) {
+            None
+        } else if deadline.saturating_duration_since(Instant::now()) < self.policy.min_idle {
+            // looking took too long, try again next time
+            self.metrics.deferred += 1;
+            None
+        } else {
+            txn.pack(builder)?;
+            self.metrics.packs += 1;
+            self.metrics.rewritten += shape.tail;
+            Some(shape)
+        };
+        self.metrics.busy += t0.elapsed();
+        Ok(packed)
+    }
+}
+
+/// Append many small batches unpacked, with and without a compactor, and compare the trees
+pub fn compaction_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let batches = 500u64;
+    let batch = 200u64;
+    println!(
+        "Example: {} unpacked batches of {} events, with idle compaction",
+        batches, batch
+    );
+    let events = crate::columnar::events(batches * batch);
+    let mut results = Vec::new();
+    for mode in ["extend", "unpacked", "idle"] {
+        let store = CountingStore::new(store.clone());
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let mut txn = Transaction::new(forest, store.clone());
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+        // always due, and always plenty of time until the next batch
+        let mut compactor = Compactor::new(
+            Policy {
+                max_tail: 20000,
+                ..Policy::new(Duration::ZERO)
+            },
+            1,
+        );
+        for chunk in events.chunks(batch as usize) {
+            match mode {
+                "extend" => txn.extend(&mut builder, chunk.iter().cloned())?,
+                _ => txn.extend_unpacked(&mut builder, chunk.iter().cloned())?,
+            }
+            if mode == "idle" {
+                let deadline = Instant::now() + Duration::from_secs(1);
+                compactor.idle(&mut txn, &mut builder, deadline)?;
+            }
+        }
+        let tree = builder.snapshot();
+        let found = txn.iter_from(&tree).collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::ensure!(
+            found
+                .iter()
+                .map(|(_, k, v)| (*k, *v))
+                .eq(events.iter().cloned()),
+            "the events changed"
+        );
+        let shape = shape(&txn, &builder)?;
+        results.push((mode, shape, store.counters().bytes(), *compactor.metrics()));
+    }
+    println!("append\tlevel\ttail\tsmall leaves\tbytes written\tpacks\trewritten");
+    for (mode, shape, bytes, metrics) in &results {
+        println!(
+            "{}\t{}\t{}\t{}/{}\t{}\t{}\t{}",
+            mode,
+            shape.level,
+            shape.tail,
+            shape.small_leaves,
+            shape.leaves,
+            bytes,
+            metrics.packs,
+            metrics.rewritten
+        );
+    }
+    let (extend, unpacked, idle) = (&results[0], &results[1], &results[2]);
+    anyhow::ensure!(
+        idle.1.tail < unpacked.1.tail && idle.1.level < unpacked.1.level,
+        "compaction did not help"
+    );
+    anyhow::ensure!(
+        idle.2 < extend.2,
+        "compaction wrote more than packing every batch"
+    );
+    println!();
+    Ok(())
+}
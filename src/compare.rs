@@ -0,0 +1,266 @@
+//! Banyan compared with an append-only file and sqlite
+//!
+//! The same events go into banyan with a few tree configs, into a flat file of fixed size records
+//! and into a sqlite table with an index on time. Then we run the same time range queries against
+//! each of them. The flat file and sqlite live in a temporary directory on disk, banyan in memory,
+//! so the query times favour banyan a bit, and banyan also pays for hashing and encryption. On the
+//! other hand, banyan is the only one of the three whose data can be replicated block by block.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use banyan::{
+    store::{BranchCache, MemStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use rusqlite::{params, Connection};
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey, TimeRangeQuery},
+    progress::CountingStore,
+};
+
+/// One line of the comparison table
+struct Row {
+    name: String,
+    ingest: f64,
+    bytes: u64,
+    /// average latency per query in milliseconds
+    query_ms: f64,
+    /// total number of events found by all queries, must be the same for all rows
+    matches: u64,
+}
+
+fn banyan(
+    name: &str,
+    config: &Config,
+    xs: &[(EventKey, u64)],
+    queries: &[TimeRangeQuery],
+) -> anyhow::Result<Row> {
+    let store = CountingStore::new(MemStore::new(usize::MAX, Sha256Digest::digest));
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::default());
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let t0 = Instant::now();
+    txn.extend(&mut builder, xs.iter().cloned())?;
+    let ingest = t0.elapsed().as_secs_f64();
+    let tree = builder.snapshot();
+    let t0 = Instant::now();
+    let mut matches = 0;
+    for query in queries {
+        for item in txn.iter_filtered(&tree, query.clone()) {
+            item?;
+            matches += 1;
+        }
+    }
+    Ok(Row {
+        name: name.to_string(),
+        ingest,
+        bytes: txn.writer().counters().bytes(),
+        query_ms: t0.elapsed().as_secs_f64() * 1000.0 / queries.len() as f64,
+        matches,
+    })
+}
+
+/// time, device and value, little endian
+const RECORD_SIZE: u64 = 20;
+
+fn read_record(file: &mut File, i: u64) -> anyhow::Result<(u64, u32, u64)> {
+    let mut buf = [0u8; RECORD_SIZE as usize];
+    file.seek(SeekFrom::Start(i * RECORD_SIZE))?;
+    file.read_exact(&mut buf)?;
+    let time = u64::from_le_bytes(buf[0..8].try_into()?);
+    let device = u32::from_le_bytes(buf[8..12].try_into()?);
+    let value = u64::from_le_bytes(buf[12..20].try_into()?);
+    Ok((time, device, value))
+}
+
+/// Fixed size records in time order, queried with a binary search for the start of the range
+fn flat_file(
+    dir: &Path,
+    xs: &[(EventKey, u64)],
+    queries: &[TimeRangeQuery],
+) -> anyhow::Result<Row> {
+    let path = dir.join("events.bin");
+    let t0 = Instant::now();
+    let mut writer = BufWriter::new(
+        OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?,
+    );
+    for (key, value) in xs {
+        writer.write_all(&key.time.to_le_bytes())?;
+        writer.write_all(&key.device.to_le_bytes())?;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.into_inner()?.sync_all()?;
+    let ingest = t0.elapsed().as_secs_f64();
+    let mut file = File::open(&path)?;
+    let n = file.metadata()?.len() / RECORD_SIZE;
+    let t0 = Instant::now();
+    let mut matches = 0;
+    for query in queries {
+        let (mut lo, mut hi) = (0, n);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if read_record(&mut file, mid)?.0 < query.min {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        for i in lo..n {
+            let (time, _device, _value) = read_record(&mut file, i)?;
+            if time > query.max {
+                break;
+            }
+            matches += 1;
+        }
+    }
+    Ok(Row {
+        name: "flat file".to_string(),
+        ingest,
+        bytes: fs::metadata(&path)?.len(),
+        query_ms: t0.elapsed().as_secs_f64() * 1000.0 / queries.len() as f64,
+        matches,
+    })
+}
+
+/// A table with an index on time, written in a single transaction
+fn sqlite(dir: &Path, xs: &[(EventKey, u64)], queries: &[TimeRangeQuery]) -> anyhow::Result<Row> {
+    let path = dir.join("events.sqlite");
+    let mut conn = Connection::open(&path)?;
+    let t0 = Instant::now();
+    conn.execute(
+        "CREATE TABLE events (time INTEGER NOT NULL, device INTEGER NOT NULL, value INTEGER NOT NULL)",
+        [],
+    )?;
+    conn.execute("CREATE INDEX events_time ON events (time)", [])?;
+    let txn = conn.transaction()?;
+    {
+        let mut insert = txn.prepare("INSERT INTO events VALUES (?, ?, ?)")?;
+        for (key, value) in xs {
+            insert.execute(params![key.time as i64, key.device, *value as i64])?;
+        }
+    }
+    txn.commit()?;
+    let ingest = t0.elapsed().as_secs_f64();
+    let t0 = Instant::now();
+    let mut matches = 0;
+    let mut select =
+        conn.prepare("SELECT time, device, value FROM events WHERE time BETWEEN ? AND ?")?;
+    for query in queries {
+        let mut rows = select.query(params![query.min as i64, query.max as i64])?;
+        while let Some(row) = rows.next()? {
+            let _value: i64 = row.get(2)?;
+            matches += 1;
+        }
+    }
+    let query_ms = t0.elapsed().as_secs_f64() * 1000.0 / queries.len() as f64;
+    drop(select);
+    conn.close().map_err(|(_, cause)| cause)?;
+    Ok(Row {
+        name: "sqlite".to_string(),
+        ingest,
+        bytes: fs::metadata(&path)?.len(),
+        query_ms,
+        matches,
+    })
+}
+
+/// Ingest `n` events into each of the candidates, run `queries` time range queries of about 1000
+/// events each, and print a table of ingest time, storage size and query latency
+pub fn report(config: &Config, n: u64, queries: u64) -> anyhow::Result<()> {
+    anyhow::ensure!(n >= 1000, "need at least 1000 events");
+    let xs = columnar::events(n);
+    let queries = (0..queries)
+        .map(|i| {
+            // pseudo random windows, spread over the whole range
+            let start = (i.wrapping_mul(0x9e3779b97f4a7c15) % (n - 999)) as usize;
+            TimeRangeQuery {
+                min: xs[start].0.time,
+                max: xs[start + 999].0.time,
+            }
+        })
+        .collect::<Vec<_>>();
+    let configs = [
+        ("banyan", config.clone()),
+        (
+            "banyan small leaves",
+            Config {
+                target_leaf_size: 1 << 12,
+                max_leaf_count: 1 << 10,
+                ..config.clone()
+            },
+        ),
+        (
+            "banyan large leaves",
+            Config {
+                target_leaf_size: 1 << 18,
+                max_leaf_count: 1 << 16,
+                ..config.clone()
+            },
+        ),
+        (
+            "banyan zstd 10",
+            Config {
+                zstd_level: 10,
+                ..config.clone()
+            },
+        ),
+    ];
+    for (_, config) in &configs {
+        config.validate()?;
+    }
+    let dir = TempDir::new()?;
+    let mut rows = Vec::new();
+    for (name, config) in &configs {
+        rows.push(banyan(name, config, &xs, &queries)?);
+    }
+    rows.push(flat_file(&dir.0, &xs, &queries)?);
+    rows.push(sqlite(&dir.0, &xs, &queries)?);
+
+    println!(
+        "{} events, {} queries of 1000 events each",
+        n,
+        queries.len()
+    );
+    println!("store\tingest s\tbytes\tbytes/event\tquery ms");
+    for row in &rows {
+        println!(
+            "{}\t{:.3}\t{}\t{:.2}\t{:.3}",
+            row.name,
+            row.ingest,
+            row.bytes,
+            row.bytes as f64 / n as f64,
+            row.query_ms
+        );
+    }
+    anyhow::ensure!(
+        rows.iter().all(|row| row.matches == rows[0].matches),
+        "the stores found different events"
+    );
+    Ok(())
+}
+
+/// A directory that is removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> anyhow::Result<Self> {
+        let path = std::env::temp_dir().join(format!("banyan-compare-{}", std::process::id()));
+        fs::create_dir(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
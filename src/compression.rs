@@ -0,0 +1,65 @@
+//! Report on how the zstd level affects write time and stored size
+//!
+//! Write throughput for simple sequences is dominated by compression, so it is worth knowing
+//! what a higher level buys you.
+use std::time::Instant;
+
+use banyan::{store::MemStore, Config, Forest, Secrets, StreamBuilder, Transaction};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec};
+
+use crate::progress::CountingStore;
+
+#[derive(Debug, Clone)]
+struct ReportTT;
+
+impl banyan::TreeTypes for ReportTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Zstd level report nonce.";
+}
+
+/// Build the same sequence of `n` values at zstd levels 1 to 19 and print a table comparing
+/// stored bytes with raw encoded bytes
+pub fn report(config: &Config, n: u64) -> anyhow::Result<()> {
+    // some values that are not trivially compressible
+    let xs = (0..n)
+        .map(|i| ((), (i, i.wrapping_mul(0x9e3779b97f4a7c15) >> 48)))
+        .collect::<Vec<_>>();
+    // the size of the values as plain dag-cbor, before compression and encryption
+    let mut raw_bytes = 0;
+    for (_, v) in &xs {
+        raw_bytes += DagCborCodec.encode(v)?.len() as u64;
+    }
+    println!("level\tseconds\tstored\tvalues\traw\tratio");
+    for zstd_level in 1..=19 {
+        let config = Config {
+            zstd_level,
+            ..config.clone()
+        };
+        // a fresh store per level, so the counters only see the blocks of this tree
+        let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let forest = Forest::<ReportTT, _>::new(store.clone(), Default::default());
+        let mut txn = Transaction::new(forest, CountingStore::new(store));
+        let mut builder = StreamBuilder::new(config, Secrets::default());
+        let t0 = Instant::now();
+        txn.extend(&mut builder, xs.iter().cloned())?;
+        let dt = t0.elapsed().as_secs_f64();
+        let tree = builder.snapshot();
+        let stored = txn.writer().counters().bytes();
+        let values = tree.index().map(|x| x.value_bytes()).unwrap_or_default();
+        println!(
+            "{}\t{:.3}\t{}\t{}\t{}\t{:.2}",
+            zstd_level,
+            dt,
+            stored,
+            values,
+            raw_bytes,
+            raw_bytes as f64 / stored as f64
+        );
+    }
+    Ok(())
+}
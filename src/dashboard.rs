@@ -0,0 +1,267 @@
+//! Streaming ingest with a live dashboard
+//!
+//! Events are generated at a fixed rate and appended in small batches, as they would arrive from
+//! a live source. Every few seconds we take a snapshot and run a query for the newest events
+//! against it, like a reader tailing the stream. The dashboard shows what the tree looks like
+//! under this load: ingest rate, depth, blocks written, branch cache hit rate of the tail query
+//! and the root of the last snapshot.
+//!
+//! The hit rate is inferred like in the cache report: the same query on a forest without cache
+//! gives the number of branch lookups.
+//!
+//! With compaction, batches are appended unpacked, and a [Compactor] packs the tree in the time
+//! that is left of a tick. With webhooks, a [Notifier] posts the record of every snapshot.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BranchCache, MemStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use indicatif::HumanBytes;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode},
+    layout::{Constraint, Layout},
+    widgets::{Block, Paragraph, Sparkline},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    cache::CacheStats,
+    columnar::{ColumnarTT, EventKey},
+    compaction::{self, Compactor},
+    progress::CountingStore,
+    snapshots::now,
+    webhooks::{self, Notifier},
+};
+
+/// Time between batches, and between redraws
+const TICK: Duration = Duration::from_millis(100);
+
+/// Number of newest events the tail query reads
+const TAIL: u64 = 1000;
+
+/// Everything the dashboard shows
+#[derive(Debug, Default)]
+struct Stats {
+    events: u64,
+    /// events per second, one entry per tick, newest last
+    rates: VecDeque<u64>,
+    level: i32,
+    blocks: u64,
+    bytes: u64,
+    lookups: u64,
+    misses: u64,
+    snapshots: u64,
+    last_root: Option<Sha256Digest>,
+    compaction: Option<compaction::Metrics>,
+    webhooks: Option<webhooks::NotifyStats>,
+}
+
+impl Stats {
+    fn rate(&self) -> u64 {
+        self.rates.back().cloned().unwrap_or_default()
+    }
+
+    fn hit_rate(&self) -> f64 {
+        if self.lookups == 0 {
+            0.0
+        } else {
+            100.0 * (self.lookups - self.misses.min(self.lookups)) as f64 / self.lookups as f64
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, stats: &Stats) {
+    let [text, chart] =
+        Layout::vertical([Constraint::Length(12), Constraint::Min(3)]).areas(frame.area());
+    let root = stats
+        .last_root
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "-".into());
+    let compaction = match &stats.compaction {
+        Some(metrics) => format!(
+            "compaction    {} packs of {} checks, {} events rewritten, {:.1}s",
+            metrics.packs,
+            metrics.checks,
+            metrics.rewritten,
+            metrics.busy.as_secs_f64()
+        ),
+        None => "compaction    off, every batch is packed".to_string(),
+    };
+    let webhooks = match &stats.webhooks {
+        Some(notify) => format!(
+            "webhooks      {} sent, {} failed, {} replaced",
+            notify.sent, notify.failed, notify.replaced
+        ),
+        None => "webhooks      off".to_string(),
+    };
+    let lines = [
+        format!("events        {}", stats.events),
+        format!("ingest rate   {} events/s", stats.rate()),
+        format!("tree level    {}", stats.level),
+        format!("blocks        {}", stats.blocks),
+        format!("bytes         {}", HumanBytes(stats.bytes)),
+        format!(
+            "cache hits    {:.1}% of {} branch lookups",
+            stats.hit_rate(),
+            stats.lookups
+        ),
+        format!("snapshots     {}", stats.snapshots),
+        format!("last root     {}", root),
+        compaction,
+        webhooks,
+    ];
+    frame.render_widget(
+        Paragraph::new(lines.join("\n"))
+            .block(Block::bordered().title(" banyan ingest (q to quit) ")),
+        text,
+    );
+    let rates = stats.rates.iter().cloned().collect::<Vec<_>>();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(" events/s "))
+            .data(&rates),
+        chart,
+    );
+}
+
+/// Query the newest events of the snapshot, once without and once with the branch cache, and
+/// return the number of branch lookups and misses
+fn tail(
+    store: &CountingStore<MemStore<Sha256Digest>>,
+    forest: &Forest<ColumnarTT, impl banyan::store::ReadOnlyStore<Sha256Digest>>,
+    stats: &CacheStats<Sha256Digest>,
+    tree: &Tree<ColumnarTT, u64>,
+) -> anyhow::Result<(u64, u64)> {
+    let query = OffsetRangeQuery::from(tree.count().saturating_sub(TAIL)..);
+    let uncached = CacheStats::new();
+    let forest0 = Forest::<ColumnarTT, _>::new(uncached.store(store.clone()), BranchCache::new(0));
+    for item in forest0.iter_filtered(tree, uncached.query(query.clone())) {
+        item?;
+    }
+    let misses = stats.misses();
+    for item in forest.iter_filtered(tree, stats.query(query)) {
+        item?;
+    }
+    Ok((uncached.misses(), stats.misses() - misses))
+}
+
+fn ingest_loop(
+    terminal: &mut DefaultTerminal,
+    config: &Config,
+    rate: u64,
+    snapshot_interval: Duration,
+    count: Option<u64>,
+    compact: Option<Duration>,
+    notifier: Option<&Notifier>,
+) -> anyhow::Result<()> {
+    let store = CountingStore::new(MemStore::new(usize::MAX, Sha256Digest::digest));
+    let mut txn = Transaction::new(
+        Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(0)),
+        store.clone(),
+    );
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    // the reader has its own forest, so its cache is not affected by the writer
+    let cache_stats = CacheStats::new();
+    let reader =
+        Forest::<ColumnarTT, _>::new(cache_stats.store(store.clone()), BranchCache::new(1 << 20));
+    let per_tick = (rate as f64 * TICK.as_secs_f64()).ceil() as u64;
+    let mut stats = Stats::default();
+    let mut rng = 0x2545f4914f6cdd1du64;
+    let mut last_snapshot = Instant::now();
+    let mut next_tick = Instant::now();
+    let mut compactor =
+        compact.map(|interval| Compactor::new(compaction::Policy::new(interval), rng));
+    loop {
+        let t0 = Instant::now();
+        let n = count.map_or(per_tick, |count| per_tick.min(count - stats.events));
+        let time = now();
+        let xs = (0..n).map(|i| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            let device = (rng % 100) as u32;
+            (EventKey { time, device }, stats.events + i)
+        });
+        match compactor {
+            Some(_) => txn.extend_unpacked(&mut builder, xs)?,
+            None => txn.extend(&mut builder, xs)?,
+        }
+        stats.events += n;
+        // the rate we actually achieved, which is below the target if extend can not keep up
+        let dt = t0.elapsed().max(TICK);
+        stats.rates.push_back((n as f64 / dt.as_secs_f64()) as u64);
+        if stats.rates.len() > 200 {
+            stats.rates.pop_front();
+        }
+        stats.level = builder.level();
+        stats.blocks = store.counters().blocks();
+        stats.bytes = store.counters().bytes();
+
+        if last_snapshot.elapsed() >= snapshot_interval {
+            let tree = builder.snapshot();
+            let (lookups, misses) = tail(&store, &reader, &cache_stats, &tree)?;
+            stats.lookups += lookups;
+            stats.misses += misses;
+            stats.snapshots += 1;
+            stats.last_root = tree.root().cloned();
+            if let Some(notifier) = notifier {
+                notifier.notify(webhooks::record("ingest", &tree));
+            }
+            last_snapshot = Instant::now();
+        }
+
+        stats.webhooks = notifier.map(|notifier| notifier.stats());
+        terminal.draw(|frame| draw(frame, &stats))?;
+        if Some(stats.events) == count {
+            return Ok(());
+        }
+        next_tick += TICK;
+        if let Some(compactor) = &mut compactor {
+            compactor.idle(&mut txn, &mut builder, next_tick)?;
+            stats.compaction = Some(*compactor.metrics());
+        }
+        while event::poll(next_tick.saturating_duration_since(Instant::now()))? {
+            if let Event::Key(key) = event::read()? {
+                if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Ingest generated events at the given rate into an in memory store and show a live dashboard,
+/// until `q` is pressed or `count` events are written. With `compact`, the tree is checked about
+/// that often and packed while idle, instead of on every batch. The record of every snapshot is
+/// posted to the webhooks
+pub fn ingest(
+    config: &Config,
+    rate: u64,
+    snapshot_interval: Duration,
+    count: Option<u64>,
+    compact: Option<Duration>,
+    webhooks: Vec<String>,
+) -> anyhow::Result<()> {
+    let notifier = match webhooks.is_empty() {
+        true => None,
+        false => Some(Notifier::new(webhooks, Duration::from_secs(10))?),
+    };
+    let mut terminal = ratatui::try_init()?;
+    let result = ingest_loop(
+        &mut terminal,
+        config,
+        rate,
+        snapshot_interval,
+        count,
+        compact,
+        notifier.as_ref(),
+    );
+    ratatui::try_restore()?;
+    result
+}
@@ -0,0 +1,74 @@
+//! Structural sharing between trees
+//!
+//! Successive snapshots of a stream share most of their blocks, and since blocks are content
+//! addressed, they are only stored once. The links of a block are not encrypted, so we can walk
+//! the blocks of any tree without knowing its tree types or secrets.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+};
+
+use banyan::store::ReadOnlyStore;
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+
+/// All blocks reachable from the root, with their sizes
+pub fn blocks<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+) -> anyhow::Result<BTreeMap<Sha256Digest, u64>> {
+    let mut result = BTreeMap::new();
+    let mut todo = vec![root];
+    while let Some(link) = todo.pop() {
+        if result.contains_key(&link) {
+            continue;
+        }
+        let data = store.get(&link)?;
+        // a block that is not dag-cbor, like a chunk of a blob, has no links
+        if let Ok(ipld) = DagCborCodec.decode::<Ipld>(&data) {
+            let mut links = BTreeSet::<Cid>::new();
+            ipld.references(&mut links);
+            for cid in links {
+                todo.push(Sha256Digest::try_from(cid)?);
+            }
+        }
+        result.insert(link, data.len() as u64);
+    }
+    Ok(result)
+}
+
+/// Print the number of blocks and bytes per root, and how many of them are shared between roots
+pub fn report<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    roots: &[Sha256Digest],
+) -> anyhow::Result<()> {
+    // for every block, its size and the number of roots it is reachable from
+    let mut all = BTreeMap::<Sha256Digest, (u64, u64)>::new();
+    let mut total_bytes = 0;
+    println!("root\tblocks\tbytes");
+    for root in roots {
+        let blocks = blocks(store, *root)?;
+        let bytes = blocks.values().sum::<u64>();
+        println!("{}\t{}\t{}", root, blocks.len(), bytes);
+        total_bytes += bytes;
+        for (link, size) in blocks {
+            all.entry(link).or_insert((size, 0)).1 += 1;
+        }
+    }
+    let stored_bytes = all.values().map(|(size, _)| size).sum::<u64>();
+    let shared = all.values().filter(|(_, n)| *n > 1).count();
+    println!(
+        "{} unique blocks, {} shared by more than one root ({:.1}%)",
+        all.len(),
+        shared,
+        100.0 * shared as f64 / all.len().max(1) as f64
+    );
+    println!(
+        "{} bytes for all roots separately, {} stored, {} deduplicated ({:.1}%)",
+        total_bytes,
+        stored_bytes,
+        total_bytes - stored_bytes,
+        100.0 * (total_bytes - stored_bytes) as f64 / total_bytes.max(1) as f64
+    );
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Value-level compression for numeric time series
+//!
+//! zstd sees every value as an opaque cbor item, so for a monotonically increasing counter it can
+//! not do much better than storing 8 bytes per sample. If each event carries a batch of samples,
+//! we can store the deltas between samples as varints before the value ever gets to cbor.
+use std::{
+    io::{Read, Seek, Write},
+    time::Instant,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Decode, Encode},
+};
+
+/// Pack a sequence of numbers as zigzag encoded deltas, each stored as a varint
+///
+/// Works for any sequence, but is only compact if consecutive values are close to each other.
+pub fn pack(values: impl IntoIterator<Item = u64>) -> Vec<u8> {
+    let mut packed = Vec::new();
+    let mut buf = unsigned_varint::encode::u64_buffer();
+    let mut prev = 0u64;
+    for x in values {
+        let delta = x.wrapping_sub(prev) as i64;
+        let zigzag = ((delta << 1) ^ (delta >> 63)) as u64;
+        packed.extend_from_slice(unsigned_varint::encode::u64(zigzag, &mut buf));
+        prev = x;
+    }
+    packed
+}
+
+/// Inverse of [pack]
+pub fn unpack(mut packed: &[u8]) -> anyhow::Result<Vec<u64>> {
+    let mut values = Vec::new();
+    let mut prev = 0u64;
+    while !packed.is_empty() {
+        let (zigzag, rest) = unsigned_varint::decode::u64(packed)?;
+        let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+        prev = prev.wrapping_add(delta as u64);
+        values.push(prev);
+        packed = rest;
+    }
+    Ok(values)
+}
+
+/// A batch of counter samples, stored as varint packed deltas
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaBatch(pub Vec<u64>);
+
+impl Encode<DagCborCodec> for DeltaBatch {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+        // store as a cbor byte string
+        pack(self.0.iter().cloned()).into_boxed_slice().encode(c, w)
+    }
+}
+
+impl Decode<DagCborCodec> for DeltaBatch {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+        let packed = Box::<[u8]>::decode(c, r)?;
+        Ok(Self(unpack(&packed)?))
+    }
+}
+
+/// Build two trees with the same batches of counter samples, one storing the batch as a plain
+/// `Vec<u64>` and one as a [DeltaBatch], and compare the compressed value bytes
+pub fn delta_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    let batch = 1000;
+    println!(
+        "Example: {} counter samples in batches of {}, plain vs. delta encoded",
+        n, batch
+    );
+
+    #[derive(Debug, Clone)]
+    struct CounterTT;
+
+    impl banyan::TreeTypes for CounterTT {
+        type Key = (); // no keys, we only care about the values here
+        type Summary = ();
+        type KeySeq = banyan::index::UnitSeq;
+        type SummarySeq = banyan::index::UnitSeq;
+        type Link = Sha256Digest;
+        const NONCE: &'static [u8; 24] = b"Delta encoding for camp.";
+    }
+
+    // a counter that increases by a small, irregular amount per sample (xorshift for the noise)
+    let mut counter = 0u64;
+    let mut rng = 0x2545f4914f6cdd1du64;
+    let samples = (0..n)
+        .map(|_| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            counter += 1 + (rng >> 60);
+            counter
+        })
+        .collect::<Vec<_>>();
+
+    let forest = Forest::<CounterTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+
+    // plain: a vec of u64s per event, cbor encoded and then compressed by zstd
+    let t0 = Instant::now();
+    let mut builder = StreamBuilder::<CounterTT, Vec<u64>>::new(config.clone(), Secrets::default());
+    txn.extend(
+        &mut builder,
+        samples.chunks(batch).map(|chunk| ((), chunk.to_vec())),
+    )?;
+    let plain = builder.snapshot();
+    println!("plain {:#?} {}s", plain, t0.elapsed().as_secs_f64());
+
+    // delta: the same batches, delta and varint encoded before cbor
+    let t0 = Instant::now();
+    let mut builder =
+        StreamBuilder::<CounterTT, DeltaBatch>::new(config.clone(), Secrets::default());
+    txn.extend(
+        &mut builder,
+        samples
+            .chunks(batch)
+            .map(|chunk| ((), DeltaBatch(chunk.to_vec()))),
+    )?;
+    let delta = builder.snapshot();
+    println!("delta {:#?} {}s", delta, t0.elapsed().as_secs_f64());
+
+    // reading the delta tree gives back exactly the same samples
+    let mut decoded = Vec::with_capacity(samples.len());
+    for item in txn.iter_from(&delta) {
+        let (_i, _k, v) = item?;
+        decoded.extend(v.0);
+    }
+    anyhow::ensure!(decoded == samples, "delta encoding roundtrip failed");
+    let plain_bytes = plain.index().map(|x| x.value_bytes()).unwrap_or_default();
+    let delta_bytes = delta.index().map(|x| x.value_bytes()).unwrap_or_default();
+    println!(
+        "value bytes: plain {}, delta {} ({:.1}x smaller)",
+        plain_bytes,
+        delta_bytes,
+        plain_bytes as f64 / delta_bytes as f64
+    );
+    println!();
+    Ok(())
+}
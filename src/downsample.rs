@@ -0,0 +1,410 @@
+//! Downsampled trees: per window aggregates of a raw event tree
+//!
+//! A dashboard that shows a day of a value per minute does not need the raw events, only the
+//! count, sum, min and max for each minute. [downsample] writes them into a tree of their own,
+//! keyed by the start of the window and the device, or [ALL] when the devices are not aggregated
+//! on their own. It is a [ColumnarTT] tree, so the dashboard can query it by time like the raw
+//! one, and [Downsample::raw] is the query for the raw events behind a bucket when it wants to
+//! drill into one.
+//!
+//! Like a [view](crate::views), a [Downsample] block records the source and how much of it is
+//! in the tree, and [refresh] only reads what came after. The window of the last event can still
+//! get more events, so it is not in the tree but in the block, and the tree has the windows
+//! before it. A refresh reads the source from the first event of that open window, so it is
+//! aggregated again with the new events.
+//!
+//! The events of the source have to be in time order, like for [Tumbling]. An event in a window
+//! before the open one is an error.
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+use crate::{
+    aggregate::{Bucket, Stats, Tumbling},
+    cache::CacheStats,
+    columnar::{self, ColumnarTT, EventKey, TimeRangeQuery},
+    kubo::KuboStore,
+    query_json::JsonQuery,
+    views,
+};
+
+/// The device of a bucket over all devices
+pub const ALL: u32 = u32::MAX;
+
+/// Where a downsampled tree came from, and where it is
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct Downsample {
+    /// the source tree
+    pub source: Sha256Digest,
+    /// the number of source events that are aggregated, in the tree or in `open`
+    pub source_count: u64,
+    /// the offset of the first source event in the open window
+    pub closed_count: u64,
+    /// the width of a window, in milliseconds
+    pub width: u64,
+    pub per_device: bool,
+    /// the tree of the closed windows, `None` while there are none
+    pub root: Option<Sha256Digest>,
+    /// the number of buckets in the tree
+    pub count: u64,
+    /// the buckets of the open window
+    pub open: Vec<(EventKey, Stats)>,
+}
+
+impl Downsample {
+    pub fn load<S: ReadOnlyStore<Sha256Digest>>(
+        store: &S,
+        link: Sha256Digest,
+    ) -> anyhow::Result<Self> {
+        DagCborCodec
+            .decode(&store.get(&link)?)
+            .map_err(|cause| anyhow::anyhow!("{} is not a downsampled tree: {}", link, cause))
+    }
+
+    /// The buckets of windows that start in the time range, from the tree and the open window
+    pub fn buckets<R: ReadOnlyStore<Sha256Digest>>(
+        &self,
+        forest: &Forest<ColumnarTT, R>,
+        secrets: &Secrets,
+        query: TimeRangeQuery,
+    ) -> anyhow::Result<Vec<(EventKey, Stats)>> {
+        let mut buckets = match self.root {
+            Some(root) => {
+                let tree = forest.load_tree::<Stats>(secrets.clone(), root)?;
+                forest
+                    .iter_filtered(&tree, query.clone())
+                    .map(|item| item.map(|(_, key, stats)| (key, stats)))
+                    .collect::<anyhow::Result<Vec<_>>>()?
+            }
+            None => Vec::new(),
+        };
+        buckets.extend(
+            self.open
+                .iter()
+                .filter(|(key, _)| query.min <= key.time && key.time <= query.max)
+                .cloned(),
+        );
+        Ok(buckets)
+    }
+
+    /// The query for the raw events of a bucket
+    pub fn raw(&self, key: &EventKey) -> JsonQuery {
+        let time = JsonQuery::Time {
+            from: key.time,
+            to: key.time + self.width - 1,
+        };
+        if key.device == ALL {
+            time
+        } else {
+            JsonQuery::And(vec![time, JsonQuery::Devices([key.device].into())])
+        }
+    }
+}
+
+/// Aggregate the source events from `from` on, append the closed windows to the tree, and write
+/// the block. Returns its link
+fn update<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    builder: &mut StreamBuilder<ColumnarTT, Stats>,
+    source: &Tree<ColumnarTT, u64>,
+    from: u64,
+    width: u64,
+    per_device: bool,
+) -> anyhow::Result<(Sha256Digest, Downsample)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest> + Clone,
+{
+    anyhow::ensure!(width > 0, "windows need a width");
+    let root = source
+        .link()
+        .ok_or_else(|| anyhow::anyhow!("the source is empty"))?;
+    let events = txn
+        .iter_filtered(source, OffsetRangeQuery::from(from..))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // the window of the last event is the open one
+    let open_start = events.last().map(|(_, key, _)| key.time - key.time % width);
+    let closed_count = events
+        .iter()
+        .find(|(_, key, _)| Some(key.time - key.time % width) == open_start)
+        .map(|(offset, _, _)| *offset)
+        .unwrap_or(from);
+    let items = events.iter().map(|(_, key, value)| {
+        let group = if per_device { key.device } else { ALL };
+        Ok((key.time, group, *value))
+    });
+    let mut closed = Vec::new();
+    let mut open = Vec::new();
+    for bucket in Tumbling::new(items, width) {
+        let Bucket {
+            start,
+            group,
+            stats,
+        } = bucket?;
+        let bucket = (
+            EventKey {
+                time: start,
+                device: group,
+            },
+            stats,
+        );
+        if Some(start) == open_start {
+            open.push(bucket);
+        } else {
+            closed.push(bucket);
+        }
+    }
+    txn.extend(builder, closed)?;
+    let tree = builder.snapshot();
+    let downsample = Downsample {
+        source: root,
+        source_count: source.count(),
+        closed_count,
+        width,
+        per_device,
+        root: tree.link(),
+        count: tree.count(),
+        open,
+    };
+    let link = txn
+        .writer()
+        .clone()
+        .put(DagCborCodec.encode(&downsample)?)?;
+    Ok((link, downsample))
+}
+
+/// Aggregate the events of a tree per window of `width` milliseconds, and per device if
+/// `per_device`. Returns the link of the [Downsample] block
+pub fn downsample<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    config: &Config,
+    secrets: &Secrets,
+    source: Sha256Digest,
+    width: u64,
+    per_device: bool,
+) -> anyhow::Result<(Sha256Digest, Downsample)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest> + Clone,
+{
+    let source = txn.load_tree::<u64>(secrets.clone(), source)?;
+    let mut builder = StreamBuilder::new(config.clone(), secrets.clone());
+    update(txn, &mut builder, &source, 0, width, per_device)
+}
+
+/// Bring a downsampled tree up to date with a newer version of its source. Returns the link of
+/// the new [Downsample] block, which is the old one if the source did not change
+pub fn refresh<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    config: &Config,
+    secrets: &Secrets,
+    link: Sha256Digest,
+    source: Sha256Digest,
+) -> anyhow::Result<(Sha256Digest, Downsample)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest> + Clone,
+{
+    let old = Downsample::load(txn.store(), link)?;
+    if old.source == source {
+        return Ok((link, old));
+    }
+    let new_source = txn.load_tree::<u64>(secrets.clone(), source)?;
+    anyhow::ensure!(
+        new_source.count() >= old.source_count,
+        "{} has {} events, fewer than the {} that are downsampled",
+        source,
+        new_source.count(),
+        old.source_count
+    );
+    views::check_extends(txn, secrets, old.source, old.source_count, &new_source)?;
+    let mut builder = match old.root {
+        Some(root) => txn.load_stream_builder(secrets.clone(), config.clone(), root)?,
+        None => StreamBuilder::new(config.clone(), secrets.clone()),
+    };
+    update(
+        txn,
+        &mut builder,
+        &new_source,
+        old.closed_count,
+        old.width,
+        old.per_device,
+    )
+}
+
+/// Downsample a columnar tree in kubo, or refresh an earlier downsampled tree to it, and print
+/// the block and the buckets
+pub fn print_downsample(
+    config: &Config,
+    secrets: &Secrets,
+    source: Sha256Digest,
+    width: u64,
+    per_device: bool,
+    previous: Option<Sha256Digest>,
+) -> anyhow::Result<()> {
+    let store = KuboStore::from_env()?;
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let (link, downsample) = match previous {
+        Some(previous) => refresh(&mut txn, config, secrets, previous, source)?,
+        None => downsample(&mut txn, config, secrets, source, width, per_device)?,
+    };
+    let root = downsample
+        .root
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "-".into());
+    println!("downsample\t{}", link);
+    println!("root\t{}\t{} buckets", root, downsample.count);
+    println!(
+        "source\t{}\t{} events",
+        downsample.source, downsample.source_count
+    );
+    println!("start\tdevice\tcount\tsum\tavg\tmin\tmax");
+    let all = TimeRangeQuery {
+        min: 0,
+        max: u64::MAX,
+    };
+    for (key, stats) in downsample.buckets(&txn, secrets, all)? {
+        let device = match key.device {
+            ALL => "*".to_string(),
+            device => device.to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{:.2}\t{}\t{}",
+            key.time,
+            device,
+            stats.count,
+            stats.sum,
+            stats.avg(),
+            stats.min,
+            stats.max
+        );
+    }
+    Ok(())
+}
+
+/// Downsample a growing tree per minute and per hour and device, and compare the buckets with
+/// aggregates of the raw events
+pub fn downsample_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let steps = 4;
+    let minute = 60 * 1000;
+    let hour = 60 * minute;
+    println!(
+        "Example: downsampling {} events per minute and per hour, in {} steps",
+        n, steps
+    );
+    // small leaves, so a scan of the whole source is more than a few blocks
+    let config = &Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let secrets = Secrets::default();
+    // no branch cache, so every block a refresh reads is counted
+    let stats = CacheStats::new();
+    let forest = Forest::<ColumnarTT, _>::new(stats.store(store.clone()), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, store);
+    let reads = || stats.misses() + stats.leaves();
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets.clone());
+    let events = columnar::events(n);
+    let all = TimeRangeQuery {
+        min: 0,
+        max: u64::MAX,
+    };
+    let chunk = (n / steps) as usize;
+    let mut links: Vec<Option<Sha256Digest>> = vec![None, None];
+    println!("source\twidth\tbuckets\tblocks read\tfrom scratch");
+    for (i, part) in events.chunks(chunk).enumerate() {
+        txn.extend(&mut builder, part.iter().cloned())?;
+        let source = builder.snapshot().link().expect("not empty");
+        let seen = &events[..(i * chunk + part.len())];
+        for (link, (width, per_device)) in links.iter_mut().zip([(minute, false), (hour, true)]) {
+            let before = reads();
+            let (scratch, from_scratch) =
+                downsample(&mut txn, config, &secrets, source, width, per_device)?;
+            let scratch_reads = reads() - before;
+            let (new, current) = match *link {
+                None => (scratch, from_scratch.clone()),
+                Some(old) => {
+                    let before = reads();
+                    let refreshed = refresh(&mut txn, config, &secrets, old, source)?;
+                    let read = reads() - before;
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}",
+                        refreshed.1.source_count,
+                        width,
+                        refreshed.1.count + refreshed.1.open.len() as u64,
+                        read,
+                        scratch_reads
+                    );
+                    anyhow::ensure!(read < scratch_reads, "the refresh read the whole source");
+                    refreshed
+                }
+            };
+            // the buckets have to be the aggregates of all events so far
+            let expected = Tumbling::new(
+                seen.iter().map(|(key, value)| {
+                    let group = if per_device { key.device } else { ALL };
+                    Ok((key.time, group, *value))
+                }),
+                width,
+            )
+            .map(|bucket| {
+                bucket.map(|b| {
+                    (
+                        EventKey {
+                            time: b.start,
+                            device: b.group,
+                        },
+                        b.stats,
+                    )
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+            let buckets = current.buckets(&txn, &secrets, all.clone())?;
+            anyhow::ensure!(
+                buckets == expected,
+                "the buckets differ from the aggregates"
+            );
+            anyhow::ensure!(
+                buckets == from_scratch.buckets(&txn, &secrets, all.clone())?,
+                "the refreshed buckets are not the buckets from scratch"
+            );
+            *link = Some(new);
+        }
+    }
+    // drill into the busiest hour of a device
+    let hourly = Downsample::load(txn.store(), links[1].expect("downsampled"))?;
+    let (key, busiest) = hourly
+        .buckets(&txn, &secrets, all)?
+        .into_iter()
+        .max_by_key(|(_, stats)| stats.count)
+        .expect("not empty");
+    let tree = builder.snapshot();
+    let raw = txn
+        .iter_filtered(&tree, hourly.raw(&key).to_query())
+        .map(|item| item.map(|(_, _, value)| value))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        raw.len() as u64 == busiest.count && raw.iter().sum::<u64>() == busiest.sum,
+        "the raw events are not the ones of the bucket"
+    );
+    println!(
+        "{} buckets per minute and {} per hour and device for {} events, {} in the busiest",
+        Downsample::load(txn.store(), links[0].expect("downsampled"))?.count,
+        hourly.count,
+        n,
+        busiest.count
+    );
+    println!();
+    Ok(())
+}
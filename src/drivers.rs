@@ -0,0 +1,469 @@
+//! Opening a tree without knowing its types
+//!
+//! A [Forest] is generic over the [TreeTypes], so code that reads a tree has to know them at
+//! compile time. For commands that work the same for every tree, like printing stats or
+//! exporting all events, the [Registry] has a boxed [TreeDriver] for each of the built-in tree
+//! types, keyed by their nonce.
+//!
+//! A root does not say which types it was written with. But the nonce is part of the encryption,
+//! so loading a root with the wrong types fails to decrypt or decode, and [Registry::detect] tries
+//! each driver until one succeeds.
+use std::{collections::BTreeMap, fmt::Write as _, io::Write, marker::PhantomData};
+
+use banyan::{
+    index::Index,
+    query::{AllQuery, OffsetRangeQuery},
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, Encode},
+    Ipld,
+};
+use multihash::{Code, MultihashDigest};
+
+use crate::{
+    columnar::{self, ColumnarTT},
+    retention::TaggedTT,
+    schema::{Schema, TreeSchema},
+    schemaless::{self, SchemalessTT},
+    snapshots::LogTT,
+    versioned::{Versioned, VersionedTT},
+};
+
+/// The size of a tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeStats {
+    pub count: u64,
+    pub level: i32,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+}
+
+/// The operations that work on a tree of any type
+pub trait TreeDriver<S> {
+    /// The name of the tree types, for printing
+    fn name(&self) -> &'static str;
+
+    /// Fails if the root is not a tree of these types with these secrets
+    fn stats(&self, store: &S, root: Sha256Digest, secrets: &Secrets) -> anyhow::Result<TreeStats>;
+
+    /// Write the events from offset `from` on as dag-json lines with offset, key and value, and
+    /// return their number
+    fn export(
+        &self,
+        store: &S,
+        root: Sha256Digest,
+        secrets: &Secrets,
+        from: u64,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<u64>;
+
+    /// Write the index of every node as a dag-json line, and return their number. Only needs the
+    /// index key of the secrets
+    fn index(
+        &self,
+        store: &S,
+        root: Sha256Digest,
+        secrets: &Secrets,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<u64>;
+
+    /// Write a line per leaf with its offset range, link, size and the hash of its values, see
+    /// [print_audit_manifest], and return the number of leaves
+    fn audit(&self, store: &S, root: Sha256Digest, out: &mut dyn Write) -> anyhow::Result<u64>;
+
+    /// Check the invariants of the tree and decode all events, and return their number
+    fn verify(&self, store: &S, config: &Config, root: Sha256Digest) -> anyhow::Result<u64>;
+
+    /// The schema of the key and value types
+    fn schema(&self) -> TreeSchema;
+}
+
+/// The driver for a tree with types `T` and values `V`
+struct Driver<T, V> {
+    name: &'static str,
+    _types: PhantomData<fn() -> (T, V)>,
+}
+
+/// An event as [Ipld], to print it without knowing its type
+fn to_ipld(value: &impl Encode<DagCborCodec>) -> anyhow::Result<Ipld> {
+    DagCborCodec.decode(&DagCborCodec.encode(value)?)
+}
+
+impl<S, T, V> TreeDriver<S> for Driver<T, V>
+where
+    S: ReadOnlyStore<Sha256Digest>,
+    T: TreeTypes<Link = Sha256Digest>,
+    T::Key: Encode<DagCborCodec> + Schema,
+    T::Summary: Encode<DagCborCodec>,
+    V: BanyanValue + Schema,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self, store: &S, root: Sha256Digest, secrets: &Secrets) -> anyhow::Result<TreeStats> {
+        let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+        let tree = forest.load_tree::<V>(secrets.clone(), root)?;
+        // the root of a small tree is a leaf, which decodes with the wrong types until a value
+        // is decoded
+        if let Some(item) = forest.iter_from(&tree).next() {
+            item?;
+        }
+        let index = tree.index().expect("a root is not empty");
+        Ok(TreeStats {
+            count: tree.count(),
+            level: tree.level(),
+            key_bytes: index.key_bytes(),
+            value_bytes: index.value_bytes(),
+        })
+    }
+
+    fn export(
+        &self,
+        store: &S,
+        root: Sha256Digest,
+        secrets: &Secrets,
+        from: u64,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<u64> {
+        let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+        let tree = forest.load_tree::<V>(secrets.clone(), root)?;
+        let mut count = 0;
+        for item in forest.iter_filtered(&tree, OffsetRangeQuery::from(from..)) {
+            let (i, k, v) = item?;
+            let event = Ipld::StringMap(
+                vec![
+                    ("offset".to_string(), Ipld::Integer(i.into())),
+                    ("key".to_string(), to_ipld(&k)?),
+                    ("value".to_string(), to_ipld(&v)?),
+                ]
+                .into_iter()
+                .collect(),
+            );
+            writeln!(out, "{}", schemaless::to_json(&event)?)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn index(
+        &self,
+        store: &S,
+        root: Sha256Digest,
+        secrets: &Secrets,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<u64> {
+        let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+        let tree = forest.load_tree::<V>(secrets.clone(), root)?;
+        let (mut offset, mut count) = (0u64, 0u64);
+        for index in forest.iter_index(&tree, AllQuery) {
+            let index = index?;
+            let mut fields = vec![
+                ("level", Ipld::Integer(index.level().into())),
+                ("count", Ipld::Integer(index.count().into())),
+                ("summary", to_ipld(&index.summarize())?),
+                ("sealed", Ipld::Bool(index.sealed())),
+                ("key_bytes", Ipld::Integer(index.key_bytes().into())),
+                ("value_bytes", Ipld::Integer(index.value_bytes().into())),
+            ];
+            if let Index::Leaf(leaf) = &index {
+                let keys = leaf.keys().collect::<Vec<_>>();
+                fields.push(("offset", Ipld::Integer(offset.into())));
+                if let (Some(first), Some(last)) = (keys.first(), keys.last()) {
+                    fields.push(("first_key", to_ipld(first)?));
+                    fields.push(("last_key", to_ipld(last)?));
+                }
+                offset += index.count();
+            }
+            let node = fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value));
+            writeln!(
+                out,
+                "{}",
+                schemaless::to_json(&Ipld::StringMap(node.collect()))?
+            )?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn audit(&self, store: &S, root: Sha256Digest, out: &mut dyn Write) -> anyhow::Result<u64> {
+        let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+        let tree = forest.load_tree::<V>(Secrets::default(), root)?;
+        // the branches have the offsets, links and sizes of the leaves
+        let mut leaves = Vec::new();
+        let mut offset = 0u64;
+        for index in forest.iter_index(&tree, AllQuery) {
+            let index = index?;
+            if let Index::Leaf(leaf) = &index {
+                leaves.push((offset, offset + index.count(), leaf.link, leaf.value_bytes));
+                offset += index.count();
+            }
+        }
+        let mut events = forest.iter_from(&tree);
+        for (from, to, link, bytes) in &leaves {
+            let mut values = Vec::new();
+            for expected in *from..*to {
+                let (i, _, v) = events
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("the leaf at {} is short", from))??;
+                anyhow::ensure!(i == expected, "offset {} instead of {}", i, expected);
+                values.extend(DagCborCodec.encode(&v)?);
+            }
+            let hash = Code::Sha2_256.digest(&values).digest().iter().fold(
+                String::new(),
+                |mut res, byte| {
+                    let _ = write!(res, "{:02x}", byte);
+                    res
+                },
+            );
+            let link = link.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+            writeln!(out, "{}\t{}\t{}\t{}\t{}", from, to, link, bytes, hash)?;
+        }
+        Ok(leaves.len() as u64)
+    }
+
+    fn verify(&self, store: &S, config: &Config, root: Sha256Digest) -> anyhow::Result<u64> {
+        let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+        let builder = forest.load_stream_builder::<V>(Secrets::default(), config.clone(), root)?;
+        let problems = forest.check_invariants(&builder)?;
+        anyhow::ensure!(problems.is_empty(), "invalid tree: {}", problems.join(", "));
+        let mut count = 0;
+        for item in forest.iter_from(&builder.snapshot()) {
+            let (i, _, _) = item?;
+            anyhow::ensure!(i == count, "offset {} instead of {}", i, count);
+            count += 1;
+        }
+        anyhow::ensure!(count == builder.count(), "events are missing");
+        Ok(count)
+    }
+
+    fn schema(&self) -> TreeSchema {
+        TreeSchema::of::<T, V>()
+    }
+}
+
+/// The drivers for all tree types this binary knows
+pub struct Registry<S> {
+    drivers: BTreeMap<&'static [u8; 24], Box<dyn TreeDriver<S>>>,
+}
+
+impl<S: ReadOnlyStore<Sha256Digest>> Registry<S> {
+    /// A registry with the tree types of the examples
+    pub fn builtin() -> Self {
+        let mut res = Self {
+            drivers: BTreeMap::new(),
+        };
+        res.register::<LogTT, u64>("log");
+        res.register::<SchemalessTT, Ipld>("schemaless");
+        res.register::<VersionedTT, Versioned>("versioned");
+        res.register::<ColumnarTT, u64>("columnar");
+        res.register::<TaggedTT, u64>("tagged");
+        res
+    }
+
+    /// Add the driver for trees with types `T` and values `V`
+    pub fn register<T, V>(&mut self, name: &'static str)
+    where
+        T: TreeTypes<Link = Sha256Digest>,
+        T::Key: Encode<DagCborCodec> + Schema,
+        T::Summary: Encode<DagCborCodec>,
+        V: BanyanValue + Schema,
+    {
+        let driver = Driver::<T, V> {
+            name,
+            _types: PhantomData,
+        };
+        let previous = self.drivers.insert(T::NONCE, Box::new(driver));
+        assert!(previous.is_none(), "nonce of {} is already used", name);
+    }
+
+    /// The nonce and name of each tree type
+    pub fn types(&self) -> impl Iterator<Item = (&'static [u8; 24], &'static str)> + '_ {
+        self.drivers
+            .iter()
+            .map(|(nonce, driver)| (*nonce, driver.name()))
+    }
+
+    /// The driver for the root, and the index of its nodes as written by [TreeDriver::index].
+    /// Unlike [Registry::detect], this only needs the index key
+    pub fn detect_index(
+        &self,
+        store: &S,
+        root: Sha256Digest,
+        secrets: &Secrets,
+    ) -> anyhow::Result<(&dyn TreeDriver<S>, Vec<u8>)> {
+        for driver in self.drivers.values() {
+            let mut out = Vec::new();
+            if driver.index(store, root, secrets, &mut out).is_ok() {
+                return Ok((driver.as_ref(), out));
+            }
+        }
+        anyhow::bail!(
+            "{} is not a tree of any known type with this index key",
+            root
+        )
+    }
+
+    /// The driver for the root, and its stats
+    pub fn detect(
+        &self,
+        store: &S,
+        root: Sha256Digest,
+        secrets: &Secrets,
+    ) -> anyhow::Result<(&dyn TreeDriver<S>, TreeStats)> {
+        for driver in self.drivers.values() {
+            if let Ok(stats) = driver.stats(store, root, secrets) {
+                return Ok((driver.as_ref(), stats));
+            }
+        }
+        anyhow::bail!("{} is not a tree of any known type", root)
+    }
+}
+
+/// Print the type and size of a tree
+pub fn print_stats<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+) -> anyhow::Result<()> {
+    let registry = Registry::builtin();
+    let (driver, stats) = registry.detect(store, root, &Secrets::default())?;
+    println!("types\t{}", driver.name());
+    println!("count\t{}", stats.count);
+    println!("level\t{}", stats.level);
+    println!("key bytes\t{}", stats.key_bytes);
+    println!("value bytes\t{}", stats.value_bytes);
+    Ok(())
+}
+
+/// Print all events of a tree as dag-json, one per line
+pub fn print_export<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+) -> anyhow::Result<()> {
+    let registry = Registry::builtin();
+    let (driver, _) = registry.detect(store, root, &Secrets::default())?;
+    driver.export(
+        store,
+        root,
+        &Secrets::default(),
+        0,
+        &mut std::io::stdout().lock(),
+    )?;
+    Ok(())
+}
+
+/// Print the audit manifest of a tree: a line per leaf with the offsets from and to, exclusive,
+/// the link, the size of the values in bytes as the index records it, and the sha2-256 of the
+/// values as dag-cbor, one after the other, in hex. The same tree always has the same manifest,
+/// so it can be archived as a fingerprint of the data, and checked against the data later,
+/// without keeping a copy. Another tree with the same events can have other leaves
+pub fn print_audit_manifest<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+) -> anyhow::Result<()> {
+    let registry = Registry::builtin();
+    let (driver, _) = registry.detect(store, root, &Secrets::default())?;
+    driver.audit(store, root, &mut std::io::stdout().lock())?;
+    Ok(())
+}
+
+/// Check a tree and print the number of events
+pub fn print_verify<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    config: &Config,
+    root: Sha256Digest,
+) -> anyhow::Result<()> {
+    let registry = Registry::builtin();
+    let (driver, _) = registry.detect(store, root, &Secrets::default())?;
+    let count = driver.verify(store, config, root)?;
+    println!("{} tree with {} events is valid", driver.name(), count);
+    Ok(())
+}
+
+/// Build trees of different types, and detect, verify and export each one by its root alone
+pub fn drivers_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 10000u64;
+    println!(
+        "Example: opening trees of {} events without knowing their types",
+        n
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let log = builder.link().expect("not empty");
+
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, columnar::events(n))?;
+    let events = builder.link().expect("not empty");
+
+    let forest = Forest::<SchemalessTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<SchemalessTT, Ipld>::new(config.clone(), Secrets::default());
+    let value = schemaless::from_json(r#"{"type":"note","text":"hello"}"#)?;
+    txn.extend(&mut builder, (0..n).map(|_| ((), value.clone())))?;
+    let notes = builder.link().expect("not empty");
+
+    let registry = Registry::builtin();
+    println!("root\ttypes\tcount\tlevel\texported bytes\tleaves");
+    for (root, expected) in [(log, "log"), (events, "columnar"), (notes, "schemaless")] {
+        let (driver, stats) = registry.detect(&store, root, &Secrets::default())?;
+        anyhow::ensure!(
+            driver.name() == expected,
+            "{} detected as {}",
+            root,
+            driver.name()
+        );
+        anyhow::ensure!(
+            driver.verify(&store, config, root)? == n,
+            "events are missing"
+        );
+        let mut json = Vec::new();
+        anyhow::ensure!(
+            driver.export(&store, root, &Secrets::default(), 0, &mut json)? == n,
+            "export is short"
+        );
+        anyhow::ensure!(
+            driver.export(&store, root, &Secrets::default(), n - 1, &mut Vec::new())? == 1,
+            "export from the last offset is not the last event"
+        );
+        // the audit manifest covers every event once, and is the same every time
+        let mut manifest = Vec::new();
+        let leaves = driver.audit(&store, root, &mut manifest)?;
+        let mut again = Vec::new();
+        driver.audit(&store, root, &mut again)?;
+        let mut next = 0;
+        for line in std::str::from_utf8(&manifest)?.lines() {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            anyhow::ensure!(fields.len() == 5, "invalid manifest line {}", line);
+            anyhow::ensure!(fields[0].parse::<u64>()? == next, "gap before {}", line);
+            next = fields[1].parse()?;
+        }
+        anyhow::ensure!(
+            next == n && manifest == again,
+            "the manifest is not deterministic or misses events"
+        );
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            root,
+            driver.name(),
+            stats.count,
+            stats.level,
+            json.len(),
+            leaves
+        );
+    }
+    println!();
+    Ok(())
+}
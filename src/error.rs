@@ -0,0 +1,402 @@
+//! Errors that callers can tell apart
+//!
+//! Everything returns `anyhow::Result`, like banyan itself, and most errors are just reported. A
+//! caller that wants to react differently to "kubo is down" and "the tree is corrupt" gets the
+//! [ErrorKind] of any error with [kind]. It looks through the chain of causes for an [Error] of
+//! this crate, one of the other typed errors, like a [RootConflict], or an error of a library
+//! that says what happened, like a connection that was refused.
+//!
+//! The stores of this crate report a missing block as [Error::BlockMissing], and a put that does
+//! not fit as [Error::QuotaExceeded], so a retry layer or a fallback to another store does not
+//! have to parse messages. The errors of banyan itself are untyped, so they are mapped where they
+//! come from: [BoundedMemStore](crate::mem_store::BoundedMemStore) wraps its `MemStore`, and the
+//! crate decodes blocks that it has to tell apart itself, like [projection::load_children], which
+//! report [Error::Decode]. [kind] never looks at the text of a message.
+use std::{fmt, io};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+
+use crate::{
+    cancel::{Cancel, CancellableStore},
+    columnar::{self, ColumnarTT},
+    flaky,
+    fs_store::FsStore,
+    projection,
+    roots::{ManifestFile, RootConflict},
+    schema::{self, TreeSchema},
+    snapshots::LogTT,
+};
+
+/// An error of this crate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// the store can not be reached, like a kubo that is not running
+    StoreUnavailable { reason: String },
+    /// the store works, but does not have the block
+    BlockMissing { cid: Cid },
+    /// a block is there, but is not what it should be. Either the tree is corrupt, or it is read
+    /// with the wrong types or secrets
+    Decode { link: String, reason: String },
+    /// the store returned a block whose hash is not its link
+    Corrupt { cid: Cid, actual: Cid },
+    /// the store has no room for the block, with the bytes it has and the most it takes
+    QuotaExceeded { used: u64, limit: u64 },
+    /// the tree was written with other types than it is read with
+    SchemaMismatch {
+        name: String,
+        expected: TreeSchema,
+        actual: TreeSchema,
+    },
+}
+
+impl Error {
+    pub fn missing(link: impl Into<Cid>) -> Self {
+        Self::BlockMissing { cid: link.into() }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StoreUnavailable { reason } => write!(f, "store unavailable: {}", reason),
+            Self::BlockMissing { cid } => write!(f, "block {} not found", cid),
+            Self::Decode { link, reason } => {
+                write!(f, "block {} can not be decoded: {}", link, reason)
+            }
+            Self::Corrupt { cid, actual } => {
+                write!(f, "block {} is corrupt, its hash is {}", cid, actual)
+            }
+            Self::QuotaExceeded { used, limit } => {
+                write!(f, "store is full, {} of {} bytes are used", used, limit)
+            }
+            Self::SchemaMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "tree {} was written with {:?}, but is read as {:?}",
+                name, actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What kind of error an error is, for deciding what to do about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// try again later, or use another store
+    StoreUnavailable,
+    /// a get that failed once, but might work when tried again
+    Transient,
+    /// the block is not in the store, trying again will not help
+    BlockMissing,
+    /// the tree is corrupt, or was opened with the wrong types or secrets
+    Decode,
+    /// the store returned other bytes than the block, another store might have the right ones
+    Corrupt,
+    /// the store is full, free some space or use another one
+    QuotaExceeded,
+    SchemaMismatch,
+    /// someone else changed a root, rebase and try again
+    Conflict,
+    /// the query was cancelled or ran out of time
+    Cancelled,
+    /// anything else
+    Other,
+}
+
+/// The kind of the first cause in the chain that says what happened
+pub fn kind(err: &anyhow::Error) -> ErrorKind {
+    err.chain()
+        .find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<Error>() {
+                return Some(match err {
+                    Error::StoreUnavailable { .. } => ErrorKind::StoreUnavailable,
+                    Error::BlockMissing { .. } => ErrorKind::BlockMissing,
+                    Error::Decode { .. } => ErrorKind::Decode,
+                    Error::Corrupt { .. } => ErrorKind::Corrupt,
+                    Error::QuotaExceeded { .. } => ErrorKind::QuotaExceeded,
+                    Error::SchemaMismatch { .. } => ErrorKind::SchemaMismatch,
+                });
+            }
+            if cause.is::<flaky::BlockMissing>() {
+                return Some(ErrorKind::BlockMissing);
+            }
+            if cause.is::<flaky::TransientError>() {
+                return Some(ErrorKind::Transient);
+            }
+            if cause.is::<crate::cancel::Cancelled>() {
+                return Some(ErrorKind::Cancelled);
+            }
+            if cause.is::<RootConflict<Sha256Digest>>() {
+                return Some(ErrorKind::Conflict);
+            }
+            if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                if err.is_connect() || err.is_timeout() {
+                    return Some(ErrorKind::StoreUnavailable);
+                }
+            }
+            if let Some(err) = cause.downcast_ref::<io::Error>() {
+                if err.kind() == io::ErrorKind::ConnectionRefused {
+                    return Some(ErrorKind::StoreUnavailable);
+                }
+            }
+            if is_cbor_decode(cause) {
+                return Some(ErrorKind::Decode);
+            }
+            None
+        })
+        .unwrap_or(ErrorKind::Other)
+}
+
+/// Whether the error is dag-cbor that does not decode as the type it is read as
+fn is_cbor_decode(cause: &(dyn std::error::Error + 'static)) -> bool {
+    use libipld::cbor::error::*;
+    cause.is::<UnexpectedCode>()
+        || cause.is::<UnexpectedEof>()
+        || cause.is::<UnexpectedKey>()
+        || cause.is::<MissingKey>()
+        || cause.is::<UnknownTag>()
+        || cause.is::<InvalidCidPrefix>()
+        || cause.is::<LengthOutOfRange>()
+        || cause.is::<NumberOutOfRange>()
+}
+
+/// Provoke one error of each kind, and check that [kind] tells them apart
+pub fn error_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    println!("Example: telling errors apart");
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let secrets = Secrets::new([1u8; 32].into(), [2u8; 32].into());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets);
+    txn.extend(&mut builder, columnar::events(10000))?;
+    let root = builder.link().expect("not empty");
+
+    // a store without the tree, where the log tree types were recorded as tree `events`
+    let dir = std::env::temp_dir().join(format!("banyan-errors-{}", std::process::id()));
+    let mut other = FsStore::<Sha256Digest>::open(&dir)?;
+    let roots = ManifestFile::new(dir.join("manifest"));
+    schema::record::<LogTT, u64, _>(&mut other, &roots, "events")?;
+    let cancel = Cancel::new();
+    cancel.cancel();
+    let errors = [
+        (
+            "missing block",
+            other.get(&root).unwrap_err(),
+            ErrorKind::BlockMissing,
+        ),
+        (
+            "wrong secrets",
+            projection::load_children::<ColumnarTT>(&store, &Secrets::default(), &root)
+                .unwrap_err(),
+            ErrorKind::Decode,
+        ),
+        (
+            "wrong types",
+            schema::check::<ColumnarTT, u64, _>(&other, &roots, "events").unwrap_err(),
+            ErrorKind::SchemaMismatch,
+        ),
+        (
+            "cancelled",
+            CancellableStore::new(store, cancel).get(&root).unwrap_err(),
+            ErrorKind::Cancelled,
+        ),
+    ];
+    println!("error\tkind\tmessage");
+    for (name, err, expected) in errors {
+        anyhow::ensure!(kind(&err) == expected, "{} is {:?}", name, kind(&err));
+        println!("{}\t{:?}\t{}", name, kind(&err), err);
+    }
+    std::fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use banyan::store::MemStore;
+    use libipld::{cbor::DagCborCodec, codec::Codec};
+
+    use super::*;
+    use crate::{
+        borrowed,
+        car::{self, CarStore},
+        gc_store::GcStore,
+        kubo::{Endpoint, KuboStore},
+        mem_store::BoundedMemStore,
+        peer::{self, PeerStore},
+        sqlite_store::SqliteStore,
+    };
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("banyan-kind-{}-{}", name, std::process::id()))
+    }
+
+    /// A kubo api that answers one request with this status and body
+    fn fake_kubo(status: &'static str, body: &'static str) -> KuboStore {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            let mut length = 0;
+            while r.read_line(&mut line).unwrap() > 2 {
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            // the block of a block/put, before the answer
+            io::copy(&mut r.by_ref().take(length), &mut io::sink()).unwrap();
+            write!(
+                &stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        KuboStore::new(Endpoint::new(&format!("http://{}", addr), None, None).unwrap())
+    }
+
+    #[test]
+    fn missing_blocks() {
+        let link = Sha256Digest::digest(b"not in any store");
+        let fs_dir = temp_dir("fs");
+        let sqlite = temp_dir("sqlite");
+        let car = temp_dir("car");
+        let mem = BoundedMemStore::new(u64::MAX);
+        car::write_car(&mem, &[], &car).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || peer::serve(listener, GcStore::new(u64::MAX)));
+        let kubo = fake_kubo(
+            "404 Not Found",
+            r#"{"Message":"ipld: could not find bafy","Code":3,"Type":"error"}"#,
+        );
+        let errors = [
+            ("mem", mem.get(&link).unwrap_err()),
+            (
+                "fs",
+                FsStore::<Sha256Digest>::open(&fs_dir)
+                    .unwrap()
+                    .get(&link)
+                    .unwrap_err(),
+            ),
+            (
+                "sqlite",
+                SqliteStore::<Sha256Digest>::open(&sqlite)
+                    .unwrap()
+                    .get(&link)
+                    .unwrap_err(),
+            ),
+            ("gc", GcStore::new(u64::MAX).get(&link).unwrap_err()),
+            ("car", CarStore::open(&car).unwrap().get(&link).unwrap_err()),
+            (
+                "peer",
+                PeerStore::new(addr.to_string()).get(&link).unwrap_err(),
+            ),
+            ("kubo", kubo.get(&link).unwrap_err()),
+        ];
+        for (name, err) in errors {
+            assert_eq!(kind(&err), ErrorKind::BlockMissing, "{}: {:#}", name, err);
+        }
+        std::fs::remove_dir_all(&fs_dir).unwrap();
+        std::fs::remove_file(&sqlite).unwrap();
+        std::fs::remove_file(&car).unwrap();
+    }
+
+    #[test]
+    fn kubo_failures() {
+        let link = Sha256Digest::digest(b"not in any store");
+        let kubo = fake_kubo(
+            "400 Bad Request",
+            r#"{"Message":"merkledag: not a valid cid","Code":1,"Type":"error"}"#,
+        );
+        let err = kubo.get(&link).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::Other, "{:#}", err);
+        // whatever the body says, a 5xx is a kubo that can not answer now
+        let kubo = fake_kubo(
+            "500 Internal Server Error",
+            r#"{"Message":"could not find the repo lock","Code":0,"Type":"error"}"#,
+        );
+        let err = kubo.get(&link).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::StoreUnavailable, "{:#}", err);
+        // a proxy in front of kubo that does not answer in json
+        let mut kubo = fake_kubo("502 Bad Gateway", "<html>bad gateway</html>");
+        let err = kubo.put(b"a block".to_vec()).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::StoreUnavailable, "{:#}", err);
+        // a port that nothing listens on, once the listener is gone
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let kubo = KuboStore::new(Endpoint::new(&format!("http://{}", addr), None, None).unwrap());
+        let err = kubo.get(&link).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::StoreUnavailable, "{:#}", err);
+    }
+
+    #[test]
+    fn full_stores() {
+        let block = vec![0u8; 100];
+        let mut mem = BoundedMemStore::new(150);
+        mem.put(block.clone()).unwrap();
+        // the same block again takes no room
+        mem.put(block).unwrap();
+        let err = mem.put(vec![1u8; 100]).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::QuotaExceeded, "{:#}", err);
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::QuotaExceeded {
+                used: 100,
+                limit: 150
+            })
+        ));
+        // nothing is reachable from a root, but the block is new
+        let mut gc = GcStore::new(150);
+        gc.put(vec![0u8; 100]).unwrap();
+        let err = gc.put(vec![1u8; 100]).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::QuotaExceeded, "{:#}", err);
+    }
+
+    #[test]
+    fn undecodable_blocks() {
+        let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(0));
+        let mut txn = Transaction::new(forest, store.clone());
+        let secrets = Secrets::new([1u8; 32].into(), [2u8; 32].into());
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(Config::debug_fast(), secrets);
+        txn.extend(&mut builder, columnar::events(10000)).unwrap();
+        let root = builder.link().unwrap();
+        // a wrong index key fails on the branches, a wrong value key on the first leaf
+        let wrong_index = Secrets::new([9u8; 32].into(), [2u8; 32].into());
+        let err = projection::load_children::<ColumnarTT>(&store, &wrong_index, &root).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::Decode, "{:#}", err);
+        let wrong_value = Secrets::new([1u8; 32].into(), [9u8; 32].into());
+        let tree = txn.load_tree::<u64>(wrong_value, root).unwrap();
+        let err = borrowed::scan(&store, &tree, |_, _, _| Ok(())).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::Decode, "{:#}", err);
+        // a string where a number should be
+        let err = DagCborCodec.decode::<u64>(&[0x61, b'a']).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::Decode, "{:#}", err);
+    }
+}
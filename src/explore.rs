@@ -0,0 +1,253 @@
+//! Looking at a single block
+//!
+//! The ipfs explorer shows a banyan node as an offset, some links and a blob of bytes, since
+//! everything else is compressed and encrypted. [describe] decodes what it can: a node that one
+//! of the tree types of the [Registry] can decrypt with the secrets is a branch with the indices
+//! of its children, or a leaf with its values. Another dag-cbor block, like a snapshot record, is
+//! shown as is, and anything else is raw bytes, like a chunk of an attachment.
+//!
+//! The tree types are found the same way as for a root: the nonce is part of the encryption, so
+//! only the right types decrypt the node into something that decompresses.
+use std::io::Cursor;
+
+use banyan::{
+    chacha20::XNonce,
+    store::{BlockWriter, BranchCache, ReadOnlyStore, ZstdDagCborSeq},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Decode, Cid, Ipld};
+
+use crate::{
+    blobs,
+    drivers::Registry,
+    schemaless,
+    snapshots::{self, LogTT},
+};
+
+/// What a block is, as far as it can be decoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum Block {
+    /// a branch, with the index of each child. The index has the link of the child, the links
+    /// of the node are only the set of them
+    Branch {
+        types: &'static str,
+        links: Vec<Cid>,
+        children: Vec<Ipld>,
+    },
+    /// a leaf, with its values
+    Leaf {
+        types: &'static str,
+        values: Vec<Ipld>,
+    },
+    /// a banyan node that no known types decrypt with the secrets
+    Encrypted { links: Vec<Cid>, bytes: usize },
+    /// a dag-cbor block that is not a banyan node
+    DagCbor(Ipld),
+    /// anything else
+    Raw(Box<[u8]>),
+}
+
+/// The links and encrypted bytes of a banyan node, which is a dag-cbor list of the offset in the
+/// key stream, the links, and the bytes
+fn node(ipld: &Ipld) -> Option<(Vec<Cid>, usize)> {
+    match ipld {
+        Ipld::List(fields) => match fields.as_slice() {
+            [Ipld::Integer(_), Ipld::List(links), Ipld::Bytes(bytes)] => {
+                let links = links.iter().map(|link| match link {
+                    Ipld::Link(cid) => Some(*cid),
+                    _ => None,
+                });
+                Some((links.collect::<Option<Vec<_>>>()?, bytes.len()))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The block as dag-cbor, if all of it is. Some raw bytes start with a valid dag-cbor item
+fn decode(data: &[u8]) -> Option<Ipld> {
+    let mut cursor = Cursor::new(data);
+    let ipld = Ipld::decode(DagCborCodec, &mut cursor).ok()?;
+    (cursor.position() == data.len() as u64).then_some(ipld)
+}
+
+/// Decode a block as far as the known types and the secrets allow
+pub fn describe<S: ReadOnlyStore<Sha256Digest>>(
+    registry: &Registry<S>,
+    secrets: &Secrets,
+    data: &[u8],
+) -> Block {
+    let Some(ipld) = decode(data) else {
+        return Block::Raw(data.into());
+    };
+    let Some((links, bytes)) = node(&ipld) else {
+        return Block::DagCbor(ipld);
+    };
+    // leaves have no links, branches have a link to each child
+    let key = if links.is_empty() {
+        secrets.value_key()
+    } else {
+        secrets.index_key()
+    };
+    for (nonce, types) in registry.types() {
+        let items = ZstdDagCborSeq::decrypt(data, key, <&XNonce>::from(nonce))
+            .and_then(|(seq, _)| seq.items::<Ipld>());
+        match items {
+            Ok(values) if links.is_empty() => return Block::Leaf { types, values },
+            Ok(children) => {
+                return Block::Branch {
+                    types,
+                    links,
+                    children,
+                }
+            }
+            Err(_) => {}
+        }
+    }
+    Block::Encrypted { links, bytes }
+}
+
+/// Print a block: what it is, and what it contains as dag-json, one item per line
+pub fn print_block<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    link: Sha256Digest,
+    secrets: &Secrets,
+) -> anyhow::Result<()> {
+    let data = store.get(&link)?;
+    println!("block\t{}", Cid::from(link));
+    println!("bytes\t{}", data.len());
+    match describe(&Registry::<S>::builtin(), secrets, &data) {
+        Block::Branch {
+            types,
+            links,
+            children,
+        } => {
+            println!("kind\tbranch of {} tree", types);
+            println!("links\t{}", links.len());
+            for child in children {
+                println!("{}", schemaless::to_json(&child)?);
+            }
+        }
+        Block::Leaf { types, values } => {
+            println!("kind\tleaf of {} tree", types);
+            for value in values {
+                println!("{}", schemaless::to_json(&value)?);
+            }
+        }
+        Block::Encrypted { links, bytes } => {
+            println!("kind\tbanyan node of unknown types, or with other secrets");
+            println!("encrypted\t{}", bytes);
+            for link in links {
+                println!("{}", link);
+            }
+        }
+        Block::DagCbor(ipld) => {
+            println!("kind\tdag-cbor");
+            println!("{}", schemaless::to_json(&ipld)?);
+        }
+        Block::Raw(data) => {
+            println!("kind\traw");
+            let preview = &data[..data.len().min(64)];
+            match std::str::from_utf8(preview) {
+                Ok(text) => println!("{:?}", text),
+                Err(_) => println!("{}", hex(preview)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The link in the index of a child
+fn child_link(child: &Ipld) -> Option<Sha256Digest> {
+    match child.get("link").ok()? {
+        Ipld::Link(cid) => Sha256Digest::try_from(*cid).ok(),
+        _ => None,
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Describe each kind of block of a log tree with an attachment and a snapshot record
+pub fn explore_example<S: ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>>(
+    mut store: S,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 10000u64;
+    println!(
+        "Example: looking at single blocks of a tree of {} events",
+        n
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let secrets = Secrets::new([3u8; 32].into(), [4u8; 32].into());
+    // small leaves, so the tree has a few levels
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config, secrets.clone());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+    let root = builder.link().expect("not empty");
+    let record = snapshots::record(&mut store, "example", snapshots::now(), &tree, None)?;
+    let blob = blobs::put_blob(&mut store, &blobs::noise(1 << 16, 1), 1 << 14)?;
+    let chunk = blobs::chunk_links(&store, &blob)?[0];
+
+    let registry = Registry::<S>::builtin();
+    let get = |link: &Sha256Digest| store.get(link);
+    let Block::Branch { children, .. } = describe(&registry, &secrets, &get(&root)?) else {
+        anyhow::bail!("the root is not a branch");
+    };
+    // follow the first child down to a leaf
+    let first_child = |children: &[Ipld]| {
+        children
+            .first()
+            .and_then(child_link)
+            .ok_or_else(|| anyhow::anyhow!("no link to the first child"))
+    };
+    let mut first = first_child(&children)?;
+    let leaf = loop {
+        match describe(&registry, &secrets, &get(&first)?) {
+            Block::Branch { children, .. } => first = first_child(&children)?,
+            Block::Leaf { types, values } => break (types, values),
+            other => anyhow::bail!("not a tree node: {:?}", other),
+        }
+    };
+    anyhow::ensure!(leaf.0 == "log", "leaf of {} tree", leaf.0);
+    anyhow::ensure!(
+        leaf.1.first() == Some(&Ipld::Integer(0)),
+        "not the first leaf"
+    );
+    println!(
+        "root\tbranch with {} children, first leaf with {} values",
+        children.len(),
+        leaf.1.len()
+    );
+    let without = describe(&registry, &Secrets::default(), &get(&root)?);
+    anyhow::ensure!(
+        matches!(without, Block::Encrypted { .. }),
+        "decrypted without the secrets"
+    );
+    println!("root without the secrets\tencrypted");
+    let kinds = [
+        ("snapshot record", get(&record)?, "dag-cbor"),
+        ("attachment chunk", get(&chunk)?, "raw"),
+    ];
+    for (name, data, expected) in kinds {
+        let kind = match describe(&registry, &secrets, &data) {
+            Block::DagCbor(_) => "dag-cbor",
+            Block::Raw(_) => "raw",
+            other => anyhow::bail!("{} is {:?}", name, other),
+        };
+        anyhow::ensure!(kind == expected, "{} is {}", name, kind);
+        println!("{}\t{}", name, kind);
+    }
+    println!();
+    Ok(())
+}
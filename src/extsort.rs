@@ -0,0 +1,258 @@
+//! Sorting event streams that do not fit into memory
+//!
+//! [sort] reads events until their estimated size reaches the memory budget, sorts them by key
+//! and spills them to the store as a temporary tree, a run. Then it merges up to [FAN_IN] runs at
+//! a time with a heap, streaming each from the store, until a single tree is left. An open run
+//! only holds its current leaf in memory, so merging needs about `FAN_IN` leaves.
+//!
+//! The sort is stable: events with the same key stay in the order they came in. The temporary
+//! trees are garbage once the sort is done.
+use std::{cmp::Reverse, collections::BinaryHeap, mem, time::Instant};
+
+use banyan::{
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec};
+
+use crate::columnar::{self, ColumnarTT};
+
+/// The number of runs that are merged at once
+pub const FAN_IN: usize = 16;
+
+/// The number of events written at a time while merging. Each extend packs the tree, so small
+/// batches make the merge much slower
+const BATCH: usize = 1 << 14;
+
+/// A sorted tree, and how much spilling it took
+pub struct Sorted<T: TreeTypes, V> {
+    pub tree: Tree<T, V>,
+    /// the number of runs spilled to the store
+    pub runs: usize,
+    /// the number of merge passes over all events
+    pub passes: usize,
+}
+
+/// Merge iterators that are each ordered by key, taking the first one on equal keys
+pub struct KWayMerge<I, K, V> {
+    inputs: Vec<I>,
+    /// the next key of each input that has one, with the input index
+    heap: BinaryHeap<Reverse<(K, usize)>>,
+    /// the value that goes with the key of each input in the heap
+    values: Vec<Option<V>>,
+    /// an error from an input, returned next
+    error: Option<anyhow::Error>,
+}
+
+impl<I, K, V> KWayMerge<I, K, V>
+where
+    I: Iterator<Item = anyhow::Result<(K, V)>>,
+    K: Ord,
+{
+    pub fn new(inputs: Vec<I>) -> Self {
+        let mut res = Self {
+            values: inputs.iter().map(|_| None).collect(),
+            inputs,
+            heap: BinaryHeap::new(),
+            error: None,
+        };
+        for i in 0..res.inputs.len() {
+            res.advance(i);
+        }
+        res
+    }
+
+    /// Put the next event of input `i` into the heap
+    fn advance(&mut self, i: usize) {
+        match self.inputs[i].next() {
+            Some(Ok((key, value))) => {
+                self.heap.push(Reverse((key, i)));
+                self.values[i] = Some(value);
+            }
+            Some(Err(cause)) => self.error = Some(cause),
+            None => {}
+        }
+    }
+}
+
+impl<I, K, V> Iterator for KWayMerge<I, K, V>
+where
+    I: Iterator<Item = anyhow::Result<(K, V)>>,
+    K: Ord,
+{
+    type Item = anyhow::Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(cause) = self.error.take() {
+            self.heap.clear();
+            return Some(Err(cause));
+        }
+        let Reverse((key, i)) = self.heap.pop()?;
+        let value = self.values[i].take().expect("a value for each key");
+        self.advance(i);
+        Some(Ok((key, value)))
+    }
+}
+
+/// Write events to a new tree in batches, without collecting them
+pub fn build<T, V, R, W>(
+    txn: &mut Transaction<T, R, W>,
+    config: &Config,
+    events: impl Iterator<Item = anyhow::Result<(T::Key, V)>>,
+) -> anyhow::Result<Tree<T, V>>
+where
+    T: TreeTypes,
+    V: BanyanValue,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+{
+    let mut builder = StreamBuilder::new(config.clone(), Secrets::default());
+    extend(txn, &mut builder, events)?;
+    Ok(builder.snapshot())
+}
+
+/// Append events to a builder in batches, without collecting them
+pub fn extend<T, V, R, W>(
+    txn: &mut Transaction<T, R, W>,
+    builder: &mut StreamBuilder<T, V>,
+    mut events: impl Iterator<Item = anyhow::Result<(T::Key, V)>>,
+) -> anyhow::Result<()>
+where
+    T: TreeTypes,
+    V: BanyanValue,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+{
+    loop {
+        let batch = events
+            .by_ref()
+            .take(BATCH)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        txn.extend(builder, batch)?;
+    }
+}
+
+/// A tree with the events in key order, using about `budget` bytes of memory for sorting
+pub fn sort<T, V, R, W>(
+    txn: &mut Transaction<T, R, W>,
+    config: &Config,
+    events: impl IntoIterator<Item = anyhow::Result<(T::Key, V)>>,
+    budget: usize,
+) -> anyhow::Result<Sorted<T, V>>
+where
+    T: TreeTypes,
+    T::Key: Ord,
+    V: BanyanValue,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+{
+    // a second handle to read from while the transaction writes
+    let forest = Forest::clone(txn);
+    let events_of = |tree: &Tree<T, V>| {
+        forest
+            .iter_from(tree)
+            .map(|item| item.map(|(_, key, value)| (key, value)))
+    };
+
+    let mut runs = Vec::new();
+    let mut buffer = Vec::new();
+    let mut size = 0;
+    for item in events {
+        let (key, value) = item?;
+        // the key and value themselves, and what the value owns, which its encoding approximates
+        size += mem::size_of::<(T::Key, V)>() + DagCborCodec.encode(&value)?.len();
+        buffer.push((key, value));
+        if size >= budget {
+            runs.push(spill(txn, config, &mut buffer)?);
+            size = 0;
+        }
+    }
+    if !buffer.is_empty() || runs.is_empty() {
+        runs.push(spill(txn, config, &mut buffer)?);
+    }
+
+    let spilled = runs.len();
+    let mut passes = 0;
+    while runs.len() > 1 {
+        let mut merged = Vec::with_capacity(runs.len().div_ceil(FAN_IN));
+        let mut rest = runs.into_iter();
+        loop {
+            let mut group = rest.by_ref().take(FAN_IN).collect::<Vec<_>>();
+            match group.len() {
+                0 => break,
+                1 => merged.extend(group.pop()),
+                _ => {
+                    let events = KWayMerge::new(group.iter().map(events_of).collect());
+                    merged.push(build(txn, config, events)?);
+                }
+            }
+        }
+        runs = merged;
+        passes += 1;
+    }
+    Ok(Sorted {
+        tree: runs.pop().expect("at least one run"),
+        runs: spilled,
+        passes,
+    })
+}
+
+/// Sort the buffered events and write them as a run
+fn spill<T, V, R, W>(
+    txn: &mut Transaction<T, R, W>,
+    config: &Config,
+    buffer: &mut Vec<(T::Key, V)>,
+) -> anyhow::Result<Tree<T, V>>
+where
+    T: TreeTypes,
+    T::Key: Ord,
+    V: BanyanValue,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+{
+    buffer.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut builder = StreamBuilder::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, buffer.drain(..))?;
+    Ok(builder.snapshot())
+}
+
+/// Sort the same events with different memory budgets
+pub fn extsort_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: sorting {} events by key with a memory budget", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut events = columnar::events(n);
+    events.sort_by_key(|(key, _)| key.device);
+    let mut expected = events.clone();
+    expected.sort_by_key(|(key, _)| *key);
+
+    println!("budget\truns\tpasses\ttime");
+    for budget in [1 << 24, 1 << 20, 1 << 16] {
+        let t0 = Instant::now();
+        let sorted = sort(&mut txn, config, events.iter().cloned().map(Ok), budget)?;
+        let elapsed = t0.elapsed();
+        let result = txn
+            .iter_from(&sorted.tree)
+            .map(|item| item.map(|(_, key, value)| (key, value)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // stable, so even the values of events with the same key are in order
+        anyhow::ensure!(result == expected, "wrong order with budget {}", budget);
+        println!(
+            "{}\t{}\t{}\t{:.3}s",
+            budget,
+            sorted.runs,
+            sorted.passes,
+            elapsed.as_secs_f64()
+        );
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! Canonical trees with known root links
+//!
+//! Fixed data, a fixed config and fixed secrets give a deterministic tree, so the root link only
+//! changes if the on-disk encoding changes. `cargo test` compares them with the golden values, so
+//! a bump of banyan, libipld or zstd that would change how existing trees are read fails the
+//! tests. `check-fixtures --print` prints the current values, for when a change is intended.
+use banyan::{
+    chacha20::Key as SecretKey,
+    query::AllQuery,
+    store::{BranchCache, MemStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use std::str::FromStr;
+
+use crate::columnar::{ColumnarTT, EventKey};
+
+/// Root links of the fixtures, as produced by banyan 0.17 with libipld 0.12
+const GOLDEN: &[(&str, &str)] = &[
+    (
+        "sequence",
+        "bafyreibgqgrz2xxfjlzjtgqwm2rytdh5i4nws2ozuwon456lkfwkmx44fu",
+    ),
+    (
+        "columnar",
+        "bafyreiapn22yetgo66uya6wgyl634l5ctcy6545kppehsvivdurffcagda",
+    ),
+    (
+        "actyx",
+        "bafyreihpxsktrmnhzfwzwnarudd2smfxcnvulidnfoblq7dthxdvei2e5q",
+    ),
+];
+
+#[derive(Debug, Clone)]
+struct SequenceTT;
+
+impl TreeTypes for SequenceTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Fixture sequence nonce..";
+}
+
+/// A config with small nodes, so even small fixtures have several levels
+fn config() -> Config {
+    Config {
+        target_leaf_size: 1 << 12,
+        max_leaf_count: 1 << 10,
+        max_summary_branches: 8,
+        max_key_branches: 8,
+        zstd_level: 3,
+        max_uncompressed_leaf_size: 1 << 20,
+    }
+}
+
+fn secrets() -> Secrets {
+    Secrets::new(SecretKey::from([1u8; 32]), SecretKey::from([2u8; 32]))
+}
+
+/// Build a tree from the events in a fresh store, check that it reads back, and return its root
+fn build<T: TreeTypes<Link = Sha256Digest>>(
+    xs: Vec<(T::Key, u64)>,
+) -> anyhow::Result<Sha256Digest> {
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<T, u64>::new(config(), secrets());
+    let expected = xs.iter().map(|(_, v)| *v).collect::<Vec<_>>();
+    txn.extend(&mut builder, xs)?;
+    let tree = builder.snapshot();
+    let mut values = Vec::with_capacity(expected.len());
+    for item in txn.iter_filtered(&tree, AllQuery) {
+        let (_i, _k, v) = item?;
+        values.push(v);
+    }
+    anyhow::ensure!(values == expected, "tree does not read back");
+    tree.root()
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("empty tree"))
+}
+
+/// The root links of all fixtures
+fn roots() -> anyhow::Result<Vec<(&'static str, Sha256Digest)>> {
+    use banyan_utils::tag_index::TagSet as ActyxTagSet;
+    use banyan_utils::tags::{Key as ActyxKey, TT as ActyxTT};
+
+    let n = 10000u64;
+    let sequence = build::<SequenceTT>((0..n).map(|i| ((), i)).collect())?;
+    let columnar = build::<ColumnarTT>(
+        (0..n)
+            .map(|i| {
+                let key = EventKey {
+                    time: 1_000_000 + i * 7,
+                    device: (i % 5) as u32,
+                };
+                (key, i)
+            })
+            .collect(),
+    )?;
+    let actyx = build::<ActyxTT>(
+        (0..n)
+            .map(|i| (ActyxKey::single(i, i, ActyxTagSet::empty()), i))
+            .collect(),
+    )?;
+    Ok(vec![
+        ("sequence", sequence),
+        ("columnar", columnar),
+        ("actyx", actyx),
+    ])
+}
+
+/// Build all fixtures and compare their roots with the golden values, or just print them
+pub fn check(print: bool) -> anyhow::Result<()> {
+    let mut mismatches = 0;
+    for ((name, root), (golden_name, golden)) in roots()?.into_iter().zip(GOLDEN) {
+        debug_assert_eq!(name, *golden_name);
+        if print {
+            println!("(\"{}\", \"{}\"),", name, root);
+        } else if Sha256Digest::from_str(golden).ok() == Some(root) {
+            println!("{}\tok", name);
+        } else {
+            println!("{}\tchanged\texpected {}, got {}", name, golden, root);
+            mismatches += 1;
+        }
+    }
+    anyhow::ensure!(mismatches == 0, "{} fixtures changed", mismatches);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixtures_match_golden_roots() {
+        let roots = roots().unwrap();
+        assert_eq!(roots.len(), GOLDEN.len());
+        for ((name, root), (golden_name, golden)) in roots.into_iter().zip(GOLDEN) {
+            assert_eq!(name, *golden_name);
+            assert_eq!(
+                root,
+                Sha256Digest::from_str(golden).unwrap(),
+                "fixture {} changed",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Fault injection for the store
+//!
+//! A remote store fails now and then, is sometimes slow, and might not have every block. The
+//! [FlakyStore] simulates all of this on top of a reliable store, and the [RetryStore] turns the
+//! transient failures back into successful gets. A block that is missing stays missing, so the
+//! retry layer gives up on it right away, and the error comes out of the iterator unchanged.
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    query::AllQuery,
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+/// A get that failed, but might succeed when tried again
+#[derive(Debug)]
+pub struct TransientError;
+
+impl fmt::Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "simulated transient failure")
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// A block that is not in the store, and never will be
+#[derive(Debug)]
+pub struct BlockMissing;
+
+impl fmt::Display for BlockMissing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "simulated missing block")
+    }
+}
+
+impl std::error::Error for BlockMissing {}
+
+/// What can go wrong on a get
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// fraction of gets that fail with a [TransientError]
+    pub failure_rate: f64,
+    /// every get takes a random time between zero and this
+    pub jitter: Duration,
+    /// fraction of blocks that fail with [BlockMissing]. Which blocks are missing only depends
+    /// on the link, so they are missing on every try
+    pub missing_rate: f64,
+}
+
+/// A store wrapper that injects faults into gets. Puts are passed through unchanged
+#[derive(Clone)]
+pub struct FlakyStore<S> {
+    inner: S,
+    faults: Faults,
+    rng: Arc<AtomicU64>,
+}
+
+impl<S> FlakyStore<S> {
+    pub fn new(inner: S, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            rng: Arc::new(AtomicU64::new(0x2545f4914f6cdd1d)),
+        }
+    }
+
+    /// A random number between 0 and 1, from a xorshift generator shared by all clones
+    fn random(&self) -> f64 {
+        let next = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            Some(x)
+        };
+        let x = self
+            .rng
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, next)
+            .unwrap_or_default();
+        unit(x)
+    }
+}
+
+/// Map a random u64 to the interval [0, 1)
+fn unit(x: u64) -> f64 {
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl<L: Hash, S: ReadOnlyStore<L>> ReadOnlyStore<L> for FlakyStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        thread::sleep(self.faults.jitter.mul_f64(self.random()));
+        let mut hasher = DefaultHasher::new();
+        link.hash(&mut hasher);
+        if unit(hasher.finish()) < self.faults.missing_rate {
+            return Err(BlockMissing.into());
+        }
+        if self.random() < self.faults.failure_rate {
+            return Err(TransientError.into());
+        }
+        self.inner.get(link)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for FlakyStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.inner.put(data)
+    }
+}
+
+/// A store wrapper that retries failed gets, unless the block is missing
+#[derive(Clone)]
+pub struct RetryStore<S> {
+    inner: S,
+    attempts: u32,
+    backoff: Duration,
+    retries: Arc<AtomicU64>,
+}
+
+impl<S> RetryStore<S> {
+    /// Try each get up to `attempts` times, waiting `backoff` times the attempt number in between
+    pub fn new(inner: S, attempts: u32, backoff: Duration) -> Self {
+        Self {
+            inner,
+            attempts: attempts.max(1),
+            backoff,
+            retries: Default::default(),
+        }
+    }
+
+    /// The number of gets that were retried so far
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for RetryStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let mut attempt = 1;
+        loop {
+            match self.inner.get(link) {
+                Err(cause) if attempt < self.attempts && !cause.is::<BlockMissing>() => {
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    thread::sleep(self.backoff * attempt);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for RetryStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.inner.put(data)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FlakyTT;
+
+impl TreeTypes for FlakyTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Fault injection camp....";
+}
+
+/// Scan the tree and check that all values up to the first error are right
+fn scan<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<FlakyTT, R>,
+    tree: &banyan::Tree<FlakyTT, u64>,
+) -> anyhow::Result<(u64, Option<anyhow::Error>)> {
+    let mut n = 0;
+    for item in forest.iter_from(tree) {
+        match item {
+            Ok((i, _k, v)) => {
+                anyhow::ensure!(i == n && v == n, "wrong value {} at offset {}", v, i);
+                n += 1;
+            }
+            Err(cause) => return Ok((n, Some(cause))),
+        }
+    }
+    Ok((n, None))
+}
+
+/// Run all examples through a store with transient failures and jitter, then scan a tree with
+/// missing blocks, with and without retries
+pub fn report(config: &Config, faults: Faults, attempts: u32) -> anyhow::Result<()> {
+    let mem = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let transient = Faults {
+        missing_rate: 0.0,
+        ..faults.clone()
+    };
+    println!("Running all examples with {:?}", transient);
+    let store = RetryStore::new(
+        FlakyStore::new(mem.clone(), transient),
+        attempts,
+        Duration::from_millis(1),
+    );
+    let t0 = Instant::now();
+    crate::run(store.clone(), config, true)?;
+    println!(
+        "all examples passed with {} retries {}s",
+        store.retries(),
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+
+    let n = 1000000u64;
+    println!("Scanning a tree of {} values with {:?}", n, faults);
+    let forest = Forest::<FlakyTT, _>::new(mem.clone(), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, mem.clone());
+    let mut builder = StreamBuilder::<FlakyTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+    let flaky = FlakyStore::new(mem, faults);
+    let mut blocks = 0;
+    let mut missing = 0;
+    for index in txn.iter_index(&tree, AllQuery) {
+        if let Some(link) = index?.link() {
+            blocks += 1;
+            if matches!(flaky.get(link), Err(cause) if cause.is::<BlockMissing>()) {
+                missing += 1;
+            }
+        }
+    }
+    println!("{} of {} blocks are missing", missing, blocks);
+
+    // without retries, the scan stops at the first failure of any kind
+    let forest = Forest::new(flaky.clone(), BranchCache::new(0));
+    let (count, error) = scan(&forest, &tree)?;
+    println!(
+        "no retry\t{} values, stopped by {:?}",
+        count,
+        error.map(|x| x.to_string())
+    );
+
+    // with retries, it only stops at a missing block
+    let retry = RetryStore::new(flaky, attempts, Duration::from_millis(1));
+    let forest = Forest::new(retry.clone(), BranchCache::new(0));
+    let (count, error) = scan(&forest, &tree)?;
+    let stopped = error.as_ref().map(|cause| cause.is::<BlockMissing>());
+    println!(
+        "retry\t{} values, stopped by {:?}, {} retries",
+        count,
+        error.map(|x| x.to_string()),
+        retry.retries()
+    );
+    match stopped {
+        Some(true) => anyhow::ensure!(missing > 0, "missing block error without missing blocks"),
+        Some(false) => anyhow::bail!("transient failure got through {} attempts", attempts),
+        None => anyhow::ensure!(count == n && missing == 0, "scan ended early"),
+    }
+    Ok(())
+}
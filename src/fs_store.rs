@@ -0,0 +1,452 @@
+//! A block store in plain files
+//!
+//! Each block is a file named after the hex digest of its link, in a directory named after the
+//! first byte, like `blocks/ab/cdef...`. A block is written to a temporary file first and renamed
+//! when complete, so a crash never leaves a partial block under its final name.
+//!
+//! A put syncs the block before the rename, but not its directory, and not the index, which would
+//! cost a few more syncs per block. [FsStore::sync] does that for all puts so far, so a caller that
+//! is about to persist a root that links to the blocks, or forget a log of what is in them, knows
+//! they are on disk.
+//!
+//! An index file has a line with the name and size of each block. It is read on open, so the
+//! store knows its size without looking at all files. If a crash loses the last line, the block
+//! is just written again on the next put. A removed block gets a line with a size of `-`.
+//!
+//! Removed blocks leave their lines in the index, and a crash can leave a temporary file behind,
+//! so a store that runs for a long time keeps growing even when its trees do not.
+//! [FsStore::compact] removes every block that is not reachable from the roots to keep, and
+//! writes the index again with a line per block that is left. The new index is on disk before
+//! any file is removed, so a crash in between leaves files that the next compaction removes, not
+//! an index with blocks that are gone. A block is a file of its own, so there are no holes inside
+//! of files to rewrite. It must not run while another process uses the store.
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid};
+
+use crate::{
+    dedup,
+    error::Error,
+    link::Link,
+    probe::ProbingStore,
+    roots::{ManifestFile, RootStore},
+    snapshots::LogTT,
+};
+
+/// The blocks that are in the store, with their sizes
+#[derive(Debug)]
+struct Index {
+    sizes: HashMap<String, u64>,
+    bytes: u64,
+    file: File,
+    /// directories with entries that are not synced yet
+    dirty: HashSet<PathBuf>,
+}
+
+/// Sync a directory, so the files created, renamed or removed in it are on disk
+pub fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// What [FsStore::compact] did. The bytes are on disk, with the index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Compacted {
+    pub blocks_before: u64,
+    pub bytes_before: u64,
+    pub blocks_after: u64,
+    pub bytes_after: u64,
+    /// temporary files of puts that never completed
+    pub temporary: u64,
+}
+
+/// A store that keeps each block in its own file
+#[derive(Debug)]
+pub struct FsStore<L> {
+    root: PathBuf,
+    index: Arc<Mutex<Index>>,
+    _link: PhantomData<L>,
+}
+
+impl<L> Clone for FsStore<L> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            index: self.index.clone(),
+            _link: PhantomData,
+        }
+    }
+}
+
+impl<L: Link> FsStore<L> {
+    /// Open the store in the directory, or create it
+    pub fn open(root: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join("blocks"))?;
+        let path = root.join("index");
+        let mut sizes = HashMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                let (name, size) = line
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow::anyhow!("invalid index line {}", line))?;
+                match size {
+                    "-" => sizes.remove(name),
+                    size => sizes.insert(name.to_string(), size.parse()?),
+                };
+            }
+        }
+        let bytes = sizes.values().sum();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        // the index and the blocks directory may be new
+        let dirty = [root.clone(), root.join("blocks")].into_iter().collect();
+        let index = Index {
+            sizes,
+            bytes,
+            file,
+            dirty,
+        };
+        Ok(Self {
+            root,
+            index: Arc::new(Mutex::new(index)),
+            _link: PhantomData,
+        })
+    }
+
+    /// The number of blocks and their total size in bytes
+    pub fn usage(&self) -> (u64, u64) {
+        let index = self.index.lock().unwrap();
+        (index.sizes.len() as u64, index.bytes)
+    }
+
+    /// The links of all blocks in the index
+    pub fn links(&self) -> anyhow::Result<Vec<L>> {
+        // the hash function and codec of the link type, the digest is in the name
+        let template: Cid = L::digest(&[]).into();
+        let index = self.index.lock().unwrap();
+        index
+            .sizes
+            .keys()
+            .map(|name| {
+                let digest = (0..name.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(name.get(i..i + 2).unwrap_or("?"), 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| anyhow::anyhow!("invalid block name {}", name))?;
+                let hash = multihash::Multihash::wrap(template.hash().code(), &digest)?;
+                let cid = Cid::new_v1(template.codec(), hash);
+                DagCborCodec.decode::<L>(&DagCborCodec.encode(&cid)?)
+            })
+            .collect()
+    }
+
+    /// Sync the directories of the blocks put since the last sync, and then the index, see the
+    /// module docs. In this order, a crash never leaves an index with blocks that are not on disk
+    pub fn sync(&self) -> anyhow::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        for dir in std::mem::take(&mut index.dirty) {
+            sync_dir(&dir)?;
+        }
+        index.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Remove a block, returning its size if it was there
+    pub fn remove(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        let name = Self::name(link);
+        let mut index = self.index.lock().unwrap();
+        let Some(size) = index.sizes.remove(&name) else {
+            return Ok(None);
+        };
+        writeln!(index.file, "{} -", name)?;
+        index.bytes -= size;
+        match fs::remove_file(self.path(&name)) {
+            Err(cause) if cause.kind() != io::ErrorKind::NotFound => Err(cause.into()),
+            _ => Ok(Some(size)),
+        }
+    }
+
+    /// Remove every block that is not in `live`, and the temporary files, and write the index
+    /// again. This looks at the files, so it also finds blocks that are missing from the index
+    pub fn compact(&self, live: &HashSet<L>) -> anyhow::Result<Compacted> {
+        let keep = live.iter().map(Self::name).collect::<HashSet<_>>();
+        let mut index = self.index.lock().unwrap();
+        let path = self.root.join("index");
+        let mut res = Compacted {
+            bytes_before: fs::metadata(&path)?.len(),
+            ..Compacted::default()
+        };
+        let mut sizes = HashMap::new();
+        let mut dirs = Vec::new();
+        let mut removed = Vec::new();
+        for dir in fs::read_dir(self.root.join("blocks"))? {
+            let dir = dir?.path();
+            let Some(prefix) = dir.file_name().and_then(|x| x.to_str()) else {
+                continue;
+            };
+            if !dir.is_dir() {
+                continue;
+            }
+            let prefix = prefix.to_string();
+            for file in fs::read_dir(&dir)? {
+                let file = file?;
+                let size = file.metadata()?.len();
+                let name = format!("{}{}", prefix, file.file_name().to_string_lossy());
+                res.bytes_before += size;
+                if name.ends_with(".tmp") {
+                    res.temporary += 1;
+                } else {
+                    res.blocks_before += 1;
+                    if keep.contains(&name) {
+                        sizes.insert(name, size);
+                        continue;
+                    }
+                }
+                removed.push(file.path());
+            }
+            dirs.push(dir);
+        }
+        // the blocks that are kept must be on disk before the new index lists them
+        for dir in std::mem::take(&mut index.dirty) {
+            sync_dir(&dir)?;
+        }
+        // write, sync and rename, so a crash leaves either the old or the new index. Only then
+        // remove the files, since a put skips every block that the index has
+        let text = sizes.iter().fold(String::new(), |mut res, (name, size)| {
+            let _ = writeln!(res, "{} {}", name, size);
+            res
+        });
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.root)?;
+        for file in removed {
+            fs::remove_file(file)?;
+        }
+        for dir in dirs {
+            // fails if there are blocks left, which is fine
+            let _ = fs::remove_dir(&dir);
+        }
+        index.file = OpenOptions::new().append(true).open(&path)?;
+        index.bytes = sizes.values().sum();
+        index.sizes = sizes;
+        res.blocks_after = index.sizes.len() as u64;
+        res.bytes_after = index.bytes + fs::metadata(&path)?.len();
+        Ok(res)
+    }
+
+    fn name(link: &L) -> String {
+        let cid: Cid = (*link).into();
+        cid.hash()
+            .digest()
+            .iter()
+            .fold(String::new(), |mut res, byte| {
+                let _ = write!(res, "{:02x}", byte);
+                res
+            })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join("blocks").join(&name[..2]).join(&name[2..])
+    }
+}
+
+impl<L: Link> ReadOnlyStore<L> for FsStore<L> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let path = self.path(&Self::name(link));
+        match fs::read(&path) {
+            Ok(data) => Ok(data.into()),
+            Err(cause) if cause.kind() == io::ErrorKind::NotFound => {
+                Err(Error::missing(*link).into())
+            }
+            Err(cause) => Err(cause.into()),
+        }
+    }
+}
+
+impl<L: Link> ProbingStore<L> for FsStore<L> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        let index = self.index.lock().unwrap();
+        Ok(index.sizes.get(&Self::name(link)).cloned())
+    }
+}
+
+impl<L: Link> BlockWriter<L> for FsStore<L> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let link = L::digest(&data);
+        let name = Self::name(&link);
+        let mut index = self.index.lock().unwrap();
+        if index.sizes.contains_key(&name) {
+            return Ok(link);
+        }
+        let path = self.path(&name);
+        let tmp = path.with_extension("tmp");
+        let dir = path.parent().expect("blocks are in a directory");
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+            index.dirty.insert(self.root.join("blocks"));
+        }
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        // so the rename never makes a block visible whose data is not on disk yet
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        index.dirty.insert(dir.to_path_buf());
+        writeln!(index.file, "{} {}", name, data.len())?;
+        index.bytes += data.len() as u64;
+        index.sizes.insert(name, data.len() as u64);
+        Ok(link)
+    }
+}
+
+/// A store whose puts are only sure to survive a crash after a sync, like an [FsStore]. A writer
+/// syncs before it persists a root that links to the blocks it put
+pub trait DurableStore<L>: BlockWriter<L> {
+    /// Make all puts so far durable
+    fn sync(&self) -> anyhow::Result<()>;
+}
+
+impl<L: Link> DurableStore<L> for FsStore<L> {
+    fn sync(&self) -> anyhow::Result<()> {
+        FsStore::sync(self)
+    }
+}
+
+impl DurableStore<Sha256Digest> for MemStore<Sha256Digest> {
+    /// Nothing in memory survives a crash, the blocks and the roots are gone together
+    fn sync(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The blocks reachable from the roots, and from the roots in the manifest files
+fn live(
+    store: &FsStore<Sha256Digest>,
+    roots: &[Sha256Digest],
+    manifests: &[PathBuf],
+) -> anyhow::Result<HashSet<Sha256Digest>> {
+    let mut all = roots.to_vec();
+    for path in manifests {
+        all.extend(
+            ManifestFile::new(path)
+                .roots::<Sha256Digest>()?
+                .into_values(),
+        );
+    }
+    // keeping nothing is much more likely a mistake than what was meant
+    anyhow::ensure!(!all.is_empty(), "no roots to keep");
+    let mut live = HashSet::new();
+    for root in all {
+        live.extend(dedup::blocks(store, root)?.into_keys());
+    }
+    Ok(live)
+}
+
+/// Compact the store in a directory, keeping what is reachable from the roots and from the roots
+/// in the manifest files, or in every `.manifest` file in the directory if there are none
+pub fn print_compact(
+    path: &Path,
+    roots: &[Sha256Digest],
+    manifests: Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let manifests = match manifests.is_empty() {
+        true => fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .filter(|path| {
+                path.as_ref()
+                    .map_or(true, |path| path.extension() == Some("manifest".as_ref()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        false => manifests,
+    };
+    let store = FsStore::<Sha256Digest>::open(path)?;
+    let live = live(&store, roots, &manifests)?;
+    let res = store.compact(&live)?;
+    println!("\tblocks\tbytes");
+    println!("before\t{}\t{}", res.blocks_before, res.bytes_before);
+    println!("after\t{}\t{}", res.blocks_after, res.bytes_after);
+    println!(
+        "{} blocks and {} temporary files removed, {} bytes reclaimed",
+        res.blocks_before - res.blocks_after,
+        res.temporary,
+        res.bytes_before - res.bytes_after
+    );
+    Ok(())
+}
+
+/// Write a few versions of a stream, remove blocks like tiering does, and compact the store to
+/// the latest version
+pub fn compact_example(config: &Config) -> anyhow::Result<()> {
+    let versions = 10u64;
+    println!(
+        "Example: compacting a file store with {} versions of a stream to the latest",
+        versions
+    );
+    let dir = std::env::temp_dir().join(format!("banyan-compact-{}", std::process::id()));
+    let store = FsStore::<Sha256Digest>::open(&dir)?;
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    let mut roots = Vec::new();
+    for i in 0..versions {
+        txn.extend_unpacked(&mut builder, (i * 1000..(i + 1) * 1000).map(|x| ((), x)))?;
+        roots.push(builder.snapshot().link().expect("not empty"));
+    }
+    txn.pack(&mut builder)?;
+    let latest = builder.snapshot().link().expect("not empty");
+    // blocks that are removed and put again each leave two lines in the index
+    let first = dedup::blocks(&store, roots[0])?;
+    for link in first.keys() {
+        let data = store.get(link)?;
+        store.remove(link)?;
+        store.clone().put(data.to_vec())?;
+    }
+    // a put that was interrupted
+    let name = FsStore::name(&latest);
+    fs::write(store.path(&name).with_extension("tmp"), b"partial")?;
+
+    let manifest = dir.join("events.manifest");
+    ManifestFile::new(&manifest).compare_and_swap("events", None, latest)?;
+    let live = live(&store, &[], &[manifest])?;
+    let res = store.compact(&live)?;
+    println!("{:?}", res);
+    anyhow::ensure!(
+        res.blocks_after == live.len() as u64 && res.blocks_after < res.blocks_before,
+        "blocks that are not live are left"
+    );
+    anyhow::ensure!(res.temporary == 1, "the temporary file is left");
+    let reopened = FsStore::<Sha256Digest>::open(&dir)?;
+    anyhow::ensure!(
+        reopened.usage() == store.usage() && reopened.usage().0 == live.len() as u64,
+        "the index is not the blocks"
+    );
+    let forest = Forest::<LogTT, _>::new(reopened, BranchCache::new(0));
+    let tree = forest.load_tree::<u64>(Secrets::default(), latest)?;
+    let values = forest
+        .iter_from(&tree)
+        .map(|item| item.map(|(_, _, value)| value))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        values == (0..versions * 1000).collect::<Vec<_>>(),
+        "the latest version lost events"
+    );
+    fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
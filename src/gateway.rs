@@ -0,0 +1,75 @@
+//! Blocks from an http gateway
+//!
+//! A trustless gateway serves the bytes of a block at `/ipfs/<cid>?format=raw`, see
+//! <https://specs.ipfs.tech/http-gateways/trustless-gateway/>. It can only be read from, which is
+//! all the query commands need, see [crate::readonly]. The gateway can be anyone's, so the blocks
+//! are checked against their links by a [VerifyingStore](crate::verify::VerifyingStore) around it.
+use std::{io::Read, time::Duration};
+
+use banyan::store::ReadOnlyStore;
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+use reqwest::{
+    blocking::Client,
+    header::{HeaderValue, ACCEPT},
+    StatusCode, Url,
+};
+
+use crate::{car::MAX_SECTION, error::Error};
+
+/// A read-only store that gets blocks from a gateway
+#[derive(Debug, Clone)]
+pub struct GatewayStore {
+    /// the url the `ipfs/<cid>` paths are relative to, ending with a slash
+    base: Url,
+    client: Client,
+}
+
+impl GatewayStore {
+    /// A store for the gateway at the url, like `https://ipfs.io`
+    pub fn new(mut base: Url) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            matches!(base.scheme(), "http" | "https"),
+            "{} is not an http url",
+            base
+        );
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        let client = Client::builder().timeout(Duration::from_secs(60)).build()?;
+        Ok(Self { base, client })
+    }
+}
+
+impl ReadOnlyStore<Sha256Digest> for GatewayStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        let cid = Cid::from(*link);
+        let url = self.base.join(&format!("ipfs/{}?format=raw", cid))?;
+        let unavailable = |reason: String| Error::StoreUnavailable { reason };
+        let response = self
+            .client
+            .get(url)
+            .header(ACCEPT, HeaderValue::from_static("application/vnd.ipld.raw"))
+            .send()
+            .map_err(|cause| unavailable(format!("gateway {}: {}", self.base, cause)))?;
+        match response.status() {
+            status if status.is_success() => {
+                // the length comes from the gateway, so only a block's worth of it is read
+                let mut data = Vec::new();
+                response.take(MAX_SECTION + 1).read_to_end(&mut data)?;
+                anyhow::ensure!(
+                    data.len() as u64 <= MAX_SECTION,
+                    "gateway {} sent more than a block for {}",
+                    self.base,
+                    cid
+                );
+                Ok(data.into())
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => Err(Error::missing(cid).into()),
+            status if status.is_server_error() => {
+                Err(unavailable(format!("gateway {} answered {}", self.base, status)).into())
+            }
+            status => anyhow::bail!("gateway {} answered {} for {}", self.base, status, cid),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! An in memory store that knows which blocks are still needed
+//!
+//! A [MemStore](banyan::store::MemStore) only has a byte budget, and once it is full, it stays
+//! full. This store keeps a set of named roots, and a reference count for each block: the number
+//! of roots and live blocks that link to it. A block with a count of zero is not reachable from
+//! any root, and [GcStore::evict] removes it.
+//!
+//! Blocks start out unreachable, unless a live block already links to them, so eviction must only
+//! run when all trees that are being built have their roots registered. A live block may link to a
+//! block that is put later, so the store also counts the links to blocks it does not have yet. When
+//! a put would exceed the byte budget, the store evicts first, but keeps the blocks that were put
+//! since the last root was set, since they are most likely part of a tree that is being built.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+
+use crate::{
+    error::Error,
+    probe::ProbingStore,
+    retention::{self, Policy, Rule, TaggedKey, TaggedTT},
+};
+
+#[derive(Debug)]
+struct Block {
+    data: Box<[u8]>,
+    links: Vec<Sha256Digest>,
+    refs: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    blocks: HashMap<Sha256Digest, Block>,
+    roots: BTreeMap<String, Sha256Digest>,
+    /// blocks put since the last root was set
+    new: HashSet<Sha256Digest>,
+    /// the references of roots and live blocks to blocks that are not in the store
+    missing: HashMap<Sha256Digest, u64>,
+    bytes: u64,
+}
+
+impl Inner {
+    /// Add a reference to the block, and to everything below it if it just became live
+    fn inc(&mut self, link: Sha256Digest) {
+        let mut todo = vec![link];
+        while let Some(link) = todo.pop() {
+            match self.blocks.get_mut(&link) {
+                Some(block) => {
+                    block.refs += 1;
+                    if block.refs == 1 {
+                        todo.extend(block.links.iter().cloned());
+                    }
+                }
+                // a block that is not put yet, which starts with these references when it is
+                None => *self.missing.entry(link).or_default() += 1,
+            }
+        }
+    }
+
+    /// Remove a reference from the block, and from everything below it if it is no longer live
+    fn dec(&mut self, link: Sha256Digest) -> anyhow::Result<()> {
+        let mut todo = vec![link];
+        while let Some(link) = todo.pop() {
+            let refs = match self.blocks.get_mut(&link) {
+                Some(block) => &mut block.refs,
+                None => self.missing.entry(link).or_default(),
+            };
+            *refs = refs
+                .checked_sub(1)
+                .ok_or_else(|| anyhow::anyhow!("{} has no references to remove", link))?;
+            match self.blocks.get(&link) {
+                Some(block) if block.refs == 0 => todo.extend(block.links.iter().cloned()),
+                Some(_) => {}
+                None => {
+                    if self.missing[&link] == 0 {
+                        self.missing.remove(&link);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove unreachable blocks, except the new ones if `keep_new` is set
+    fn evict(&mut self, keep_new: bool) -> (u64, u64) {
+        let before = (self.blocks.len() as u64, self.bytes);
+        let new = &self.new;
+        self.blocks
+            .retain(|link, block| block.refs > 0 || (keep_new && new.contains(link)));
+        self.bytes = self.blocks.values().map(|b| b.data.len() as u64).sum();
+        (before.0 - self.blocks.len() as u64, before.1 - self.bytes)
+    }
+}
+
+/// Number of blocks and bytes in the store, all and reachable from a root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub blocks: u64,
+    pub bytes: u64,
+    pub live_blocks: u64,
+    pub live_bytes: u64,
+}
+
+/// An in memory store with named roots and eviction of unreachable blocks
+#[derive(Debug, Clone)]
+pub struct GcStore {
+    inner: Arc<Mutex<Inner>>,
+    max_bytes: u64,
+}
+
+impl GcStore {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            inner: Default::default(),
+            max_bytes,
+        }
+    }
+
+    /// Set the root with this name, or remove it with `None`, and return the previous one
+    pub fn set_root(
+        &self,
+        name: &str,
+        root: Option<Sha256Digest>,
+    ) -> anyhow::Result<Option<Sha256Digest>> {
+        let mut inner = self.inner.lock().unwrap();
+        // increment first, so blocks shared by the old and the new root never drop to zero
+        if let Some(root) = root {
+            inner.inc(root);
+        }
+        let previous = match root {
+            Some(root) => inner.roots.insert(name.to_string(), root),
+            None => inner.roots.remove(name),
+        };
+        if let Some(previous) = previous {
+            inner.dec(previous)?;
+        }
+        inner.new.clear();
+        Ok(previous)
+    }
+
+    /// Remove all blocks that are not reachable from a root, and return how many and their size
+    pub fn evict(&self) -> (u64, u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.new.clear();
+        inner.evict(false)
+    }
+
+    pub fn usage(&self) -> Usage {
+        let inner = self.inner.lock().unwrap();
+        let live = inner.blocks.values().filter(|block| block.refs > 0);
+        Usage {
+            blocks: inner.blocks.len() as u64,
+            bytes: inner.bytes,
+            live_blocks: live.clone().count() as u64,
+            live_bytes: live.map(|block| block.data.len() as u64).sum(),
+        }
+    }
+}
+
+impl ReadOnlyStore<Sha256Digest> for GcStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        match self.inner.lock().unwrap().blocks.get(link) {
+            Some(block) => Ok(block.data.clone()),
+            None => Err(Error::missing(*link).into()),
+        }
+    }
+}
+
+impl ProbingStore<Sha256Digest> for GcStore {
+    fn size(&self, link: &Sha256Digest) -> anyhow::Result<Option<u64>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.blocks.get(link).map(|block| block.data.len() as u64))
+    }
+}
+
+impl BlockWriter<Sha256Digest> for GcStore {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+        let link = Sha256Digest::digest(&data);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.blocks.contains_key(&link) {
+            // an unreachable block that is part of a new tree again
+            inner.new.insert(link);
+            return Ok(link);
+        }
+        if inner.bytes + data.len() as u64 > self.max_bytes {
+            inner.evict(true);
+            // what is left is reachable from roots or new
+            if inner.bytes + data.len() as u64 > self.max_bytes {
+                return Err(Error::QuotaExceeded {
+                    used: inner.bytes,
+                    limit: self.max_bytes,
+                }
+                .into());
+            }
+        }
+        // blocks that are not dag-cbor, like payload chunks, have no links
+        let mut cids = BTreeSet::<Cid>::new();
+        if let Ok(ipld) = DagCborCodec.decode::<Ipld>(&data) {
+            ipld.references(&mut cids);
+        }
+        let links = cids
+            .into_iter()
+            .filter_map(|cid| Sha256Digest::try_from(cid).ok())
+            .collect::<Vec<_>>();
+        inner.bytes += data.len() as u64;
+        inner.new.insert(link);
+        // the references of live blocks that were put before this one
+        let refs = inner.missing.remove(&link).unwrap_or_default();
+        if refs > 0 {
+            for child in &links {
+                inner.inc(*child);
+            }
+        }
+        let block = Block {
+            data: data.into(),
+            links,
+            refs,
+        };
+        inner.blocks.insert(link, block);
+        Ok(link)
+    }
+}
+
+/// Run a stream with a retention policy for a while, evicting forgotten blocks after each day
+pub fn gc_example(config: &Config) -> anyhow::Result<()> {
+    let days = 30u64;
+    let per_day = 20000u64;
+    let policy = Policy(vec![Rule::Last(1000), Rule::Days(3)]);
+    println!(
+        "Example: {} days of {} events in memory with retention policy {:?}",
+        days, per_day, policy
+    );
+    let store = GcStore::new(1 << 30);
+    let forest = Forest::<TaggedTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<TaggedTT, u64>::new(config.clone(), Secrets::default());
+    let t0 = 1_600_000_000_000u64;
+    println!("day\tblocks\tbytes\tlive\tlive bytes\tevicted");
+    for day in 0..days {
+        let xs = (0..per_day).map(|i| {
+            let time = t0 + day * retention::DAY + i * (retention::DAY / per_day);
+            (TaggedKey { time, tags: 0 }, day * per_day + i)
+        });
+        txn.extend(&mut builder, xs)?;
+        retention::apply(
+            &mut txn,
+            &mut builder,
+            &policy,
+            t0 + (day + 1) * retention::DAY,
+        )?;
+        store.set_root("events", builder.snapshot().link())?;
+        let usage = store.usage();
+        let (evicted, _) = store.evict();
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            day, usage.blocks, usage.bytes, usage.live_blocks, usage.live_bytes, evicted
+        );
+    }
+
+    // everything that is still in the tree is still in the store
+    let tree = builder.snapshot();
+    let start = tree.count() - 1000;
+    let mut count = 0;
+    for item in txn.iter_filtered(&tree, OffsetRangeQuery::from(start..)) {
+        item?;
+        count += 1;
+    }
+    anyhow::ensure!(count == 1000, "events are missing");
+    let usage = store.usage();
+    anyhow::ensure!(usage.blocks == usage.live_blocks, "unreachable blocks left");
+    println!("{:?}", usage);
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn child_after_its_live_parent() {
+        let mut store = GcStore::new(u64::MAX);
+        let child = b"child".to_vec();
+        let link = Sha256Digest::digest(&child);
+        let parent = Ipld::List(vec![Ipld::Link(link.into())]);
+        let parent = store.put(DagCborCodec.encode(&parent).unwrap()).unwrap();
+        store.set_root("tree", Some(parent)).unwrap();
+        // the parent is live, so the child is as soon as it is there
+        store.put(child).unwrap();
+        assert_eq!(store.evict(), (0, 0));
+        assert_eq!(store.usage().live_blocks, 2);
+        store.set_root("tree", None).unwrap();
+        assert_eq!(store.evict().0, 2);
+        assert!(store.inner.lock().unwrap().missing.is_empty());
+    }
+}
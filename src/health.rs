@@ -0,0 +1,123 @@
+//! Checking a backend before using it
+//!
+//! Whether kubo is running used to be checked with the put of an empty block, which says nothing
+//! but that the put worked. A [Health] says which backend it is, which version, how long a round
+//! trip takes, and whether it can take new blocks. The examples use it to pick a backend, the
+//! `health` command prints it, and the [server](crate::server) answers `/healthz` with it.
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{error::Error, kubo};
+
+/// The state of a backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// the kind of backend, like `kubo` or `files`
+    pub backend: &'static str,
+    /// which one it is, like the peer id of a kubo, or the directory of files
+    pub identity: String,
+    pub version: String,
+    /// the time of a single request, or of a small write and read for files
+    pub latency: Duration,
+    /// whether a put can work. A kubo with a full repo only takes blocks it already has
+    pub writable: bool,
+}
+
+impl Health {
+    pub fn print(&self) {
+        println!("backend\t{}", self.backend);
+        println!("identity\t{}", self.identity);
+        println!("version\t{}", self.version);
+        println!("latency\t{:.3}ms", self.latency.as_secs_f64() * 1000.0);
+        println!("writable\t{}", self.writable);
+    }
+
+    /// The health as json, with the latency in milliseconds
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "backend": self.backend,
+            "identity": self.identity,
+            "version": self.version,
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+            "writable": self.writable,
+        })
+    }
+}
+
+/// Probe the kubo api at the [endpoint](kubo::endpoint), failing with [Error::StoreUnavailable] if
+/// it does not answer
+pub fn probe_kubo() -> anyhow::Result<Health> {
+    let endpoint = kubo::endpoint()?;
+    let unavailable = |cause: anyhow::Error| Error::StoreUnavailable {
+        reason: format!(
+            "kubo api not reachable on {}, is the daemon running? {}",
+            endpoint.api(),
+            cause
+        ),
+    };
+    let t0 = Instant::now();
+    let version = endpoint.call("version", &[]).map_err(unavailable)?;
+    let latency = t0.elapsed();
+    let id = endpoint.call("id", &[]).map_err(unavailable)?;
+    let repo = endpoint
+        .call("repo/stat", &[("size-only", "true")])
+        .map_err(unavailable)?;
+    let size = repo["RepoSize"].as_u64().unwrap_or_default();
+    let max = repo["StorageMax"].as_u64().unwrap_or(u64::MAX);
+    Ok(Health {
+        backend: "kubo",
+        identity: id["ID"].as_str().unwrap_or_default().to_string(),
+        version: version["Version"].as_str().unwrap_or_default().to_string(),
+        latency,
+        writable: size < max,
+    })
+}
+
+/// Probe a directory for the backends that keep their blocks in files, by writing, reading and
+/// removing a small file. A directory that does not exist yet is created
+pub fn probe_dir(path: &Path) -> anyhow::Result<Health> {
+    fs::create_dir_all(path)?;
+    let file = path.join(format!(".probe-{}", std::process::id()));
+    let t0 = Instant::now();
+    let writable = fs::write(&file, b"probe")
+        .and_then(|_| fs::read(&file))
+        .and_then(|_| fs::remove_file(&file))
+        .is_ok();
+    let latency = t0.elapsed();
+    Ok(Health {
+        backend: "files",
+        identity: path.display().to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        latency,
+        writable,
+    })
+}
+
+/// Print the health of kubo and of the directory for the other backends
+pub fn report(path: &Path) -> anyhow::Result<()> {
+    match probe_kubo() {
+        Ok(health) => health.print(),
+        Err(cause) => println!("backend\tkubo\nerror\t{}", cause),
+    }
+    println!();
+    probe_dir(path)?.print();
+    Ok(())
+}
+
+/// The kubo if it is good enough for the examples, which need it to answer and take new blocks
+pub fn kubo_usable() -> Option<Health> {
+    match probe_kubo() {
+        Ok(health) if health.writable => Some(health),
+        Ok(health) => {
+            println!("kubo {} has a full repo", health.identity);
+            None
+        }
+        Err(cause) => {
+            println!("{}", cause);
+            None
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Appending each batch exactly once
+//!
+//! A crash between appending a batch and acknowledging it to the source makes the source send the
+//! batch again, and the tree would get its events twice. So each batch has an id that grows with
+//! every batch, and the named root points to a [Head] block with both the root of the tree and
+//! the id of the last batch in it. Both change in the same compare-and-swap, so the tree never has
+//! a batch without its id or the other way round.
+//!
+//! [append_batch] skips a batch whose id is not above the one in the head. It syncs the store
+//! before the swap, so the head never links to blocks a crash can lose. A crash before the swap
+//! leaves the head as it was, and the blocks written so far are garbage that the next try writes
+//! again.
+use std::fs;
+
+use banyan::{
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+use crate::{
+    fs_store::DurableStore, roots::RootStore, snapshots::LogTT, sqlite_store::SqliteStore,
+};
+
+/// The root of a tree and the id of the last batch in it
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct Head {
+    /// none if all batches so far were empty
+    pub tree: Option<Sha256Digest>,
+    pub batch: u64,
+}
+
+/// The head that the root `name` points to, and its link
+pub fn head<R: RootStore<Sha256Digest>>(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    roots: &R,
+    name: &str,
+) -> anyhow::Result<Option<(Sha256Digest, Head)>> {
+    match roots.root(name)? {
+        Some(link) => Ok(Some((link, DagCborCodec.decode(&store.get(&link)?)?))),
+        None => Ok(None),
+    }
+}
+
+/// Append the events of the batch to the tree of `name`, written with `secrets`, unless it
+/// already has this batch. True if the batch was appended. The writer is the store the
+/// transaction writes to, which is synced before the swap
+#[allow(clippy::too_many_arguments)]
+pub fn append_batch<T, V, R, W, S>(
+    txn: &mut Transaction<T, R, W>,
+    writer: &mut impl DurableStore<Sha256Digest>,
+    roots: &S,
+    name: &str,
+    config: &Config,
+    secrets: &Secrets,
+    batch: u64,
+    events: Vec<(T::Key, V)>,
+) -> anyhow::Result<bool>
+where
+    T: TreeTypes<Link = Sha256Digest>,
+    V: BanyanValue,
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest>,
+    S: RootStore<Sha256Digest>,
+{
+    let current = head(txn.store(), roots, name)?;
+    let mut builder = match &current {
+        Some((_, head)) if batch <= head.batch => return Ok(false),
+        Some((
+            _,
+            Head {
+                tree: Some(tree), ..
+            },
+        )) => txn.load_stream_builder::<V>(secrets.clone(), config.clone(), *tree)?,
+        _ => StreamBuilder::new(config.clone(), secrets.clone()),
+    };
+    txn.extend(&mut builder, events)?;
+    let next = Head {
+        tree: builder.link(),
+        batch,
+    };
+    let link = writer.put(DagCborCodec.encode(&next)?)?;
+    writer.sync()?;
+    roots.compare_and_swap(name, current.map(|(link, _)| link), link)?;
+    Ok(true)
+}
+
+/// A writer that crashes after appending a batch but before the source saw the ack, and once in
+/// the middle of a batch, and gets the same batches again after each restart
+pub fn idempotent_example(config: &Config) -> anyhow::Result<()> {
+    let batches = 100u64;
+    let size = 1000u64;
+    println!(
+        "Example: {} batches of {} events, with resubmits after crashes",
+        batches, size
+    );
+    let dir = std::env::temp_dir().join(format!("banyan-idempotent-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let events = |batch: u64| (0..size).map(|i| ((), (batch - 1) * size + i)).collect();
+    // an encrypted tree, so every batch has to be appended with the same secrets
+    let secrets = Secrets::new([3u8; 32].into(), [4u8; 32].into());
+    // every start of the writer opens the store again, like a new process
+    let start = || -> anyhow::Result<_> {
+        let store = SqliteStore::<Sha256Digest>::open(dir.join("blocks.sqlite"))?;
+        let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+        Ok((Transaction::new(forest, store.clone()), store))
+    };
+    let submit = |txn: &mut Transaction<LogTT, _, _>, store: &SqliteStore<_>, batch| {
+        let mut writer = store.clone();
+        append_batch(
+            txn,
+            &mut writer,
+            store,
+            "events",
+            config,
+            &secrets,
+            batch,
+            events(batch),
+        )
+    };
+
+    let (mut txn, mut store) = start()?;
+    let mut skipped = 0;
+    for batch in 1..=batches {
+        if batch == 40 {
+            // a crash in the middle of the batch, after some blocks but before the swap
+            let tree = head(&store, &store, "events")?.and_then(|(_, head)| head.tree);
+            let tree = tree.expect("not empty");
+            let mut builder =
+                txn.load_stream_builder::<u64>(secrets.clone(), config.clone(), tree)?;
+            txn.extend(&mut builder, events(batch))?;
+            (txn, store) = start()?;
+        }
+        anyhow::ensure!(
+            submit(&mut txn, &store, batch)?,
+            "batch {} was not appended",
+            batch
+        );
+        if batch % 25 == 0 {
+            // a crash before the ack, so the source sends the last two batches again
+            (txn, store) = start()?;
+            for batch in batch - 1..=batch {
+                if !submit(&mut txn, &store, batch)? {
+                    skipped += 1;
+                }
+            }
+        }
+    }
+
+    let (_, head) = head(&store, &store, "events")?.expect("there is a head");
+    let root = head.tree.expect("not empty");
+    anyhow::ensure!(
+        txn.load_tree::<u64>(Secrets::default(), root)
+            .and_then(|tree| txn.iter_from(&tree).collect::<anyhow::Result<Vec<_>>>())
+            .is_err(),
+        "the tree can be read without its secrets"
+    );
+    let tree = txn.load_tree::<u64>(secrets.clone(), root)?;
+    anyhow::ensure!(
+        tree.count() == batches * size,
+        "{} events instead of {}",
+        tree.count(),
+        batches * size
+    );
+    for item in txn.iter_from(&tree) {
+        let (i, _, value) = item?;
+        anyhow::ensure!(i == value, "event {} is {}", i, value);
+    }
+    println!(
+        "{} events after batch {}, {} resubmitted batches skipped",
+        tree.count(),
+        head.batch,
+        skipped
+    );
+    fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
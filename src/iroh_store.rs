@@ -0,0 +1,63 @@
+//! A block store backed by iroh blobs
+//!
+//! iroh addresses blobs by their blake3 hash, so the links are [Blake3Digest]s and the link of a
+//! block is the same hash iroh uses for it. Any iroh node that serves the store can provide the
+//! blocks of a tree to other nodes, without kubo.
+//!
+//! The iroh api is async, so the store runs its own tokio runtime and blocks on each call.
+//! Only available with the `iroh` feature.
+use std::{path::Path, sync::Arc};
+
+use banyan::store::{BlockWriter, ReadOnlyStore};
+use iroh_blobs::{api::proto::BlobStatus, store::fs::FsStore};
+use tokio::runtime::Runtime;
+
+use crate::{link::Blake3Digest, probe::ProbingStore};
+
+/// A store that keeps its blocks in an iroh blob store on disk
+#[derive(Debug, Clone)]
+pub struct IrohStore {
+    store: FsStore,
+    runtime: Arc<Runtime>,
+}
+
+impl IrohStore {
+    /// Open the blob store in the directory, or create it
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let runtime = Runtime::new()?;
+        let store = runtime
+            .block_on(FsStore::load(path))
+            .map_err(|cause| anyhow::anyhow!("{}", cause))?;
+        Ok(Self {
+            store,
+            runtime: Arc::new(runtime),
+        })
+    }
+}
+
+impl ReadOnlyStore<Blake3Digest> for IrohStore {
+    fn get(&self, link: &Blake3Digest) -> anyhow::Result<Box<[u8]>> {
+        let data = self.runtime.block_on(self.store.get_bytes(*link))?;
+        Ok(data.to_vec().into())
+    }
+}
+
+impl ProbingStore<Blake3Digest> for IrohStore {
+    fn size(&self, link: &Blake3Digest) -> anyhow::Result<Option<u64>> {
+        // a partial blob is as good as none for a block
+        match self.runtime.block_on(self.store.status(*link))? {
+            BlobStatus::Complete { size } => Ok(Some(size)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl BlockWriter<Blake3Digest> for IrohStore {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Blake3Digest> {
+        // iroh has no gc unless it is configured, so the blob stays after the temp tag is dropped
+        let tag = self
+            .runtime
+            .block_on(self.store.add_bytes(data).temp_tag())?;
+        Ok(tag.hash().into())
+    }
+}
@@ -0,0 +1,165 @@
+//! Joining two trees on their keys
+//!
+//! Two trees that are both ordered by the same key, like events and the annotations that
+//! operators made for some of them, can be joined in a single pass over both, like a merge join
+//! in a database. [MergeJoin] keeps just the right values with the current key, so neither tree
+//! has to fit into memory.
+use std::{
+    collections::{HashMap, VecDeque},
+    iter::Peekable,
+    time::Instant,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::columnar::{self, ColumnarTT, EventKey};
+
+/// The inner join of two iterators of `(key, value)` that are each ordered by key
+///
+/// A key that is on both sides several times gives every combination.
+pub struct MergeJoin<A, B: Iterator, K, X, Y> {
+    a: A,
+    b: Peekable<B>,
+    /// the key of the last left item, and the right values with that key
+    run: Option<(K, Vec<Y>)>,
+    /// joined items of the last left item
+    pending: VecDeque<(K, X, Y)>,
+}
+
+impl<A, B: Iterator, K, X, Y> MergeJoin<A, B, K, X, Y> {
+    pub fn new(a: A, b: B) -> Self {
+        Self {
+            a,
+            b: b.peekable(),
+            run: None,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<A, B, K, X, Y> MergeJoin<A, B, K, X, Y>
+where
+    B: Iterator<Item = anyhow::Result<(K, Y)>>,
+    K: Ord,
+{
+    /// Skip the right values before `key`, and collect the ones with `key`
+    fn seek(&mut self, key: &K) -> anyhow::Result<Vec<Y>> {
+        let mut values = Vec::new();
+        loop {
+            match self.b.peek() {
+                Some(Ok((k, _))) if k > key => return Ok(values),
+                None => return Ok(values),
+                _ => {}
+            }
+            let (k, value) = self.b.next().expect("peeked")?;
+            if k == *key {
+                values.push(value);
+            }
+        }
+    }
+}
+
+impl<A, B, K, X, Y> Iterator for MergeJoin<A, B, K, X, Y>
+where
+    A: Iterator<Item = anyhow::Result<(K, X)>>,
+    B: Iterator<Item = anyhow::Result<(K, Y)>>,
+    K: Ord + Clone,
+    X: Clone,
+    Y: Clone,
+{
+    type Item = anyhow::Result<(K, X, Y)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(Ok(item));
+            }
+            let (key, x) = match self.a.next()? {
+                Ok(item) => item,
+                Err(cause) => return Some(Err(cause)),
+            };
+            // the right values are kept for as long as the left key stays the same
+            let same = matches!(&self.run, Some((k, _)) if *k == key);
+            if !same {
+                match self.seek(&key) {
+                    Ok(values) => self.run = Some((key.clone(), values)),
+                    Err(cause) => return Some(Err(cause)),
+                }
+            }
+            let (_, values) = self.run.as_ref().expect("just set");
+            self.pending
+                .extend(values.iter().map(|y| (key.clone(), x.clone(), y.clone())));
+        }
+    }
+}
+
+/// Join events with the annotations that operators made for some times
+pub fn join_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: joining {} events with annotations by time", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let events = columnar::events(n);
+    // an annotation for the time of every 100th event, by one of three operators
+    let annotations = events
+        .iter()
+        .step_by(100)
+        .map(|(key, i)| {
+            let key = EventKey {
+                time: key.time,
+                device: (i % 3) as u32,
+            };
+            (key, i + 1_000_000)
+        })
+        .collect::<Vec<_>>();
+    let mut expected = 0;
+    let mut per_time = HashMap::<u64, u64>::new();
+    for (key, _) in &events {
+        *per_time.entry(key.time).or_default() += 1;
+    }
+    for (key, _) in &annotations {
+        expected += per_time[&key.time];
+    }
+
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, events)?;
+    let events = builder.snapshot();
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, annotations)?;
+    let annotations = builder.snapshot();
+
+    let t0 = Instant::now();
+    let by_time = |tree| {
+        txn.iter_from(tree)
+            .map(|item| item.map(|(_, key, value)| (key.time, (key, value))))
+    };
+    let mut count = 0;
+    for item in MergeJoin::new(by_time(&events), by_time(&annotations)) {
+        let (time, (event, _), (annotation, _)) = item?;
+        anyhow::ensure!(
+            event.time == time && annotation.time == time,
+            "joined different times"
+        );
+        count += 1;
+    }
+    anyhow::ensure!(
+        count == expected,
+        "{} joined instead of {}",
+        count,
+        expected
+    );
+    println!(
+        "{} annotated events, {}s",
+        count,
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,195 @@
+//! Per-stream value keys in an encrypted key registry
+//!
+//! The index key and the value key of a tree are independent. All streams share one index key,
+//! but every stream gets its own random value key. The value keys are kept in a registry, which
+//! is stored as a single block encrypted with a master key. To erase the payloads of a stream,
+//! remove its key from the registry and store a new version. Without the key, the value blocks
+//! are just noise, but the keys and summaries in the index can still be queried.
+//!
+//! Old versions of the registry still contain the key, so they must be unpinned or deleted for
+//! the key to be really gone.
+//!
+//! Every save encrypts with a fresh random nonce, which is stored in front of the ciphertext.
+//! Two saves of the same loaded registry, e.g. from two processes or a retry, are different
+//! plaintexts, and must never share a key stream.
+use std::collections::BTreeMap;
+
+use banyan::{
+    chacha20::{Key as SecretKey, XNonce},
+    index::Index,
+    store::{BlockWriter, BranchCache, ReadOnlyStore, ZstdDagCborSeq},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::DagCbor;
+
+use crate::columnar::{events, ColumnarTT, TimeRangeQuery};
+
+/// Random bytes from the operating system
+fn random<const N: usize>() -> anyhow::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).map_err(|cause| anyhow::anyhow!("no randomness: {}", cause))?;
+    Ok(bytes)
+}
+
+/// A fresh random key
+fn random_key() -> anyhow::Result<SecretKey> {
+    Ok(SecretKey::from(random::<32>()?))
+}
+
+/// The value keys of all streams, by stream name
+#[derive(Debug, Clone, Default, PartialEq, Eq, DagCbor)]
+pub struct KeyRegistry {
+    /// incremented on every save
+    version: u64,
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl KeyRegistry {
+    /// Create a new random value key for the stream, replacing any existing one
+    pub fn create(&mut self, stream: &str) -> anyhow::Result<()> {
+        let key = random_key()?;
+        self.keys.insert(stream.to_string(), key.to_vec());
+        Ok(())
+    }
+
+    /// Forget the value key of the stream, returning whether there was one
+    pub fn forget(&mut self, stream: &str) -> bool {
+        self.keys.remove(stream).is_some()
+    }
+
+    /// The secrets for a stream, or `None` if its value key is unknown or was forgotten
+    pub fn secrets(&self, index_key: &SecretKey, stream: &str) -> Option<Secrets> {
+        let value_key = <[u8; 32]>::try_from(self.keys.get(stream)?.as_slice()).ok()?;
+        Some(Secrets::new(*index_key, SecretKey::from(value_key)))
+    }
+
+    /// Store a new version of the registry, encrypted with the master key and a random nonce,
+    /// and return its link
+    pub fn save(
+        &mut self,
+        store: &mut impl BlockWriter<Sha256Digest>,
+        master: &SecretKey,
+    ) -> anyhow::Result<Sha256Digest> {
+        self.version += 1;
+        let nonce = random::<24>()?;
+        let mut data = nonce.to_vec();
+        data.extend(ZstdDagCborSeq::single(self, 3)?.encrypt(
+            master,
+            <&XNonce>::from(&nonce),
+            0,
+        )?);
+        store.put(data)
+    }
+
+    /// Load and decrypt a registry
+    pub fn load(
+        store: &impl ReadOnlyStore<Sha256Digest>,
+        master: &SecretKey,
+        link: &Sha256Digest,
+    ) -> anyhow::Result<Self> {
+        let data = store.get(link)?;
+        anyhow::ensure!(data.len() > 24, "key registry {} is too short", link);
+        let (nonce, data) = data.split_at(24);
+        let (seq, _) = ZstdDagCborSeq::decrypt(data, master, <&XNonce>::from(nonce))?;
+        seq.items::<Self>()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("empty key registry"))
+    }
+}
+
+/// Number of events in the time range, using only the index
+fn count_keys<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<ColumnarTT, R>,
+    tree: &Tree<ColumnarTT, u64>,
+    query: &TimeRangeQuery,
+) -> anyhow::Result<usize> {
+    let mut n = 0;
+    for index in forest.iter_index(tree, query.clone()) {
+        if let Index::Leaf(leaf) = index? {
+            n += leaf
+                .keys()
+                .filter(|k| k.time >= query.min && k.time <= query.max)
+                .count();
+        }
+    }
+    Ok(n)
+}
+
+/// Write two streams with their own value keys, forget one key, and query both streams
+pub fn keys_example(
+    mut store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!(
+        "Example: two streams of {} events with their own value keys, one of them forgotten",
+        n
+    );
+    let master = random_key()?;
+    let index_key = random_key()?;
+    let mut registry = KeyRegistry::default();
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut trees = BTreeMap::new();
+    for stream in ["alice", "bob"] {
+        registry.create(stream)?;
+        let secrets = registry
+            .secrets(&index_key, stream)
+            .expect("key was just created");
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets);
+        txn.extend(&mut builder, events(n))?;
+        trees.insert(
+            stream,
+            builder.snapshot().root().cloned().expect("not empty"),
+        );
+    }
+    let before = registry.save(&mut store, &master)?;
+    anyhow::ensure!(registry.forget("bob"), "bob had no key");
+    let after = registry.save(&mut store, &master)?;
+    println!("registry {} before, {} after forgetting bob", before, after);
+    // two saves of the same version, like two processes that loaded the same registry
+    let (mut a, mut b) = (registry.clone(), registry.clone());
+    let (a, b) = (a.save(&mut store, &master)?, b.save(&mut store, &master)?);
+    anyhow::ensure!(a != b, "two saves of the same version are the same block");
+
+    // from here on, only the master key, the index key and the new registry are known
+    let registry = KeyRegistry::load(&store, &master, &after)?;
+    let xs = events(n);
+    let query = TimeRangeQuery {
+        min: xs[1000].0.time,
+        max: xs[2000].0.time,
+    };
+    let expected = xs
+        .iter()
+        .filter(|(k, _)| k.time >= query.min && k.time <= query.max)
+        .count();
+    for (stream, root) in trees {
+        let secrets = registry
+            .secrets(&index_key, stream)
+            .unwrap_or_else(|| Secrets::new(index_key, SecretKey::default()));
+        let tree = txn.load_tree::<u64>(secrets, root)?;
+        let keys = count_keys(&txn, &tree, &query)?;
+        let values = txn
+            .iter_filtered(&tree, query.clone())
+            .collect::<anyhow::Result<Vec<_>>>();
+        println!(
+            "{}: {} keys in range, values {}",
+            stream,
+            keys,
+            match &values {
+                Ok(values) => format!("{} readable", values.len()),
+                Err(cause) => format!("unreadable ({})", cause),
+            }
+        );
+        anyhow::ensure!(keys == expected, "index query failed for {}", stream);
+        anyhow::ensure!(
+            values.is_ok() == (stream == "alice"),
+            "values of {} have the wrong visibility",
+            stream
+        );
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,298 @@
+//! The kubo http api, on this machine or elsewhere
+//!
+//! The `IpfsStore` of banyan-utils always talks to `localhost:5001`. The [KuboStore] and every
+//! other call to kubo in this crate go to the [endpoint] instead, which is configured from the
+//! environment, so the examples and commands can also use a remote kubo, like one behind a
+//! reverse proxy with tls and authentication the way pinning services run it:
+//!
+//! - `BANYAN_KUBO_API`: the api address, as a url like `https://kubo.example.com` or as a
+//!   multiaddr like `/dns4/kubo.example.com/tcp/443/https`. Defaults to `/ip4/127.0.0.1/tcp/5001`
+//! - `BANYAN_KUBO_TOKEN`: a bearer token sent with every request
+//! - `BANYAN_KUBO_USER` and `BANYAN_KUBO_PASSWORD`: basic auth, instead of a token
+//! - `BANYAN_KUBO_CA`: a pem file with a root certificate to trust, for a proxy with a private ca
+use std::{
+    fmt,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use banyan::store::{BlockWriter, ReadOnlyStore};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+use reqwest::{
+    blocking::{Client, ClientBuilder, RequestBuilder, Response},
+    Certificate, StatusCode, Url,
+};
+use serde_json::Value;
+
+use crate::error::Error;
+
+/// The api of a kubo on this machine, the default of kubo itself
+pub const LOCAL: &str = "/ip4/127.0.0.1/tcp/5001";
+
+/// How to authenticate to the api
+#[derive(Clone, PartialEq, Eq)]
+pub enum Auth {
+    Bearer(String),
+    Basic {
+        user: String,
+        password: Option<String>,
+    },
+}
+
+impl fmt::Debug for Auth {
+    // no secrets in logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bearer(_) => write!(f, "Bearer(..)"),
+            Self::Basic { user, .. } => write!(f, "Basic({}, ..)", user),
+        }
+    }
+}
+
+/// Where the kubo api is, and how to talk to it
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// the url of the api, up to and including `/api/v0`
+    api: Url,
+    auth: Option<Auth>,
+    client: Client,
+    /// a client without a timeout, for responses that never end, like a pubsub subscription
+    streaming: Client,
+}
+
+/// Parse the multiaddr of an http api, like kubo writes it to its `api` file and shows it in its
+/// config as `Addresses.API`.
+///
+/// This is not a general multiaddr parser. It knows the address protocols `ip4`, `ip6`, `dns`,
+/// `dns4` and `dns6`, followed by `tcp` and a port, optionally followed by `http` or `https`. Kubo
+/// itself only listens on plain http, so that is the default. A tls proxy in front of it is written
+/// with a trailing `https`, or `tls/http`, both of which are common
+pub fn parse_multiaddr(addr: &str) -> anyhow::Result<Url> {
+    let parts = addr.strip_prefix('/').unwrap_or(addr).split('/');
+    let parts = parts.collect::<Vec<_>>();
+    let (host, rest) = match parts.as_slice() {
+        ["ip4" | "dns" | "dns4" | "dns6", host, rest @ ..] => (host.to_string(), rest),
+        ["ip6", host, rest @ ..] => (format!("[{}]", host), rest),
+        _ => anyhow::bail!("{} is not an ip or dns multiaddr", addr),
+    };
+    let (port, rest) = match rest {
+        ["tcp", port, rest @ ..] => (port.parse::<u16>()?, rest),
+        _ => anyhow::bail!("{} has no tcp port", addr),
+    };
+    let scheme = match rest {
+        [] | ["http"] => "http",
+        ["https"] | ["tls", "http"] => "https",
+        _ => anyhow::bail!("{} has unknown protocols {}", addr, rest.join("/")),
+    };
+    Ok(Url::parse(&format!("{}://{}:{}", scheme, host, port))?)
+}
+
+impl Endpoint {
+    /// An api at an address, either a url or a multiaddr
+    pub fn new(address: &str, auth: Option<Auth>, ca: Option<&Path>) -> anyhow::Result<Self> {
+        let mut base = if address.starts_with('/') {
+            parse_multiaddr(address)?
+        } else {
+            Url::parse(address)?
+        };
+        // so the api is below the path of a proxy, not next to it
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        anyhow::ensure!(
+            matches!(base.scheme(), "http" | "https"),
+            "kubo api {} is not http or https",
+            base
+        );
+        let api = base.join("api/v0/")?;
+        let builder = || -> anyhow::Result<ClientBuilder> {
+            let builder = Client::builder();
+            Ok(match ca {
+                Some(path) => builder.add_root_certificate(Certificate::from_pem(
+                    &std::fs::read(path).map_err(|cause| {
+                        anyhow::anyhow!("can not read ca {}: {}", path.display(), cause)
+                    })?,
+                )?),
+                None => builder,
+            })
+        };
+        Ok(Self {
+            api,
+            auth,
+            client: builder()?.build()?,
+            streaming: builder()?.timeout(None).build()?,
+        })
+    }
+
+    /// The endpoint the environment asks for, see the module docs
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let address = var("BANYAN_KUBO_API").unwrap_or_else(|| LOCAL.to_string());
+        let auth = match (var("BANYAN_KUBO_TOKEN"), var("BANYAN_KUBO_USER")) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("set either BANYAN_KUBO_TOKEN or BANYAN_KUBO_USER, not both")
+            }
+            (Some(token), None) => Some(Auth::Bearer(token)),
+            (None, Some(user)) => Some(Auth::Basic {
+                user,
+                password: var("BANYAN_KUBO_PASSWORD"),
+            }),
+            (None, None) => None,
+        };
+        let ca = var("BANYAN_KUBO_CA");
+        Self::new(&address, auth, ca.as_deref().map(Path::new))
+    }
+
+    /// The url of the api, for messages
+    pub fn api(&self) -> &Url {
+        &self.api
+    }
+
+    fn request(&self, client: &Client, command: &str, args: &[(&str, &str)]) -> RequestBuilder {
+        let mut url = self
+            .api
+            .join(command)
+            .expect("a command is a relative path");
+        if !args.is_empty() {
+            url.query_pairs_mut().extend_pairs(args);
+        }
+        let request = client.post(url);
+        match &self.auth {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic { user, password }) => request.basic_auth(user, password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// A request for a command, with the auth of the endpoint
+    pub fn post(&self, command: &str, args: &[(&str, &str)]) -> RequestBuilder {
+        self.request(&self.client, command, args)
+    }
+
+    /// A request for a command whose response does not end, without a timeout
+    pub fn post_streaming(&self, command: &str, args: &[(&str, &str)]) -> RequestBuilder {
+        self.request(&self.streaming, command, args)
+    }
+
+    /// Call a command and parse the json response
+    pub fn call(&self, command: &str, args: &[(&str, &str)]) -> anyhow::Result<Value> {
+        let response = self.post(command, args).send()?;
+        let status = response.status();
+        // a proxy that rejects the auth does not answer in json
+        let text = response.text()?;
+        let value: Value = serde_json::from_str(&text)
+            .map_err(|_| anyhow::anyhow!("kubo {} failed: {} {}", command, status, text))?;
+        if !status.is_success() {
+            anyhow::bail!("kubo {} failed: {}", command, value["Message"]);
+        }
+        Ok(value)
+    }
+}
+
+/// The endpoint from the environment, read once
+pub fn endpoint() -> anyhow::Result<&'static Endpoint> {
+    static ENDPOINT: OnceLock<Endpoint> = OnceLock::new();
+    if let Some(endpoint) = ENDPOINT.get() {
+        return Ok(endpoint);
+    }
+    let endpoint = Endpoint::from_env()?;
+    Ok(ENDPOINT.get_or_init(|| endpoint))
+}
+
+/// Call a command on the endpoint from the environment
+pub fn call(command: &str, args: &[(&str, &str)]) -> anyhow::Result<Value> {
+    endpoint()?.call(command, args)
+}
+
+/// Whether the status of a block command says that kubo does not have the block. Kubo answers
+/// its not found errors with a 404, and a gateway in front of it with a 404 or a 410
+pub fn is_not_found(status: StatusCode) -> bool {
+    matches!(status, StatusCode::NOT_FOUND | StatusCode::GONE)
+}
+
+/// The response of a command, or an error by its status. A 5xx is a kubo, or a proxy in front of
+/// it, that can not answer right now, so trying again later or another store can help
+pub fn checked(command: &str, response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    // the body is only for the message, a proxy does not answer in json
+    let text = response.text().unwrap_or_default();
+    if status.is_server_error() {
+        let reason = format!("kubo {} failed: {} {}", command, status, text);
+        return Err(Error::StoreUnavailable { reason }.into());
+    }
+    anyhow::bail!("kubo {} failed: {} {}", command, status, text)
+}
+
+/// A block store on the kubo api of an [Endpoint]
+#[derive(Debug, Clone)]
+pub struct KuboStore {
+    endpoint: Arc<Endpoint>,
+}
+
+impl KuboStore {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint: Arc::new(endpoint),
+        }
+    }
+
+    /// A store on the endpoint from the environment
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self::new(endpoint()?.clone()))
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl ReadOnlyStore<Sha256Digest> for KuboStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        let cid = Cid::from(*link).to_string();
+        let response = self.endpoint.post("block/get", &[("arg", &cid)]).send()?;
+        if is_not_found(response.status()) {
+            return Err(Error::missing(*link).into());
+        }
+        let response = checked(&format!("block/get {}", cid), response)?;
+        Ok(response.bytes()?.to_vec().into())
+    }
+}
+
+/// What banyan links are, and what kubo is asked to hash and tag a block with. A [Sha256Digest]
+/// only has the digest, and always becomes a dag-cbor cid with a sha2-256 multihash
+const PUT_ARGS: &[(&str, &str)] = &[
+    ("cid-codec", "dag-cbor"),
+    ("mhtype", "sha2-256"),
+    ("mhlen", "32"),
+];
+
+impl BlockWriter<Sha256Digest> for KuboStore {
+    /// Put the block, and check that kubo stored it under the link banyan computes for it. A kubo
+    /// that hashes or tags blocks differently, like one too old to know the put args, would store
+    /// the tree in blocks that nothing links to, and the mistake would only show on the first get
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+        let expected = Sha256Digest::digest(&data);
+        let part = reqwest::blocking::multipart::Part::bytes(data);
+        let form = reqwest::blocking::multipart::Form::new().part("file", part);
+        let response = self
+            .endpoint
+            .post("block/put", PUT_ARGS)
+            .multipart(form)
+            .send()?;
+        let value: Value = checked("block/put", response)?.json()?;
+        let cid: Cid = value["Key"].as_str().unwrap_or_default().parse()?;
+        anyhow::ensure!(
+            cid == Cid::from(expected),
+            "kubo stored a block as {}, but it is {}. Is the kubo at {} too old for {:?}?",
+            cid,
+            Cid::from(expected),
+            self.endpoint.api(),
+            PUT_ARGS
+        );
+        Ok(expected)
+    }
+}
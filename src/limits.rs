@@ -0,0 +1,173 @@
+//! Rate limits, and a limit of the queries of the server at the same time
+//!
+//! A query of the [server](crate::server) walks a tree, with a get for every block that is not in
+//! its cache, and a tail of a long stream from offset 0 is a get for every block of the stream.
+//! Without limits, a few of those take all connections of kubo, and every other request waits
+//! behind them. So the server has three limits, and answers a request that is over one of them
+//! with a 429 and a `Retry-After`:
+//!
+//! - a [RateLimit] per client address: a bucket of `burst` requests, which refills at `rate`
+//!   requests per second. A connection takes from the bucket before its request is read, so a
+//!   client that opens connections and sends nothing is limited as well
+//! - a [Semaphore] of `max_queries` traversals at the same time, of all clients together: sync
+//!   requests, appends, and each push of new events to a tail. A tail is already open, so instead
+//!   of a 429 it waits for its turn
+//! - `max_connections` connections at the same time, each with a thread of the server. A client
+//!   has a few seconds for each read of its request, see [crate::server]
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The limits of a server, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub rate: f64,
+    pub burst: f64,
+    pub max_queries: usize,
+    pub max_connections: usize,
+}
+
+/// The clients with a bucket, before the full buckets are forgotten
+const MAX_CLIENTS: usize = 10000;
+
+/// A bucket of requests per client address
+#[derive(Debug)]
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    /// the requests left, and when that was
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: f64) -> anyhow::Result<Self> {
+        anyhow::ensure!(rate > 0.0, "a rate of {} requests per second", rate);
+        anyhow::ensure!(burst >= 1.0, "a burst of {} requests", burst);
+        Ok(Self {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Take a request from the bucket of a client, or fail with how long until there is one
+    pub fn take(&self, client: IpAddr) -> Result<(), Duration> {
+        self.take_at(client, Instant::now())
+    }
+
+    fn take_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let (rate, burst) = (self.rate, self.burst);
+        let refill = |(left, at): (f64, Instant)| {
+            (left + rate * now.saturating_duration_since(at).as_secs_f64()).min(burst)
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            // a full bucket is the same as none
+            buckets.retain(|_, bucket| refill(*bucket) < burst);
+        }
+        let bucket = buckets.entry(client).or_insert((burst, now));
+        let left = refill(*bucket);
+        if left >= 1.0 {
+            *bucket = (left - 1.0, now);
+            Ok(())
+        } else {
+            *bucket = (left, now);
+            Err(Duration::from_secs_f64((1.0 - left) / rate))
+        }
+    }
+}
+
+/// A number of permits, for the queries of the server
+#[derive(Debug)]
+pub struct Semaphore {
+    free: Mutex<usize>,
+    cond: Condvar,
+}
+
+/// A permit of a [Semaphore], given back when dropped
+#[derive(Debug)]
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.cond.notify_one();
+    }
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            free: Mutex::new(permits),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// A permit, if there is one left
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut free = self.free.lock().unwrap();
+        if *free == 0 {
+            return None;
+        }
+        *free -= 1;
+        Some(Permit(self))
+    }
+
+    /// Wait for a permit
+    pub fn acquire(&self) -> Permit<'_> {
+        let free = self.free.lock().unwrap();
+        let mut free = self.cond.wait_while(free, |free| *free == 0).unwrap();
+        *free -= 1;
+        Permit(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn buckets_refill() {
+        let limit = RateLimit::new(2.0, 3.0).unwrap();
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limit.take_at(a, t0), Ok(()));
+        }
+        assert_eq!(limit.take_at(a, t0), Err(Duration::from_millis(500)));
+        // another client has a bucket of its own
+        assert_eq!(limit.take_at(b, t0), Ok(()));
+        let t1 = t0 + Duration::from_millis(500);
+        assert_eq!(limit.take_at(a, t1), Ok(()));
+        assert!(limit.take_at(a, t1).is_err());
+        // never more than the burst
+        let t2 = t1 + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limit.take_at(a, t2), Ok(()));
+        }
+        assert!(limit.take_at(a, t2).is_err());
+        assert!(RateLimit::new(0.0, 1.0).is_err());
+        assert!(RateLimit::new(1.0, 0.5).is_err());
+    }
+
+    #[test]
+    fn permits() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let first = semaphore.try_acquire().unwrap();
+        let second = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        let waiter = {
+            let semaphore = semaphore.clone();
+            thread::spawn(move || drop(semaphore.acquire()))
+        };
+        drop(first);
+        waiter.join().unwrap();
+        drop(second);
+        assert!(semaphore.try_acquire().is_some());
+    }
+}
@@ -0,0 +1,109 @@
+//! Link types for banyan trees
+//!
+//! The link type is part of the [TreeTypes](banyan::TreeTypes), so each tree type can pick its
+//! own hash function. Stores need to compute the link when a block is put, so every link type
+//! we use implements [Link].
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::{DagCbor, DagCborCodec},
+    codec::{Decode, Encode},
+    Cid,
+};
+use multihash::{Code, MultihashDigest};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    hash::Hash,
+    io::{Read, Seek, Write},
+    str::FromStr,
+};
+
+/// A link type that can be computed from the block data
+pub trait Link:
+    fmt::Display + fmt::Debug + Hash + Eq + Copy + Send + Sync + DagCbor + Into<Cid> + 'static
+{
+    /// compute the link of a block
+    fn digest(data: &[u8]) -> Self;
+}
+
+impl Link for Sha256Digest {
+    fn digest(data: &[u8]) -> Self {
+        Sha256Digest::digest(data)
+    }
+}
+
+/// A 32 byte blake3 digest, encoded as a dag-cbor cid with the blake3 multihash
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Blake3Digest([u8; 32]);
+
+impl Link for Blake3Digest {
+    fn digest(data: &[u8]) -> Self {
+        let mh = Code::Blake3_256.digest(data);
+        Blake3Digest(mh.digest().try_into().unwrap())
+    }
+}
+
+impl Decode<DagCborCodec> for Blake3Digest {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+        Self::try_from(Cid::decode(c, r)?)
+    }
+}
+
+impl Encode<DagCborCodec> for Blake3Digest {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+        Cid::encode(&Cid::from(*self), c, w)
+    }
+}
+
+impl From<Blake3Digest> for Cid {
+    fn from(value: Blake3Digest) -> Self {
+        // https://github.com/multiformats/multicodec/blob/master/table.csv
+        let mh = multihash::Multihash::wrap(0x1e, &value.0).unwrap();
+        Cid::new_v1(0x71, mh)
+    }
+}
+
+impl TryFrom<Cid> for Blake3Digest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Cid) -> Result<Self, Self::Error> {
+        anyhow::ensure!(value.codec() == 0x71, "Unexpected codec");
+        anyhow::ensure!(value.hash().code() == 0x1e, "Unexpected hash algorithm");
+        let digest: [u8; 32] = value.hash().digest().try_into()?;
+        Ok(Self(digest))
+    }
+}
+
+impl FromStr for Blake3Digest {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cid = Cid::from_str(s)?;
+        cid.try_into()
+    }
+}
+
+impl fmt::Display for Blake3Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Cid::from(*self))
+    }
+}
+
+impl fmt::Debug for Blake3Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Cid::from(*self))
+    }
+}
+
+#[cfg(feature = "iroh")]
+impl From<Blake3Digest> for iroh_blobs::Hash {
+    fn from(value: Blake3Digest) -> Self {
+        Self::from_bytes(value.0)
+    }
+}
+
+#[cfg(feature = "iroh")]
+impl From<iroh_blobs::Hash> for Blake3Digest {
+    fn from(value: iroh_blobs::Hash) -> Self {
+        Self(*value.as_bytes())
+    }
+}
@@ -0,0 +1,263 @@
+//! Last writer wins registers on top of an event stream
+//!
+//! The trees of this crate are logs, but most applications want the current state, like the
+//! latest setting of each key. Here an event of a [ColumnarTT] tree is an [Update] of one key,
+//! and its [EventKey] is its version: the time of the writer, and the writer as the device. The
+//! update with the highest version wins, and updates of the same time are ordered by the writer.
+//! Two updates of the same writer in the same millisecond have the same version, so the one whose
+//! value is greater as dag-cbor bytes wins, with a delete as null. With a total order on all
+//! updates, every reader ends up with the same value, in whatever order it applies the updates.
+//! That is a last writer wins register per key, a very simple CRDT.
+//!
+//! A delete is an update without a value. [Registers] keeps it as a tombstone, so an older
+//! update that turns up later, e.g. from a writer whose clock is behind, does not bring the key
+//! back.
+//!
+//! [Registers::catch_up] folds the events after the ones it has seen into the state, so a reader
+//! that follows a growing stream only reads the new events. The state is in memory, and is built
+//! again from the start of the stream after a restart.
+use std::collections::BTreeMap;
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor, Ipld};
+
+use crate::{
+    columnar::{ColumnarTT, EventKey},
+    schemaless,
+};
+
+/// The new value of a key, or none to delete it
+#[derive(Debug, Clone, PartialEq, DagCbor)]
+pub struct Update {
+    pub key: String,
+    pub value: Option<Ipld>,
+}
+
+/// The value of every key, from the updates of a stream, see the module docs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Registers {
+    /// the version and value of every key, with tombstones for deleted keys
+    entries: BTreeMap<String, (EventKey, Option<Ipld>)>,
+    /// the number of events seen
+    offset: u64,
+}
+
+/// What orders two updates with the same version, the value as dag-cbor
+fn tie_breaker(value: &Option<Ipld>) -> Vec<u8> {
+    // the value was decoded from dag-cbor, or can be encoded to be appended
+    DagCborCodec.encode(value).expect("a value is dag-cbor")
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of events that are folded into the state
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Apply an update with its version. Returns whether it won, which it does not if the key
+    /// has a newer version already, or the same version with a value that is not less
+    pub fn apply(&mut self, version: EventKey, update: Update) -> bool {
+        let wins = match self.entries.get(&update.key) {
+            None => true,
+            Some((current, value)) if *current == version => {
+                tie_breaker(&update.value) > tie_breaker(value)
+            }
+            Some((current, _)) => *current < version,
+        };
+        if wins {
+            self.entries.insert(update.key, (version, update.value));
+        }
+        wins
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Ipld> {
+        self.entries.get(key).and_then(|(_, value)| value.as_ref())
+    }
+
+    /// The keys that have a value, with their version
+    pub fn iter(&self) -> impl Iterator<Item = (&str, EventKey, &Ipld)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, (version, value))| Some((key.as_str(), *version, value.as_ref()?)))
+    }
+
+    /// The number of keys that have a value
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Apply the events of the tree after the ones seen so far. The tree has to be a newer
+    /// version of the stream. Returns the number of events applied
+    pub fn catch_up<R: ReadOnlyStore<Sha256Digest>>(
+        &mut self,
+        forest: &Forest<ColumnarTT, R>,
+        tree: &Tree<ColumnarTT, Update>,
+    ) -> anyhow::Result<u64> {
+        anyhow::ensure!(
+            tree.count() >= self.offset,
+            "the tree has {} events, fewer than the {} seen already",
+            tree.count(),
+            self.offset
+        );
+        let from = self.offset;
+        for item in forest.iter_filtered(tree, OffsetRangeQuery::from(from..)) {
+            let (offset, version, update) = item?;
+            self.apply(version, update);
+            self.offset = offset + 1;
+        }
+        Ok(self.offset - from)
+    }
+}
+
+/// Print the value of one key, or of all keys, of a tree of updates in kubo
+pub fn print_registers<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    secrets: &Secrets,
+    root: Sha256Digest,
+    key: Option<String>,
+) -> anyhow::Result<()> {
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<Update>(secrets.clone(), root)?;
+    let mut registers = Registers::new();
+    registers.catch_up(&forest, &tree)?;
+    match key {
+        Some(key) => match registers.get(&key) {
+            Some(value) => println!("{}", schemaless::to_json(value)?),
+            None => anyhow::bail!("{} has no value", key),
+        },
+        None => {
+            println!("key\ttime\twriter\tvalue");
+            for (key, version, value) in registers.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    key,
+                    version.time,
+                    version.device,
+                    schemaless::to_json(value)?
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Updates of `keys` keys by `writers` writers whose clocks are up to a second apart, with a
+/// delete every so often
+fn updates(n: u64, keys: u64, writers: u32) -> Vec<(EventKey, Update)> {
+    let mut rng = 0x9e3779b97f4a7c15u64;
+    let mut next = move || {
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng
+    };
+    let start = 1_600_000_000_000u64;
+    (0..n)
+        .map(|i| {
+            let writer = (next() % writers as u64) as u32;
+            // a writer whose clock is behind writes versions that lose to older updates
+            let skew = writer as u64 * 1000 / writers as u64;
+            let key = format!("key{}", next() % keys);
+            let value = if next() % 10 == 0 {
+                None
+            } else {
+                Some(Ipld::Integer(i as i128))
+            };
+            let version = EventKey {
+                time: start + i * 10 - skew,
+                device: writer,
+            };
+            (version, Update { key, value })
+        })
+        .collect()
+}
+
+/// Fold a growing stream of updates into registers, and compare them with the newest update of
+/// each key
+pub fn lww_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let keys = 1000;
+    let writers = 5;
+    let steps = 4;
+    println!(
+        "Example: last writer wins registers of {} keys from {} updates by {} writers",
+        keys, n, writers
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<ColumnarTT, Update>::new(config.clone(), Secrets::default());
+    let updates = updates(n, keys, writers);
+    let mut registers = Registers::new();
+    println!("events\tapplied\tkeys");
+    for part in updates.chunks((n / steps) as usize) {
+        txn.extend(&mut builder, part.iter().cloned())?;
+        let applied = registers.catch_up(&txn, &builder.snapshot())?;
+        anyhow::ensure!(applied == part.len() as u64, "applied {} events", applied);
+        println!("{}\t{}\t{}", registers.offset(), applied, registers.len());
+    }
+    // the newest update of each key, by version
+    let mut newest = BTreeMap::new();
+    for (version, update) in &updates {
+        let entry = newest
+            .entry(&update.key)
+            .or_insert((*version, &update.value));
+        if *version > entry.0 {
+            *entry = (*version, &update.value);
+        }
+    }
+    for (key, (_, value)) in &newest {
+        anyhow::ensure!(
+            registers.get(key) == value.as_ref(),
+            "{} has the wrong value",
+            key
+        );
+    }
+    // the same updates in another order give the same state
+    let mut reversed = Registers::new();
+    for (version, update) in updates.iter().rev() {
+        reversed.apply(*version, update.clone());
+    }
+    anyhow::ensure!(
+        reversed.entries == registers.entries,
+        "the order of the updates matters"
+    );
+    // two writes of one writer in the same millisecond win by value, in either order
+    let version = EventKey { time: 0, device: 0 };
+    let write = |value| Update {
+        key: "tie".into(),
+        value: Some(Ipld::Integer(value)),
+    };
+    let mut ties = [Registers::new(), Registers::new()];
+    ties[0].apply(version, write(1));
+    ties[0].apply(version, write(2));
+    ties[1].apply(version, write(2));
+    ties[1].apply(version, write(1));
+    anyhow::ensure!(
+        ties[0].entries == ties[1].entries,
+        "the order of writes with the same version matters"
+    );
+    let deleted = newest.values().filter(|(_, value)| value.is_none()).count();
+    anyhow::ensure!(
+        registers.len() + deleted == newest.len(),
+        "deleted keys have a value"
+    );
+    println!(
+        "{} keys have a value, {} are deleted",
+        registers.len(),
+        deleted
+    );
+    println!();
+    Ok(())
+}
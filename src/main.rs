@@ -1,30 +1,134 @@
 #![allow(clippy::redundant_clone)]
-use std::time::Instant;
+use std::{marker::PhantomData, time::Instant};
 
 use banyan::{
     store::{BlockWriter, BranchCache, ReadOnlyStore},
     *,
 };
 use banyan_utils::tags::Sha256Digest;
+use link::{Blake3Digest, Link};
+use profile::Profile;
+use progress::CountingStore;
+use structopt::StructOpt;
+use trace::{TracedQuery, TracingStore};
+
+mod access;
+mod aggregate;
+mod allocs;
+mod attachments;
+mod backfill;
+mod batch;
+mod blobs;
+mod borrowed;
+mod bridge;
+mod bundle;
+mod cache;
+mod cancel;
+mod car;
+mod columnar;
+mod compaction;
+mod compare;
+mod compression;
+mod dashboard;
+mod dedup;
+mod delta;
+mod downsample;
+mod drivers;
+mod error;
+mod explore;
+mod extsort;
+mod fixtures;
+mod flaky;
+mod fs_store;
+mod gateway;
+mod gc_store;
+mod health;
+mod idempotent;
+#[cfg(feature = "iroh")]
+mod iroh_store;
+mod join;
+mod keys;
+mod kubo;
+mod limits;
+mod link;
+mod lww;
+mod mem_store;
+mod merge;
+mod metadata;
+mod metrics;
+mod mqtt;
+mod nats;
+mod overlay;
+mod partial;
+mod peer;
+mod pipeline;
+mod prefetch;
+mod probe;
+mod profile;
+mod progress;
+mod projection;
+mod proof;
+mod query_json;
+mod range_filter;
+mod read_txn;
+mod readonly;
+mod remote;
+mod replay;
+mod result_cache;
+mod retention;
+mod rle;
+#[cfg(feature = "rocksdb")]
+mod rocks_store;
+mod rollup;
+mod roots;
+mod sample;
+mod schema;
+mod schemaless;
+mod search;
+mod secondary;
+#[cfg(feature = "serde")]
+mod serde_bridge;
+mod server;
+mod sharded;
+mod signed;
+mod snapshots;
+mod sort;
+mod sqlite_store;
+mod sweep;
+mod sync;
+mod tenants;
+mod tiering;
+mod topk;
+mod trace;
+mod unique;
+mod verify;
+mod versioned;
+mod views;
+mod wal;
+mod webhooks;
+mod websocket;
 
 /// Example to use banyan as just an efficient compressed event sequence without any indexes
 ///
 /// You will only be able to access by index or query/stream by index range
-fn sequence_example(
-    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+///
+/// The example is generic over the link type, so it can be used to compare the hashing cost on the write path
+fn sequence_example<L: Link>(
+    store: impl ReadOnlyStore<L> + BlockWriter<L>,
+    config: &Config,
 ) -> anyhow::Result<()> {
     let n = 1000000u64;
     println!("Example 1: building sequence of {} blocks on banyan", n);
 
     #[derive(Debug, Clone)]
-    struct SimpleTT;
+    struct SimpleTT<L>(PhantomData<L>);
 
-    impl banyan::TreeTypes for SimpleTT {
+    impl<L: Link> banyan::TreeTypes for SimpleTT<L> {
         type Key = (); // no keys
         type Summary = (); // no summaries
         type KeySeq = banyan::index::UnitSeq; // a sequence of unit keys
         type SummarySeq = banyan::index::UnitSeq; // a sequence of unit summaries
-        type Link = Sha256Digest; // use a 32 byte sha256 digest as link
+        type Link = L; // use whatever link the store produces, e.g. a 32 byte sha256 digest
         const NONCE: &'static [u8; 24] = b"Simple example for camp.";
     }
 
@@ -33,18 +137,19 @@ fn sequence_example(
 
     // setup
     // create a forest
-    let forest = Forest::<SimpleTT, _>::new(store.clone(), BranchCache::new(1024));
-    // configure a tree builder with a reasonable tree config and default secrets (not secure)
-    let mut builder = StreamBuilder::new(Config::debug_fast(), Secrets::default());
+    let forest = Forest::<SimpleTT<L>, _>::new(store.clone(), BranchCache::new(1024));
+    // configure a tree builder with the tree config from the command line and default secrets (not secure)
+    let mut builder = StreamBuilder::new(config.clone(), Secrets::default());
     // open a transaction.
     // Since we use the same store for reading and for writing, we don't have to commit the transaction
     // it is just a pair of a block reader and a block writer
-    let mut txn = Transaction::new(forest, store);
+    // the writer counts blocks and bytes so we can show progress
+    let mut txn = Transaction::new(forest, CountingStore::new(store));
 
     // writing
     let t0 = Instant::now();
-    // in the transaction, add to the builder from the vec
-    txn.extend(&mut builder, xs)?;
+    // in the transaction, add to the builder from the vec, in batches so we can show progress
+    progress::extend(&mut txn, &mut builder, xs)?;
     // take a snapshot of the builder. We are writing straight to ipfs, so no need to commit the txn
     let tree = builder.snapshot();
     // now we have a persistent tree
@@ -68,6 +173,7 @@ fn sequence_example(
 /// You will only be able to access by index or query/stream by index range
 fn custom_index_example(
     store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
 ) -> anyhow::Result<()> {
     let n = 1000000u64;
     println!(
@@ -96,11 +202,8 @@ fn custom_index_example(
 
     impl banyan::query::Query<IndexTT> for RangeQuery {
         fn containing(&self, _offset: u64, index: &index::LeafIndex<IndexTT>, res: &mut [bool]) {
-            let keys = index.keys.as_ref();
-            for (i, key) in keys.iter().enumerate() {
-                // true if the key is within range
-                res[i] = *key >= self.min && *key <= self.max;
-            }
+            // true if the key is within range, several keys at a time with the simd feature
+            range_filter::in_range(index.keys.as_ref(), self.min, self.max, res);
         }
 
         fn intersecting(
@@ -161,17 +264,18 @@ fn custom_index_example(
     // setup
     // create a forest
     let forest = Forest::<IndexTT, _>::new(store.clone(), BranchCache::new(1024));
-    // configure a tree builder with a reasonable tree config and default secrets (not secure)
-    let mut builder = StreamBuilder::new(Config::debug_fast(), Secrets::default());
+    // configure a tree builder with the tree config from the command line and default secrets (not secure)
+    let mut builder = StreamBuilder::new(config.clone(), Secrets::default());
     // open a transaction.
     // Since we use the same store for reading and for writing, we don't have to commit the transaction
     // it is just a pair of a block reader and a block writer
-    let mut txn = Transaction::new(forest, store);
+    // the writer counts blocks and bytes so we can show progress
+    let mut txn = Transaction::new(forest, CountingStore::new(store));
 
     // writing
     let t0 = Instant::now();
-    // in the transaction, add to the builder from the vec
-    txn.extend(&mut builder, xs)?;
+    // in the transaction, add to the builder from the vec, in batches so we can show progress
+    progress::extend(&mut txn, &mut builder, xs)?;
     // take a snapshot of the builder. We are writing straight to ipfs, so no need to commit the txn
     let tree = builder.snapshot();
     // now we have a persistent tree
@@ -192,10 +296,10 @@ fn custom_index_example(
     let mut n = 0;
     for item in txn.iter_filtered(
         &tree,
-        RangeQuery {
+        TracedQuery(RangeQuery {
             min: 500,
             max: 1000,
-        },
+        }),
     ) {
         let (_i, _k, v) = item?;
         // println!("{} {:?} {}", i, k, v);
@@ -212,6 +316,7 @@ fn custom_index_example(
 /// The tree types are not exactly the same, the query capabilities in actyx are much more advances, but the general idea is the same.
 fn actyx_example(
     store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
 ) -> anyhow::Result<()> {
     let n = 1000000u64;
     println!(
@@ -230,17 +335,18 @@ fn actyx_example(
     // setup
     // create a forest with the actyx tree types
     let forest = Forest::<ActyxTT, _>::new(store.clone(), BranchCache::new(1024));
-    // configure a tree builder with a reasonable tree config and default secrets (not secure)
-    let mut builder = StreamBuilder::new(Config::debug_fast(), Secrets::default());
+    // configure a tree builder with the tree config from the command line and default secrets (not secure)
+    let mut builder = StreamBuilder::new(config.clone(), Secrets::default());
     // open a transaction.
     // Since we use the same store for reading and for writing, we don't have to commit the transaction
     // it is just a pair of a block reader and a block writer
-    let mut txn = Transaction::new(forest, store);
+    // the writer counts blocks and bytes so we can show progress
+    let mut txn = Transaction::new(forest, CountingStore::new(store));
 
     // writing
     let t0 = Instant::now();
-    // in the transaction, add to the builder from the vec
-    txn.extend(&mut builder, xs)?;
+    // in the transaction, add to the builder from the vec, in batches so we can show progress
+    progress::extend(&mut txn, &mut builder, xs)?;
     // take a snapshot of the builder. We are writing straight to ipfs, so no need to commit the txn
     let tree = builder.snapshot();
     // now we have a persistent tree
@@ -259,25 +365,1134 @@ fn actyx_example(
     Ok(())
 }
 
-fn run(store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>) -> anyhow::Result<()> {
-    sequence_example(store.clone())?;
-    custom_index_example(store.clone())?;
-    actyx_example(store.clone())?;
+fn run(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+    all: bool,
+) -> anyhow::Result<()> {
+    sequence_example(store.clone(), config)?;
+    custom_index_example(store.clone(), config)?;
+    actyx_example(store.clone(), config)?;
+    if all {
+        examples(store, config)?;
+    }
+    Ok(())
+}
+
+/// The examples of the modules, each with checks that fail the run, for the examples command
+fn examples(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    delta::delta_example(store.clone(), config)?;
+    columnar::columnar_example(store.clone(), config)?;
+    rle::rle_example(store.clone(), config)?;
+    merge::merge_example(store.clone(), config)?;
+    secondary::secondary_example(store.clone(), config)?;
+    snapshots::snapshots_example(store.clone(), config)?;
+    overlay::overlay_example(store.clone(), config)?;
+    probe::sync_example(store.clone(), config)?;
+    retention::retention_example(store.clone(), config)?;
+    compaction::compaction_example(store.clone(), config)?;
+    tiering::tiering_example(store.clone(), config)?;
+    gc_store::gc_example(config)?;
+    roots::roots_example(config)?;
+    fs_store::compact_example(config)?;
+    read_txn::read_txn_example(config)?;
+    idempotent::idempotent_example(config)?;
+    wal::wal_example(config)?;
+    webhooks::webhooks_example(config)?;
+    sync::sync_example(config)?;
+    peer::peer_example(config)?;
+    sharded::sharded_example(config)?;
+    pipeline::pipeline_example(config)?;
+    range_filter::range_filter_example()?;
+    tenants::tenants_example(config)?;
+    keys::keys_example(store.clone(), config)?;
+    metadata::metadata_example(store.clone(), config)?;
+    schemaless::schemaless_example(store.clone(), config)?;
+    versioned::versioned_example(store.clone(), config)?;
+    schema::schema_example(store.clone(), config)?;
+    drivers::drivers_example(store.clone(), config)?;
+    explore::explore_example(store.clone(), config)?;
+    projection::projection_example(store.clone(), config)?;
+    query_json::query_json_example(store.clone(), config)?;
+    result_cache::result_cache_example(store.clone(), config)?;
+    views::views_example(store.clone(), config)?;
+    downsample::downsample_example(store.clone(), config)?;
+    lww::lww_example(store.clone(), config)?;
+    replay::replay_example(store.clone(), config)?;
+    aggregate::aggregate_example(store.clone(), config)?;
+    rollup::rollup_example(store.clone(), config)?;
+    topk::topk_example(store.clone(), config)?;
+    sample::sample_example(store.clone(), config)?;
+    search::search_example(store.clone(), config)?;
+    join::join_example(store.clone(), config)?;
+    unique::unique_example(store.clone(), config)?;
+    backfill::backfill_example(store.clone(), config)?;
+    sort::sort_example(store.clone(), config)?;
+    extsort::extsort_example(store.clone(), config)?;
+    bundle::bundle_example(store.clone(), config)?;
+    partial::partial_example(store.clone(), config)?;
+    proof::proof_example(store.clone(), config)?;
+    cancel::cancel_example(store.clone(), config)?;
+    error::error_example(store.clone(), config)?;
+    verify::verify_example(store.clone(), config)?;
+    borrowed::borrowed_example(store.clone(), config)?;
+    borrowed::filtered_example(store.clone(), config)?;
+    signed::signed_example(store.clone(), config)?;
+    bridge::bridge_example(config)?;
+    mqtt::mqtt_example(config)?;
+    blobs::blobs_example(store.clone(), config)?;
+    attachments::attachments_example(store.clone(), config)?;
+    #[cfg(feature = "serde")]
+    serde_bridge::serde_example(store.clone(), config)?;
+    // the same sequence with blake3 instead of sha256 links. kubo only hashes with sha256, so use a memstore
+    println!("Using blake3 links");
+    sequence_example(
+        banyan::store::MemStore::new(1000000000, Blake3Digest::digest),
+        config,
+    )?;
     Ok(())
 }
 
+#[derive(StructOpt)]
+#[structopt(about = "Examples for using banyan as an event log on ipfs")]
+struct Opts {
+    #[structopt(long, global = true)]
+    /// Log spans for block get/put and query traversal, with timings
+    trace: bool,
+    #[structopt(long, global = true)]
+    /// A profile of the config file, for everything not given on the command line
+    profile: Option<String>,
+    #[structopt(long, default_value = "banyan-camp.toml", global = true)]
+    /// The config file with the profiles
+    profile_file: std::path::PathBuf,
+    #[structopt(long, global = true)]
+    /// The zstd level for leaves and branches, from 1 to 22. Defaults to 3
+    zstd_level: Option<i32>,
+    #[structopt(long, global = true)]
+    /// Where the examples store blocks: auto (kubo if available, else fs), kubo, fs, sqlite, mem,
+    /// rocks, iroh or sharded (four file stores). Defaults to auto
+    backend: Option<Backend>,
+    #[structopt(long, global = true)]
+    /// Stop query commands that take longer than this many milliseconds, or for a server, requests
+    timeout_ms: Option<u64>,
+    #[structopt(long, global = true)]
+    /// The directory for backends that keep their blocks in files. Defaults to banyan-data
+    path: Option<std::path::PathBuf>,
+    #[structopt(long, global = true)]
+    /// Where the query commands read blocks from: kubo, fs:<dir>, sqlite:<dir>, sharded:<dir>,
+    /// car:<file> or the url of an http gateway. Defaults to the store of the backend in the path
+    source: Option<readonly::Source>,
+    #[structopt(subcommand)]
+    /// Runs all examples if no command is given
+    cmd: Option<Command>,
+}
+
+impl Opts {
+    /// Fill in what the command line leaves open from the profile, if one was asked for
+    fn with_profile(mut self) -> anyhow::Result<(Self, Profile)> {
+        let profile = match &self.profile {
+            Some(name) => profile::load(&self.profile_file, name)?,
+            None => Profile::default(),
+        };
+        self.backend = self.backend.or(profile.backend);
+        self.path = self.path.or_else(|| profile.path.clone());
+        self.timeout_ms = self.timeout_ms.or(profile.timeout_ms);
+        self.zstd_level = self.zstd_level.or(profile.zstd_level);
+        Ok((self, profile))
+    }
+
+    /// The tree config used by the examples
+    fn config(&self, profile: &Profile) -> anyhow::Result<Config> {
+        let config = Config {
+            zstd_level: self.zstd_level.unwrap_or(3),
+            ..profile.config.clone().unwrap_or_else(Config::debug_fast)
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// The store the examples run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Auto,
+    Kubo,
+    Fs,
+    Sqlite,
+    Mem,
+    Rocks,
+    Iroh,
+    Sharded,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "kubo" => Ok(Self::Kubo),
+            "fs" => Ok(Self::Fs),
+            "sqlite" => Ok(Self::Sqlite),
+            "mem" => Ok(Self::Mem),
+            "rocks" => Ok(Self::Rocks),
+            "iroh" => Ok(Self::Iroh),
+            "sharded" => Ok(Self::Sharded),
+            _ => anyhow::bail!("unknown backend {}", s),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Run the examples of all modules after the three of a run without a command, on the
+    /// backend. Takes a while, and stops at the first one that fails its checks
+    Examples,
+    /// Build a sequence at zstd levels 1 to 19 and compare stored bytes with raw encoded bytes
+    ZstdReport {
+        #[structopt(long, default_value = "100000")]
+        /// The number of values
+        count: u64,
+    },
+    /// Compare ingest time, storage size and range query latency with a flat file and sqlite
+    Compare {
+        #[structopt(long, default_value = "1000000")]
+        /// The number of events
+        count: u64,
+        #[structopt(long, default_value = "100")]
+        /// The number of range queries
+        queries: u64,
+    },
+    /// Build the same events with a grid of leaf size options and print write time, tree level,
+    /// stored bytes and range query latency as CSV
+    LeafSweep {
+        #[structopt(long, default_value = "1000000")]
+        /// The number of events
+        count: u64,
+        #[structopt(long, default_value = "100")]
+        /// The number of range queries per config
+        queries: u64,
+        #[structopt(
+            long,
+            use_delimiter = true,
+            default_value = "256,1024,4096,16384,65536"
+        )]
+        /// Values of max_leaf_count, separated by commas
+        leaf_counts: Vec<usize>,
+        #[structopt(long, use_delimiter = true, default_value = "4096,16384,65536,262144")]
+        /// Values of target_leaf_size in bytes, separated by commas
+        leaf_sizes: Vec<usize>,
+    },
+    /// Run repeated filtered queries with different branch cache sizes and show cache hits and misses
+    CacheReport {
+        #[structopt(long, default_value = "100000")]
+        /// The number of events
+        count: u64,
+        #[structopt(long, default_value = "100")]
+        /// The number of queries per cache size
+        queries: u64,
+        #[structopt(
+            long,
+            use_delimiter = true,
+            default_value = "0,1024,65536,1048576,67108864"
+        )]
+        /// Branch cache capacities in bytes, separated by commas
+        sizes: Vec<usize>,
+    },
+    /// Scan a sequence through a store with simulated latency, with and without read-ahead
+    PrefetchScan {
+        #[structopt(long, default_value = "1000000")]
+        /// The number of values
+        count: u64,
+        #[structopt(long, default_value = "16")]
+        /// The number of leaves to fetch ahead, which is also the number of fetch threads
+        lookahead: usize,
+        #[structopt(long, default_value = "5")]
+        /// The simulated latency per block get, in milliseconds
+        latency_ms: u64,
+    },
+    /// Run a filtered query through a store with simulated latency, with and without batched fetches
+    BatchQuery {
+        #[structopt(long, default_value = "1000000")]
+        /// The number of events
+        count: u64,
+        #[structopt(long, default_value = "16")]
+        /// The maximum number of concurrent block gets
+        parallelism: usize,
+        #[structopt(long, default_value = "5")]
+        /// The simulated latency per block get, in milliseconds
+        latency_ms: u64,
+    },
+    /// Build small canonical trees and compare their root links with known good values
+    CheckFixtures {
+        #[structopt(long)]
+        /// Print the current root links in the format of the golden values instead of comparing
+        print: bool,
+    },
+    /// Probe kubo and the directory of the file backends: version, latency and whether they can write
+    Health,
+    /// Verify the chain of signed snapshots from a writer, and list it newest first
+    VerifyChain {
+        #[structopt(long)]
+        /// The link of the newest signed snapshot
+        head: Sha256Digest,
+        #[structopt(long)]
+        /// The public key of the writer
+        writer_key: String,
+        #[structopt(long)]
+        /// A signed snapshot seen before, which the head has to extend
+        known: Option<Sha256Digest>,
+    },
+    /// List the snapshot records in kubo, newest first
+    History {
+        #[structopt(long)]
+        /// The link of the newest snapshot record
+        head: Sha256Digest,
+    },
+    /// Print all values of a snapshot in kubo, by label
+    Checkout {
+        #[structopt(long)]
+        /// The link of the newest snapshot record
+        head: Sha256Digest,
+        /// The label of the snapshot
+        label: String,
+    },
+    /// Query an offset range of the stream in kubo as it was at a point in its history
+    Query {
+        #[structopt(long)]
+        /// The link of the newest snapshot record
+        head: Sha256Digest,
+        #[structopt(long)]
+        /// A snapshot label, or a unix time in milliseconds
+        as_of: snapshots::AsOf,
+        #[structopt(long, default_value = "0")]
+        /// The first offset
+        from: u64,
+        #[structopt(long)]
+        /// The end of the offset range, exclusive
+        to: Option<u64>,
+    },
+    /// Remove the blocks of the local file store that are not reachable from the roots to keep,
+    /// and leftovers of interrupted writes, and print the sizes before and after
+    StoreCompact {
+        /// Root links to keep, in addition to the roots in the manifest files
+        roots: Vec<Sha256Digest>,
+        #[structopt(long)]
+        /// Manifest files with roots to keep, by default all `.manifest` files in the store
+        manifest: Vec<std::path::PathBuf>,
+    },
+    /// Show how many blocks of trees in kubo are shared, for example between successive snapshots
+    DedupReport {
+        #[structopt(required = true, min_values = 2)]
+        /// The root links of the trees
+        roots: Vec<Sha256Digest>,
+    },
+    /// Print the types and size of a tree in kubo, found out from the root alone
+    Stats {
+        /// The root link of the tree
+        root: Sha256Digest,
+    },
+    /// Print what a block in kubo is, a branch, a leaf or something else, and what it contains
+    CatBlock {
+        /// The link of the block
+        link: Sha256Digest,
+        #[structopt(long)]
+        /// A file with the index key and the value key, instead of the secrets of the profile
+        secrets: Option<std::path::PathBuf>,
+    },
+    /// Print the index of every node of a tree in kubo, with only the index key
+    Index {
+        /// The root link of the tree
+        root: Sha256Digest,
+        #[structopt(long)]
+        /// A file with the index key, or with the secrets, instead of the secrets of the profile
+        index_key: Option<std::path::PathBuf>,
+    },
+    /// Print all events of a tree in kubo as dag-json lines, whatever its types
+    Export {
+        /// The root link of the tree
+        root: Sha256Digest,
+    },
+    /// Print a line per leaf of a tree in kubo with its offset range, link, size and the hash of
+    /// its values, whatever its types, as a fingerprint of the data for an audit
+    AuditManifest {
+        /// The root link of the tree
+        root: Sha256Digest,
+    },
+    /// Check the invariants of a tree in kubo and decode all its events, whatever its types
+    Verify {
+        /// The root link of the tree
+        root: Sha256Digest,
+    },
+    /// Write a tree in kubo with its types and schema to a single bundle file
+    Bundle {
+        /// The root link of the tree
+        root: Sha256Digest,
+        #[structopt(long, default_value = "tree")]
+        /// The name of the tree in the bundle
+        name: String,
+        /// The bundle file to write
+        file: std::path::PathBuf,
+    },
+    /// Check a bundle file and print what is in it
+    OpenBundle {
+        /// The bundle file
+        file: std::path::PathBuf,
+    },
+    /// Aggregate the events of a columnar tree in kubo per time window, and optionally per device
+    Aggregate {
+        /// The root link of the tree
+        root: Sha256Digest,
+        #[structopt(long, default_value = "3600000")]
+        /// The width of a window, in milliseconds
+        window_ms: u64,
+        #[structopt(long)]
+        /// Aggregate each device on its own
+        per_device: bool,
+        #[structopt(long, default_value = "0")]
+        /// The first time, in unix milliseconds
+        from: u64,
+        #[structopt(long)]
+        /// The last time, inclusive
+        to: Option<u64>,
+    },
+    /// Print the events of a columnar tree in kubo that match a query in json, like
+    /// `{"and": [{"time": {"from": 0, "to": 1000}}, {"devices": [1, 2]}]}`
+    Select {
+        /// The root link of the tree
+        root: Sha256Digest,
+        /// The query
+        query: String,
+        #[structopt(long)]
+        /// A sqlite file with the results of earlier queries, which is also where this result goes
+        result_cache: Option<std::path::PathBuf>,
+    },
+    /// Write the events of a json query on a columnar tree in kubo into a tree of their own, and
+    /// print the view block that records where they came from
+    Materialize {
+        /// The root link of the source tree
+        root: Sha256Digest,
+        /// The query, see `select`
+        query: String,
+    },
+    /// Append the events of a newer version of the source to a view in kubo, and print the new
+    /// view block
+    Refresh {
+        /// The link of the view block
+        view: Sha256Digest,
+        /// The root link of the newer source tree
+        root: Sha256Digest,
+    },
+    /// Write the count, sum, min and max of the values of a columnar tree in kubo per window
+    /// into a tree of their own, or bring an earlier one up to date, and print the buckets
+    Downsample {
+        /// The root link of the source tree
+        root: Sha256Digest,
+        #[structopt(long, default_value = "60000")]
+        /// The width of a window, in milliseconds
+        window_ms: u64,
+        #[structopt(long)]
+        /// Aggregate each device on its own
+        per_device: bool,
+        #[structopt(long)]
+        /// The link of an earlier downsample block of an older version of the source, to refresh
+        /// instead of starting over. Its window and devices are used
+        from: Option<Sha256Digest>,
+    },
+    /// Print the current value of every key, or of one key, of a columnar tree of last writer
+    /// wins updates in kubo
+    Registers {
+        /// The root link of the tree
+        root: Sha256Digest,
+        /// Only the value of this key, as json
+        key: Option<String>,
+    },
+    /// Serve the streams of a manifest file over http, with their blocks in kubo: the blocks a
+    /// reader is missing, appends of JSON events, and a WebSocket tail, see src/server.rs
+    SyncServe {
+        /// The manifest file with the roots, by name
+        manifest: std::path::PathBuf,
+        #[structopt(long, default_value = "127.0.0.1:8435")]
+        /// The address to listen on
+        listen: String,
+        #[structopt(long)]
+        /// A file with the tokens that may read and append to which streams, see src/access.rs.
+        /// Without it, everyone may do everything
+        access: Option<std::path::PathBuf>,
+        #[structopt(long, default_value = "50")]
+        /// The requests per second of each client address, on average, see src/limits.rs
+        rate: f64,
+        #[structopt(long, default_value = "100")]
+        /// The requests of a client address in a row, after a pause
+        burst: f64,
+        #[structopt(long, default_value = "8")]
+        /// The tree traversals at the same time, of all clients: sync requests, appends and
+        /// pushes of new events to tails
+        max_queries: usize,
+        #[structopt(long, default_value = "256")]
+        /// The open connections, each with a thread, of all clients, including the tails
+        max_connections: usize,
+    },
+    /// Serve the blocks of the local file store to other instances over tcp, see src/peer.rs
+    PeerServe {
+        #[structopt(long, default_value = "127.0.0.1:8436")]
+        /// The address to listen on
+        listen: String,
+    },
+    /// Copy trees from another instance that serves its blocks to the local file store
+    PeerFetch {
+        /// The address of the peer, like 10.0.0.2:8436
+        peer: String,
+        #[structopt(required = true)]
+        /// The root links of the trees
+        roots: Vec<Sha256Digest>,
+    },
+    /// Print the events of a stream on a server as dag-json lines, and the new ones as they come
+    Tail {
+        /// The url of the server, like http://127.0.0.1:8435
+        url: String,
+        /// The name of the root
+        name: String,
+        #[structopt(long, default_value = "0")]
+        /// The offset of the first event
+        offset: u64,
+    },
+    /// Get the blocks of the latest root of a stream that are missing in kubo from a sync server,
+    /// and print the root
+    SyncPull {
+        /// The url of the server, like http://127.0.0.1:8435
+        url: String,
+        /// The name of the root
+        name: String,
+        #[structopt(long)]
+        /// The root of the stream that kubo has all blocks of already
+        have: Option<Sha256Digest>,
+    },
+    /// Print the count, sum, min or max of the values in a time range of a columnar tree in kubo,
+    /// counting from the summaries where they are enough
+    Rollup {
+        /// The root link of the tree
+        root: Sha256Digest,
+        #[structopt(long, default_value = "count")]
+        /// count, sum, min or max
+        op: rollup::Op,
+        #[structopt(long, default_value = "0")]
+        /// The first time, in unix milliseconds
+        from: u64,
+        #[structopt(long)]
+        /// The last time, inclusive
+        to: Option<u64>,
+    },
+    /// Print the devices with the most events of a columnar tree in kubo
+    Top {
+        /// The root link of the tree
+        root: Sha256Digest,
+        #[structopt(short, default_value = "10")]
+        /// The number of devices
+        k: usize,
+        #[structopt(long, default_value = "0")]
+        /// The first time, in unix milliseconds
+        from: u64,
+        #[structopt(long)]
+        /// The last time, inclusive
+        to: Option<u64>,
+    },
+    /// Run the examples through a store that fails, stalls and loses blocks, with a retry layer
+    FlakyRun {
+        #[structopt(long, default_value = "0.05")]
+        /// The fraction of gets that fail, but succeed when retried
+        failure_rate: f64,
+        #[structopt(long, default_value = "1")]
+        /// The maximum random delay per get, in milliseconds
+        jitter_ms: u64,
+        #[structopt(long, default_value = "0.01")]
+        /// The fraction of blocks that are missing
+        missing_rate: f64,
+        #[structopt(long, default_value = "5")]
+        /// The number of attempts per get
+        attempts: u32,
+    },
+    /// Ingest generated events at a fixed rate into memory, with a live dashboard
+    Ingest {
+        #[structopt(long, default_value = "100000")]
+        /// The target number of events per second
+        rate: u64,
+        #[structopt(long, default_value = "2000")]
+        /// The time between snapshots, in milliseconds
+        snapshot_ms: u64,
+        #[structopt(long)]
+        /// Stop after this many events instead of waiting for q
+        count: Option<u64>,
+        #[structopt(long)]
+        /// Append batches unpacked, and pack the tree while idle, checking about this often, in
+        /// milliseconds
+        compact_ms: Option<u64>,
+        #[structopt(long = "webhook")]
+        /// A url to post the record of every snapshot to, can be given more than once
+        webhooks: Vec<String>,
+    },
+    /// Append events to a stream in kubo and publish the newest snapshot, for a reader elsewhere
+    Writer {
+        #[structopt(long)]
+        /// The name of the kubo key to publish the newest snapshot record with, like `self`
+        ipns_key: Option<String>,
+        #[structopt(long)]
+        /// A file to write the link of the newest snapshot record to, instead of IPNS
+        manifest: Option<std::path::PathBuf>,
+        #[structopt(long, requires = "manifest")]
+        /// The tenant whose head to write in the manifest, with the secrets of the tenant from
+        /// the master key of the profile
+        tenant: Option<String>,
+        #[structopt(long, default_value = "1000")]
+        /// The number of events per snapshot
+        batch: u64,
+        #[structopt(long, default_value = "10000")]
+        /// The time between snapshots, in milliseconds
+        interval_ms: u64,
+        #[structopt(long)]
+        /// Stop after this many snapshots
+        count: Option<u64>,
+        #[structopt(long)]
+        /// Also announce each snapshot on this pubsub topic, for readers that subscribe to it
+        topic: Option<String>,
+        #[structopt(long)]
+        /// A file with the key to sign each snapshot with, created if it does not exist
+        signing_key: Option<std::path::PathBuf>,
+    },
+    /// Follow a stream published by a writer, and print new events as they arrive via bitswap
+    Reader {
+        #[structopt(long)]
+        /// The IPNS name of the writer
+        ipns: Option<String>,
+        #[structopt(long)]
+        /// The manifest file the writer writes to, instead of IPNS
+        manifest: Option<std::path::PathBuf>,
+        #[structopt(long, requires = "manifest")]
+        /// The tenant whose head to read from the manifest, with the secrets of the tenant from
+        /// the master key of the profile
+        tenant: Option<String>,
+        #[structopt(long, default_value = "5000")]
+        /// The time between polls, in milliseconds
+        interval_ms: u64,
+        #[structopt(long, requires = "peer")]
+        /// Wait for announcements on this pubsub topic instead of polling
+        topic: Option<String>,
+        #[structopt(long)]
+        /// The peer id of the writer's kubo, the only one whose announcements are accepted
+        peer: Option<String>,
+        #[structopt(long)]
+        /// The public key the writer prints on start, to only accept snapshots it signed
+        writer_key: Option<String>,
+    },
+    /// Append events from a NATS JetStream consumer to a columnar tree in the fs store, and ack
+    /// them once they are in the write-ahead log. The server is in BANYAN_NATS_URL and
+    /// BANYAN_NATS_TOKEN
+    Bridge {
+        /// The JetStream stream
+        stream: String,
+        /// The durable pull consumer of the stream
+        consumer: String,
+        #[structopt(long, default_value = "100")]
+        /// The number of records per fetch, at most
+        batch: usize,
+        #[structopt(long, default_value = "10")]
+        /// The number of batches per snapshot, at most
+        snapshot_every: usize,
+        #[structopt(long)]
+        /// Stop after this many records
+        count: Option<u64>,
+        #[structopt(long)]
+        /// The manifest file with the head of the bridge. Defaults to bridge.manifest in the path
+        manifest: Option<std::path::PathBuf>,
+        #[structopt(long)]
+        /// The write-ahead log of the records that are not in a snapshot yet. Defaults to
+        /// bridge.wal in the path
+        wal: Option<std::path::PathBuf>,
+        #[structopt(long = "webhook")]
+        /// A url to post the record of every snapshot to, can be given more than once
+        webhooks: Vec<String>,
+    },
+    /// Append messages from an MQTT broker to a tree in the fs store, with the topic segments as
+    /// tags. The broker is in BANYAN_MQTT_URL, BANYAN_MQTT_USER and BANYAN_MQTT_PASSWORD
+    Mqtt {
+        /// The topic filters to subscribe to, like `factory/#`
+        #[structopt(required = true)]
+        filters: Vec<String>,
+        #[structopt(long, default_value = "banyan")]
+        /// The client id, which is also the name of the tree in the manifest
+        client_id: String,
+        #[structopt(long, default_value = "1000")]
+        /// The number of messages per batch, at most
+        batch: usize,
+        #[structopt(long, default_value = "10")]
+        /// The number of batches per snapshot, at most
+        snapshot_every: usize,
+        #[structopt(long)]
+        /// Stop after this many messages
+        count: Option<u64>,
+        #[structopt(long)]
+        /// The manifest file with the root of the tree. Defaults to mqtt.manifest in the path
+        manifest: Option<std::path::PathBuf>,
+        #[structopt(long)]
+        /// The write-ahead log of the messages that are not in a snapshot yet. Defaults to
+        /// mqtt.wal in the path
+        wal: Option<std::path::PathBuf>,
+    },
+}
+
 fn main() -> anyhow::Result<()> {
-    // create a store that reads and writes from ipfs. Requires kubo (go-ipfs) compatible API on port 5001
-    let mut store = banyan_utils::ipfs::IpfsStore::new()?;
-    match store.put(vec![]) {
-        Ok(_) => {
-            println!("kubo seems to be available. Using kubo interface on port 5001");
-            run(store)
+    let (opts, profile) = Opts::from_args().with_profile()?;
+    if opts.trace {
+        trace::init();
+    }
+    let config = opts.config(&profile)?;
+    let timeout = opts.timeout_ms.map(std::time::Duration::from_millis);
+    let path = opts
+        .path
+        .clone()
+        .unwrap_or_else(|| std::path::PathBuf::from("banyan-data"));
+    let trees = remote::TreeOptions {
+        config: config.clone(),
+        secrets: profile.secrets.unwrap_or_default(),
+        master_key: profile.master_key,
+        cache_bytes: profile.cache_bytes.unwrap_or(profile::DEFAULT_CACHE_BYTES),
+    };
+    // only opened by the commands that query, so the others don't need kubo or the path
+    let read = || {
+        let source = match &opts.source {
+            Some(source) => source.clone(),
+            None => source_of(opts.backend, &path)?,
+        };
+        readonly::store(&source, timeout)
+    };
+    if let Some(cmd) = opts.cmd {
+        return match cmd {
+            Command::Examples => run_on(opts.backend, &path, &config, true),
+            Command::ZstdReport { count } => compression::report(&config, count),
+            Command::Compare { count, queries } => compare::report(&config, count, queries),
+            Command::LeafSweep {
+                count,
+                queries,
+                leaf_counts,
+                leaf_sizes,
+            } => sweep::report(&config, count, queries, &leaf_counts, &leaf_sizes),
+            Command::CacheReport {
+                count,
+                queries,
+                sizes,
+            } => cache::report(&config, count, queries, &sizes),
+            Command::PrefetchScan {
+                count,
+                lookahead,
+                latency_ms,
+            } => prefetch::report(
+                &config,
+                count,
+                lookahead,
+                std::time::Duration::from_millis(latency_ms),
+            ),
+            Command::CheckFixtures { print } => fixtures::check(print),
+            Command::Health => health::report(&path),
+            Command::VerifyChain {
+                head,
+                writer_key,
+                known,
+            } => signed::print_chain(&read()?, head, &signed::parse_key(&writer_key)?, known),
+            Command::History { head } => snapshots::print_history(&read()?, head),
+            Command::Checkout { head, label } => {
+                snapshots::print_query(&read()?, head, &snapshots::AsOf::Label(label), 0, None)
+            }
+            Command::Query {
+                head,
+                as_of,
+                from,
+                to,
+            } => snapshots::print_query(&read()?, head, &as_of, from, to),
+            Command::StoreCompact { roots, manifest } => {
+                fs_store::print_compact(&path, &roots, manifest)
+            }
+            Command::DedupReport { roots } => dedup::report(&read()?, &roots),
+            Command::Stats { root } => drivers::print_stats(&read()?, root),
+            Command::CatBlock { link, secrets } => explore::print_block(
+                &read()?,
+                link,
+                &match secrets {
+                    Some(path) => profile::load_secrets(path)?,
+                    None => trees.secrets.clone(),
+                },
+            ),
+            Command::Index { root, index_key } => metadata::print_index(
+                &read()?,
+                root,
+                &match index_key {
+                    Some(path) => metadata::load_index_key(&path)?,
+                    None => *trees.secrets.index_key(),
+                },
+            ),
+            Command::Export { root } => drivers::print_export(&read()?, root),
+            Command::AuditManifest { root } => drivers::print_audit_manifest(&read()?, root),
+            Command::Verify { root } => drivers::print_verify(&read()?, &config, root),
+            Command::Bundle { root, name, file } => {
+                bundle::print_bundle(&read()?, root, &name, &file)
+            }
+            Command::OpenBundle { file } => bundle::print_open_bundle(&config, &file),
+            Command::Aggregate {
+                root,
+                window_ms,
+                per_device,
+                from,
+                to,
+            } => aggregate::print_aggregate(
+                &read()?,
+                root,
+                window_ms,
+                per_device,
+                columnar::TimeRangeQuery {
+                    min: from,
+                    max: to.unwrap_or(u64::MAX),
+                },
+            ),
+            Command::Select {
+                root,
+                query,
+                result_cache,
+            } => query_json::print_select(
+                &read()?,
+                root,
+                &query_json::JsonQuery::parse(&query)?,
+                result_cache
+                    .map(|path| result_cache::ResultCache::open(path, 1 << 26))
+                    .transpose()?
+                    .as_ref(),
+            ),
+            Command::Materialize { root, query } => views::print_materialize(
+                &config,
+                &trees.secrets,
+                root,
+                &query_json::JsonQuery::parse(&query)?,
+            ),
+            Command::Refresh { view, root } => {
+                views::print_refresh(&config, &trees.secrets, view, root)
+            }
+            Command::Downsample {
+                root,
+                window_ms,
+                per_device,
+                from,
+            } => downsample::print_downsample(
+                &config,
+                &trees.secrets,
+                root,
+                window_ms,
+                per_device,
+                from,
+            ),
+            Command::Registers { root, key } => {
+                lww::print_registers(&read()?, &trees.secrets, root, key)
+            }
+            Command::SyncServe {
+                manifest,
+                listen,
+                access,
+                rate,
+                burst,
+                max_queries,
+                max_connections,
+            } => server::print_serve(
+                server::kubo_store(timeout)?,
+                health::probe_kubo,
+                &manifest,
+                &listen,
+                &config,
+                trees.cache_bytes,
+                access.as_deref(),
+                limits::Limits {
+                    rate,
+                    burst,
+                    max_queries,
+                    max_connections,
+                },
+                trees.master_key,
+            ),
+            Command::Tail { url, name, offset } => server::print_tail(&url, &name, offset),
+            Command::SyncPull { url, name, have } => {
+                sync::print_pull(&mut kubo::KuboStore::from_env()?, &url, &name, have)
+            }
+            Command::PeerServe { listen } => peer::print_serve(&path, &listen),
+            Command::PeerFetch { peer, roots } => peer::print_fetch(&path, &peer, &roots),
+            Command::Rollup { root, op, from, to } => rollup::print_rollup(
+                &read()?,
+                root,
+                op,
+                columnar::TimeRangeQuery {
+                    min: from,
+                    max: to.unwrap_or(u64::MAX),
+                },
+            ),
+            Command::Top { root, k, from, to } => topk::print_top(
+                &read()?,
+                root,
+                k,
+                columnar::TimeRangeQuery {
+                    min: from,
+                    max: to.unwrap_or(u64::MAX),
+                },
+            ),
+            Command::FlakyRun {
+                failure_rate,
+                jitter_ms,
+                missing_rate,
+                attempts,
+            } => flaky::report(
+                &config,
+                flaky::Faults {
+                    failure_rate,
+                    jitter: std::time::Duration::from_millis(jitter_ms),
+                    missing_rate,
+                },
+                attempts,
+            ),
+            Command::Ingest {
+                rate,
+                snapshot_ms,
+                count,
+                compact_ms,
+                webhooks,
+            } => dashboard::ingest(
+                &config,
+                rate,
+                std::time::Duration::from_millis(snapshot_ms),
+                count,
+                compact_ms.map(std::time::Duration::from_millis),
+                webhooks,
+            ),
+            Command::Bridge {
+                stream,
+                consumer,
+                batch,
+                snapshot_every,
+                count,
+                manifest,
+                wal,
+                webhooks,
+            } => bridge::run(
+                &format!("{}.{}", stream, consumer),
+                &mut nats::JetStream::new(nats::Client::from_env()?, &stream, &consumer),
+                fs_store::FsStore::<Sha256Digest>::open(&path)?,
+                &manifest.unwrap_or_else(|| path.join("bridge.manifest")),
+                &wal.unwrap_or_else(|| path.join("bridge.wal")),
+                &config,
+                &trees.secrets,
+                batch,
+                snapshot_every,
+                count,
+                match webhooks.is_empty() {
+                    true => None,
+                    false => Some(webhooks::Notifier::new(
+                        webhooks,
+                        std::time::Duration::from_secs(10),
+                    )?),
+                },
+            ),
+            Command::Mqtt {
+                filters,
+                client_id,
+                batch,
+                snapshot_every,
+                count,
+                manifest,
+                wal,
+            } => mqtt::run(
+                mqtt::Client::from_env(&client_id)?,
+                &client_id,
+                &filters,
+                fs_store::FsStore::<Sha256Digest>::open(&path)?,
+                &manifest.unwrap_or_else(|| path.join("mqtt.manifest")),
+                &wal.unwrap_or_else(|| path.join("mqtt.wal")),
+                &config,
+                &trees.secrets,
+                batch,
+                snapshot_every,
+                count,
+            ),
+            Command::Writer {
+                ipns_key,
+                manifest,
+                tenant,
+                batch,
+                interval_ms,
+                count,
+                topic,
+                signing_key,
+            } => remote::writer(
+                &trees.for_tenant(tenant.as_deref())?,
+                &remote::Channel::from_options(ipns_key, manifest, tenant)?,
+                batch,
+                std::time::Duration::from_millis(interval_ms),
+                count,
+                topic.as_deref(),
+                signing_key
+                    .map(|path| signed::load_or_create_key(&path))
+                    .transpose()?
+                    .as_ref(),
+            ),
+            Command::Reader {
+                ipns,
+                manifest,
+                tenant,
+                interval_ms,
+                topic,
+                peer,
+                writer_key,
+            } => remote::reader(
+                &trees.for_tenant(tenant.as_deref())?,
+                &remote::Channel::from_options(ipns, manifest, tenant)?,
+                std::time::Duration::from_millis(interval_ms),
+                topic.as_deref().zip(peer),
+                writer_key.as_deref().map(signed::parse_key).transpose()?,
+            ),
+            Command::BatchQuery {
+                count,
+                parallelism,
+                latency_ms,
+            } => batch::report(
+                &config,
+                count,
+                parallelism,
+                std::time::Duration::from_millis(latency_ms),
+            ),
+        };
+    }
+    run_on(opts.backend, &path, &config, false)
+}
+
+/// The sqlite store of the sqlite backend, a file in the directory, which is created if needed
+fn open_sqlite(dir: &std::path::Path) -> anyhow::Result<sqlite_store::SqliteStore<Sha256Digest>> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join("blocks.sqlite");
+    println!("Using sqlite in {}", path.display());
+    sqlite_store::SqliteStore::open(&path)
+}
+
+/// The source with the blocks the examples wrote to a backend, with the same choice for auto
+fn source_of(backend: Option<Backend>, path: &std::path::Path) -> anyhow::Result<readonly::Source> {
+    use readonly::Source;
+    Ok(match backend.unwrap_or(Backend::Auto) {
+        Backend::Auto if health::kubo_usable().is_some() => Source::Kubo,
+        Backend::Auto | Backend::Fs => Source::Fs(path.to_path_buf()),
+        Backend::Kubo => Source::Kubo,
+        Backend::Sqlite => Source::Sqlite(path.to_path_buf()),
+        Backend::Sharded => Source::Sharded(path.join("sharded")),
+        backend => anyhow::bail!(
+            "the query commands can not read from the {:?} backend, give a --source",
+            backend
+        ),
+    })
+}
+
+/// Run the examples on a backend, and all of them if `all`
+fn run_on(
+    backend: Option<Backend>,
+    path: &std::path::Path,
+    config: &Config,
+    all: bool,
+) -> anyhow::Result<()> {
+    // blocks in files survive a restart, unlike a memstore, and don't need anything running
+    let fs = |path: &std::path::Path| -> anyhow::Result<()> {
+        println!("Using files in {}", path.display());
+        let store = fs_store::FsStore::<Sha256Digest>::open(path)?;
+        run(TracingStore::new(store.clone()), config, all)?;
+        let (blocks, bytes) = store.usage();
+        println!("{} blocks, {} bytes in {}", blocks, bytes, path.display());
+        Ok(())
+    };
+    match backend.unwrap_or(Backend::Auto) {
+        Backend::Auto => {
+            // create a store that reads and writes from ipfs. Requires kubo (go-ipfs) compatible API at the endpoint
+            match health::kubo_usable() {
+                Some(health) => {
+                    println!(
+                        "kubo {} is available, {:.3}ms per request. Using kubo interface on {}",
+                        health.version,
+                        health.latency.as_secs_f64() * 1000.0,
+                        kubo::endpoint()?.api()
+                    );
+                    run(TracingStore::new(kubo::KuboStore::from_env()?), config, all)
+                }
+                None => fs(path),
+            }
         }
-        Err(_) => {
-            println!("kubo seems not to be available. Using in memory store");
-            let store = banyan::store::MemStore::new(1000000000, Sha256Digest::digest);
-            run(store)
+        Backend::Kubo => run(TracingStore::new(kubo::KuboStore::from_env()?), config, all),
+        Backend::Fs => fs(path),
+        Backend::Sqlite => {
+            let store = open_sqlite(path)?;
+            run(TracingStore::new(store.clone()), config, all)?;
+            let (blocks, bytes) = store.usage()?;
+            println!("{} blocks, {} bytes in sqlite", blocks, bytes);
+            Ok(())
         }
+        Backend::Sharded => {
+            let path = path.join("sharded");
+            println!("Using four file stores in {}", path.display());
+            let store = sharded::open_fs(&path, 4)?;
+            run(TracingStore::new(store.clone()), config, all)?;
+            for (i, shard) in store.shards().iter().enumerate() {
+                let (blocks, bytes) = shard.usage();
+                println!("{} blocks, {} bytes in shard {}", blocks, bytes, i);
+            }
+            Ok(())
+        }
+        Backend::Mem => {
+            let store = mem_store::BoundedMemStore::new(1000000000);
+            run(TracingStore::new(store.clone()), config, all)?;
+            println!("{} bytes in memory", store.used());
+            Ok(())
+        }
+        #[cfg(feature = "rocksdb")]
+        Backend::Rocks => {
+            let path = path.join("rocksdb");
+            println!("Using rocksdb in {}", path.display());
+            let store = rocks_store::RocksStore::<Sha256Digest>::open(path)?;
+            run(TracingStore::new(store.clone()), config, all)?;
+            if all {
+                rocks_store::rocks_example(store.clone(), config)?;
+            }
+            store.flush()
+        }
+        #[cfg(not(feature = "rocksdb"))]
+        Backend::Rocks => anyhow::bail!(
+            "the rocks backend needs the rocksdb feature, to store blocks in {}",
+            path.join("rocksdb").display()
+        ),
+        #[cfg(feature = "iroh")]
+        Backend::Iroh => {
+            let path = path.join("iroh");
+            println!("Using iroh blobs in {}", path.display());
+            // iroh only hashes with blake3, so only the example that is generic over the link
+            let store = iroh_store::IrohStore::open(path)?;
+            sequence_example(TracingStore::new(store), config)
+        }
+        #[cfg(not(feature = "iroh"))]
+        Backend::Iroh => anyhow::bail!(
+            "the iroh backend needs the iroh feature, to store blocks in {}",
+            path.join("iroh").display()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_backend_in_empty_dir() {
+        let dir = std::env::temp_dir().join(format!("banyan-sqlite-{}", std::process::id()));
+        let mut store = open_sqlite(&dir.join("new")).unwrap();
+        let link = store.put(b"block".to_vec()).unwrap();
+        assert_eq!(&*store.get(&link).unwrap(), b"block");
+        assert!(dir.join("new/blocks.sqlite").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,69 @@
+//! An in memory store with typed errors
+//!
+//! The [MemStore] of banyan answers a missing block with "not there" and a put over its budget
+//! with "full", as plain messages. [BoundedMemStore] wraps it and reports them as
+//! [Error::BlockMissing] and [Error::QuotaExceeded], so [kind](crate::error::kind) can tell them
+//! apart like the errors of every other store of this crate.
+use std::sync::{Arc, Mutex};
+
+use banyan::store::{BlockWriter, MemStore, ReadOnlyStore};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::error::Error;
+
+/// A [MemStore] of at most `limit` bytes, see the module docs
+#[derive(Clone)]
+pub struct BoundedMemStore {
+    inner: MemStore<Sha256Digest>,
+    /// the bytes of the blocks so far, which the inner store does not tell
+    used: Arc<Mutex<u64>>,
+    limit: u64,
+}
+
+impl BoundedMemStore {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            inner: MemStore::new(
+                usize::try_from(limit).unwrap_or(usize::MAX),
+                Sha256Digest::digest,
+            ),
+            used: Default::default(),
+            limit,
+        }
+    }
+
+    /// The bytes of the blocks in the store
+    pub fn used(&self) -> u64 {
+        *self.used.lock().unwrap()
+    }
+}
+
+impl ReadOnlyStore<Sha256Digest> for BoundedMemStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        // not there is the only error of the inner store
+        self.inner
+            .get(link)
+            .map_err(|_| Error::missing(*link).into())
+    }
+}
+
+impl BlockWriter<Sha256Digest> for BoundedMemStore {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+        let link = Sha256Digest::digest(&data);
+        let mut used = self.used.lock().unwrap();
+        if self.inner.get(&link).is_ok() {
+            return Ok(link);
+        }
+        let size = data.len() as u64;
+        if *used + size > self.limit {
+            return Err(Error::QuotaExceeded {
+                used: *used,
+                limit: self.limit,
+            }
+            .into());
+        }
+        self.inner.put(data)?;
+        *used += size;
+        Ok(link)
+    }
+}
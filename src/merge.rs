@@ -0,0 +1,130 @@
+//! Merging trees from partitioned writers
+//!
+//! Two devices that are offline can each append to their own tree. Since every event is only
+//! written by one device, combining the trees is never a conflict: we stream both trees in key
+//! order and build a new tree from the merged sequence. The result only depends on the events,
+//! not on which side does the merge, so both devices end up with the same root.
+use std::{iter::Peekable, time::Instant};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{ColumnarTT, EventKey},
+    extsort,
+};
+
+pub type Event = (EventKey, u64);
+
+/// Merge two iterators of events that are each ordered by key into one ordered iterator
+pub struct MergeByKey<A: Iterator, B: Iterator> {
+    a: Peekable<A>,
+    b: Peekable<B>,
+}
+
+impl<A: Iterator, B: Iterator> MergeByKey<A, B> {
+    /// Events with the same key come from `a` first
+    pub fn new(a: A, b: B) -> Self {
+        Self {
+            a: a.peekable(),
+            b: b.peekable(),
+        }
+    }
+}
+
+impl<A, B> Iterator for MergeByKey<A, B>
+where
+    A: Iterator<Item = anyhow::Result<Event>>,
+    B: Iterator<Item = anyhow::Result<Event>>,
+{
+    type Item = anyhow::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let take_a = match (self.a.peek(), self.b.peek()) {
+            (Some(Ok(a)), Some(Ok(b))) => a.0 <= b.0,
+            // errors are passed on as soon as we see them
+            (Some(Err(_)), _) => true,
+            (_, Some(Err(_))) => false,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if take_a {
+            self.a.next()
+        } else {
+            self.b.next()
+        }
+    }
+}
+
+/// Build a new tree with the events of both trees, ordered by key
+pub fn merge<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    config: &Config,
+    a: &Tree<ColumnarTT, u64>,
+    b: &Tree<ColumnarTT, u64>,
+) -> anyhow::Result<Tree<ColumnarTT, u64>>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest>,
+{
+    // a second handle to read from while the transaction writes, so the trees are streamed
+    let forest = Forest::clone(txn);
+    let events = |tree| {
+        forest
+            .iter_from(tree)
+            .map(|item| item.map(|(_i, k, v)| (k, v)))
+    };
+    extsort::build(txn, config, MergeByKey::new(events(a), events(b)))
+}
+
+/// Two devices write disjoint events while offline, then merge their trees in both directions
+pub fn merge_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!(
+        "Example: merging the trees of two offline devices with {} events each",
+        n
+    );
+
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+
+    // each device writes its own events, with interleaved timestamps
+    let mut trees = Vec::new();
+    for device in 0..2u32 {
+        let mut builder = StreamBuilder::new(config.clone(), Secrets::default());
+        let xs = (0..n).map(|i| {
+            let time = i * 10 + (device as u64) * 3 + (i * 7 + device as u64) % 5;
+            (EventKey { time, device }, i)
+        });
+        txn.extend(&mut builder, xs)?;
+        let tree = builder.snapshot();
+        println!("device {} {:#?}", device, tree);
+        trees.push(tree);
+    }
+
+    let t0 = Instant::now();
+    let ab = merge(&mut txn, config, &trees[0], &trees[1])?;
+    println!("merged {:#?} {}s", ab, t0.elapsed().as_secs_f64());
+    let ba = merge(&mut txn, config, &trees[1], &trees[0])?;
+
+    // the merge is deterministic, so it does not matter which device does it
+    anyhow::ensure!(ab.root() == ba.root(), "merge depends on the order");
+    anyhow::ensure!(ab.count() == 2 * n, "merge lost events");
+    let mut prev = None;
+    for item in txn.iter_from(&ab) {
+        let (_i, k, _v) = item?;
+        anyhow::ensure!(prev < Some(k), "merged tree is not ordered by key");
+        prev = Some(k);
+    }
+    if let Some(root) = ab.root() {
+        println!("both merge orders give {}", root);
+    }
+    println!();
+    Ok(())
+}
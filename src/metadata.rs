@@ -0,0 +1,108 @@
+//! Reading the index without the value key
+//!
+//! The index key encrypts the branches, with the keys and summaries of everything below them,
+//! and the value key encrypts the leaves with the values. Someone who only has the index key,
+//! like an operator of the store, can see how many events there are, in which key ranges, and
+//! what their summaries are, but not a single value. [index_only] makes the secrets for such a
+//! reader, and the `index` command prints every node of a tree with them.
+use std::{fs, path::Path};
+
+use banyan::{
+    chacha20::Key as SecretKey,
+    index::Index,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    drivers::Registry,
+};
+
+/// The secrets of a reader that only has the index key. The value key is all zeros, so every leaf
+/// fails to decode
+pub fn index_only(index_key: &SecretKey) -> Secrets {
+    Secrets::new(*index_key, SecretKey::default())
+}
+
+/// Load an index key from a file with its 32 bytes, or the first 32 bytes of a secrets file
+pub fn load_index_key(path: &Path) -> anyhow::Result<SecretKey> {
+    let bytes = fs::read(path)
+        .map_err(|cause| anyhow::anyhow!("can not read key {}: {}", path.display(), cause))?;
+    anyhow::ensure!(
+        bytes.len() == 32 || bytes.len() == 64,
+        "{} has {} bytes, not an index key of 32 or secrets of 64",
+        path.display(),
+        bytes.len()
+    );
+    Ok(SecretKey::from(<[u8; 32]>::try_from(&bytes[..32])?))
+}
+
+/// Print the types of a tree and the index of each of its nodes, with only the index key
+pub fn print_index<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    index_key: &SecretKey,
+) -> anyhow::Result<()> {
+    let registry = Registry::<S>::builtin();
+    let (driver, nodes) = registry.detect_index(store, root, &index_only(index_key))?;
+    println!("types\t{}", driver.name());
+    print!("{}", String::from_utf8(nodes)?);
+    Ok(())
+}
+
+/// Read the index of a tree of events with only the index key, and fail to read a value
+pub fn metadata_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: the index of {} events without the value key", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let index_key = SecretKey::from([5u8; 32]);
+    let secrets = Secrets::new(index_key, SecretKey::from([6u8; 32]));
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets);
+    let events = columnar::events(n);
+    txn.extend(&mut builder, events.iter().cloned())?;
+    let root = builder.link().expect("not empty");
+
+    let registry = Registry::builtin();
+    let (driver, nodes) = registry.detect_index(&store, root, &index_only(&index_key))?;
+    anyhow::ensure!(driver.name() == "columnar", "detected as {}", driver.name());
+    let nodes = String::from_utf8(nodes)?;
+    println!(
+        "{} nodes of a {} tree",
+        nodes.lines().count(),
+        driver.name()
+    );
+    let root_index = nodes.lines().next().unwrap_or_default();
+    println!("root\t{}", root_index);
+
+    // the index is enough to count the events in a time range, but not to read one of them
+    let tree = txn.load_tree::<u64>(index_only(&index_key), root)?;
+    let query = TimeRangeQuery {
+        min: events[1000].0.time,
+        max: events[1999].0.time,
+    };
+    let mut count = 0;
+    for index in txn.iter_index(&tree, query.clone()) {
+        if let Index::Leaf(leaf) = index? {
+            count += leaf
+                .keys()
+                .filter(|key| key.time >= query.min && key.time <= query.max)
+                .count();
+        }
+    }
+    anyhow::ensure!(count >= 1000, "only {} events in the range", count);
+    println!("{} events in the time range, from the index alone", count);
+    let value = txn.iter_filtered(&tree, query).next();
+    anyhow::ensure!(
+        matches!(value, Some(Err(_))),
+        "read a value without the value key"
+    );
+    println!("reading a value fails");
+    println!();
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Prometheus metrics of the server
+//!
+//! The [server](crate::server) counts what it does in its [Metrics], and answers `GET /metrics`
+//! with them in the text format of prometheus, along with the histograms banyan itself keeps of
+//! loading and storing nodes and blocks:
+//!
+//! - `banyan_server_blocks_fetched_total` and `banyan_server_block_bytes_fetched_total`: the
+//!   blocks the server got from its store, which are the misses of its block cache
+//! - `banyan_server_block_cache_hits_total`: the gets that the block cache answered. The hit rate
+//!   is the hits over the hits plus the blocks fetched
+//! - `banyan_server_query_seconds`: how long a request took, by route. For a tail, how long each
+//!   push of new events took, since the tail itself lasts as long as the client wants
+//! - `banyan_server_appended_events_total`: the events appended, whose rate is the throughput of
+//!   the appends
+//! - `banyan_server_rejected_total`: the requests answered with a 429, by the limit they were
+//!   over, `rate`, `queries` or `connections`, see [crate::limits]
+//!
+//! The server has a forest per request, whose [BranchCache](banyan::store::BranchCache) is gone
+//! with the request, and a branch cache can not tell its hits anyway, see [crate::cache]. So the
+//! server has a [CachedStore] of its own in front of the store, shared by all requests. A block
+//! never changes, so a cached block is always right. When the cache is full, the oldest block
+//! goes first.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use banyan::store::{BlockWriter, ReadOnlyStore};
+use banyan_utils::tags::Sha256Digest;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// What the server counts, see the module docs
+pub struct Metrics {
+    registry: Registry,
+    pub blocks_fetched: IntCounter,
+    pub bytes_fetched: IntCounter,
+    pub cache_hits: IntCounter,
+    pub query_seconds: HistogramVec,
+    pub appended_events: IntCounter,
+    pub rejected: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str| -> anyhow::Result<IntCounter> {
+            let counter = IntCounter::new(name, help)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let blocks_fetched = counter(
+            "banyan_server_blocks_fetched_total",
+            "Blocks got from the store",
+        )?;
+        let bytes_fetched = counter(
+            "banyan_server_block_bytes_fetched_total",
+            "Bytes of the blocks got from the store",
+        )?;
+        let cache_hits = counter(
+            "banyan_server_block_cache_hits_total",
+            "Gets answered by the block cache",
+        )?;
+        let appended_events = counter(
+            "banyan_server_appended_events_total",
+            "Events appended to streams",
+        )?;
+        let query_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "banyan_server_query_seconds",
+                "Time to answer a request, or to push new events to a tail",
+            )
+            .buckets(prometheus::exponential_buckets(0.0001, 2.0, 18)?),
+            &["route"],
+        )?;
+        registry.register(Box::new(query_seconds.clone()))?;
+        let rejected = IntCounterVec::new(
+            Opts::new(
+                "banyan_server_rejected_total",
+                "Requests over a limit, by the limit",
+            ),
+            &["limit"],
+        )?;
+        registry.register(Box::new(rejected.clone()))?;
+        banyan::register(&registry)?;
+        Ok(Self {
+            registry,
+            blocks_fetched,
+            bytes_fetched,
+            cache_hits,
+            query_seconds,
+            appended_events,
+            rejected,
+        })
+    }
+
+    /// All metrics in the text format of prometheus
+    pub fn text(&self) -> anyhow::Result<Vec<u8>> {
+        let mut text = Vec::new();
+        prometheus::Encoder::encode(&TextEncoder::new(), &self.registry.gather(), &mut text)?;
+        Ok(text)
+    }
+}
+
+/// The blocks of a [CachedStore], with the order they came in
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: HashMap<Sha256Digest, Box<[u8]>>,
+    order: VecDeque<Sha256Digest>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl BlockCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    fn insert(&mut self, link: Sha256Digest, data: &[u8]) {
+        if data.len() > self.max_bytes || self.blocks.contains_key(&link) {
+            return;
+        }
+        while self.bytes + data.len() > self.max_bytes {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(block) = self.blocks.remove(&oldest) {
+                self.bytes -= block.len();
+            }
+        }
+        self.bytes += data.len();
+        self.blocks.insert(link, data.into());
+        self.order.push_back(link);
+    }
+}
+
+/// A store wrapper that answers gets from a [BlockCache] when it can, and counts the hits and the
+/// blocks it had to get from the inner store
+#[derive(Clone)]
+pub struct CachedStore<S> {
+    inner: S,
+    cache: Arc<Mutex<BlockCache>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> CachedStore<S> {
+    pub fn new(inner: S, cache: Arc<Mutex<BlockCache>>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            cache,
+            metrics,
+        }
+    }
+}
+
+impl<S: ReadOnlyStore<Sha256Digest>> ReadOnlyStore<Sha256Digest> for CachedStore<S> {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        if let Some(data) = self.cache.lock().unwrap().blocks.get(link) {
+            self.metrics.cache_hits.inc();
+            return Ok(data.clone());
+        }
+        let data = self.inner.get(link)?;
+        self.metrics.blocks_fetched.inc();
+        self.metrics.bytes_fetched.inc_by(data.len() as u64);
+        self.cache.lock().unwrap().insert(*link, &data);
+        Ok(data)
+    }
+}
+
+impl<S: BlockWriter<Sha256Digest>> BlockWriter<Sha256Digest> for CachedStore<S> {
+    /// Put the block, and keep it, since a tail is about to read the blocks of a new root
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+        let link = self.inner.put(data.clone())?;
+        self.cache.lock().unwrap().insert(link, &data);
+        Ok(link)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_block_goes_first() {
+        let mut cache = BlockCache::new(10);
+        let blocks = (0u8..4)
+            .map(|i| (Sha256Digest::digest(&[i]), vec![i; 4]))
+            .collect::<Vec<_>>();
+        for (link, data) in &blocks {
+            cache.insert(*link, data);
+        }
+        assert_eq!(cache.bytes, 8);
+        assert!(!cache.blocks.contains_key(&blocks[1].0));
+        assert!(cache.blocks.contains_key(&blocks[3].0));
+        // too large to be cached at all
+        cache.insert(Sha256Digest::digest(b"large"), &[0; 11]);
+        assert_eq!(cache.blocks.len(), 2);
+    }
+}
@@ -0,0 +1,674 @@
+//! Ingesting MQTT messages into the tag-indexed tree of actyx
+//!
+//! There is no MQTT client in the dependencies, so this speaks MQTT 3.1.1 on a tcp connection,
+//! just enough to subscribe with QoS 1 and ack what it got. No tls, no QoS 2, no reconnects.
+//!
+//! Every message becomes an event of the actyx [TT], with each segment of its topic as a tag, so
+//! `factory/line1/temp` has the tags `factory`, `line1` and `temp`. The lamport timestamp is the
+//! offset in the tree. MQTT 3.1.1 does not tell when the broker got a message, so the time is
+//! when it arrives here. The value is the topic and the payload, since the tags alone do not say
+//! in which order the segments were.
+//!
+//! The session is not clean, so the broker keeps messages that were not acked while the
+//! subscriber is away. A message is acked only after it is in a [Wal] with its offset and time, so
+//! nothing is lost, but a crash in between stores the messages of a batch twice. Every few
+//! batches, the store is synced, the snapshot is swapped in the manifest, and the log is emptied.
+//! On startup, the messages in the log that are not in the snapshot are appended again, with the
+//! keys they had. Retained messages are acked and skipped, since the broker sends them again on
+//! every subscribe.
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    index::{BranchIndex, CompactSeq, LeafIndex},
+    query::Query,
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::{
+    tag_index::{Tag, TagSet},
+    tags::{Key, Sha256Digest, TT},
+};
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+use crate::{
+    fs_store::DurableStore,
+    roots::{ManifestFile, RootStore},
+    snapshots,
+    wal::{self, Wal},
+};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+/// A message as it is stored in the tree
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Box<[u8]>,
+}
+
+/// The messages of a batch in the log, with the offset of the first one and the times they came
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+struct Logged {
+    offset: u64,
+    times: Vec<u64>,
+    messages: Vec<Message>,
+}
+
+/// The events of the batches in the log that are not in a tree of `count` events yet
+fn replay(entries: Vec<Vec<u8>>, count: u64) -> anyhow::Result<Vec<(Key, Message)>> {
+    let mut batches = Vec::new();
+    for entry in entries {
+        let logged = DagCborCodec.decode::<Logged>(&entry)?;
+        let events = (logged.offset..)
+            .zip(logged.times)
+            .zip(logged.messages)
+            .map(|((lamport, time), message)| {
+                let key = Key::single(lamport, time, topic_tags(&message.topic));
+                (key, message)
+            });
+        batches.push((logged.offset, events.collect()));
+    }
+    wal::replay(batches, count)
+}
+
+/// A message from the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    /// only for QoS 1, which needs an ack
+    pub packet_id: Option<u16>,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Append a string with its length
+fn put_string(out: &mut Vec<u8>, text: &[u8]) {
+    out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    out.extend_from_slice(text);
+}
+
+/// A string with its length at the start of data, and the rest
+fn take_string(data: &[u8]) -> anyhow::Result<(&[u8], &[u8])> {
+    anyhow::ensure!(data.len() >= 2, "truncated mqtt string");
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    anyhow::ensure!(data.len() >= 2 + len, "truncated mqtt string");
+    Ok(data[2..].split_at(len))
+}
+
+/// A packet with its fixed header
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        out.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// The rest of a packet after its first byte
+fn read_body(reader: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut len = 0usize;
+    for i in 0..4 {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body)?;
+            return Ok(body);
+        }
+    }
+    anyhow::bail!("invalid mqtt packet length")
+}
+
+fn read_packet(reader: &mut impl Read) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut kind = [0u8];
+    reader.read_exact(&mut kind)?;
+    Ok((kind[0], read_body(reader)?))
+}
+
+fn publish(flags: u8, body: &[u8]) -> anyhow::Result<Publish> {
+    let qos = (flags >> 1) & 3;
+    anyhow::ensure!(qos < 2, "QoS {} is not supported", qos);
+    let (topic, rest) = take_string(body)?;
+    let (packet_id, payload) = match qos {
+        0 => (None, rest),
+        _ => {
+            anyhow::ensure!(rest.len() >= 2, "truncated mqtt publish");
+            (Some(u16::from_be_bytes([rest[0], rest[1]])), &rest[2..])
+        }
+    };
+    Ok(Publish {
+        topic: String::from_utf8(topic.to_vec())?,
+        packet_id,
+        retain: flags & 1 != 0,
+        payload: payload.to_vec(),
+    })
+}
+
+/// A connection to an MQTT broker
+#[derive(Debug)]
+pub struct Client {
+    stream: TcpStream,
+    keep_alive: Duration,
+    last_sent: Instant,
+    next_id: u16,
+    /// messages that came while waiting for something else
+    pending: VecDeque<Publish>,
+}
+
+impl Client {
+    /// Connect to a broker at `host:port`, with a user name and password if it needs them
+    pub fn connect(
+        address: &str,
+        client_id: &str,
+        credentials: Option<(&str, &str)>,
+        keep_alive: Duration,
+    ) -> anyhow::Result<Self> {
+        let address = address.strip_prefix("mqtt://").unwrap_or(address);
+        let stream = TcpStream::connect(address)
+            .map_err(|cause| anyhow::anyhow!("can not connect to mqtt {}: {}", address, cause))?;
+        let mut client = Self {
+            stream,
+            keep_alive,
+            last_sent: Instant::now(),
+            next_id: 1,
+            pending: VecDeque::new(),
+        };
+        let mut body = Vec::new();
+        put_string(&mut body, b"MQTT");
+        // protocol level 4 is 3.1.1, and the session is not clean
+        let mut flags = 0u8;
+        if credentials.is_some() {
+            flags |= 0x80 | 0x40;
+        }
+        body.extend_from_slice(&[4, flags]);
+        body.extend_from_slice(&(keep_alive.as_secs() as u16).to_be_bytes());
+        put_string(&mut body, client_id.as_bytes());
+        if let Some((user, password)) = credentials {
+            put_string(&mut body, user.as_bytes());
+            put_string(&mut body, password.as_bytes());
+        }
+        client.send(&packet(CONNECT, &body))?;
+        let (kind, body) = read_packet(&mut client.stream)?;
+        anyhow::ensure!(
+            kind == CONNACK && body.len() == 2,
+            "not an mqtt broker at {}",
+            address
+        );
+        let reason = match body[1] {
+            0 => return Ok(client),
+            1 => "unacceptable protocol version",
+            2 => "client id rejected",
+            3 => "server unavailable",
+            4 => "bad user name or password",
+            5 => "not authorized",
+            _ => "unknown reason",
+        };
+        anyhow::bail!("mqtt connect failed: {}", reason)
+    }
+
+    /// The broker and credentials from `BANYAN_MQTT_URL`, `BANYAN_MQTT_USER` and
+    /// `BANYAN_MQTT_PASSWORD`, by default a broker on this machine
+    pub fn from_env(client_id: &str) -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let address = var("BANYAN_MQTT_URL").unwrap_or_else(|| "127.0.0.1:1883".to_string());
+        let user = var("BANYAN_MQTT_USER");
+        let password = var("BANYAN_MQTT_PASSWORD").unwrap_or_default();
+        let credentials = user.as_deref().map(|user| (user, password.as_str()));
+        Self::connect(&address, client_id, credentials, Duration::from_secs(30))
+    }
+
+    fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(data)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// The next packet, or none after the timeout
+    fn next_packet(&mut self, timeout: Duration) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+        if timeout.is_zero() {
+            return Ok(None);
+        }
+        // only wait for the first byte, a packet that started is read to the end
+        self.stream.set_read_timeout(Some(timeout))?;
+        let mut kind = [0u8];
+        let read = self.stream.read_exact(&mut kind);
+        self.stream.set_read_timeout(None)?;
+        match read {
+            Ok(()) => Ok(Some((kind[0], read_body(&mut self.stream)?))),
+            Err(cause)
+                if matches!(
+                    cause.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(cause) => Err(cause.into()),
+        }
+    }
+
+    /// Subscribe to topic filters with QoS 1, and wait until the broker confirms
+    pub fn subscribe(&mut self, filters: &[String]) -> anyhow::Result<()> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let mut body = id.to_be_bytes().to_vec();
+        for filter in filters {
+            put_string(&mut body, filter.as_bytes());
+            body.push(1);
+        }
+        self.send(&packet(SUBSCRIBE, &body))?;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.next_packet(left)? {
+                Some((SUBACK, body)) if body.get(..2) == Some(&id.to_be_bytes()[..]) => {
+                    for (filter, code) in filters.iter().zip(&body[2..]) {
+                        anyhow::ensure!(*code != 0x80, "the broker refused {}", filter);
+                    }
+                    return Ok(());
+                }
+                Some((kind, body)) if kind & 0xf0 == PUBLISH => {
+                    self.pending.push_back(publish(kind & 0x0f, &body)?)
+                }
+                Some(_) => {}
+                None => anyhow::bail!("no answer to subscribe"),
+            }
+        }
+    }
+
+    /// The next message, pinging the broker while waiting, or none after the timeout
+    pub fn next_publish(&mut self, timeout: Duration) -> anyhow::Result<Option<Publish>> {
+        if let Some(publish) = self.pending.pop_front() {
+            return Ok(Some(publish));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.keep_alive.is_zero() && self.last_sent.elapsed() > self.keep_alive / 2 {
+                self.send(&packet(PINGREQ, &[]))?;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            // wake up in time for the next ping
+            let left = match self.keep_alive.is_zero() {
+                true => left,
+                false => left.min(self.keep_alive / 2),
+            };
+            match self.next_packet(left)? {
+                Some((kind, body)) if kind & 0xf0 == PUBLISH => {
+                    return Ok(Some(publish(kind & 0x0f, &body)?))
+                }
+                Some((PINGRESP, _)) => {}
+                Some((kind, _)) => anyhow::bail!("unexpected mqtt packet {:#x}", kind),
+                None if Instant::now() >= deadline => return Ok(None),
+                None => {}
+            }
+        }
+    }
+
+    /// Tell the broker that a QoS 1 message does not have to be sent again
+    pub fn ack(&mut self, publish: &Publish) -> anyhow::Result<()> {
+        match publish.packet_id {
+            Some(id) => self.send(&packet(PUBACK, &id.to_be_bytes())),
+            None => Ok(()),
+        }
+    }
+
+    pub fn disconnect(mut self) -> anyhow::Result<()> {
+        self.send(&packet(DISCONNECT, &[]))
+    }
+}
+
+/// The tags of a topic, one per segment
+pub fn topic_tags(topic: &str) -> TagSet {
+    topic
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(Tag::from)
+        .collect()
+}
+
+/// Whether a topic matches a filter with `+` and `#` wildcards
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // wildcards at the start do not match system topics like `$SYS/...`
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic = topic.split('/');
+    for segment in filter.split('/') {
+        match (segment, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (segment, Some(other)) if segment == other => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+/// The tags of a key or summary. Its fields are private, but it serializes with them
+fn key_tags(key: &Key) -> BTreeSet<String> {
+    let value = serde_json::to_value(key).unwrap_or_default();
+    value["tags"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|tag| tag.as_str().map(String::from))
+        .collect()
+}
+
+/// Events with all the plain segments of a topic filter as tags. The index does not know where
+/// a segment was in the topic, so this is a superset of the events that match, see [select]
+#[derive(Debug, Clone)]
+pub struct TopicQuery(BTreeSet<String>);
+
+impl TopicQuery {
+    pub fn new(filter: &str) -> Self {
+        Self(
+            filter
+                .split('/')
+                .filter(|segment| !matches!(*segment, "" | "+" | "#"))
+                .map(String::from)
+                .collect(),
+        )
+    }
+
+    fn admits(&self, key: Option<Key>) -> bool {
+        key.is_some_and(|key| key_tags(&key).is_superset(&self.0))
+    }
+}
+
+impl Query<TT> for TopicQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<TT>, res: &mut [bool]) {
+        for (i, res) in res.iter_mut().enumerate() {
+            *res = *res && self.admits(index.keys.get(i));
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<TT>, res: &mut [bool]) {
+        for (i, res) in res.iter_mut().enumerate() {
+            *res = *res && self.admits(index.summaries.get(i));
+        }
+    }
+}
+
+/// The offsets and messages of all events on topics that match a filter
+pub fn select<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<TT, R>,
+    tree: &Tree<TT, Message>,
+    filter: &str,
+) -> anyhow::Result<Vec<(u64, Message)>> {
+    let mut res = Vec::new();
+    for item in forest.iter_filtered(tree, TopicQuery::new(filter)) {
+        let (offset, _, message) = item?;
+        if matches(filter, &message.topic) {
+            res.push((offset, message));
+        }
+    }
+    Ok(res)
+}
+
+/// Wait up to `wait` for up to `batch` messages, log them and append them. Returns all messages,
+/// to ack since they are in the log
+pub fn append<R, W>(
+    client: &mut Client,
+    txn: &mut Transaction<TT, R, W>,
+    builder: &mut StreamBuilder<TT, Message>,
+    wal: &mut Wal,
+    batch: usize,
+    wait: Duration,
+) -> anyhow::Result<Vec<Publish>>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest>,
+{
+    let deadline = Instant::now() + wait;
+    let mut received = Vec::new();
+    let mut logged = Logged {
+        offset: builder.snapshot().count(),
+        times: Vec::new(),
+        messages: Vec::new(),
+    };
+    while received.len() < batch {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let Some(publish) = client.next_publish(left)? else {
+            break;
+        };
+        if !publish.retain {
+            logged.times.push(snapshots::now());
+            logged.messages.push(Message {
+                topic: publish.topic.clone(),
+                payload: publish.payload.clone().into(),
+            });
+        }
+        received.push(publish);
+    }
+    if !logged.messages.is_empty() {
+        let entry = DagCborCodec.encode(&logged)?;
+        wal.append(&entry)?;
+        // with the same keys as after a replay
+        txn.extend(builder, replay(vec![entry], logged.offset)?)?;
+    }
+    Ok(received)
+}
+
+/// Append messages from the broker to a tree in a store, with the root in a manifest under the
+/// client id, until `count` messages are received. A snapshot is persisted every
+/// `snapshot_every` batches, when nothing came, and at the end
+#[allow(clippy::too_many_arguments)]
+pub fn run<S: ReadOnlyStore<Sha256Digest> + DurableStore<Sha256Digest>>(
+    mut client: Client,
+    client_id: &str,
+    filters: &[String],
+    store: S,
+    manifest: &Path,
+    wal: &Path,
+    config: &Config,
+    secrets: &Secrets,
+    batch: usize,
+    snapshot_every: usize,
+    count: Option<u64>,
+) -> anyhow::Result<()> {
+    let manifest = ManifestFile::new(manifest);
+    let forest = Forest::<TT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut root = manifest.root(client_id)?;
+    let mut builder = match root {
+        Some(root) => txn.load_stream_builder(secrets.clone(), config.clone(), root)?,
+        None => StreamBuilder::new(config.clone(), secrets.clone()),
+    };
+    let (mut wal, entries) = Wal::open(wal)?;
+    let replayed = replay(entries, builder.snapshot().count())?;
+    let replayed_count = replayed.len();
+    // the first batch or a pause persists what came from the log
+    let mut batches = usize::from(replayed_count > 0);
+    if replayed_count > 0 {
+        txn.extend(&mut builder, replayed)?;
+    }
+    client.subscribe(filters)?;
+    eprintln!(
+        "{} subscribed to {} with {} events, {} of them from the log",
+        client_id,
+        filters.join(" "),
+        builder.snapshot().count(),
+        replayed_count
+    );
+    // persist the tree, and empty the log
+    let mut snapshot = |builder: &StreamBuilder<TT, Message>, wal: &mut Wal| {
+        let tree = builder.snapshot();
+        if let Some(link) = tree.link().filter(|link| Some(*link) != root) {
+            store.sync()?;
+            manifest.compare_and_swap(client_id, root, link)?;
+            root = Some(link);
+            println!("{}\t{}", tree.count(), link);
+        }
+        wal.checkpoint()
+    };
+    let mut received = 0u64;
+    while count.is_none_or(|count| received < count) {
+        let publishes = append(
+            &mut client,
+            &mut txn,
+            &mut builder,
+            &mut wal,
+            batch,
+            Duration::from_secs(1),
+        )?;
+        for publish in &publishes {
+            client.ack(publish)?;
+        }
+        received += publishes.len() as u64;
+        batches += usize::from(!publishes.is_empty());
+        if batches >= snapshot_every || (batches > 0 && publishes.is_empty()) {
+            snapshot(&builder, &mut wal)?;
+            batches = 0;
+        }
+    }
+    snapshot(&builder, &mut wal)?;
+    client.disconnect()
+}
+
+/// A broker for one subscriber, that sends the messages and returns the packet ids it got acks for
+fn fake_broker(
+    listener: TcpListener,
+    messages: Vec<(String, Vec<u8>, bool)>,
+) -> anyhow::Result<Vec<u16>> {
+    let (mut stream, _) = listener.accept()?;
+    let (kind, _) = read_packet(&mut stream)?;
+    anyhow::ensure!(kind == CONNECT, "expected connect");
+    stream.write_all(&packet(CONNACK, &[0, 0]))?;
+    let (kind, body) = read_packet(&mut stream)?;
+    anyhow::ensure!(kind == SUBSCRIBE, "expected subscribe");
+    stream.write_all(&packet(SUBACK, &[body[0], body[1], 1]))?;
+    for (id, (topic, payload, retain)) in messages.iter().enumerate() {
+        let mut body = Vec::new();
+        put_string(&mut body, topic.as_bytes());
+        body.extend_from_slice(&(id as u16 + 1).to_be_bytes());
+        body.extend_from_slice(payload);
+        stream.write_all(&packet(PUBLISH | 0x02 | *retain as u8, &body))?;
+    }
+    let mut acked = Vec::new();
+    loop {
+        match read_packet(&mut stream)? {
+            (PUBACK, body) => acked.push(u16::from_be_bytes([body[0], body[1]])),
+            (PINGREQ, _) => stream.write_all(&packet(PINGRESP, &[]))?,
+            (DISCONNECT, _) => return Ok(acked),
+            (kind, _) => anyhow::bail!("unexpected packet {:#x}", kind),
+        }
+    }
+}
+
+/// Ingest sensor readings from a local fake broker, and query them by topic
+pub fn mqtt_example(config: &Config) -> anyhow::Result<()> {
+    let n = 2000usize;
+    println!(
+        "Example: ingesting {} mqtt messages, and selecting by topic",
+        n
+    );
+    let lines = ["line1", "line2", "line3", "line4"];
+    let sensors = ["temp", "humidity", "pressure"];
+    let mut messages = (0..n)
+        .map(|i| {
+            let topic = format!("factory/{}/{}", lines[i / 500], sensors[i % sensors.len()]);
+            (topic, i.to_string().into_bytes(), false)
+        })
+        .collect::<Vec<_>>();
+    // the broker sends the retained state of a topic first
+    messages.insert(0, ("factory/line1/temp".into(), b"-1".to_vec(), true));
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?.to_string();
+    let broker = thread::spawn({
+        let messages = messages.clone();
+        move || fake_broker(listener, messages)
+    });
+
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let forest = Forest::<TT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<TT, Message>::new(config.clone(), Secrets::default());
+    let wal_path = std::env::temp_dir().join(format!("banyan-mqtt-{}.wal", std::process::id()));
+    let (mut wal, _) = Wal::open(&wal_path)?;
+    let mut client = Client::connect(&address, "example", None, Duration::from_secs(30))?;
+    client.subscribe(&["factory/#".to_string()])?;
+    let mut received = 0;
+    while received < messages.len() {
+        let batch = append(
+            &mut client,
+            &mut txn,
+            &mut builder,
+            &mut wal,
+            500,
+            Duration::from_secs(5),
+        )?;
+        anyhow::ensure!(!batch.is_empty(), "the broker stopped sending");
+        for publish in &batch {
+            client.ack(publish)?;
+        }
+        received += batch.len();
+    }
+    client.disconnect()?;
+    let acked = broker.join().expect("broker panicked")?;
+    anyhow::ensure!(
+        acked.len() == messages.len(),
+        "{} of {} acked",
+        acked.len(),
+        messages.len()
+    );
+
+    let tree = builder.snapshot();
+    anyhow::ensure!(tree.count() == n as u64, "retained message was stored");
+    // without a snapshot, everything acked comes back from the log
+    let (_, entries) = Wal::open(&wal_path)?;
+    let replayed = replay(entries, 0)?;
+    let stored = txn.iter_from(&tree).collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        replayed
+            .into_iter()
+            .eq(stored.into_iter().map(|(_, key, message)| (key, message))),
+        "the log does not have what was acked"
+    );
+    std::fs::remove_file(&wal_path)?;
+    println!("filter\tmatching\tcandidates");
+    for filter in [
+        "factory/#",
+        "factory/+/temp",
+        "factory/line2/#",
+        "factory/line2",
+    ] {
+        let found = select(&txn, &tree, filter)?;
+        let expected = messages
+            .iter()
+            .filter(|(topic, _, retain)| !retain && matches(filter, topic))
+            .map(|(_, payload, _)| payload.as_slice());
+        anyhow::ensure!(
+            found.iter().map(|(_, m)| &*m.payload).eq(expected),
+            "wrong messages for {}",
+            filter
+        );
+        let candidates = txn.iter_filtered(&tree, TopicQuery::new(filter)).count();
+        println!("{}\t{}\t{}", filter, found.len(), candidates);
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,434 @@
+//! A minimal NATS client, for pulling from a JetStream consumer
+//!
+//! There is no NATS client in the dependencies, and the core protocol is a few text commands on
+//! a tcp connection, so this speaks it directly. It only knows what the [bridge](crate::bridge)
+//! needs: connect with an optional token, subscribe to an inbox, publish, and read messages,
+//! with and without headers. No tls, no reconnects, no clustering.
+//!
+//! A pull consumer is asked for the next messages with a request to
+//! `$JS.API.CONSUMER.MSG.NEXT.<stream>.<consumer>`. The messages come to the inbox of the
+//! request, each with a reply subject to ack it on, and the request ends with a status message
+//! if there are fewer than asked for. The reply subject also has the sequence of the message in
+//! the stream and the time it was stored.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::bridge::{Record, Source};
+
+/// The largest payload a nats server allows, and the limit for a server that does not say
+const MAX_PAYLOAD: usize = 64 << 20;
+
+/// A message from the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub subject: String,
+    pub reply: Option<String>,
+    /// the status line of the headers if it has a code, like `NATS/1.0 404 No Messages`
+    pub status: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+/// A connection to a NATS server
+#[derive(Debug)]
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_sid: u64,
+    /// the start of a line whose read timed out, for the next read to continue
+    partial: Vec<u8>,
+    /// the `max_payload` of the server, which no message it sends is longer than
+    max_payload: usize,
+}
+
+impl Client {
+    /// Connect to a server at `host:port`, with a token if it needs one
+    pub fn connect(address: &str, token: Option<&str>) -> anyhow::Result<Self> {
+        let address = address.strip_prefix("nats://").unwrap_or(address);
+        let writer = TcpStream::connect(address)
+            .map_err(|cause| anyhow::anyhow!("can not connect to nats {}: {}", address, cause))?;
+        let mut client = Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            next_sid: 1,
+            partial: Vec::new(),
+            max_payload: MAX_PAYLOAD,
+        };
+        let info = client.line()?;
+        let Some(info) = info.strip_prefix("INFO ") else {
+            anyhow::bail!("not a nats server: {}", info);
+        };
+        let info: Value = serde_json::from_str(info)?;
+        if let Some(max) = info["max_payload"].as_u64() {
+            client.max_payload = (max as usize).min(MAX_PAYLOAD);
+        }
+        let mut connect = json!({
+            "verbose": false,
+            "pedantic": false,
+            "headers": true,
+            "no_responders": true,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+        });
+        if let Some(token) = token {
+            connect["auth_token"] = token.into();
+        }
+        client.send(format!("CONNECT {}\r\nPING\r\n", connect).as_bytes())?;
+        // the server answers a bad connect with an -ERR before the PONG
+        loop {
+            match client.line()?.as_str() {
+                "PONG" => return Ok(client),
+                "+OK" => {}
+                line => anyhow::bail!("nats connect failed: {}", line),
+            }
+        }
+    }
+
+    /// The address and token from `BANYAN_NATS_URL` and `BANYAN_NATS_TOKEN`, by default a
+    /// server on this machine
+    pub fn from_env() -> anyhow::Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let address = var("BANYAN_NATS_URL").unwrap_or_else(|| "127.0.0.1:4222".to_string());
+        Self::connect(&address, var("BANYAN_NATS_TOKEN").as_deref())
+    }
+
+    fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    /// The next line. Unlike `read_line`, a read that times out keeps the bytes it got, so the
+    /// next call returns the whole line instead of its rest
+    fn line(&mut self) -> anyhow::Result<String> {
+        self.reader.read_until(b'\n', &mut self.partial)?;
+        anyhow::ensure!(self.partial.ends_with(b"\n"), "nats closed the connection");
+        let line = String::from_utf8(std::mem::take(&mut self.partial))?;
+        Ok(line.trim_end().to_string())
+    }
+
+    /// Subscribe to a subject and return the subscription id
+    pub fn subscribe(&mut self, subject: &str) -> anyhow::Result<u64> {
+        let sid = self.next_sid;
+        self.next_sid += 1;
+        self.send(format!("SUB {} {}\r\n", subject, sid).as_bytes())?;
+        Ok(sid)
+    }
+
+    pub fn unsubscribe(&mut self, sid: u64) -> anyhow::Result<()> {
+        self.send(format!("UNSUB {}\r\n", sid).as_bytes())
+    }
+
+    /// Publish a message, optionally with a subject for the answer
+    pub fn publish(
+        &mut self,
+        subject: &str,
+        reply: Option<&str>,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let reply = reply.map(|reply| format!(" {}", reply)).unwrap_or_default();
+        let mut data = format!("PUB {}{} {}\r\n", subject, reply, payload.len()).into_bytes();
+        data.extend_from_slice(payload);
+        data.extend_from_slice(b"\r\n");
+        self.send(&data)
+    }
+
+    /// The next message, answering pings while waiting, or none after the timeout
+    pub fn next_message(&mut self, timeout: Duration) -> anyhow::Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            self.writer.set_read_timeout(Some(left))?;
+            let line = match self.line() {
+                Ok(line) => line,
+                Err(cause) => match cause.downcast_ref::<std::io::Error>() {
+                    Some(io)
+                        if matches!(
+                            io.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        return Ok(None)
+                    }
+                    _ => return Err(cause),
+                },
+            };
+            self.writer.set_read_timeout(None)?;
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("PING") => self.send(b"PONG\r\n")?,
+                Some("PONG") | Some("+OK") => {}
+                Some("-ERR") => anyhow::bail!("nats error: {}", line),
+                Some(op @ ("MSG" | "HMSG")) => {
+                    let args = parts.collect::<Vec<_>>();
+                    return Ok(Some(self.message(op == "HMSG", &args)?));
+                }
+                _ => anyhow::bail!("unexpected nats line: {}", line),
+            }
+        }
+    }
+
+    /// Read the body of a `MSG subject sid [reply] len` or
+    /// `HMSG subject sid [reply] header_len len`
+    fn message(&mut self, headers: bool, args: &[&str]) -> anyhow::Result<Message> {
+        let lengths = if headers { 2 } else { 1 };
+        anyhow::ensure!(
+            args.len() == 2 + lengths || args.len() == 3 + lengths,
+            "invalid nats message {:?}",
+            args
+        );
+        let (fixed, lengths) = args.split_at(args.len() - lengths);
+        let total: usize = lengths.last().expect("a length").parse()?;
+        let header_len: usize = if headers { lengths[0].parse()? } else { 0 };
+        // the lengths come from the server, so they are checked before anything is allocated
+        anyhow::ensure!(
+            total <= self.max_payload,
+            "a nats message of {} bytes is longer than {}",
+            total,
+            self.max_payload
+        );
+        anyhow::ensure!(
+            header_len <= total,
+            "nats message headers of {} bytes in a message of {}",
+            header_len,
+            total
+        );
+        let len = total
+            .checked_add(2)
+            .ok_or_else(|| anyhow::anyhow!("invalid nats message length {}", total))?;
+        let mut data = vec![0u8; len];
+        self.reader.read_exact(&mut data)?;
+        anyhow::ensure!(data.ends_with(b"\r\n"), "invalid nats message");
+        data.truncate(total);
+        let payload = data.split_off(header_len);
+        // a message with headers starts with a bare `NATS/1.0`, a status has a code after it
+        let status = std::str::from_utf8(&data)?
+            .lines()
+            .next()
+            .map(str::trim)
+            .filter(|line| line.split_whitespace().nth(1).is_some())
+            .map(str::to_string);
+        Ok(Message {
+            subject: fixed[0].to_string(),
+            reply: fixed.get(2).map(|reply| reply.to_string()),
+            status,
+            payload,
+        })
+    }
+}
+
+/// The stream sequence and the time in unix milliseconds from the reply subject of a JetStream
+/// message, `$JS.ACK.<stream>.<consumer>.<delivered>.<stream seq>.<consumer seq>.<time>.
+/// <pending>`, with a domain and an account hash after `ACK` on newer servers
+pub fn ack_info(reply: &str) -> anyhow::Result<(u64, u64)> {
+    let tokens = reply.split('.').collect::<Vec<_>>();
+    let fields = match tokens.as_slice() {
+        ["$JS", "ACK", rest @ ..] if rest.len() == 7 => rest,
+        ["$JS", "ACK", _domain, _account, rest @ ..] if rest.len() >= 7 => &rest[..7],
+        _ => anyhow::bail!("{} is not a JetStream ack subject", reply),
+    };
+    let sequence = fields[3].parse()?;
+    let nanos: u64 = fields[5].parse()?;
+    Ok((sequence, nanos / 1_000_000))
+}
+
+/// A pull consumer of a JetStream stream
+#[derive(Debug)]
+pub struct JetStream {
+    client: Client,
+    stream: String,
+    consumer: String,
+    inbox: String,
+    requests: u64,
+}
+
+impl JetStream {
+    pub fn new(client: Client, stream: &str, consumer: &str) -> Self {
+        let inbox = format!(
+            "_INBOX.banyan.{}.{}",
+            std::process::id(),
+            crate::snapshots::now()
+        );
+        Self {
+            client,
+            stream: stream.to_string(),
+            consumer: consumer.to_string(),
+            inbox,
+            requests: 0,
+        }
+    }
+}
+
+impl Source for JetStream {
+    /// Ask for up to `n` messages, and wait up to `wait` for them
+    fn fetch(&mut self, n: usize, wait: Duration) -> anyhow::Result<Vec<Record>> {
+        self.requests += 1;
+        let inbox = format!("{}.{}", self.inbox, self.requests);
+        let sid = self.client.subscribe(&inbox)?;
+        let subject = format!(
+            "$JS.API.CONSUMER.MSG.NEXT.{}.{}",
+            self.stream, self.consumer
+        );
+        let request = json!({ "batch": n, "expires": wait.as_nanos() as u64 });
+        self.client
+            .publish(&subject, Some(&inbox), request.to_string().as_bytes())?;
+        let mut records = Vec::new();
+        // the server ends the request itself after `wait`, this is in case it does not
+        let deadline = Instant::now() + wait + Duration::from_secs(5);
+        while records.len() < n {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Some(message) = self.client.next_message(left)? else {
+                break;
+            };
+            match (&message.status, &message.reply) {
+                (None, Some(reply)) => {
+                    let (sequence, time) = ack_info(reply)?;
+                    records.push(Record {
+                        sequence,
+                        time,
+                        payload: message.payload.clone(),
+                        ack: reply.clone(),
+                    });
+                }
+                // 404 no messages, 408 request timeout, 409 for a consumer that is gone
+                (Some(status), _) if status.contains(" 404") || status.contains(" 408") => break,
+                (Some(status), _) => anyhow::bail!("jetstream fetch failed: {}", status),
+                (None, None) => {
+                    let error: Value = serde_json::from_slice(&message.payload).unwrap_or_default();
+                    anyhow::bail!("jetstream fetch failed: {}", error["error"]);
+                }
+            }
+        }
+        self.client.unsubscribe(sid)?;
+        Ok(records)
+    }
+
+    fn ack(&mut self, record: &Record) -> anyhow::Result<()> {
+        self.client.publish(&record.ack, None, b"+ACK")
+    }
+
+    fn reject(&mut self, record: &Record) -> anyhow::Result<()> {
+        // never deliver it again
+        self.client.publish(&record.ack, None, b"+TERM")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// A server that accepts a client, answers its connect, and then sends the chunks with a
+    /// pause between them
+    fn server(chunks: &'static [&'static [u8]]) -> (Client, thread::JoinHandle<()>) {
+        server_with_info(b"INFO {}\r\n", chunks)
+    }
+
+    /// Like [server], with the INFO line of the server
+    fn server_with_info(
+        info: &'static [u8],
+        chunks: &'static [&'static [u8]],
+    ) -> (Client, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(info).unwrap();
+            let mut r = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while !line.starts_with("PING") {
+                line.clear();
+                r.read_line(&mut line).unwrap();
+            }
+            stream.write_all(b"PONG\r\n").unwrap();
+            for (i, chunk) in chunks.iter().enumerate() {
+                if i > 0 {
+                    thread::sleep(Duration::from_millis(300));
+                }
+                stream.write_all(chunk).unwrap();
+            }
+        });
+        (Client::connect(&addr.to_string(), None).unwrap(), server)
+    }
+
+    #[test]
+    fn line_split_by_a_timeout() {
+        let (mut client, server) = server(&[b"MSG events 1 ", b"5\r\nhello\r\n"]);
+        assert_eq!(
+            client.next_message(Duration::from_millis(50)).unwrap(),
+            None
+        );
+        assert_eq!(
+            client.next_message(Duration::from_millis(50)).unwrap(),
+            None
+        );
+        let message = client.next_message(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            message,
+            Some(Message {
+                subject: "events".to_string(),
+                reply: None,
+                status: None,
+                payload: b"hello".to_vec(),
+            })
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn headers_without_a_status() {
+        let (mut client, server) = server(&[
+            b"HMSG events 1 $JS.ACK.s.c.1.7.7.1700000000000000000.0 28 33\r\n",
+            b"NATS/1.0\r\nNats-Msg-Id: 7\r\n\r\nhello\r\n",
+            b"HMSG _INBOX.x 1 28 28\r\nNATS/1.0 404 No Messages\r\n\r\n\r\n",
+        ]);
+        let message = client.next_message(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            message,
+            Some(Message {
+                subject: "events".to_string(),
+                reply: Some("$JS.ACK.s.c.1.7.7.1700000000000000000.0".to_string()),
+                status: None,
+                payload: b"hello".to_vec(),
+            })
+        );
+        let message = client.next_message(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            message.unwrap().status.as_deref(),
+            Some("NATS/1.0 404 No Messages")
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn lengths_are_checked() {
+        let info = b"INFO {\"max_payload\":1024}\r\n";
+        let messages: [(&'static [&'static [u8]], &str); 3] = [
+            (&[b"MSG events 1 1025\r\n"], "longer than 1024"),
+            (
+                &[b"MSG events 1 18446744073709551615\r\n"],
+                "longer than 1024",
+            ),
+            (&[b"HMSG events 1 10 5\r\n"], "headers of 10 bytes"),
+        ];
+        for (chunks, expected) in messages {
+            let (mut client, server) = server_with_info(info, chunks);
+            let cause = client.next_message(Duration::from_secs(10)).unwrap_err();
+            assert!(cause.to_string().contains(expected), "{}", cause);
+            server.join().unwrap();
+        }
+        // a server without a max_payload has the largest one nats allows
+        let (mut client, server) = server(&[b"MSG events 1 67108865\r\n"]);
+        let cause = client.next_message(Duration::from_secs(10)).unwrap_err();
+        assert!(cause.to_string().contains("longer than"), "{}", cause);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,104 @@
+//! A writable store on top of a read-only one
+//!
+//! A published tree, e.g. in a CAR file, does not change. To append to it, [OverlayStore] reads
+//! from a writable store first and from the base second, and writes only to the writable store.
+//! So the new tree only costs the blocks that were added or changed, and everything older is
+//! still served from the base.
+use std::time::Instant;
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    car::{write_car, CarStore},
+    probe::ProbingStore,
+    progress::CountingStore,
+    snapshots::LogTT,
+};
+
+/// Reads from the top store and then from the base, writes to the top store
+#[derive(Debug, Clone)]
+pub struct OverlayStore<B, T> {
+    base: B,
+    top: T,
+}
+
+impl<B, T> OverlayStore<B, T> {
+    pub fn new(base: B, top: T) -> Self {
+        Self { base, top }
+    }
+}
+
+impl<L, B: ReadOnlyStore<L>, T: ReadOnlyStore<L>> ReadOnlyStore<L> for OverlayStore<B, T> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        // the top store has the newest blocks, which are the ones that are read the most
+        self.top.get(link).or_else(|_| self.base.get(link))
+    }
+}
+
+impl<L, B: ProbingStore<L>, T: ProbingStore<L>> ProbingStore<L> for OverlayStore<B, T> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        match self.top.size(link)? {
+            Some(size) => Ok(Some(size)),
+            None => self.base.size(link),
+        }
+    }
+}
+
+impl<L, B: Clone + Send + Sync + 'static, T: BlockWriter<L>> BlockWriter<L> for OverlayStore<B, T> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.top.put(data)
+    }
+}
+
+/// Append to a tree that was published as a CAR file, writing only the new blocks to memory
+pub fn overlay_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let m = 10000u64;
+    println!(
+        "Example: appending {} events to a tree of {} events in a CAR file",
+        m, n
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let root = builder.snapshot().link().expect("not empty");
+    let path = std::env::temp_dir().join(format!("banyan-overlay-{}.car", std::process::id()));
+    write_car(&store, &[root], &path)?;
+
+    let base = CarStore::open(&path)?;
+    anyhow::ensure!(base.roots() == [root], "roots changed");
+    let top = CountingStore::new(MemStore::new(1 << 30, Sha256Digest::digest));
+    let overlay = OverlayStore::new(base.clone(), top.clone());
+    let t0 = Instant::now();
+    let forest = Forest::<LogTT, _>::new(overlay.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, overlay);
+    let mut builder = txn.load_stream_builder(Secrets::default(), config.clone(), root)?;
+    txn.extend(&mut builder, (n..n + m).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+    let mut count = 0;
+    for item in txn.iter_filtered(&tree, OffsetRangeQuery::from(n - m..)) {
+        let (i, _, value) = item?;
+        anyhow::ensure!(i == value, "value {} changed", i);
+        count += 1;
+    }
+    anyhow::ensure!(count == 2 * m, "values are missing");
+    std::fs::remove_file(&path)?;
+    println!(
+        "{} blocks in the CAR file, {} new blocks with {} bytes in memory {}s",
+        base.blocks(),
+        top.counters().blocks(),
+        top.counters().bytes(),
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! Exporting only what a query needs
+//!
+//! Someone who only needs one day of events does not need the whole tree. A query traversal only
+//! loads the branches whose summaries can match and the leaves with matching keys, so
+//! [query_blocks] does the same walk and collects those blocks, and [write_partial_car] writes
+//! just them to a CAR file. The file still has the original root, and the same query on it gives
+//! the same events. Anything else, like iterating the whole tree, fails with the block that is
+//! not in the file.
+use std::{collections::BTreeSet, fs};
+
+use banyan::{
+    index::{CompactSeq, Index},
+    query::Query,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    car::{write_car, write_car_blocks, CarStore},
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    progress::{self, ProgressStore},
+    projection,
+};
+
+/// The blocks that a traversal with the query loads, in the order it loads them
+pub fn query_blocks<T, V, R, Q>(
+    store: &R,
+    tree: &Tree<T, V>,
+    query: &Q,
+) -> anyhow::Result<Vec<T::Link>>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    Q: Query<T>,
+{
+    let secrets = tree.secrets().cloned().unwrap_or_default();
+    let mut blocks = Vec::new();
+    // nodes still to visit with their offsets, the next one last
+    let mut stack = tree
+        .index()
+        .map(|index| (0, index.clone()))
+        .into_iter()
+        .collect::<Vec<_>>();
+    while let Some((offset, index)) = stack.pop() {
+        match index {
+            Index::Leaf(leaf) => {
+                let mut res = vec![true; leaf.keys.count() as usize];
+                query.containing(offset, &leaf, &mut res);
+                if let Some(link) = leaf.link.filter(|_| res.contains(&true)) {
+                    blocks.push(link);
+                }
+            }
+            Index::Branch(branch) => {
+                let mut res = vec![true; branch.summaries.count() as usize];
+                query.intersecting(offset, &branch, &mut res);
+                let link = match branch.link {
+                    Some(link) if res.contains(&true) => link,
+                    _ => continue,
+                };
+                blocks.push(link);
+                let mut offset = offset;
+                let mut children = Vec::new();
+                for (child, matches) in projection::load_children(store, &secrets, &link)?
+                    .into_iter()
+                    .zip(res)
+                {
+                    let count = child.count();
+                    if matches {
+                        children.push((offset, child));
+                    }
+                    offset += count;
+                }
+                stack.extend(children.into_iter().rev());
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// Write the blocks a query on the tree needs to a CAR file with the root of the tree, and return
+/// their number
+pub fn write_partial_car<T, V, R, Q>(
+    store: &R,
+    tree: &Tree<T, V>,
+    query: &Q,
+    path: impl AsRef<std::path::Path>,
+) -> anyhow::Result<u64>
+where
+    T: TreeTypes<Link = Sha256Digest>,
+    R: ReadOnlyStore<Sha256Digest>,
+    Q: Query<T>,
+{
+    let root = tree
+        .link()
+        .ok_or_else(|| anyhow::anyhow!("an empty tree has no blocks"))?;
+    let blocks = query_blocks(store, tree, query)?;
+    // blocks are content addressed, so a block that is at several places is only written once
+    let unique = blocks
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let bar = progress::block_bar(Some(unique.len() as u64))?;
+    write_car_blocks(
+        &ProgressStore::new(store.clone(), bar.clone()),
+        &[root],
+        &unique,
+        path,
+    )?;
+    bar.finish();
+    Ok(unique.len() as u64)
+}
+
+/// Export one time range of a tree, and query it from the partial CAR file
+pub fn partial_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!(
+        "Example: exporting the blocks for one query of {} events",
+        n
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, so a query needs only a few of them
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, Secrets::default());
+    let events = columnar::events(n);
+    let query = TimeRangeQuery {
+        min: events[50000].0.time,
+        max: events[50999].0.time,
+    };
+    txn.extend(&mut builder, events)?;
+    let tree = builder.snapshot();
+    let expected = txn
+        .iter_filtered(&tree, query.clone())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let dir = std::env::temp_dir();
+    let full = dir.join(format!("banyan-full-{}.car", std::process::id()));
+    let partial = dir.join(format!("banyan-partial-{}.car", std::process::id()));
+    let bar = progress::block_bar(None)?;
+    let progress = ProgressStore::new(store.clone(), bar.clone());
+    let all = write_car(&progress, &[tree.link().expect("not empty")], &full)?;
+    bar.finish();
+    let some = write_partial_car(&store, &tree, &query, &partial)?;
+
+    let car = CarStore::open(&partial)?;
+    let forest = Forest::<ColumnarTT, _>::new(car, BranchCache::new(1024));
+    let tree = forest.load_tree::<u64>(Secrets::default(), tree.link().expect("not empty"))?;
+    let found = forest
+        .iter_filtered(&tree, query)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(found == expected, "the partial export gives other events");
+    let err = forest
+        .iter_from(&tree)
+        .find_map(|item| item.err())
+        .expect("the rest of the tree is not in the file");
+    println!("full\t{} blocks\t{} bytes", all, fs::metadata(&full)?.len());
+    println!(
+        "partial\t{} blocks\t{} bytes",
+        some,
+        fs::metadata(&partial)?.len()
+    );
+    println!(
+        "{} events in the query, the rest fails with: {}",
+        found.len(),
+        err
+    );
+    fs::remove_file(&full)?;
+    fs::remove_file(&partial)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,324 @@
+//! Getting blocks straight from another instance, without kubo
+//!
+//! Where there is no kubo, e.g. on an air-gapped network, or where bitswap takes too long to find
+//! a block, two instances of this crate can talk to each other directly. [serve] answers requests
+//! for blocks of a store over tcp, and a [PeerStore] is a read-only store that gets its blocks
+//! from such a server. To replicate a tree, [probe::sync] copies the blocks from a peer store to a
+//! local one.
+//!
+//! The protocol is a length prefixed frame per message, with the length as a big endian u32. A
+//! request is the cid of a block, and the answer is its data, or the length `u32::MAX` if the
+//! server does not have it, or fails to get it. A connection is for as many requests as the client
+//! likes, one after the other. Either side drops a connection whose frame is longer than a cid or a
+//! block can be, before it allocates anything for it. There is no encryption and no authentication,
+//! the blocks of a tree with secrets are encrypted anyway, and a [PeerStore] checks the hash of
+//! every block it gets.
+//!
+//! A forest on a [PeerStore] reads a tree of the peer without copying it first, with a round
+//! trip per block it does not have in its cache.
+use std::{
+    convert::TryFrom,
+    io::{BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use banyan::{
+    query::AllQuery,
+    store::{BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+
+use crate::{
+    columnar::{self, ColumnarTT},
+    error::{self, Error, ErrorKind},
+    fs_store::FsStore,
+    mem_store::BoundedMemStore,
+    probe::{self, ProbingStore},
+};
+
+/// The length of the answer for a block the server does not have
+const MISSING: u32 = u32::MAX;
+
+/// The longest request, a cid is less than 100 bytes
+const MAX_CID: u32 = 128;
+
+/// The longest answer, the same limit as for a block in a CAR
+const MAX_BLOCK: u32 = 4 << 20;
+
+fn write_frame(w: &mut impl Write, data: &[u8]) -> anyhow::Result<()> {
+    let len = u32::try_from(data.len())?;
+    anyhow::ensure!(len <= MAX_BLOCK, "a block of {} bytes is too large", len);
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Read the data of a frame after its length, if the length is at most `max`
+fn read_data(r: &mut impl Read, len: u32, max: u32) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        len <= max,
+        "a frame of {} bytes is longer than {}",
+        len,
+        max
+    );
+    let mut data = vec![0u8; len as usize];
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read a frame, `None` for a missing block
+fn read_frame(r: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len == MISSING {
+        return Ok(None);
+    }
+    Ok(Some(read_data(r, len, MAX_BLOCK)?))
+}
+
+/// Answer the requests of one connection until the client closes it
+fn handle<S: ReadOnlyStore<Sha256Digest>>(stream: TcpStream, store: &S) -> anyhow::Result<()> {
+    let mut r = BufReader::new(stream.try_clone()?);
+    let mut w = BufWriter::new(stream);
+    loop {
+        let mut len = [0u8; 4];
+        match r.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(cause) if cause.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(cause) => return Err(cause.into()),
+        }
+        let cid = read_data(&mut r, u32::from_be_bytes(len), MAX_CID)?;
+        let link = Sha256Digest::try_from(Cid::try_from(cid.as_slice())?)?;
+        match store.get(&link) {
+            Ok(data) => write_frame(&mut w, &data)?,
+            Err(cause) => {
+                // not every store says whether a block is missing or it failed to get it
+                if error::kind(&cause) != ErrorKind::BlockMissing {
+                    tracing::warn!("no block {} for a peer: {}", link, cause);
+                }
+                w.write_all(&MISSING.to_be_bytes())?
+            }
+        }
+        w.flush()?;
+    }
+}
+
+/// Answer requests for the blocks of the store forever, with a thread per connection
+pub fn serve<S: ReadOnlyStore<Sha256Digest>>(
+    listener: TcpListener,
+    store: S,
+) -> anyhow::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(cause) = handle(stream, &store) {
+                tracing::warn!("peer connection failed: {}", cause);
+            }
+        });
+    }
+    Ok(())
+}
+
+type Connection = (BufReader<TcpStream>, BufWriter<TcpStream>);
+
+/// A read-only store with the blocks of a peer that [serve]s them, see the module docs
+#[derive(Debug, Clone)]
+pub struct PeerStore {
+    addr: String,
+    /// the connection, if there is one. Connecting again after a failure is up to the next get
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+impl PeerStore {
+    /// A store for the peer at this address, like `10.0.0.2:8436`. Connects on the first get
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            connection: Arc::default(),
+        }
+    }
+
+    fn request(
+        connection: &mut Connection,
+        link: &Sha256Digest,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let (r, w) = connection;
+        write_frame(w, &Cid::from(*link).to_bytes())?;
+        w.flush()?;
+        read_frame(r)
+    }
+}
+
+impl ReadOnlyStore<Sha256Digest> for PeerStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            let addr = self
+                .addr
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("{} has no address", self.addr))?;
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            *connection = Some((BufReader::new(stream.try_clone()?), BufWriter::new(stream)));
+        }
+        let data = match Self::request(connection.as_mut().expect("connected"), link) {
+            Ok(data) => data.ok_or_else(|| Error::missing(*link))?,
+            Err(cause) => {
+                // the connection is in an unknown state
+                *connection = None;
+                return Err(cause);
+            }
+        };
+        let actual = Sha256Digest::digest(&data);
+        if actual != *link {
+            return Err(Error::Corrupt {
+                cid: (*link).into(),
+                actual: actual.into(),
+            }
+            .into());
+        }
+        Ok(data.into())
+    }
+}
+
+impl ProbingStore<Sha256Digest> for PeerStore {
+    fn size(&self, link: &Sha256Digest) -> anyhow::Result<Option<u64>> {
+        // the protocol has no way to ask, so this gets the block
+        match self.get(link) {
+            Ok(data) => Ok(Some(data.len() as u64)),
+            Err(cause) if error::kind(&cause) == ErrorKind::BlockMissing => Ok(None),
+            Err(cause) => Err(cause),
+        }
+    }
+}
+
+/// Serve the blocks of the local file store to peers
+pub fn print_serve(path: &std::path::Path, listen: &str) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    println!(
+        "serving the blocks in {} on {}",
+        path.display(),
+        listener.local_addr()?
+    );
+    serve(listener, FsStore::<Sha256Digest>::open(path)?)
+}
+
+/// Copy trees from a peer to the local file store, and print what was copied
+pub fn print_fetch(
+    path: &std::path::Path,
+    peer: &str,
+    roots: &[Sha256Digest],
+) -> anyhow::Result<()> {
+    let source = PeerStore::new(peer);
+    let mut target = FsStore::<Sha256Digest>::open(path)?;
+    println!("root\tcopied\tbytes\tskipped");
+    for root in roots {
+        let stats = probe::sync(&source, &mut target, *root)?;
+        println!(
+            "{}\t{}\t{}\t{}",
+            root, stats.copied, stats.bytes, stats.skipped
+        );
+    }
+    Ok(())
+}
+
+/// Replicate a growing tree from a peer, and query a tree of the peer without copying it
+pub fn peer_example(config: &Config) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let steps = 4;
+    println!(
+        "Example: replicating a tree of {} events from a peer in {} steps",
+        n, steps
+    );
+    let store = BoundedMemStore::new(u64::MAX);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    {
+        let store = store.clone();
+        // runs until the process ends
+        thread::spawn(move || serve(listener, store));
+    }
+
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let events = columnar::events(n);
+    let peer = PeerStore::new(&addr);
+    let mut local = MemStore::new(usize::MAX, Sha256Digest::digest);
+    println!("events\tcopied\tbytes\tskipped");
+    for part in events.chunks((n / steps) as usize) {
+        txn.extend(&mut builder, part.iter().cloned())?;
+        let root = builder.snapshot().link().expect("not empty");
+        let stats = probe::sync(&peer, &mut local, root)?;
+        println!(
+            "{}\t{}\t{}\t{}",
+            builder.snapshot().count(),
+            stats.copied,
+            stats.bytes,
+            stats.skipped
+        );
+    }
+    let tree = builder.snapshot();
+    let root = tree.link().expect("not empty");
+    let stats = probe::sync(&peer, &mut local, root)?;
+    anyhow::ensure!(stats.copied == 0, "sync is not done");
+
+    // the replica has all events, and so does the peer, read through the peer store
+    for (name, count) in [
+        ("local", count(local, root)?),
+        ("peer", count(peer.clone(), root)?),
+    ] {
+        anyhow::ensure!(count == n, "the {} tree has {} events", name, count);
+    }
+    let unknown = Sha256Digest::digest(b"not a block");
+    let err = peer.get(&unknown).expect_err("the peer has no such block");
+    anyhow::ensure!(
+        error::kind(&err) == ErrorKind::BlockMissing,
+        "{} is {:?}",
+        err,
+        error::kind(&err)
+    );
+    anyhow::ensure!(
+        !peer.has(&unknown)?,
+        "the peer has a block it does not have"
+    );
+    println!("{} events in the replica and the peer", n);
+    println!();
+    Ok(())
+}
+
+fn count<S: ReadOnlyStore<Sha256Digest>>(store: S, root: Sha256Digest) -> anyhow::Result<u64> {
+    let forest = Forest::<ColumnarTT, _>::new(store, BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    Ok(forest.iter_filtered(&tree, AllQuery).count() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_too_long() {
+        let frame = (MAX_BLOCK + 1).to_be_bytes();
+        let err = read_frame(&mut frame.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{}", err);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle(stream, &MemStore::new(usize::MAX, Sha256Digest::digest))
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&(MAX_CID + 1).to_be_bytes()).unwrap();
+        let err = server.join().unwrap().unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{}", err);
+    }
+}
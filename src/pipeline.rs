@@ -0,0 +1,250 @@
+//! Writing blocks on worker threads while the tree is built
+//!
+//! `extend` builds the tree on the thread that calls it, and writes every block before it goes on
+//! with the next one. For a store where a write is a request, like kubo, most of the time of an
+//! extend is waiting for those requests. A [PipelinedStore] computes the link of a block, hands
+//! the block to a pool of worker threads that write it to the inner store, and returns right
+//! away. The blocks that are not written yet are kept in memory, and gets of them are answered
+//! from there, so a forest on the same store reads what it just wrote.
+//!
+//! A failed write fails the next put, and [PipelinedStore::flush], which waits for all writes.
+//! A root is only safe to publish after a flush.
+//!
+//! The encoding, compression and encryption of leaves stay on the thread of `extend`, since
+//! banyan does them inside it, between the writes, and does not have a way to hand them out. So
+//! the pipeline only helps where the writes are what takes the time. For a store in memory or on
+//! a local disk, the time goes to the encoding, the pipeline only adds the handoff to the
+//! workers, and building the tree is a bit slower with it than without.
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    query::AllQuery,
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT},
+    link::Link,
+};
+
+#[derive(Debug)]
+struct Pending<L> {
+    blocks: HashMap<L, Arc<[u8]>>,
+    /// the first write that failed
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Shared<L> {
+    pending: Mutex<Pending<L>>,
+    written: Condvar,
+}
+
+type Queue<L> = Arc<Mutex<mpsc::Receiver<(L, Arc<[u8]>)>>>;
+
+/// A store that writes blocks to the inner store on worker threads, see the module docs
+#[derive(Debug)]
+pub struct PipelinedStore<L, S> {
+    inner: S,
+    shared: Arc<Shared<L>>,
+    sender: mpsc::SyncSender<(L, Arc<[u8]>)>,
+}
+
+impl<L, S: Clone> Clone for PipelinedStore<L, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<L: Link, S: BlockWriter<L> + Clone + Send + 'static> PipelinedStore<L, S> {
+    /// Write with `workers` threads, with at most `queue` blocks waiting for one of them. The
+    /// threads end when the last clone of the store is dropped
+    pub fn new(inner: S, workers: usize, queue: usize) -> Self {
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(Pending {
+                blocks: HashMap::new(),
+                error: None,
+            }),
+            written: Condvar::new(),
+        });
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver: Queue<L> = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers.max(1) {
+            let (mut inner, shared, receiver) = (inner.clone(), shared.clone(), receiver.clone());
+            thread::spawn(move || loop {
+                // the lock is only held while waiting, not while writing
+                let next = receiver.lock().unwrap().recv();
+                let Ok((link, data)) = next else {
+                    break;
+                };
+                let result = inner.put(data.to_vec()).and_then(|written| {
+                    anyhow::ensure!(written == link, "{} was written as {}", link, written);
+                    Ok(())
+                });
+                let mut pending = shared.pending.lock().unwrap();
+                if let Err(cause) = result {
+                    pending.error.get_or_insert(cause.to_string());
+                }
+                pending.blocks.remove(&link);
+                shared.written.notify_all();
+            });
+        }
+        Self {
+            inner,
+            shared,
+            sender,
+        }
+    }
+
+    /// Wait until every block is written, and fail if a write failed
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let mut pending = self.shared.pending.lock().unwrap();
+        while !pending.blocks.is_empty() && pending.error.is_none() {
+            pending = self.shared.written.wait(pending).unwrap();
+        }
+        match &pending.error {
+            Some(cause) => anyhow::bail!("a pipelined write failed: {}", cause),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<L: Link, S: ReadOnlyStore<L>> ReadOnlyStore<L> for PipelinedStore<L, S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let pending = self
+            .shared
+            .pending
+            .lock()
+            .unwrap()
+            .blocks
+            .get(link)
+            .cloned();
+        match pending {
+            Some(data) => Ok(data.to_vec().into()),
+            None => self.inner.get(link),
+        }
+    }
+}
+
+impl<L: Link, S: BlockWriter<L>> BlockWriter<L> for PipelinedStore<L, S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let link = L::digest(&data);
+        let data: Arc<[u8]> = data.into();
+        {
+            let mut pending = self.shared.pending.lock().unwrap();
+            if let Some(cause) = &pending.error {
+                anyhow::bail!("a pipelined write failed: {}", cause);
+            }
+            if pending.blocks.insert(link, data.clone()).is_some() {
+                // it is on its way already
+                return Ok(link);
+            }
+        }
+        // blocks while the queue is full, so a slow store holds up the writer instead of
+        // filling up the memory
+        self.sender
+            .send((link, data))
+            .map_err(|_| anyhow::anyhow!("the pipeline workers are gone"))?;
+        Ok(link)
+    }
+}
+
+/// A store wrapper that takes a while for every put, like a write to a remote store
+#[derive(Clone)]
+struct SlowWriter<S>(S, Duration);
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for SlowWriter<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.0.get(link)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for SlowWriter<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        thread::sleep(self.1);
+        self.0.put(data)
+    }
+}
+
+/// Build the same tree directly on a store and through a pipeline, and return the time of each
+fn build(
+    config: &Config,
+    events: &[(columnar::EventKey, u64)],
+    latency: Duration,
+    workers: usize,
+) -> anyhow::Result<(Duration, Duration)> {
+    let direct = SlowWriter(MemStore::new(usize::MAX, Sha256Digest::digest), latency);
+    let t0 = Instant::now();
+    let forest = Forest::<ColumnarTT, _>::new(direct.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, direct.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, events.iter().cloned())?;
+    let expected = builder.snapshot().link().expect("not empty");
+    let slow = t0.elapsed();
+
+    let target = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let t0 = Instant::now();
+    let store = PipelinedStore::new(SlowWriter(target.clone(), latency), workers, 64);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, events.iter().cloned())?;
+    store.flush()?;
+    let root = builder.snapshot().link().expect("not empty");
+    let fast = t0.elapsed();
+    anyhow::ensure!(root == expected, "the pipeline built another tree");
+
+    // everything is in the inner store after the flush
+    let forest = Forest::<ColumnarTT, _>::new(target, BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let count = forest.iter_filtered(&tree, AllQuery).count();
+    anyhow::ensure!(count == events.len(), "the store has {} events", count);
+    Ok((slow, fast))
+}
+
+/// Build the same tree on a store with fast and with slow writes, directly and through a pipeline
+pub fn pipeline_example(config: &Config) -> anyhow::Result<()> {
+    let n = 200000u64;
+    let workers = 8;
+    println!(
+        "Example: writing {} events directly and with {} pipeline workers",
+        n, workers
+    );
+    // small leaves, so there are enough writes to wait for
+    let config = &Config {
+        target_leaf_size: 1 << 12,
+        max_leaf_count: 1 << 10,
+        ..config.clone()
+    };
+    config.validate()?;
+    let events = columnar::events(n);
+    println!("write latency\tdirect\tpipelined\tspeedup");
+    for latency in [Duration::ZERO, Duration::from_millis(2)] {
+        let (slow, fast) = build(config, &events, latency, workers)?;
+        println!(
+            "{}ms\t{:.3}s\t{:.3}s\t{:.1}x",
+            latency.as_millis(),
+            slow.as_secs_f64(),
+            fast.as_secs_f64(),
+            slow.as_secs_f64() / fast.as_secs_f64()
+        );
+        // usually more than twice as fast, but a busy machine should not fail the examples
+        anyhow::ensure!(
+            latency.is_zero() || fast < slow,
+            "the pipeline is not faster"
+        );
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,215 @@
+//! Read-ahead for sequential iteration
+//!
+//! A full scan fetches leaves one at a time, so against a remote store like kubo it spends most
+//! of its time waiting for the network. The leaf links are all in the branches, so we can collect
+//! them up front and fetch the next few leaves with a pool of threads while the synchronous
+//! iterator is busy decoding the current one.
+use std::{
+    collections::HashSet,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    index::Index,
+    query::AllQuery,
+    store::{BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+type Block = anyhow::Result<Box<[u8]>>;
+type Job<L> = (L, SyncSender<Block>);
+type Pending<L> = (L, Receiver<Block>);
+
+/// The pending fetches, in order, and the next one
+struct Queue<L> {
+    /// bounded, so the prefetcher stays at most `lookahead` leaves ahead
+    rx: Receiver<Pending<L>>,
+    next: Option<Pending<L>>,
+}
+
+struct Inner<L, S> {
+    inner: S,
+    /// links that the prefetcher will deliver
+    scheduled: HashSet<L>,
+    pending: Mutex<Queue<L>>,
+}
+
+/// A store that serves a sequence of blocks from a background prefetcher
+///
+/// Blocks that were not scheduled, like branches, are fetched directly from the inner store.
+/// The scheduled blocks have to be requested in order, which is what a full scan does. A
+/// scheduled block that is not the next one is fetched directly as well.
+pub struct PrefetchStore<L, S>(Arc<Inner<L, S>>);
+
+impl<L, S> Clone for PrefetchStore<L, S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<L, S> PrefetchStore<L, S>
+where
+    L: std::hash::Hash + Eq + Copy + Send + Sync + 'static,
+    S: ReadOnlyStore<L>,
+{
+    /// Start fetching the links with `lookahead` threads, staying at most `lookahead` blocks ahead
+    pub fn new(inner: S, links: Vec<L>, lookahead: usize) -> Self {
+        let lookahead = lookahead.max(1);
+        let (pending_tx, pending_rx) = mpsc::sync_channel(lookahead);
+        let (job_tx, job_rx) = mpsc::channel::<Job<L>>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        // the workers exit when the dispatcher drops the job sender
+        for _ in 0..lookahead {
+            let store = inner.clone();
+            let job_rx = job_rx.clone();
+            thread::spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                match job {
+                    Ok((link, tx)) => {
+                        let _ = tx.send(store.get(&link));
+                    }
+                    Err(_) => break,
+                }
+            });
+        }
+        // the dispatcher exits when the store is dropped and it can no longer send
+        let scheduled = links.iter().cloned().collect();
+        thread::spawn(move || {
+            for link in links {
+                let (tx, rx) = mpsc::sync_channel(1);
+                if job_tx.send((link, tx)).is_err() || pending_tx.send((link, rx)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self(Arc::new(Inner {
+            inner,
+            scheduled,
+            pending: Mutex::new(Queue {
+                rx: pending_rx,
+                next: None,
+            }),
+        }))
+    }
+}
+
+impl<L, S> ReadOnlyStore<L> for PrefetchStore<L, S>
+where
+    L: std::hash::Hash + Eq + Copy + Send + Sync + 'static,
+    S: ReadOnlyStore<L>,
+{
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        if self.0.scheduled.contains(link) {
+            let next = {
+                let mut queue = self.0.pending.lock().unwrap();
+                if queue.next.is_none() {
+                    queue.next = queue.rx.recv().ok();
+                }
+                match &queue.next {
+                    Some((scheduled, _)) if scheduled == link => queue.next.take(),
+                    _ => None,
+                }
+            };
+            // wait for the block without blocking the gets of other blocks
+            if let Some((_, rx)) = next {
+                return rx.recv()?;
+            }
+        }
+        self.0.inner.get(link)
+    }
+}
+
+/// Links of all leaves of the tree, in order
+pub fn leaf_links<T, R, V>(forest: &Forest<T, R>, tree: &Tree<T, V>) -> anyhow::Result<Vec<T::Link>>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+{
+    let mut links = Vec::new();
+    for index in forest.iter_index(tree, AllQuery) {
+        if let Index::Leaf(leaf) = index? {
+            links.extend(leaf.link);
+        }
+    }
+    Ok(links)
+}
+
+/// A store wrapper that adds a fixed latency to every get, to simulate a remote store
+#[derive(Clone)]
+pub struct LatencyStore<S>(S, Duration);
+
+impl<S> LatencyStore<S> {
+    pub fn new(inner: S, latency: Duration) -> Self {
+        Self(inner, latency)
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for LatencyStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        thread::sleep(self.1);
+        self.0.get(link)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ScanTT;
+
+impl TreeTypes for ScanTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Prefetching for camp....";
+}
+
+/// Full scan of the tree, returning the sum of the values and the elapsed time
+fn scan<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<ScanTT, R>,
+    tree: &Tree<ScanTT, u64>,
+) -> anyhow::Result<(u64, f64)> {
+    let t0 = Instant::now();
+    let mut sum = 0;
+    for item in forest.iter_from(tree) {
+        let (_i, _k, v) = item?;
+        sum += v;
+    }
+    Ok((sum, t0.elapsed().as_secs_f64()))
+}
+
+/// Scan a sequence of `n` values through a store with the given latency, once without and once
+/// with read-ahead, and compare the times
+pub fn report(config: &Config, n: u64, lookahead: usize, latency: Duration) -> anyhow::Result<()> {
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let forest = Forest::<ScanTT, _>::new(store.clone(), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ScanTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+    let links = leaf_links(&txn, &tree)?;
+    let slow = LatencyStore::new(store, latency);
+
+    println!(
+        "scanning {} values in {} leaves, {}ms per get",
+        n,
+        links.len(),
+        latency.as_millis()
+    );
+    let (plain_sum, plain_dt) = scan(&Forest::new(slow.clone(), BranchCache::default()), &tree)?;
+    println!("plain\t{:.3}s", plain_dt);
+    let prefetch = PrefetchStore::new(slow, links.clone(), lookahead);
+    // a get out of order does not disturb the read-ahead
+    if let Some(last) = links.last().filter(|_| links.len() > 1) {
+        prefetch.get(last)?;
+    }
+    let (sum, dt) = scan(&Forest::new(prefetch, BranchCache::default()), &tree)?;
+    println!("prefetch\t{:.3}s ({} leaves ahead)", dt, lookahead);
+    anyhow::ensure!(sum == plain_sum, "prefetched scan differs");
+    Ok(())
+}
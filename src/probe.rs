@@ -0,0 +1,195 @@
+//! Asking a store whether it has a block, without getting it
+//!
+//! To copy a tree to another store, we only need the blocks the other store does not have yet.
+//! With only `get`, every check would download the block.
+//!
+//! A store that has a block does not always have everything below it. A demote of a
+//! [TieredStore](crate::tiering::TieredStore) removes leaves below branches it keeps, and a partial
+//! CAR import only has the blocks of a query. So [sync] does not skip the subtree of a block the
+//! target has, but reads it from the target, which is usually local, and checks its children.
+use std::{
+    collections::{BTreeSet, HashSet},
+    convert::TryFrom,
+    time::Instant,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+
+use crate::{
+    fs_store::FsStore,
+    kubo::{self, KuboStore},
+    snapshots::LogTT,
+};
+
+/// A store that can tell whether it has a block, and how large it is
+pub trait ProbingStore<L>: ReadOnlyStore<L> {
+    /// The size of the block in bytes, or `None` if the store does not have it
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>>;
+
+    /// True if the store has the block
+    fn has(&self, link: &L) -> anyhow::Result<bool> {
+        Ok(self.size(link)?.is_some())
+    }
+}
+
+impl ProbingStore<Sha256Digest> for MemStore<Sha256Digest> {
+    fn size(&self, link: &Sha256Digest) -> anyhow::Result<Option<u64>> {
+        // a memstore has no way to ask, but a get is cheap when there is no network involved
+        Ok(self.get(link).ok().map(|data| data.len() as u64))
+    }
+}
+
+impl ProbingStore<Sha256Digest> for KuboStore {
+    fn size(&self, link: &Sha256Digest) -> anyhow::Result<Option<u64>> {
+        // offline, so kubo does not go looking for the block on the network
+        let cid = Cid::from(*link).to_string();
+        let response = self
+            .endpoint()
+            .post("block/stat", &[("arg", &cid), ("offline", "true")])
+            .send()?;
+        // only a block that is not there is a no, a kubo that fails is an error
+        if kubo::is_not_found(response.status()) {
+            return Ok(None);
+        }
+        let text = kubo::checked(&format!("block/stat {}", cid), response)?.text()?;
+        let stat: serde_json::Value = serde_json::from_str(&text)?;
+        match stat["Size"].as_u64() {
+            Some(size) => Ok(Some(size)),
+            None => anyhow::bail!("kubo block/stat {} has no size: {}", cid, text),
+        }
+    }
+}
+
+/// What [sync] did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// blocks that were copied
+    pub copied: u64,
+    /// bytes that were copied
+    pub bytes: u64,
+    /// blocks the target already had
+    pub skipped: u64,
+}
+
+/// The links of a block. Blocks that are not dag-cbor, like payload chunks, have none
+fn links(data: &[u8]) -> anyhow::Result<Vec<Sha256Digest>> {
+    let mut cids = BTreeSet::<Cid>::new();
+    if let Ok(ipld) = DagCborCodec.decode::<Ipld>(data) {
+        ipld.references(&mut cids);
+    }
+    cids.into_iter().map(Sha256Digest::try_from).collect()
+}
+
+/// Copy all blocks reachable from the root that the target does not have yet
+///
+/// Blocks are written after the blocks below them, so an interrupted sync does not leave a block
+/// without its children. The blocks the target has are read from the target, to find their
+/// children, see the module docs.
+pub fn sync<S, D>(source: &S, target: &mut D, root: Sha256Digest) -> anyhow::Result<SyncStats>
+where
+    S: ReadOnlyStore<Sha256Digest>,
+    D: ProbingStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+{
+    let mut stats = SyncStats::default();
+    let mut seen = HashSet::new();
+    // a block with its data is ready to be written, since everything below it has been
+    let mut todo: Vec<(Sha256Digest, Option<Box<[u8]>>)> = vec![(root, None)];
+    while let Some((link, data)) = todo.pop() {
+        match data {
+            Some(data) => {
+                stats.copied += 1;
+                stats.bytes += data.len() as u64;
+                target.put(data.into())?;
+            }
+            None if !seen.insert(link) => {}
+            None if target.has(&link)? => {
+                stats.skipped += 1;
+                let data = target.get(&link)?;
+                todo.extend(links(&data)?.into_iter().map(|child| (child, None)));
+            }
+            None => {
+                let data = source.get(&link)?;
+                let children = links(&data)?;
+                todo.push((link, Some(data)));
+                todo.extend(children.into_iter().map(|child| (child, None)));
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Copy a growing tree to a file store, and copy only what is new on each sync
+pub fn sync_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    let step = 100000u64;
+    println!(
+        "Example: syncing a tree to a file store, after every {} of {} events",
+        step, n
+    );
+    let path = std::env::temp_dir().join(format!("banyan-sync-{}", std::process::id()));
+    let mut target = FsStore::<Sha256Digest>::open(&path)?;
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    println!("events\tcopied\tbytes\tskipped");
+    for start in (0..n).step_by(step as usize) {
+        txn.extend(&mut builder, (start..start + step).map(|i| ((), i)))?;
+        let root = builder.snapshot().link().expect("not empty");
+        let stats = sync(&store, &mut target, root)?;
+        println!(
+            "{}\t{}\t{}\t{}",
+            start + step,
+            stats.copied,
+            stats.bytes,
+            stats.skipped
+        );
+    }
+
+    // the target has everything, so syncing again does nothing
+    let root = builder.snapshot().link().expect("not empty");
+    let stats = sync(&store, &mut target, root)?;
+    anyhow::ensure!(stats.copied == 0, "sync is not done");
+
+    // a target with the root, but not a leaf below it, gets the leaf
+    let leaf = crate::dedup::blocks(&target, root)?
+        .into_keys()
+        .find(|link| {
+            matches!(target.get(link).map(|data| links(&data)),
+                Ok(Ok(children)) if children.is_empty())
+        })
+        .expect("a tree has leaves");
+    target.remove(&leaf)?;
+    anyhow::ensure!(!target.has(&leaf)?, "the leaf is still there");
+    let stats = sync(&store, &mut target, root)?;
+    anyhow::ensure!(
+        stats.copied == 1 && target.has(&leaf)?,
+        "the leaf was not copied"
+    );
+    let t0 = Instant::now();
+    let forest = Forest::<LogTT, _>::new(target.clone(), BranchCache::new(1024));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let mut count = 0;
+    for item in forest.iter_from(&tree) {
+        item?;
+        count += 1;
+    }
+    anyhow::ensure!(count == n, "events are missing in the target");
+    let (blocks, bytes) = target.usage();
+    println!(
+        "{} blocks, {} bytes in the target, read back {}s",
+        blocks,
+        bytes,
+        t0.elapsed().as_secs_f64()
+    );
+    std::fs::remove_dir_all(&path)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,194 @@
+//! Named profiles in a config file
+//!
+//! Everything the command line can set for a run, and some things it can not, can be kept in
+//! `banyan-camp.toml` as a profile, and picked with `--profile`:
+//!
+//! ```toml
+//! [profile.dev]
+//! backend = "mem"
+//!
+//! [profile.prod]
+//! backend = "kubo"
+//! path = "/var/lib/banyan"
+//! timeout_ms = 5000
+//! # the tree config starts from debug_fast or debug, and any of its fields can be changed
+//! base = "debug_fast"
+//! zstd_level = 10
+//! target_leaf_size = 65536
+//! max_leaf_count = 4096
+//! # a file with the index key and the value key, 32 bytes each
+//! secrets = "/etc/banyan/prod.secrets"
+//! # a file with the 32 byte key that the secrets of each tenant are derived from
+//! master_key = "/etc/banyan/prod.master"
+//! cache_bytes = 67108864
+//! ```
+//!
+//! The file is only read when a profile is asked for, and an option given on the command line wins
+//! over the profile. The secrets and the cache size are used by the writer and the reader, the
+//! examples bring their own. The server uses the cache size for its block cache. The writer and
+//! the reader of a tenant, and the server for the streams of tenants, use the secrets of the
+//! tenant from the master key, see [crate::tenants].
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use banyan::{chacha20::Key as SecretKey, Config, Secrets};
+use toml_edit::{Document, Item};
+
+use crate::Backend;
+
+/// The branch cache of the writer and the reader, if the profile does not say otherwise
+pub const DEFAULT_CACHE_BYTES: usize = 1 << 20;
+
+/// One profile of the config file. Everything is optional, a profile only sets what it mentions
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub backend: Option<Backend>,
+    pub path: Option<PathBuf>,
+    pub timeout_ms: Option<u64>,
+    /// the tree config, if the profile changes anything about it
+    pub config: Option<Config>,
+    pub zstd_level: Option<i32>,
+    pub secrets: Option<Secrets>,
+    pub master_key: Option<SecretKey>,
+    pub cache_bytes: Option<usize>,
+}
+
+/// The fields a profile may have, so that a typo is an error and not silently ignored
+const FIELDS: &[&str] = &[
+    "backend",
+    "path",
+    "timeout_ms",
+    "base",
+    "zstd_level",
+    "target_leaf_size",
+    "max_leaf_count",
+    "max_key_branches",
+    "max_summary_branches",
+    "max_uncompressed_leaf_size",
+    "secrets",
+    "master_key",
+    "cache_bytes",
+];
+
+/// Load a profile from a config file
+pub fn load(file: &Path, name: &str) -> anyhow::Result<Profile> {
+    let text = fs::read_to_string(file)
+        .map_err(|cause| anyhow::anyhow!("can not read {}: {}", file.display(), cause))?;
+    parse(&text, name).map_err(|cause| anyhow::anyhow!("{}: {}", file.display(), cause))
+}
+
+/// Parse a profile from the text of a config file
+pub fn parse(text: &str, name: &str) -> anyhow::Result<Profile> {
+    let doc = text.parse::<Document>()?;
+    let profiles = doc
+        .get("profile")
+        .and_then(Item::as_table)
+        .ok_or_else(|| anyhow::anyhow!("no [profile.*] sections"))?;
+    let table = profiles.get(name).and_then(Item::as_table).ok_or_else(|| {
+        let names = profiles.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        anyhow::anyhow!("no profile {}, there is {}", name, names.join(", "))
+    })?;
+    if let Some((key, _)) = table.iter().find(|(key, _)| !FIELDS.contains(key)) {
+        anyhow::bail!("profile {} has an unknown field {}", name, key);
+    }
+    let string = |key: &str| -> anyhow::Result<Option<&str>> {
+        table
+            .get(key)
+            .map(|item| {
+                item.as_str()
+                    .ok_or_else(|| anyhow::anyhow!("{} must be a string", key))
+            })
+            .transpose()
+    };
+    let integer = |key: &str| -> anyhow::Result<Option<u64>> {
+        table
+            .get(key)
+            .map(|item| {
+                item.as_integer()
+                    .and_then(|value| u64::try_from(value).ok())
+                    .ok_or_else(|| anyhow::anyhow!("{} must be a positive integer", key))
+            })
+            .transpose()
+    };
+    let size = |key: &str| -> anyhow::Result<Option<usize>> {
+        Ok(integer(key)?.map(usize::try_from).transpose()?)
+    };
+
+    let tree_fields = [
+        "base",
+        "target_leaf_size",
+        "max_leaf_count",
+        "max_key_branches",
+        "max_summary_branches",
+        "max_uncompressed_leaf_size",
+    ];
+    let config = if tree_fields.iter().any(|key| table.contains_key(key)) {
+        let base = match string("base")?.unwrap_or("debug_fast") {
+            "debug_fast" => Config::debug_fast(),
+            "debug" => Config::debug(),
+            other => anyhow::bail!("unknown base config {}, use debug_fast or debug", other),
+        };
+        let config = Config {
+            target_leaf_size: size("target_leaf_size")?.unwrap_or(base.target_leaf_size),
+            max_leaf_count: size("max_leaf_count")?.unwrap_or(base.max_leaf_count),
+            max_key_branches: size("max_key_branches")?.unwrap_or(base.max_key_branches),
+            max_summary_branches: size("max_summary_branches")?
+                .unwrap_or(base.max_summary_branches),
+            max_uncompressed_leaf_size: size("max_uncompressed_leaf_size")?
+                .unwrap_or(base.max_uncompressed_leaf_size),
+            ..base
+        };
+        Some(config)
+    } else {
+        None
+    };
+    Ok(Profile {
+        backend: string("backend")?.map(str::parse).transpose()?,
+        path: string("path")?.map(PathBuf::from),
+        timeout_ms: integer("timeout_ms")?,
+        config,
+        zstd_level: integer("zstd_level")?.map(i32::try_from).transpose()?,
+        secrets: string("secrets")?.map(load_secrets).transpose()?,
+        master_key: string("master_key")?.map(load_master_key).transpose()?,
+        cache_bytes: size("cache_bytes")?,
+    })
+}
+
+/// Load the secrets from a file with the index key and the value key. Unlike a signing key, a
+/// missing file is not created, since a new key would make the existing trees unreadable
+pub fn load_secrets(path: impl AsRef<Path>) -> anyhow::Result<Secrets> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)
+        .map_err(|cause| anyhow::anyhow!("can not read secrets {}: {}", path.display(), cause))?;
+    anyhow::ensure!(
+        bytes.len() == 64,
+        "{} has {} bytes, not two keys of 32",
+        path.display(),
+        bytes.len()
+    );
+    let index_key = <[u8; 32]>::try_from(&bytes[..32])?;
+    let value_key = <[u8; 32]>::try_from(&bytes[32..])?;
+    Ok(Secrets::new(
+        SecretKey::from(index_key),
+        SecretKey::from(value_key),
+    ))
+}
+
+/// Load the master key of the tenants from a file with 32 bytes. Like the secrets, a missing file
+/// is not created
+pub fn load_master_key(path: impl AsRef<Path>) -> anyhow::Result<SecretKey> {
+    let path = path.as_ref();
+    let bytes = fs::read(path).map_err(|cause| {
+        anyhow::anyhow!("can not read master key {}: {}", path.display(), cause)
+    })?;
+    let key = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| {
+        anyhow::anyhow!(
+            "{} has {} bytes, not a key of 32",
+            path.display(),
+            bytes.len()
+        )
+    })?;
+    Ok(SecretKey::from(key))
+}
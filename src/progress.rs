@@ -0,0 +1,160 @@
+//! Progress reporting for long running builds
+//!
+//! Building a large tree against kubo can take minutes, so we extend the builder in batches
+//! and update a progress bar after each batch. Exports to CAR files and pulls of a sync show a
+//! bar of the blocks, with a [ProgressStore] for the reads of an export.
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use banyan::{
+    store::{BanyanValue, BlockWriter, ReadOnlyStore},
+    StreamBuilder, Transaction, TreeTypes,
+};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+
+use crate::probe::ProbingStore;
+
+/// Number of events to add to the builder before updating the progress bar
+const BATCH_SIZE: usize = 1 << 16;
+
+/// Counters for blocks and bytes written, shared between clones of a [CountingStore]
+#[derive(Debug, Clone, Default)]
+pub struct Counters {
+    blocks: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Counters {
+    /// number of blocks written so far
+    pub fn blocks(&self) -> u64 {
+        self.blocks.load(Ordering::Relaxed)
+    }
+
+    /// number of bytes written so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// A store wrapper that counts the blocks and bytes that are written through it
+#[derive(Clone)]
+pub struct CountingStore<S> {
+    inner: S,
+    counters: Counters,
+}
+
+impl<S> CountingStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for CountingStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.inner.get(link)
+    }
+}
+
+impl<L, S: ProbingStore<L>> ProbingStore<L> for CountingStore<S> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        self.inner.size(link)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for CountingStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let len = data.len() as u64;
+        let link = self.inner.put(data)?;
+        self.counters.blocks.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes.fetch_add(len, Ordering::Relaxed);
+        Ok(link)
+    }
+}
+
+/// A bar for `len` blocks, or for an unknown number of them, showing blocks/sec and bytes
+pub fn block_bar(len: Option<u64>) -> anyhow::Result<ProgressBar> {
+    let (bar, template) = match len {
+        Some(len) => (
+            ProgressBar::new(len),
+            "{elapsed_precise} [{bar:40}] {human_pos}/{human_len} blocks ({per_sec}) {msg}",
+        ),
+        None => (
+            ProgressBar::no_length(),
+            "{elapsed_precise} {human_pos} blocks ({per_sec}) {msg}",
+        ),
+    };
+    bar.set_style(ProgressStyle::with_template(template)?);
+    Ok(bar)
+}
+
+/// A store wrapper that advances a bar for every block that is read through it, for exports
+/// that read every block once
+#[derive(Clone)]
+pub struct ProgressStore<S> {
+    inner: S,
+    bar: ProgressBar,
+    bytes: Arc<AtomicU64>,
+}
+
+impl<S> ProgressStore<S> {
+    pub fn new(inner: S, bar: ProgressBar) -> Self {
+        Self {
+            inner,
+            bar,
+            bytes: Default::default(),
+        }
+    }
+}
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for ProgressStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let data = self.inner.get(link)?;
+        let len = data.len() as u64;
+        let bytes = self.bytes.fetch_add(len, Ordering::Relaxed) + len;
+        self.bar.inc(1);
+        self.bar.set_message(HumanBytes(bytes).to_string());
+        Ok(data)
+    }
+}
+
+/// Extend the builder from the vec in batches, showing events/sec, blocks written and bytes
+/// uploaded
+pub fn extend<T, R, W, V>(
+    txn: &mut Transaction<T, R, CountingStore<W>>,
+    builder: &mut StreamBuilder<T, V>,
+    xs: Vec<(T::Key, V)>,
+) -> anyhow::Result<()>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+    V: BanyanValue,
+{
+    let bar = ProgressBar::new(xs.len() as u64);
+    bar.set_style(ProgressStyle::with_template(
+        "{elapsed_precise} [{bar:40}] {human_pos}/{human_len} events ({per_sec}) {msg}",
+    )?);
+    let mut xs = xs.into_iter();
+    while xs.len() > 0 {
+        let n = xs.len().min(BATCH_SIZE);
+        txn.extend(builder, xs.by_ref().take(n))?;
+        let counters = txn.writer().counters();
+        bar.inc(n as u64);
+        bar.set_message(format!(
+            "{} blocks, {}",
+            counters.blocks(),
+            HumanBytes(counters.bytes())
+        ));
+    }
+    bar.finish();
+    Ok(())
+}
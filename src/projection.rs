@@ -0,0 +1,271 @@
+//! Reading less than the whole event
+//!
+//! The keys of a leaf are in the branch above it, so a query that only needs offsets and keys,
+//! like counting events per hour, does not need to load the leaves at all. [KeyIter] walks the
+//! branches with the query, and yields the matching keys without touching a single leaf.
+//!
+//! When some fields of the value are needed, the leaves have to be loaded. But a tree can be
+//! loaded with any value type that decodes from the stored values, and [Project] decodes only the
+//! fields listed by [Pick] and skips the rest of each value without building it. That saves the
+//! decoding, which matters for large values, but not the block gets.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Seek, Write},
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    chacha20::XNonce,
+    index::{CompactSeq, Index},
+    query::Query,
+    store::{BlockWriter, BranchCache, ReadOnlyStore, ZstdDagCborSeq},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::{
+        decode::{read_len, read_u8},
+        encode::write_u64,
+        DagCborCodec,
+    },
+    codec::{Codec, Decode, Encode},
+    raw_value::{IgnoredAny, RawValue},
+    DagCbor,
+};
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey, TimeRangeQuery},
+    error::Error,
+    prefetch::LatencyStore,
+    versioned::Reading,
+};
+
+/// The indices of the children of a branch, without going through the forest
+pub fn load_children<T: TreeTypes>(
+    store: &impl ReadOnlyStore<T::Link>,
+    secrets: &Secrets,
+    link: &T::Link,
+) -> anyhow::Result<Vec<Index<T>>> {
+    let data = store.get(link)?;
+    let nonce = <&XNonce>::from(T::NONCE);
+    ZstdDagCborSeq::decrypt(&data, secrets.index_key(), nonce)
+        .and_then(|(seq, _)| seq.items::<Index<T>>())
+        .map_err(|cause| {
+            Error::Decode {
+                link: link.to_string(),
+                reason: cause.to_string(),
+            }
+            .into()
+        })
+}
+
+/// The offsets and keys of all events matching a query, from the branches alone
+pub struct KeyIter<T: TreeTypes, R, Q> {
+    store: R,
+    secrets: Secrets,
+    query: Q,
+    /// nodes still to visit with their offsets, the next one last
+    stack: Vec<(u64, Index<T>)>,
+    /// matching keys of the last leaf
+    keys: VecDeque<(u64, T::Key)>,
+}
+
+impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, Q: Query<T>> KeyIter<T, R, Q> {
+    pub fn new<V>(store: R, tree: &Tree<T, V>, query: Q) -> Self {
+        Self {
+            store,
+            secrets: tree.secrets().cloned().unwrap_or_default(),
+            query,
+            stack: tree
+                .index()
+                .map(|index| (0, index.clone()))
+                .into_iter()
+                .collect(),
+            keys: VecDeque::new(),
+        }
+    }
+
+    /// Visit the next node, and queue its matching children or keys
+    fn visit(&mut self, offset: u64, index: Index<T>) -> anyhow::Result<()> {
+        match index {
+            Index::Leaf(leaf) => {
+                let mut res = vec![true; leaf.keys.count() as usize];
+                self.query.containing(offset, &leaf, &mut res);
+                let keys = leaf.keys().zip(res).enumerate();
+                self.keys.extend(
+                    keys.filter(|(_, (_, matches))| *matches)
+                        .map(|(i, (key, _))| (offset + i as u64, key)),
+                );
+            }
+            Index::Branch(branch) => {
+                let mut res = vec![true; branch.summaries.count() as usize];
+                self.query.intersecting(offset, &branch, &mut res);
+                // a purged branch still has its summaries, but not its children
+                let link = match branch.link {
+                    Some(link) if res.contains(&true) => link,
+                    _ => return Ok(()),
+                };
+                let mut offset = offset;
+                let mut children = Vec::new();
+                for (child, matches) in load_children(&self.store, &self.secrets, &link)?
+                    .into_iter()
+                    .zip(res)
+                {
+                    let count = child.count();
+                    if matches {
+                        children.push((offset, child));
+                    }
+                    offset += count;
+                }
+                self.stack.extend(children.into_iter().rev());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: TreeTypes, R: ReadOnlyStore<T::Link>, Q: Query<T>> Iterator for KeyIter<T, R, Q> {
+    type Item = anyhow::Result<(u64, T::Key)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.keys.pop_front() {
+                return Some(Ok(item));
+            }
+            let (offset, index) = self.stack.pop()?;
+            if let Err(cause) = self.visit(offset, index) {
+                self.stack.clear();
+                return Some(Err(cause));
+            }
+        }
+    }
+}
+
+/// A struct with some of the fields of a stored value
+pub trait Pick: Encode<DagCborCodec> + Decode<DagCborCodec> {
+    /// The fields to decode, all others are skipped
+    const FIELDS: &'static [&'static str];
+}
+
+/// A value type that decodes only the fields of `P` from a stored map
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project<P>(pub P);
+
+impl<P: Pick> Decode<DagCborCodec> for Project<P> {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+        let major = read_u8(r)?;
+        anyhow::ensure!((0xa0..=0xbb).contains(&major), "expected a map");
+        let mut fields = Vec::new();
+        for _ in 0..read_len(r, major - 0xa0)? {
+            let key = String::decode(c, r)?;
+            if P::FIELDS.contains(&key.as_str()) {
+                fields.push((key, RawValue::<DagCborCodec>::decode(c, r)?));
+            } else {
+                IgnoredAny::decode(c, r)?;
+            }
+        }
+        // a map of just the picked fields, with their encoded values copied as they are
+        let mut map = Vec::new();
+        write_u64(&mut map, 5, fields.len() as u64)?;
+        for (key, value) in fields {
+            key.encode(c, &mut map)?;
+            map.extend_from_slice(value.as_ref());
+        }
+        let value = DagCborCodec.decode(&map)?;
+        Ok(Self(value))
+    }
+}
+
+impl<P: Pick> Encode<DagCborCodec> for Project<P> {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+        self.0.encode(c, w)
+    }
+}
+
+/// Just the sensor of a [Reading]
+#[derive(Debug, Clone, PartialEq, DagCbor)]
+struct Sensor {
+    sensor: u64,
+}
+
+impl Pick for Sensor {
+    const FIELDS: &'static [&'static str] = &["sensor"];
+}
+
+const HOUR: u64 = 60 * 60 * 1000;
+
+/// Count events per hour and per sensor with full values, with keys only, and with one field,
+/// through a store with a latency like a remote store
+pub fn projection_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let latency = Duration::from_millis(1);
+    println!(
+        "Example: {} events per hour, with {:?} per block get, reading all, keys or one field",
+        n, latency
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, Reading>::new(config.clone(), Secrets::default());
+    let events = columnar::events(n).into_iter().map(|(key, i)| {
+        let reading = Reading {
+            sensor: key.device as u64,
+            celsius: (i % 40) as i64 - 10,
+            location: Some(format!("room {}", key.device % 7)),
+        };
+        (key, reading)
+    });
+    txn.extend(&mut builder, events.collect::<Vec<_>>())?;
+    let tree = builder.snapshot();
+    let keys = columnar::events(n);
+    let query = TimeRangeQuery {
+        min: keys[(n / 4) as usize].0.time,
+        max: keys[(3 * n / 4) as usize].0.time,
+    };
+    let hour = |key: &EventKey| key.time / HOUR;
+
+    let slow = LatencyStore::new(store, latency);
+    let forest = Forest::<ColumnarTT, _>::new(slow.clone(), BranchCache::new(0));
+    let t0 = Instant::now();
+    let mut per_hour = BTreeMap::<u64, u64>::new();
+    let mut per_sensor = BTreeMap::<u64, u64>::new();
+    for item in forest.iter_filtered(&tree, query.clone()) {
+        let (_, key, value) = item?;
+        *per_hour.entry(hour(&key)).or_default() += 1;
+        *per_sensor.entry(value.sensor).or_default() += 1;
+    }
+    println!("all\t{:.3}s", t0.elapsed().as_secs_f64());
+
+    let t0 = Instant::now();
+    let mut keys_per_hour = BTreeMap::<u64, u64>::new();
+    for item in KeyIter::new(slow, &tree, query.clone()) {
+        let (_, key) = item?;
+        *keys_per_hour.entry(hour(&key)).or_default() += 1;
+    }
+    println!("keys\t{:.3}s", t0.elapsed().as_secs_f64());
+    anyhow::ensure!(keys_per_hour == per_hour, "keys give different counts");
+
+    let t0 = Instant::now();
+    let root = tree.link().expect("not empty");
+    let projected = forest.load_tree::<Project<Sensor>>(Secrets::default(), root)?;
+    let mut field_per_sensor = BTreeMap::<u64, u64>::new();
+    for item in forest.iter_filtered(&projected, query) {
+        let (_, _, Project(value)) = item?;
+        *field_per_sensor.entry(value.sensor).or_default() += 1;
+    }
+    println!("field\t{:.3}s", t0.elapsed().as_secs_f64());
+    anyhow::ensure!(
+        field_per_sensor == per_sensor,
+        "the field gives different counts"
+    );
+    println!(
+        "{} events in {} hours from {} sensors",
+        per_hour.values().sum::<u64>(),
+        per_hour.len(),
+        per_sensor.len()
+    );
+    println!();
+    Ok(())
+}
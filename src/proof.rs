@@ -0,0 +1,151 @@
+//! Proving that an event is in a tree
+//!
+//! Links are hashes of the blocks they point to, so the root of a tree commits to every event in
+//! it. To convince someone who only knows the root that an event is at some offset, it is enough
+//! to give them the blocks on the path from the root to the leaf with the event: they can hash
+//! the root block to check it against the root, hash each child to check it against the link in
+//! its parent, and decode the event from the leaf. [prove] collects those blocks, and
+//! [verify_proof] does the checking, returning the event the proof is for.
+//!
+//! A proof is a few blocks no matter how large the tree is. It reveals the other events in the
+//! same leaf, and the summaries of the branches on the path.
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT},
+    partial,
+};
+
+/// The blocks from the root of a tree to the leaf with the event at an offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    pub root: Sha256Digest,
+    pub offset: u64,
+    /// the root block first, the leaf last
+    pub blocks: Vec<Box<[u8]>>,
+}
+
+impl Proof {
+    /// The number of bytes a verifier has to get
+    pub fn size(&self) -> usize {
+        self.blocks.iter().map(|block| block.len()).sum()
+    }
+}
+
+/// A proof that the event at the offset is in the tree
+pub fn prove<T, V, R>(store: &R, tree: &Tree<T, V>, offset: u64) -> anyhow::Result<Proof>
+where
+    T: TreeTypes<Link = Sha256Digest>,
+    R: ReadOnlyStore<Sha256Digest>,
+{
+    let root = tree
+        .link()
+        .ok_or_else(|| anyhow::anyhow!("an empty tree has no events"))?;
+    anyhow::ensure!(
+        offset < tree.count(),
+        "offset {} is not in a tree of {} events",
+        offset,
+        tree.count()
+    );
+    // an offset query loads exactly the path to the leaf with the offset
+    let query = OffsetRangeQuery::from(offset..=offset);
+    let blocks = partial::query_blocks(store, tree, &query)?
+        .iter()
+        .map(|link| store.get(link))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Proof {
+        root,
+        offset,
+        blocks,
+    })
+}
+
+/// Check the proof against a root from a trusted source, and return the event it proves
+pub fn verify_proof<T, V>(root: Sha256Digest, proof: &Proof) -> anyhow::Result<(T::Key, V)>
+where
+    T: TreeTypes<Link = Sha256Digest>,
+    V: BanyanValue,
+{
+    anyhow::ensure!(
+        proof.root == root,
+        "the proof is for {}, not {}",
+        proof.root,
+        root
+    );
+    // the store hashes each block, so a changed block is not found under the link to it
+    let mut store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    for block in &proof.blocks {
+        store.put(block.to_vec())?;
+    }
+    let forest = Forest::<T, _>::new(store, BranchCache::new(0));
+    let event = forest
+        .load_tree::<V>(Secrets::default(), root)
+        .and_then(|tree| forest.get(&tree, proof.offset))
+        .map_err(|cause| {
+            anyhow::anyhow!(
+                "the blocks do not lead from {} to offset {}: {}",
+                root,
+                proof.offset,
+                cause
+            )
+        })?;
+    event.ok_or_else(|| anyhow::anyhow!("no event at offset {}", proof.offset))
+}
+
+/// Prove an event in a large tree, and check the proof like a third party that only has the root
+pub fn proof_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!(
+        "Example: proving that an event is in a tree of {} events",
+        n
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, so the tree has a few levels
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, Secrets::default());
+    let events = columnar::events(n);
+    let offset = 12345;
+    let expected = events[offset as usize];
+    txn.extend(&mut builder, events)?;
+    let tree = builder.snapshot();
+    let root = tree.link().expect("not empty");
+
+    let proof = prove(&store, &tree, offset)?;
+    let event = verify_proof::<ColumnarTT, u64>(root, &proof)?;
+    anyhow::ensure!(event == expected, "the proof is for another event");
+    println!(
+        "event {:?} at offset {}, proven with {} blocks of {} bytes",
+        event,
+        offset,
+        proof.blocks.len(),
+        proof.size()
+    );
+
+    // a changed value in the leaf no longer matches the link in its parent
+    let mut forged = proof.clone();
+    let leaf = forged.blocks.last_mut().expect("at least one block");
+    let i = leaf.len() - 1;
+    leaf[i] ^= 1;
+    let err = verify_proof::<ColumnarTT, u64>(root, &forged).expect_err("forged leaf");
+    println!("changed leaf: {}", err);
+    // and the proof says nothing about another tree
+    let other = Sha256Digest::digest(b"another tree");
+    let err = verify_proof::<ColumnarTT, u64>(other, &proof).expect_err("other root");
+    println!("other root: {}", err);
+    println!();
+    Ok(())
+}
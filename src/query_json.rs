@@ -0,0 +1,347 @@
+//! Queries as json
+//!
+//! A query is a value that implements [Query], so it has to be built in code. A [JsonQuery] is
+//! the same thing as data, for a client that is not written in rust, or a command line:
+//!
+//! ```json
+//! {"and": [
+//!   {"time": {"from": 1600000000000, "to": 1600000100000}},
+//!   {"not": {"devices": [3, 17]}}
+//! ]}
+//! ```
+//!
+//! - `{"offset": {"from": a, "to": b}}`: offsets from `a`, up to `b` exclusive, or to the end
+//! - `{"time": {"from": a, "to": b}}`: times from `a` to `b` inclusive
+//! - `{"devices": [..]}`: events from one of the devices
+//! - `{"and": [..]}`, `{"or": [..]}` and `{"not": query}`, and `"all"`
+//!
+//! [JsonQuery::to_query] turns it into the queries of banyan and this crate, combined with
+//! [AndQuery] and [OrQuery], so it prunes branches by their summaries like a query written in
+//! code. Only `not` can not prune: that a branch has some events that match a query does not say
+//! that all of them do, so it only filters the keys of the leaves.
+//!
+//! The `select` command takes them, and so does the [server](crate::server), which runs them
+//! where the blocks are, so a remote client gets the pruning instead of a full scan.
+//! [JsonQuery::canonical] writes equal queries the same way, so a [ResultCache] can use them as
+//! keys.
+use std::{collections::BTreeSet, sync::Arc};
+
+use banyan::{
+    index::{BranchIndex, LeafIndex},
+    query::{AllQuery, AndQuery, OffsetRangeQuery, OrQuery, Query},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use serde_json::{json, Value};
+
+use crate::{
+    cache::CacheStats,
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    result_cache::{self, ResultCache},
+};
+
+/// A query over events with an [EventKey](columnar::EventKey), as parsed from json
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonQuery {
+    All,
+    Offset { from: u64, to: Option<u64> },
+    Time { from: u64, to: u64 },
+    Devices(BTreeSet<u32>),
+    And(Vec<JsonQuery>),
+    Or(Vec<JsonQuery>),
+    Not(Box<JsonQuery>),
+}
+
+/// Events from one of the devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceQuery(pub BTreeSet<u32>);
+
+impl Query<ColumnarTT> for DeviceQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<ColumnarTT>, res: &mut [bool]) {
+        for (i, key) in index.keys().enumerate() {
+            res[i] = res[i] && self.0.contains(&key.device);
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<ColumnarTT>, res: &mut [bool]) {
+        for (i, summary) in index.summaries().enumerate() {
+            let devices = summary.min_device..=summary.max_device;
+            res[i] = res[i] && self.0.range(devices).next().is_some();
+        }
+    }
+}
+
+/// Everything the inner query does not match
+#[derive(Debug, Clone)]
+pub struct NotQuery<Q>(pub Q);
+
+impl<Q: Query<ColumnarTT>> Query<ColumnarTT> for NotQuery<Q> {
+    fn containing(&self, offset: u64, index: &LeafIndex<ColumnarTT>, res: &mut [bool]) {
+        let mut inner = vec![true; res.len()];
+        self.0.containing(offset, index, &mut inner);
+        for (res, inner) in res.iter_mut().zip(inner) {
+            *res = *res && !inner;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, _index: &BranchIndex<ColumnarTT>, _res: &mut [bool]) {
+        // a summary only says whether some events might match the inner query, so any branch
+        // might have events that do not
+    }
+}
+
+/// Parse a `{"from": .., "to": ..}` range
+fn range(value: &Value, what: &str) -> anyhow::Result<(Option<u64>, Option<u64>)> {
+    let bound = |name: &str| -> anyhow::Result<Option<u64>> {
+        match &value[name] {
+            Value::Null => Ok(None),
+            bound => Ok(Some(bound.as_u64().ok_or_else(|| {
+                anyhow::anyhow!("{}.{} must be a positive integer", what, name)
+            })?)),
+        }
+    };
+    anyhow::ensure!(value.is_object(), "{} must be an object", what);
+    Ok((bound("from")?, bound("to")?))
+}
+
+impl JsonQuery {
+    /// Parse a query from json text
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        Self::from_json(&serde_json::from_str(text)?)
+    }
+
+    pub fn from_json(value: &Value) -> anyhow::Result<Self> {
+        if value.as_str() == Some("all") {
+            return Ok(Self::All);
+        }
+        let fields = value
+            .as_object()
+            .filter(|fields| fields.len() == 1)
+            .ok_or_else(|| anyhow::anyhow!("a query is \"all\" or an object with one field"))?;
+        let (name, value) = fields.iter().next().expect("one field");
+        let list = |value: &Value| -> anyhow::Result<Vec<Self>> {
+            value
+                .as_array()
+                .ok_or_else(|| anyhow::anyhow!("{} must be a list of queries", name))?
+                .iter()
+                .map(Self::from_json)
+                .collect()
+        };
+        Ok(match name.as_str() {
+            "offset" => {
+                let (from, to) = range(value, name)?;
+                Self::Offset {
+                    from: from.unwrap_or_default(),
+                    to,
+                }
+            }
+            "time" => {
+                let (from, to) = range(value, name)?;
+                Self::Time {
+                    from: from.unwrap_or_default(),
+                    to: to.unwrap_or(u64::MAX),
+                }
+            }
+            "devices" => Self::Devices(
+                value
+                    .as_array()
+                    .ok_or_else(|| anyhow::anyhow!("devices must be a list"))?
+                    .iter()
+                    .map(|device| {
+                        device
+                            .as_u64()
+                            .and_then(|device| u32::try_from(device).ok())
+                            .ok_or_else(|| anyhow::anyhow!("invalid device {}", device))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            "and" => Self::And(list(value)?),
+            "or" => Self::Or(list(value)?),
+            "not" => Self::Not(Box::new(Self::from_json(value)?)),
+            other => anyhow::bail!("unknown query {}", other),
+        })
+    }
+
+    /// The query as a combination of queries that prune by the index
+    pub fn to_query(&self) -> Arc<dyn Query<ColumnarTT>> {
+        // an empty `and` is everything, an empty `or` is nothing
+        let combine = |queries: &[Self], and: bool| {
+            let mut queries = queries.iter().map(Self::to_query);
+            let first = queries.next().unwrap_or_else(|| match and {
+                true => Arc::new(AllQuery),
+                false => Arc::new(banyan::query::EmptyQuery),
+            });
+            queries.fold(first, |a, b| -> Arc<dyn Query<ColumnarTT>> {
+                match and {
+                    true => Arc::new(AndQuery(a, b)),
+                    false => Arc::new(OrQuery(a, b)),
+                }
+            })
+        };
+        match self {
+            Self::All => Arc::new(AllQuery),
+            Self::Offset { from, to: Some(to) } => Arc::new(OffsetRangeQuery::from(*from..*to)),
+            Self::Offset { from, to: None } => Arc::new(OffsetRangeQuery::from(*from..)),
+            Self::Time { from, to } => Arc::new(TimeRangeQuery {
+                min: *from,
+                max: *to,
+            }),
+            Self::Devices(devices) => Arc::new(DeviceQuery(devices.clone())),
+            Self::And(queries) => combine(queries, true),
+            Self::Or(queries) => combine(queries, false),
+            Self::Not(query) => Arc::new(NotQuery(query.to_query())),
+        }
+    }
+
+    /// The query as json, which [JsonQuery::from_json] parses to the same query
+    pub fn to_json(&self) -> Value {
+        let list =
+            |queries: &[Self]| Value::from(queries.iter().map(Self::to_json).collect::<Vec<_>>());
+        match self {
+            Self::All => json!("all"),
+            Self::Offset { from, to } => json!({ "offset": { "from": from, "to": to } }),
+            Self::Time { from, to } => json!({ "time": { "from": from, "to": to } }),
+            Self::Devices(devices) => json!({ "devices": devices }),
+            Self::And(queries) => json!({ "and": list(queries) }),
+            Self::Or(queries) => json!({ "or": list(queries) }),
+            Self::Not(query) => json!({ "not": query.to_json() }),
+        }
+    }
+
+    /// The same query, written the same way however it was written before: nested `and`s and
+    /// `or`s are flattened, their parts sorted and deduplicated, and double negations removed
+    pub fn canonical(&self) -> Self {
+        let flatten = |queries: &[Self], and: bool| {
+            let mut parts = Vec::new();
+            for query in queries.iter().map(Self::canonical) {
+                match (query, and) {
+                    (Self::And(inner), true) | (Self::Or(inner), false) => parts.extend(inner),
+                    (Self::All, true) => {}
+                    (query, _) => parts.push(query),
+                }
+            }
+            let mut parts = parts
+                .into_iter()
+                .map(|query| (query.to_json().to_string(), query))
+                .collect::<Vec<_>>();
+            parts.sort_by(|a, b| a.0.cmp(&b.0));
+            parts.dedup_by(|a, b| a.0 == b.0);
+            let mut parts = parts
+                .into_iter()
+                .map(|(_, query)| query)
+                .collect::<Vec<_>>();
+            match (parts.len(), and) {
+                (0, true) => Self::All,
+                (1, _) => parts.remove(0),
+                _ if !and && parts.contains(&Self::All) => Self::All,
+                (_, true) => Self::And(parts),
+                (_, false) => Self::Or(parts),
+            }
+        };
+        match self {
+            Self::And(queries) => flatten(queries, true),
+            Self::Or(queries) => flatten(queries, false),
+            Self::Not(query) => match query.canonical() {
+                Self::Not(inner) => *inner,
+                query => Self::Not(Box::new(query)),
+            },
+            query => query.clone(),
+        }
+    }
+}
+
+/// Print the events of a columnar tree that match a json query, one per line. With a cache, a
+/// query that was run on the same root before is not run again
+pub fn print_select<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    query: &JsonQuery,
+    cache: Option<&ResultCache>,
+) -> anyhow::Result<()> {
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    println!("offset\ttime\tdevice\tvalue");
+    let rows = match cache {
+        Some(cache) => cache.select(&forest, &tree, query)?,
+        None => Arc::new(result_cache::run(&forest, &tree, query)?),
+    };
+    for (offset, key, value) in rows.iter() {
+        println!("{}\t{}\t{}\t{}", offset, key.time, key.device, value);
+    }
+    Ok(())
+}
+
+/// Run json queries, and compare the blocks they read and their results with a full scan
+pub fn query_json_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: json queries on {} events", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, so there is something to prune
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, Secrets::default());
+    let events = columnar::events(n);
+    txn.extend(&mut builder, events.iter().cloned())?;
+    let tree = builder.snapshot();
+    let (from, to) = (events[5000].0.time, events[5999].0.time);
+    let queries = [
+        format!(r#"{{"time": {{"from": {}, "to": {}}}}}"#, from, to),
+        format!(
+            r#"{{"and": [{{"time": {{"from": {}, "to": {}}}}}, {{"not": {{"devices": [3, 17]}}}}]}}"#,
+            from, to
+        ),
+        r#"{"or": [{"offset": {"from": 10, "to": 20}}, {"offset": {"from": 99990}}]}"#.to_string(),
+    ];
+    println!("events\tblocks\tquery");
+    for text in queries {
+        let query = JsonQuery::parse(&text)?;
+        let stats = CacheStats::new();
+        let forest = Forest::<ColumnarTT, _>::new(stats.store(store.clone()), BranchCache::new(0));
+        let found = forest
+            .iter_filtered(&tree, stats.query(query.to_query()))
+            .map(|item| item.map(|(offset, _, _)| offset))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // the same query, one event at a time
+        let expected = events
+            .iter()
+            .enumerate()
+            .filter(|(offset, (key, _))| query.matches(*offset as u64, key))
+            .map(|(offset, _)| offset as u64)
+            .collect::<Vec<_>>();
+        anyhow::ensure!(found == expected, "{} finds other events than a scan", text);
+        let blocks = stats.misses() + stats.leaves();
+        println!("{}\t{}\t{}", found.len(), blocks, text);
+    }
+    let stats = CacheStats::new();
+    let forest = Forest::<ColumnarTT, _>::new(stats.store(store), BranchCache::new(0));
+    let all = forest.iter_filtered(&tree, stats.query(AllQuery)).count();
+    let blocks = stats.misses() + stats.leaves();
+    println!("{}\t{}\tfull scan", all, blocks);
+    anyhow::ensure!(JsonQuery::parse(r#"{"time": 1}"#).is_err(), "bad query");
+    println!();
+    Ok(())
+}
+
+impl JsonQuery {
+    /// Whether an event matches, to check the pruning against
+    fn matches(&self, offset: u64, key: &columnar::EventKey) -> bool {
+        match self {
+            Self::All => true,
+            Self::Offset { from, to } => offset >= *from && to.is_none_or(|to| offset < to),
+            Self::Time { from, to } => key.time >= *from && key.time <= *to,
+            Self::Devices(devices) => devices.contains(&key.device),
+            Self::And(queries) => queries.iter().all(|query| query.matches(offset, key)),
+            Self::Or(queries) => queries.iter().any(|query| query.matches(offset, key)),
+            Self::Not(query) => !query.matches(offset, key),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Range checks of many keys at once
+//!
+//! A query on a leaf checks every key, and for large leaves of numeric keys, like the range query
+//! of the custom index example, that loop is where the time goes. [in_range] marks the keys of a
+//! slice that are in an inclusive range, and with the `simd` feature on a machine with avx2, it
+//! checks four keys per instruction. Without either, or for the keys that are left over, it is a
+//! plain loop, and the result is the same.
+//!
+//! This is a template for fast [Query](banyan::query::Query) impls: get the keys of the leaf as a
+//! slice of numbers, and do the check on the whole slice instead of a key at a time. std::simd
+//! would be simpler, but is not stable yet.
+use std::time::Instant;
+
+/// Clear the marks of the keys that are not between `min` and `max`, inclusive
+pub fn in_range(keys: &[u64], min: u64, max: u64, res: &mut [bool]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx2") {
+        // safe, since the cpu has avx2
+        let done = unsafe { avx2::in_range(keys, min, max, res) };
+        return in_range_scalar(&keys[done..], min, max, &mut res[done..]);
+    }
+    in_range_scalar(keys, min, max, res)
+}
+
+/// [in_range] a key at a time
+pub fn in_range_scalar(keys: &[u64], min: u64, max: u64, res: &mut [bool]) {
+    for (key, res) in keys.iter().zip(res) {
+        *res = *res && *key >= min && *key <= max;
+    }
+}
+
+/// Whether [in_range] checks more than one key at a time
+pub fn vectorized() -> bool {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx2") {
+        return true;
+    }
+    false
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    /// For each four bits of keys outside of the range, the marks of the keys inside of it, as
+    /// the bytes of a little endian u32
+    const INSIDE: [u32; 16] = {
+        let mut inside = [0u32; 16];
+        let mut bits = 0;
+        while bits < 16 {
+            let mut j = 0;
+            while j < 4 {
+                if bits & (1 << j) == 0 {
+                    inside[bits] |= 1 << (8 * j);
+                }
+                j += 1;
+            }
+            bits += 1;
+        }
+        inside
+    };
+
+    /// Check the keys four at a time, and return how many were checked
+    ///
+    /// avx2 only compares signed numbers, so the keys and bounds are shifted by flipping the sign
+    /// bit, which keeps their order as unsigned numbers.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn in_range(keys: &[u64], min: u64, max: u64, res: &mut [bool]) -> usize {
+        let n = keys.len().min(res.len()) / 4 * 4;
+        let sign = _mm256_set1_epi64x(i64::MIN);
+        let min = _mm256_set1_epi64x((min ^ (1 << 63)) as i64);
+        let max = _mm256_set1_epi64x((max ^ (1 << 63)) as i64);
+        for i in (0..n).step_by(4) {
+            let x = _mm256_loadu_si256(keys.as_ptr().add(i) as *const __m256i);
+            let x = _mm256_xor_si256(x, sign);
+            let outside = _mm256_or_si256(_mm256_cmpgt_epi64(x, max), _mm256_cmpgt_epi64(min, x));
+            // a bit per key, set if it is outside of the range
+            let outside = _mm256_movemask_pd(_mm256_castsi256_pd(outside));
+            // the four marks as one u32, a byte of 0 or 1 each. The and keeps every byte a valid
+            // bool
+            let marks = res.as_mut_ptr().add(i) as *mut u32;
+            marks.write_unaligned(marks.read_unaligned() & INSIDE[outside as usize]);
+        }
+        n
+    }
+}
+
+/// Check the keys of a large leaf many times, a key at a time and with [in_range]
+pub fn range_filter_example() -> anyhow::Result<()> {
+    let keys = 1 << 16;
+    let rounds = 1000;
+    println!(
+        "Example: checking a range of {} keys {} times, vectorized: {}",
+        keys,
+        rounds,
+        vectorized()
+    );
+    let mut rng = 0x9e3779b97f4a7c15u64;
+    let keys = (0..keys)
+        .map(|_| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            // the edges of the range are the interesting part for the sign trick
+            match rng % 4 {
+                0 => rng,
+                _ => rng >> 48,
+            }
+        })
+        .collect::<Vec<_>>();
+    let (min, max) = (1000, u64::MAX - (1 << 62));
+    let mut expected = vec![true; keys.len()];
+    let mut res = vec![true; keys.len()];
+    println!("check\ttime");
+    for (name, check) in [
+        (
+            "scalar",
+            in_range_scalar as fn(&[u64], u64, u64, &mut [bool]),
+        ),
+        ("in_range", in_range),
+    ] {
+        let t0 = Instant::now();
+        for _ in 0..rounds {
+            res.fill(true);
+            check(&keys, min, max, &mut res);
+        }
+        println!("{}\t{:.3}s", name, t0.elapsed().as_secs_f64());
+        if name == "scalar" {
+            expected.copy_from_slice(&res);
+        }
+    }
+    anyhow::ensure!(res == expected, "in_range marked other keys");
+    // keys that were not marked stay that way
+    let mut some = (0..keys.len()).map(|i| i % 3 == 0).collect::<Vec<_>>();
+    in_range(&keys, min, max, &mut some);
+    anyhow::ensure!(
+        some.iter()
+            .zip(&expected)
+            .enumerate()
+            .all(|(i, (some, all))| *some == (*all && i % 3 == 0)),
+        "in_range marked keys that were not marked before"
+    );
+    let matching = expected.iter().filter(|x| **x).count();
+    println!("{} of {} keys in range", matching, keys.len());
+    println!();
+    Ok(())
+}
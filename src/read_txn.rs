@@ -0,0 +1,210 @@
+//! Reading several trees at the same version
+//!
+//! A query that uses more than one tree, like a primary tree and a secondary index or a view of
+//! it, needs roots that belong together. With a root per tree, a writer sets them one after the
+//! other, and a reader that gets one root before and one after an update sees an index that does
+//! not match its tree. [RootSet] is one block with the roots of all trees that belong together,
+//! and a writer [publish]es a new set with a single compare-and-swap of one name, so a set
+//! changes as a whole or not at all.
+//!
+//! A [ReadTxn] reads the set once when it is opened, and every tree it loads is from that set,
+//! however long the query takes and whatever the writers do in the meantime. The blocks of the
+//! pinned roots have to stay in the store until it is done, which they do unless something
+//! collects garbage.
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey},
+    roots::{ManifestFile, RootStore},
+};
+
+/// The roots of trees that belong together, by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, DagCbor)]
+pub struct RootSet {
+    pub roots: BTreeMap<String, Sha256Digest>,
+}
+
+/// Write a set of roots, and make it the current one under `name` if the current one is still
+/// `expected`. Returns the link of the set
+pub fn publish<W: BlockWriter<Sha256Digest>, M: RootStore<Sha256Digest>>(
+    writer: &mut W,
+    roots: &M,
+    name: &str,
+    expected: Option<Sha256Digest>,
+    set: &RootSet,
+) -> anyhow::Result<Sha256Digest> {
+    let link = writer.put(DagCborCodec.encode(set)?)?;
+    roots.compare_and_swap(name, expected, link)?;
+    Ok(link)
+}
+
+/// The roots of a [RootSet] as they were when it was opened, see the module docs
+#[derive(Debug, Clone)]
+pub struct ReadTxn {
+    /// the link of the set, `None` if nothing was published yet
+    link: Option<Sha256Digest>,
+    set: RootSet,
+}
+
+impl ReadTxn {
+    /// Pin the current set with this name
+    pub fn open<S: ReadOnlyStore<Sha256Digest>, M: RootStore<Sha256Digest>>(
+        store: &S,
+        roots: &M,
+        name: &str,
+    ) -> anyhow::Result<Self> {
+        let link = roots.root(name)?;
+        let set = match link {
+            Some(link) => DagCborCodec
+                .decode(&store.get(&link)?)
+                .map_err(|cause| anyhow::anyhow!("{} is not a root set: {}", link, cause))?,
+            None => RootSet::default(),
+        };
+        Ok(Self { link, set })
+    }
+
+    pub fn link(&self) -> Option<Sha256Digest> {
+        self.link
+    }
+
+    /// The pinned root of a tree, if the set has one
+    pub fn root(&self, tree: &str) -> Option<Sha256Digest> {
+        self.set.roots.get(tree).cloned()
+    }
+
+    /// Load a tree at its pinned root. A tree that is not in the set is empty
+    pub fn load<T, R, V>(
+        &self,
+        forest: &Forest<T, R>,
+        secrets: &Secrets,
+        tree: &str,
+    ) -> anyhow::Result<Tree<T, V>>
+    where
+        T: TreeTypes<Link = Sha256Digest>,
+        R: ReadOnlyStore<Sha256Digest>,
+        V: BanyanValue,
+    {
+        match self.root(tree) {
+            Some(root) => forest.load_tree(secrets.clone(), root),
+            None => Ok(Tree::default()),
+        }
+    }
+}
+
+/// A writer that appends batches to a tree and to an index of the batches, and a reader that
+/// checks that the index always matches the tree
+pub fn read_txn_example(config: &Config) -> anyhow::Result<()> {
+    let batches = 50u64;
+    let batch = 1000u64;
+    println!(
+        "Example: reading a tree and its index of {} batches while they are written",
+        batches
+    );
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let path =
+        std::env::temp_dir().join(format!("banyan-read-txn-{}.manifest", std::process::id()));
+    let manifest = ManifestFile::new(&path);
+    let done = AtomicBool::new(false);
+    let secrets = Secrets::default();
+    let events = columnar::events(batches * batch);
+
+    let write = || {
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let mut txn = Transaction::new(forest, store.clone());
+        let mut events_builder =
+            StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets.clone());
+        // the last key and the number of events after every batch
+        let mut batches_builder =
+            StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets.clone());
+        let mut current = None;
+        for part in events.chunks(batch as usize) {
+            txn.extend(&mut events_builder, part.iter().cloned())?;
+            let tree = events_builder.snapshot();
+            let last = part.last().expect("not empty").0;
+            txn.extend(&mut batches_builder, Some((last, tree.count())))?;
+            let mut set = RootSet::default();
+            set.roots
+                .insert("events".into(), tree.link().expect("not empty"));
+            set.roots.insert(
+                "batches".into(),
+                batches_builder.snapshot().link().expect("not empty"),
+            );
+            current = Some(publish(
+                &mut store.clone(),
+                &manifest,
+                "events",
+                current,
+                &set,
+            )?);
+        }
+        done.store(true, Ordering::SeqCst);
+        anyhow::Ok(())
+    };
+    let read = || {
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let mut reads = 0;
+        let mut versions = Vec::new();
+        while !done.load(Ordering::SeqCst) {
+            let txn = ReadTxn::open(&store, &manifest, "events")?;
+            let Some(link) = txn.link() else {
+                thread::yield_now();
+                continue;
+            };
+            let tree = txn.load::<_, _, u64>(&forest, &secrets, "events")?;
+            // a writer that publishes in the meantime does not change what this reads
+            thread::yield_now();
+            let index = txn.load::<_, _, u64>(&forest, &secrets, "batches")?;
+            let last = |tree: &Tree<ColumnarTT, u64>| {
+                let count = tree.count();
+                forest
+                    .iter_filtered(tree, OffsetRangeQuery::from(count - 1..count))
+                    .next()
+                    .transpose()
+            };
+            let (_, last_key, count): (u64, EventKey, u64) = last(&index)?.expect("not empty");
+            let (_, tree_key, _) = last(&tree)?.expect("not empty");
+            anyhow::ensure!(
+                count == tree.count() && last_key == tree_key,
+                "the index of {} has {} events, the tree {}",
+                link,
+                count,
+                tree.count()
+            );
+            reads += 1;
+            if versions.last() != Some(&index.count()) {
+                versions.push(index.count());
+            }
+        }
+        anyhow::Ok((reads, versions.len()))
+    };
+    let (written, read) = thread::scope(|scope| {
+        let writer = scope.spawn(write);
+        let reader = scope.spawn(read);
+        (writer.join(), reader.join())
+    });
+    written.expect("writer panicked")?;
+    let (reads, versions) = read.expect("reader panicked")?;
+    println!(
+        "{} consistent reads of {} of the {} versions",
+        reads, versions, batches
+    );
+    let txn = ReadTxn::open(&store, &manifest, "events")?;
+    let forest = Forest::<ColumnarTT, _>::new(store, BranchCache::new(1 << 20));
+    let tree = txn.load::<_, _, u64>(&forest, &secrets, "events")?;
+    anyhow::ensure!(tree.count() == batches * batch, "events are missing");
+    let _ = std::fs::remove_file(&path);
+    println!();
+    Ok(())
+}
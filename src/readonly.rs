@@ -0,0 +1,203 @@
+//! Stores for commands that only read
+//!
+//! Reading a tree only needs a [Forest](banyan::Forest), which only needs a [ReadOnlyStore]. The
+//! query commands take any read-only store, so a store that can not write, like an http gateway
+//! or a CAR file, works for them just as well as kubo. The [Source] says which one, and defaults
+//! to the store of the backend the examples write to, see `--source` of the commands.
+//!
+//! The blocks can come from anywhere, so [store] checks the hash of every block, see
+//! [VerifyingStore].
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use banyan::store::ReadOnlyStore;
+use banyan_utils::tags::Sha256Digest;
+use reqwest::Url;
+
+use crate::{
+    cancel,
+    car::CarStore,
+    fs_store::FsStore,
+    gateway::GatewayStore,
+    kubo::KuboStore,
+    sharded::{self, ShardedStore},
+    sqlite_store::SqliteStore,
+    trace::TracingStore,
+    verify::VerifyingStore,
+};
+
+/// A store wrapper that hides the [BlockWriter](banyan::store::BlockWriter) of the inner store
+#[derive(Clone)]
+pub struct ReadOnly<S>(S);
+
+impl<L, S: ReadOnlyStore<L>> ReadOnlyStore<L> for ReadOnly<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.0.get(link)
+    }
+}
+
+/// Where the query commands read blocks from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// the kubo at the [endpoint](crate::kubo::endpoint), as `kubo`
+    Kubo,
+    /// the file store in a directory, as `fs:<dir>`
+    Fs(PathBuf),
+    /// the sqlite store in a directory, as `sqlite:<dir>`
+    Sqlite(PathBuf),
+    /// four file stores in a directory, as `sharded:<dir>`
+    Sharded(PathBuf),
+    /// a CAR file, as `car:<file>`
+    Car(PathBuf),
+    /// an http gateway, as its url
+    Gateway(Url),
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = |path: &str| {
+            anyhow::ensure!(!path.is_empty(), "{} needs a path", s);
+            Ok(PathBuf::from(path))
+        };
+        Ok(match s.split_once(':') {
+            _ if s == "kubo" => Self::Kubo,
+            Some(("fs", dir)) => Self::Fs(path(dir)?),
+            Some(("sqlite", dir)) => Self::Sqlite(path(dir)?),
+            Some(("sharded", dir)) => Self::Sharded(path(dir)?),
+            Some(("car", file)) => Self::Car(path(file)?),
+            Some(("http" | "https", _)) => Self::Gateway(s.parse()?),
+            _ => anyhow::bail!(
+                "unknown source {}, not kubo, fs:<dir>, sqlite:<dir>, sharded:<dir>, car:<file> \
+                 or an http url",
+                s
+            ),
+        })
+    }
+}
+
+/// The store of a [Source]
+#[derive(Clone)]
+pub enum SourceStore {
+    Kubo(KuboStore),
+    Fs(FsStore<Sha256Digest>),
+    Sqlite(SqliteStore<Sha256Digest>),
+    Sharded(ShardedStore<FsStore<Sha256Digest>>),
+    Car(CarStore),
+    Gateway(GatewayStore),
+}
+
+impl ReadOnlyStore<Sha256Digest> for SourceStore {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        match self {
+            Self::Kubo(store) => store.get(link),
+            Self::Fs(store) => store.get(link),
+            Self::Sqlite(store) => store.get(link),
+            Self::Sharded(store) => store.get(link),
+            Self::Car(store) => store.get(link),
+            Self::Gateway(store) => store.get(link),
+        }
+    }
+}
+
+/// A directory that has to be there already, since a query of a new store finds nothing
+fn existing(dir: &Path) -> anyhow::Result<&Path> {
+    anyhow::ensure!(dir.is_dir(), "there is no store in {}", dir.display());
+    Ok(dir)
+}
+
+impl Source {
+    /// Open the store, without creating anything
+    pub fn open(&self) -> anyhow::Result<SourceStore> {
+        Ok(match self {
+            Self::Kubo => SourceStore::Kubo(KuboStore::from_env()?),
+            Self::Fs(dir) => SourceStore::Fs(FsStore::open(existing(dir)?)?),
+            Self::Sqlite(dir) => {
+                let file = existing(dir)?.join("blocks.sqlite");
+                anyhow::ensure!(
+                    file.is_file(),
+                    "there is no sqlite store in {}",
+                    dir.display()
+                );
+                SourceStore::Sqlite(SqliteStore::open(file)?)
+            }
+            Self::Sharded(dir) => SourceStore::Sharded(sharded::open_fs(existing(dir)?, 4)?),
+            Self::Car(file) => SourceStore::Car(CarStore::open(file)?),
+            Self::Gateway(url) => SourceStore::Gateway(GatewayStore::new(url.clone())?),
+        })
+    }
+}
+
+/// The store for the query commands, with the blocks of the source. With a timeout, gets fail
+/// once it has passed, so a query can not hang on a stuck kubo or gateway
+pub fn store(
+    source: &Source,
+    timeout: Option<Duration>,
+) -> anyhow::Result<impl ReadOnlyStore<Sha256Digest>> {
+    let store = VerifyingStore::new(source.open()?);
+    Ok(TracingStore::new(ReadOnly(cancel::with_timeout(
+        store, timeout,
+    ))))
+}
+
+#[cfg(test)]
+mod tests {
+    use banyan::{
+        query::AllQuery,
+        store::{BranchCache, MemStore},
+        Config, Forest, Secrets, StreamBuilder, Transaction,
+    };
+
+    use super::*;
+    use crate::{
+        car,
+        columnar::{self, ColumnarTT},
+    };
+
+    #[test]
+    fn sources() {
+        assert_eq!("kubo".parse::<Source>().unwrap(), Source::Kubo);
+        assert_eq!(
+            "car:/tmp/a.car".parse::<Source>().unwrap(),
+            Source::Car("/tmp/a.car".into())
+        );
+        assert_eq!(
+            "fs:banyan-data".parse::<Source>().unwrap(),
+            Source::Fs("banyan-data".into())
+        );
+        assert!(matches!(
+            "https://ipfs.io".parse::<Source>().unwrap(),
+            Source::Gateway(_)
+        ));
+        assert!("fs:".parse::<Source>().is_err());
+        assert!("ftp://example.com".parse::<Source>().is_err());
+    }
+
+    #[test]
+    fn query_a_car() {
+        let mem = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let forest = Forest::<ColumnarTT, _>::new(mem.clone(), BranchCache::new(1 << 20));
+        let mut txn = Transaction::new(forest, mem.clone());
+        let config = Config {
+            zstd_level: 3,
+            ..Config::debug_fast()
+        };
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, Secrets::default());
+        txn.extend(&mut builder, columnar::events(1000)).unwrap();
+        let root = builder.snapshot().link().unwrap();
+        let file = std::env::temp_dir().join(format!("banyan-query-{}.car", std::process::id()));
+        car::write_car(&mem, &[root], &file).unwrap();
+
+        // no kubo, and nothing that can write
+        let source = format!("car:{}", file.display()).parse::<Source>().unwrap();
+        let store = store(&source, None).unwrap();
+        let forest = Forest::<ColumnarTT, _>::new(store, BranchCache::new(1 << 20));
+        let tree = forest.load_tree::<u64>(Secrets::default(), root).unwrap();
+        assert_eq!(forest.iter_filtered(&tree, AllQuery).count(), 1000);
+        std::fs::remove_file(&file).unwrap();
+    }
+}
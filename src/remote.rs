@@ -0,0 +1,400 @@
+//! A writer and a reader on different machines, each with its own kubo
+//!
+//! The writer appends to a stream, records a snapshot after every batch, pins it, and publishes
+//! the link of the newest snapshot record. The reader polls for the published link, and reads the
+//! events it has not seen yet. All blocks the reader does not have yet come from the writer's kubo
+//! via bitswap, without the reader knowing where they are.
+//!
+//! The link is published either with IPNS, which needs nothing but the two kubo nodes but can
+//! take a while to propagate, or in a manifest file in a shared directory. Only the manifest is
+//! updated with a compare-and-swap, so a second writer fails instead of overwriting the first.
+//!
+//! Both are polled. For updates as soon as they happen, the writer can also announce each new
+//! snapshot on a pubsub topic, which kubo runs over libp2p gossipsub. The reader subscribes to it
+//! via its own kubo, and uses the channel only once, to catch up with what was published before
+//! it joined. Anyone can publish on a topic, so the reader only accepts announcements from the
+//! peer id of the writer's kubo. kubo signs its messages, and drops messages with a bad signature.
+//!
+//! That only says which kubo sent the announcement, not who wrote the snapshot. With a signing
+//! key, the writer publishes and pins a [signed] snapshot instead of the record, and a reader with
+//! the writer's public key skips every snapshot that is not signed with it.
+use std::{
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    chacha20::Key as SecretKey, query::OffsetRangeQuery, store::BranchCache, Config, Forest,
+    Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use libipld::cid::multibase::{self, Base};
+use serde_json::Value;
+
+use crate::{
+    batch::{self, BatchQuery, BatchStore},
+    health,
+    kubo::{self, KuboStore},
+    roots::{ManifestFile, RootStore},
+    signed,
+    snapshots::{self, LogTT},
+    tenants,
+};
+
+/// Announce a new head on a pubsub topic
+fn announce(topic: &str, head: &Sha256Digest) -> anyhow::Result<()> {
+    // kubo wants the topic multibase encoded, and the message as a file
+    let topic = multibase::encode(Base::Base64Url, topic);
+    let part = reqwest::blocking::multipart::Part::bytes(head.to_string().into_bytes());
+    let form = reqwest::blocking::multipart::Form::new().part("data", part);
+    let response = kubo::endpoint()?
+        .post("pubsub/pub", &[("arg", &topic)])
+        .multipart(form)
+        .send()?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "kubo pubsub/pub failed: {}",
+        response.text()?
+    );
+    Ok(())
+}
+
+/// Subscribe to a pubsub topic and return the heads announced by the peer as they arrive
+///
+/// Announcements from other peers, and announcements that are not a link, are skipped.
+fn subscribe(
+    topic: &str,
+    peer: String,
+) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Sha256Digest>>> {
+    let topic = multibase::encode(Base::Base64Url, topic);
+    // no timeout, the response is an endless stream of json messages, one per line
+    let response = kubo::endpoint()?
+        .post_streaming("pubsub/sub", &[("arg", &topic)])
+        .send()?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "kubo pubsub/sub failed, is pubsub enabled? {}",
+        response.status()
+    );
+    let heads = BufReader::new(response).lines().filter_map(move |line| {
+        let parse = |line: String| -> anyhow::Result<Sha256Digest> {
+            let message: Value = serde_json::from_str(&line)?;
+            let from = message["from"].as_str().unwrap_or_default();
+            anyhow::ensure!(from == peer, "announced by {}", from);
+            let data = message["data"].as_str().unwrap_or_default();
+            let (_, data) = multibase::decode(data)?;
+            std::str::from_utf8(&data)?.trim().parse()
+        };
+        match line {
+            Ok(line) => match parse(line) {
+                Ok(head) => Some(Ok(head)),
+                Err(cause) => {
+                    eprintln!("ignoring announcement: {}", cause);
+                    None
+                }
+            },
+            Err(cause) => Some(Err(cause.into())),
+        }
+    });
+    Ok(heads)
+}
+
+/// Where the link of the newest snapshot record is published
+#[derive(Debug, Clone)]
+pub enum Channel {
+    /// an IPNS name. For the writer this is the name of a key in its kubo, for the reader the
+    /// name it resolves, like `k51...`. IPNS has no compare-and-swap, so there must only be one
+    /// writer per key
+    Ipns(String),
+    /// a manifest file with the link as the root `head`, or `tenant/head` for a tenant. Several
+    /// writers can share it, since updates are a compare-and-swap
+    Manifest(ManifestFile, String),
+}
+
+/// The name of the root in the manifest file
+const HEAD: &str = "head";
+
+impl Channel {
+    /// The channel from the command line options, exactly one of which has to be given. A tenant
+    /// needs a manifest, an IPNS key is already specific to one stream
+    pub fn from_options(
+        ipns: Option<String>,
+        manifest: Option<PathBuf>,
+        tenant: Option<String>,
+    ) -> anyhow::Result<Self> {
+        match (ipns, manifest, tenant) {
+            (Some(name), None, None) => Ok(Self::Ipns(name)),
+            (None, Some(path), tenant) => {
+                let head = match tenant {
+                    Some(tenant) => tenants::scoped(&tenant, HEAD)?,
+                    None => HEAD.to_string(),
+                };
+                Ok(Self::Manifest(ManifestFile::new(path), head))
+            }
+            (Some(_), None, Some(_)) => anyhow::bail!("tenants need a manifest file"),
+            _ => anyhow::bail!("need either an IPNS name or a manifest file"),
+        }
+    }
+
+    /// Publish the new head, which was built on `expected`
+    fn publish(&self, expected: Option<Sha256Digest>, head: &Sha256Digest) -> anyhow::Result<()> {
+        match self {
+            Self::Ipns(key) => {
+                let path = format!("/ipfs/{}", head);
+                kubo::call(
+                    "name/publish",
+                    &[("arg", &path), ("key", key), ("allow-offline", "true")],
+                )?;
+            }
+            Self::Manifest(manifest, name) => manifest.compare_and_swap(name, expected, *head)?,
+        }
+        Ok(())
+    }
+
+    fn resolve(&self) -> anyhow::Result<Option<Sha256Digest>> {
+        match self {
+            Self::Ipns(name) => {
+                let name = format!("/ipns/{}", name);
+                let value = kubo::call("name/resolve", &[("arg", &name), ("nocache", "true")])?;
+                let path = value["Path"].as_str().unwrap_or_default();
+                Ok(Some(path.trim_start_matches("/ipfs/").parse()?))
+            }
+            Self::Manifest(manifest, name) => manifest.root(name),
+        }
+    }
+}
+
+/// How the writer and the reader build and open their trees
+#[derive(Debug, Clone)]
+pub struct TreeOptions {
+    pub config: Config,
+    pub secrets: Secrets,
+    /// the key the secrets of each tenant are derived from
+    pub master_key: Option<SecretKey>,
+    /// the size of the branch cache in bytes
+    pub cache_bytes: usize,
+}
+
+impl TreeOptions {
+    /// The options for the trees of a tenant, with the secrets of the tenant from the master key,
+    /// or these options without a tenant
+    pub fn for_tenant(&self, tenant: Option<&str>) -> anyhow::Result<Self> {
+        let Some(tenant) = tenant else {
+            return Ok(self.clone());
+        };
+        let master = self
+            .master_key
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("a tenant needs a master_key in the profile"))?;
+        Ok(Self {
+            secrets: tenants::tenant_secrets(master, tenant)?,
+            ..self.clone()
+        })
+    }
+}
+
+/// Append a batch of events in regular intervals and publish a snapshot after each
+pub fn writer(
+    options: &TreeOptions,
+    channel: &Channel,
+    batch_size: u64,
+    interval: Duration,
+    batches: Option<u64>,
+    topic: Option<&str>,
+    key: Option<&SigningKey>,
+) -> anyhow::Result<()> {
+    check_kubo()?;
+    if let Some(key) = key {
+        eprintln!("signing with {}", signed::format_key(&key.verifying_key()));
+    }
+    let mut store = KuboStore::from_env()?;
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(options.cache_bytes));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder =
+        StreamBuilder::<LogTT, u64>::new(options.config.clone(), options.secrets.clone());
+    let mut head: Option<Sha256Digest> = None;
+    // the link that was published last, the head or the signed snapshot
+    let mut published: Option<Sha256Digest> = None;
+    let mut signed_head = None;
+    let mut batch = 0u64;
+    while batches.is_none_or(|n| batch < n) {
+        let t0 = Instant::now();
+        let offset = builder.snapshot().count();
+        txn.extend(&mut builder, (offset..offset + batch_size).map(|i| ((), i)))?;
+        let tree = builder.snapshot();
+        let label = format!("batch{}", batch);
+        let time = snapshots::now();
+        let next = snapshots::record(&mut store, &label, time, &tree, head)?;
+        let link = match key {
+            Some(key) => {
+                let signed = signed::sign(&mut store, key, time, &tree, signed_head.as_ref())?;
+                let link = signed.0;
+                signed_head = Some(signed);
+                link
+            }
+            None => next,
+        };
+        // the record links to the tree and to all older records, so a recursive pin keeps all.
+        // The same goes for signed snapshots
+        let link_arg = link.to_string();
+        match published {
+            Some(published) => kubo::call(
+                "pin/update",
+                &[("arg", &published.to_string()), ("arg", &link_arg)],
+            )?,
+            None => kubo::call("pin/add", &[("arg", &link_arg)])?,
+        };
+        channel.publish(published, &link)?;
+        if let Some(topic) = topic {
+            announce(topic, &link)?;
+        }
+        println!("{}\t{}\t{}", label, tree.count(), link);
+        head = Some(next);
+        published = Some(link);
+        batch += 1;
+        thread::sleep(interval.saturating_sub(t0.elapsed()));
+    }
+    Ok(())
+}
+
+/// The part of the stream the reader has already printed
+struct Follower {
+    store: KuboStore,
+    forest: Forest<LogTT, BatchStore<Sha256Digest, KuboStore>>,
+    batch: BatchStore<Sha256Digest, KuboStore>,
+    cache: BranchCache<LogTT>,
+    secrets: Secrets,
+    seen: Option<Sha256Digest>,
+    offset: u64,
+    /// the key of the writer, if it signs its snapshots
+    key: Option<VerifyingKey>,
+    /// the newest signed snapshot, which every later one has to extend
+    signed: Option<(Sha256Digest, signed::Snapshot)>,
+}
+
+impl Follower {
+    fn new(options: &TreeOptions, key: Option<VerifyingKey>) -> anyhow::Result<Self> {
+        let store = KuboStore::from_env()?;
+        let batch = BatchStore::new(store.clone());
+        let cache = BranchCache::new(options.cache_bytes);
+        let forest = Forest::new(batch.clone(), cache.clone());
+        Ok(Self {
+            store,
+            forest,
+            batch,
+            cache,
+            secrets: options.secrets.clone(),
+            seen: None,
+            offset: 0,
+            key,
+            signed: None,
+        })
+    }
+
+    /// The label and root of the snapshot, or `None` if it is not signed by the writer or does
+    /// not extend the last one
+    fn snapshot(
+        &mut self,
+        head: Sha256Digest,
+    ) -> anyhow::Result<Option<(String, Option<Sha256Digest>)>> {
+        let Some(key) = &self.key else {
+            let (_, record) = snapshots::history(&self.store, head)
+                .next()
+                .expect("history starts with the head")?;
+            return Ok(Some((record.label, record.root)));
+        };
+        let checked = signed::verify(&self.store, head, key).and_then(|snapshot| {
+            if let Some(known) = &self.signed {
+                signed::extends(&self.store, head, key, known)?;
+            }
+            Ok(snapshot)
+        });
+        match checked {
+            Ok(snapshot) => {
+                let label = format!("seq {} signed at {}", snapshot.seq, snapshot.time);
+                let root = snapshot.root;
+                self.signed = Some((head, snapshot));
+                Ok(Some((label, root)))
+            }
+            Err(cause) => {
+                eprintln!("ignoring snapshot: {}", cause);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Print the events of the snapshot that were not printed yet
+    fn update(&mut self, head: Sha256Digest) -> anyhow::Result<()> {
+        if Some(head) == self.seen {
+            return Ok(());
+        }
+        let Some((label, root)) = self.snapshot(head)? else {
+            return Ok(());
+        };
+        if let Some(root) = root {
+            let tree = self.forest.load_tree::<u64>(self.secrets.clone(), root)?;
+            // the new leaves of a branch are fetched concurrently
+            let query = BatchQuery::new(
+                OffsetRangeQuery::from(self.offset..),
+                self.batch.clone(),
+                self.cache.clone(),
+                self.secrets.clone(),
+                batch::PARALLELISM,
+            );
+            for item in self.forest.iter_filtered(&tree, query) {
+                let (i, _, value) = item?;
+                println!("{}\t{}", i, value);
+            }
+            self.offset = self.offset.max(tree.count());
+        }
+        eprintln!("{} {} events", label, self.offset);
+        self.seen = Some(head);
+        Ok(())
+    }
+}
+
+/// Follow the stream, and print all events that were added since the last snapshot
+///
+/// Without a topic, the channel is polled in the interval. With a topic and the peer id of the
+/// writer, the reader waits for announcements and only resolves the channel once to catch up.
+/// With the public key of the writer, only snapshots signed with it are read.
+pub fn reader(
+    options: &TreeOptions,
+    channel: &Channel,
+    interval: Duration,
+    announcements: Option<(&str, String)>,
+    key: Option<VerifyingKey>,
+) -> anyhow::Result<()> {
+    check_kubo()?;
+    let mut follower = Follower::new(options, key)?;
+    let Some((topic, peer)) = announcements else {
+        loop {
+            match channel.resolve() {
+                Ok(Some(head)) => follower.update(head)?,
+                Ok(None) => {}
+                // the name might not be published yet, or kubo might be busy. Try again later
+                Err(cause) => eprintln!("resolve failed: {}", cause),
+            }
+            thread::sleep(interval);
+        }
+    };
+    // subscribe first, so nothing published while catching up gets lost
+    let announcements = subscribe(topic, peer)?;
+    if let Some(head) = channel.resolve()? {
+        follower.update(head)?;
+    }
+    for head in announcements {
+        follower.update(head?)?;
+    }
+    anyhow::bail!("pubsub subscription ended")
+}
+
+/// Check that the local kubo is reachable, for a better error message than a failed block get
+fn check_kubo() -> anyhow::Result<()> {
+    let health = health::probe_kubo()?;
+    eprintln!("kubo {} peer id {}", health.version, health.identity);
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Folding a stream into a state, with checkpoints to resume from
+//!
+//! [Registers](crate::lww::Registers) is one fold over a stream, and every application that
+//! builds state from events needs another one. A [Fold] is just that: a state that can be
+//! encoded, and a function that applies one event to it. [Replay] streams the events of a tree
+//! through a fold, and every so many events writes a [Checkpoint] block with the offset and the
+//! link of the encoded state, and sets a named root to it. After a restart, [Replay::open]
+//! starts from the state of the latest checkpoint, so only the events after it are read again.
+//!
+//! A fold that fails on an event stops the replay, and the state of the last checkpoint is what
+//! the next replay starts with. Events after it are applied again, so a fold whose effects go
+//! beyond its state has to cope with seeing an event twice.
+//!
+//! The tree has to be a newer version of the stream the checkpoint is from, which the replay does
+//! not check. There is no command for it, a fold is code.
+use std::cell::Cell;
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, Decode, Encode},
+    DagCbor,
+};
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey},
+    roots::{ManifestFile, RootStore},
+};
+
+/// A state that is built from the events of a stream
+pub trait Fold<T: TreeTypes, V> {
+    type State: Encode<DagCborCodec> + Decode<DagCborCodec> + Default;
+
+    /// Apply the event at `offset` to the state
+    fn apply(
+        &self,
+        state: &mut Self::State,
+        offset: u64,
+        key: T::Key,
+        value: V,
+    ) -> anyhow::Result<()>;
+}
+
+/// The state after the first `offset` events of a stream
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct Checkpoint {
+    pub offset: u64,
+    /// the link of the encoded state
+    pub state: Sha256Digest,
+}
+
+/// A fold over a stream that checkpoints its state, see the module docs
+pub struct Replay<F: Fold<T, V>, T: TreeTypes, V, M> {
+    fold: F,
+    state: F::State,
+    offset: u64,
+    name: String,
+    roots: M,
+    /// the latest checkpoint, which is the current value of the root
+    checkpoint: Option<Sha256Digest>,
+    /// the number of events between checkpoints
+    every: u64,
+    _types: std::marker::PhantomData<(T, V)>,
+}
+
+impl<F, T, V, M> Replay<F, T, V, M>
+where
+    F: Fold<T, V>,
+    T: TreeTypes<Link = Sha256Digest>,
+    V: BanyanValue,
+    M: RootStore<Sha256Digest>,
+{
+    /// Start from the latest checkpoint with this name, or from an empty state if there is none
+    pub fn open<S: ReadOnlyStore<Sha256Digest>>(
+        fold: F,
+        store: &S,
+        roots: M,
+        name: &str,
+        every: u64,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(every > 0, "checkpoints need an interval");
+        let checkpoint = roots.root(name)?;
+        let (state, offset) = match checkpoint {
+            Some(link) => {
+                let checkpoint: Checkpoint = DagCborCodec
+                    .decode(&store.get(&link)?)
+                    .map_err(|cause| anyhow::anyhow!("{} is not a checkpoint: {}", link, cause))?;
+                let state = DagCborCodec.decode(&store.get(&checkpoint.state)?)?;
+                (state, checkpoint.offset)
+            }
+            None => (F::State::default(), 0),
+        };
+        Ok(Self {
+            fold,
+            state,
+            offset,
+            name: name.to_string(),
+            roots,
+            checkpoint,
+            every,
+            _types: std::marker::PhantomData,
+        })
+    }
+
+    pub fn state(&self) -> &F::State {
+        &self.state
+    }
+
+    /// The number of events that are folded into the state
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Write the state and a checkpoint, and make it the latest one. Returns its link
+    pub fn checkpoint<W: BlockWriter<Sha256Digest> + Clone>(
+        &mut self,
+        writer: &W,
+    ) -> anyhow::Result<Sha256Digest> {
+        let mut writer = writer.clone();
+        let state = writer.put(DagCborCodec.encode(&self.state)?)?;
+        let checkpoint = Checkpoint {
+            offset: self.offset,
+            state,
+        };
+        let link = writer.put(DagCborCodec.encode(&checkpoint)?)?;
+        self.roots
+            .compare_and_swap(&self.name, self.checkpoint, link)?;
+        self.checkpoint = Some(link);
+        Ok(link)
+    }
+
+    /// Fold the events of the tree after the ones seen so far, with a checkpoint every so often
+    /// and one at the end. Returns the number of events applied
+    pub fn run<R, W>(
+        &mut self,
+        txn: &Transaction<T, R, W>,
+        tree: &Tree<T, V>,
+    ) -> anyhow::Result<u64>
+    where
+        R: ReadOnlyStore<Sha256Digest>,
+        W: BlockWriter<Sha256Digest> + Clone,
+    {
+        anyhow::ensure!(
+            tree.count() >= self.offset,
+            "the tree has {} events, fewer than the {} of the checkpoint",
+            tree.count(),
+            self.offset
+        );
+        let from = self.offset;
+        for item in txn.iter_filtered(tree, OffsetRangeQuery::from(from..)) {
+            let (offset, key, value) = item?;
+            self.fold.apply(&mut self.state, offset, key, value)?;
+            self.offset = offset + 1;
+            if self.offset.is_multiple_of(self.every) {
+                self.checkpoint(txn.writer())?;
+            }
+        }
+        if self.offset > from && !self.offset.is_multiple_of(self.every) {
+            self.checkpoint(txn.writer())?;
+        }
+        Ok(self.offset - from)
+    }
+}
+
+/// The number of events and the sum of the values per device
+#[derive(Debug, Clone, Default, PartialEq, Eq, DagCbor)]
+struct Totals {
+    counts: Vec<u64>,
+    sums: Vec<u64>,
+}
+
+/// Adds up [Totals], and fails once at an offset, like a process that is killed there
+struct TotalsFold {
+    fail_at: Cell<Option<u64>>,
+}
+
+impl Fold<ColumnarTT, u64> for TotalsFold {
+    type State = Totals;
+
+    fn apply(
+        &self,
+        state: &mut Totals,
+        offset: u64,
+        key: EventKey,
+        value: u64,
+    ) -> anyhow::Result<()> {
+        if self.fail_at.get() == Some(offset) {
+            self.fail_at.set(None);
+            anyhow::bail!("killed at {}", offset);
+        }
+        let device = key.device as usize;
+        if state.counts.len() <= device {
+            state.counts.resize(device + 1, 0);
+            state.sums.resize(device + 1, 0);
+        }
+        state.counts[device] += 1;
+        state.sums[device] += value;
+        Ok(())
+    }
+}
+
+/// Replay a stream, kill the replay in the middle, and resume it from the latest checkpoint
+pub fn replay_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let every = 10000;
+    let killed = 54321;
+    println!(
+        "Example: replaying {} events with a checkpoint every {}, killed at {}",
+        n, every, killed
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let events = columnar::events(n);
+    txn.extend(&mut builder, events.iter().cloned())?;
+    let tree = builder.snapshot();
+    let path = std::env::temp_dir().join(format!("banyan-replay-{}.manifest", std::process::id()));
+    let roots = ManifestFile::new(&path);
+
+    let fold = TotalsFold {
+        fail_at: Cell::new(Some(killed)),
+    };
+    let mut replay = Replay::open(fold, &store, roots.clone(), "totals", every)?;
+    let failed = replay.run(&txn, &tree);
+    anyhow::ensure!(failed.is_err(), "the replay was not killed");
+    println!("first run\t{}", failed.unwrap_err());
+
+    // a new process only has the checkpoint
+    let fold = TotalsFold {
+        fail_at: Cell::new(None),
+    };
+    let mut replay = Replay::open(fold, &store, roots, "totals", every)?;
+    let resumed = replay.offset();
+    anyhow::ensure!(
+        resumed == killed - killed % every,
+        "resumed at {}, not the last checkpoint",
+        resumed
+    );
+    let applied = replay.run(&txn, &tree)?;
+    println!("resumed at\t{}\t{} events applied", resumed, applied);
+    anyhow::ensure!(applied == n - resumed, "applied {} events", applied);
+
+    let mut expected = Totals::default();
+    let fold = TotalsFold {
+        fail_at: Cell::new(None),
+    };
+    for (offset, (key, value)) in events.iter().enumerate() {
+        fold.apply(&mut expected, offset as u64, *key, *value)?;
+    }
+    anyhow::ensure!(*replay.state() == expected, "the state differs");
+    anyhow::ensure!(
+        replay.state().counts.iter().sum::<u64>() == n,
+        "events are missing"
+    );
+    let _ = std::fs::remove_file(&path);
+    println!();
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Caching query results by root and query
+//!
+//! A tree never changes, so the same query on the same root always has the same result. A
+//! [ResultCache] keeps results by the root link and the [canonical](JsonQuery::canonical) json
+//! of the query, so a dashboard that runs the same queries every few seconds only runs them
+//! again when there is a new root. The key does not have the secrets, a result is cached for
+//! whoever could read the tree.
+//!
+//! The results are kept in memory up to a size, and the least recently used ones go first. With
+//! a file, they are also in a sqlite table, so they survive a restart and are shared by all
+//! processes that use the same file. Results in the file are never evicted, it is a cache that
+//! can be deleted at any time. It is sqlite since the crate has it for a block store already,
+//! and another embedded database like sled would only be one more dependency.
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    cache::CacheStats,
+    columnar::{self, ColumnarTT, EventKey},
+    query_json::JsonQuery,
+};
+
+/// The events a query found, with their offsets
+pub type Rows = Vec<(u64, EventKey, u64)>;
+
+/// The approximate size of a row in memory
+const ROW_BYTES: usize = 24;
+
+/// How the cache did so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResultStats {
+    pub hits: u64,
+    /// results that were not in memory, but in the file
+    pub file_hits: u64,
+    pub misses: u64,
+    /// results that were dropped from memory to make room
+    pub evicted: u64,
+}
+
+type Key = (Sha256Digest, String);
+
+#[derive(Debug, Default)]
+struct Memory {
+    /// the results, with the tick they were last used
+    entries: HashMap<Key, (u64, Arc<Rows>)>,
+    tick: u64,
+    bytes: usize,
+    stats: ResultStats,
+}
+
+/// Query results by root and query, see the module docs
+#[derive(Debug)]
+pub struct ResultCache {
+    memory: Mutex<Memory>,
+    max_bytes: usize,
+    file: Option<Mutex<Connection>>,
+}
+
+fn size((_, query): &Key, rows: &Rows) -> usize {
+    query.len() + 32 + rows.len() * ROW_BYTES
+}
+
+/// Run a query without a cache
+pub fn run<R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<ColumnarTT, R>,
+    tree: &Tree<ColumnarTT, u64>,
+    query: &JsonQuery,
+) -> anyhow::Result<Rows> {
+    forest.iter_filtered(tree, query.to_query()).collect()
+}
+
+impl ResultCache {
+    /// A cache in memory, for results of up to `max_bytes` in total
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            memory: Mutex::new(Memory::default()),
+            max_bytes,
+            file: None,
+        }
+    }
+
+    /// A cache in memory in front of a sqlite file, which is created if it does not exist
+    pub fn open(path: impl AsRef<Path>, max_bytes: usize) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS results (
+                root BLOB NOT NULL, query TEXT NOT NULL, rows BLOB NOT NULL,
+                PRIMARY KEY (root, query)
+            ) WITHOUT ROWID;",
+        )?;
+        Ok(Self {
+            file: Some(Mutex::new(conn)),
+            ..Self::new(max_bytes)
+        })
+    }
+
+    pub fn stats(&self) -> ResultStats {
+        self.memory.lock().unwrap().stats
+    }
+
+    /// The result from memory, and mark it as used
+    fn get(&self, key: &Key) -> Option<Arc<Rows>> {
+        let mut memory = self.memory.lock().unwrap();
+        memory.tick += 1;
+        let tick = memory.tick;
+        let rows = memory.entries.get_mut(key).map(|entry| {
+            entry.0 = tick;
+            entry.1.clone()
+        })?;
+        memory.stats.hits += 1;
+        Some(rows)
+    }
+
+    /// Keep a result in memory, evicting the least recently used ones until it fits
+    fn insert(&self, key: Key, rows: Arc<Rows>) {
+        let bytes = size(&key, &rows);
+        if bytes > self.max_bytes {
+            return;
+        }
+        let mut memory = self.memory.lock().unwrap();
+        while memory.bytes + bytes > self.max_bytes {
+            let oldest = memory
+                .entries
+                .iter()
+                .min_by_key(|(_, (tick, _))| *tick)
+                .map(|(key, _)| key.clone())
+                .expect("not empty while over the limit");
+            let (_, rows) = memory.entries.remove(&oldest).expect("just found");
+            memory.bytes -= size(&oldest, &rows);
+            memory.stats.evicted += 1;
+        }
+        memory.tick += 1;
+        let tick = memory.tick;
+        memory.bytes += bytes;
+        if let Some((_, old)) = memory.entries.insert(key.clone(), (tick, rows)) {
+            memory.bytes -= size(&key, &old);
+        }
+    }
+
+    fn load(&self, (root, query): &Key) -> anyhow::Result<Option<Rows>> {
+        let Some(conn) = &self.file else {
+            return Ok(None);
+        };
+        let conn = conn.lock().unwrap();
+        let mut select =
+            conn.prepare_cached("SELECT rows FROM results WHERE root = ? AND query = ?")?;
+        let data: Option<Vec<u8>> = select
+            .query_row(params![Cid::from(*root).to_bytes(), query], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        data.map(|data| DagCborCodec.decode(&data)).transpose()
+    }
+
+    fn store(&self, (root, query): &Key, rows: &Rows) -> anyhow::Result<()> {
+        let Some(conn) = &self.file else {
+            return Ok(());
+        };
+        let data = DagCborCodec.encode(rows)?;
+        let conn = conn.lock().unwrap();
+        conn.prepare_cached("INSERT OR REPLACE INTO results (root, query, rows) VALUES (?, ?, ?)")?
+            .execute(params![Cid::from(*root).to_bytes(), query, data])?;
+        Ok(())
+    }
+
+    /// The result of a query on a tree, from the cache if it was run on the same root before.
+    /// An empty tree has no root, and is always queried
+    pub fn select<R: ReadOnlyStore<Sha256Digest>>(
+        &self,
+        forest: &Forest<ColumnarTT, R>,
+        tree: &Tree<ColumnarTT, u64>,
+        query: &JsonQuery,
+    ) -> anyhow::Result<Arc<Rows>> {
+        let Some(root) = tree.link() else {
+            return Ok(Arc::new(run(forest, tree, query)?));
+        };
+        let key = (root, query.canonical().to_json().to_string());
+        if let Some(rows) = self.get(&key) {
+            return Ok(rows);
+        }
+        let rows = match self.load(&key)? {
+            Some(rows) => {
+                self.memory.lock().unwrap().stats.file_hits += 1;
+                rows
+            }
+            None => {
+                self.memory.lock().unwrap().stats.misses += 1;
+                let rows = run(forest, tree, query)?;
+                self.store(&key, &rows)?;
+                rows
+            }
+        };
+        let rows = Arc::new(rows);
+        self.insert(key, rows.clone());
+        Ok(rows)
+    }
+}
+
+/// Run the queries of a dashboard a few times, on the same root and on a new one
+pub fn result_cache_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!(
+        "Example: caching the results of repeated queries on {} events",
+        n
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let events = columnar::events(n + 1000);
+    txn.extend(&mut builder, events[..n as usize].iter().cloned())?;
+    let tree = builder.snapshot();
+    let (from, to) = (events[5000].0.time, events[5999].0.time);
+    let time = format!(r#"{{"time": {{"from": {}, "to": {}}}}}"#, from, to);
+    let devices = r#"{"devices": [17, 3]}"#;
+    let queries = [
+        format!(r#"{{"and": [{}, {}]}}"#, time, devices),
+        // the same query, written differently
+        format!(
+            r#"{{"and": [{{"devices": [3, 17]}}, {{"not": {{"not": {}}}}}]}}"#,
+            time
+        ),
+        r#"{"offset": {"from": 99000}}"#.to_string(),
+    ];
+    let path = std::env::temp_dir().join(format!("banyan-results-{}.sqlite", std::process::id()));
+    let stats = CacheStats::new();
+    let forest = Forest::<ColumnarTT, _>::new(stats.store(store.clone()), BranchCache::new(0));
+    let cache = ResultCache::open(&path, 1 << 20)?;
+    let uncached = Forest::<ColumnarTT, _>::new(store, BranchCache::new(0));
+    println!("run\thits\tfile hits\tmisses\tblocks read");
+    let check = |name: &str, cache: &ResultCache, tree: &Tree<ColumnarTT, u64>| {
+        let blocks = stats.misses() + stats.leaves();
+        for text in &queries {
+            let query = JsonQuery::parse(text)?;
+            let rows = cache.select(&forest, tree, &query)?;
+            anyhow::ensure!(
+                *rows == run(&uncached, tree, &query)?,
+                "stale result for {}",
+                text
+            );
+        }
+        let result = cache.stats();
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            name,
+            result.hits,
+            result.file_hits,
+            result.misses,
+            stats.misses() + stats.leaves() - blocks
+        );
+        anyhow::Ok(result)
+    };
+    let first = check("first", &cache, &tree)?;
+    // the second query is the first one again
+    anyhow::ensure!(first.misses == 2 && first.hits == 1, "{:?}", first);
+    let again = check("again", &cache, &tree)?;
+    anyhow::ensure!(again.misses == first.misses, "{:?}", again);
+    let restarted = ResultCache::open(&path, 1 << 20)?;
+    let file = check("restart", &restarted, &tree)?;
+    anyhow::ensure!(file.misses == 0 && file.file_hits == 2, "{:?}", file);
+    // new events, so a new root and new results
+    txn.extend(&mut builder, events[n as usize..].iter().cloned())?;
+    let newer = check("new root", &restarted, &builder.snapshot())?;
+    anyhow::ensure!(newer.misses == 2, "{:?}", newer);
+    drop((cache, restarted));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+    println!();
+    Ok(())
+}
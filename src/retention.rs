@@ -0,0 +1,229 @@
+//! Declarative retention policies
+//!
+//! `retain` forgets all sealed parts of a tree that do not match a query, and `pack` cleans up
+//! the tree afterwards. A retention policy is just such a query, built from a few rules that say
+//! what to keep. Everything that no rule keeps is forgotten, at leaf granularity: a leaf is kept
+//! if a single event in it is kept.
+use std::sync::Arc;
+
+use banyan::{
+    index::{BranchIndex, LeafIndex, Summarizable, VecSeq},
+    query::{AllQuery, EmptyQuery, OffsetRangeQuery, OrQuery, Query, QueryExt},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::DagCbor;
+
+pub const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// A timestamp in milliseconds and a set of up to 64 tags as a bit mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+pub struct TaggedKey {
+    pub time: u64,
+    pub tags: u64,
+}
+
+/// Time range and the union of all tags
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct TaggedSummary {
+    pub min_time: u64,
+    pub max_time: u64,
+    pub tags: u64,
+}
+
+impl TaggedSummary {
+    fn combine(iter: impl IntoIterator<Item = TaggedSummary>) -> Self {
+        let mut iter = iter.into_iter();
+        let first = iter
+            .next()
+            .expect("summarize is only called on non-empty sequences");
+        iter.fold(first, |a, b| TaggedSummary {
+            min_time: a.min_time.min(b.min_time),
+            max_time: a.max_time.max(b.max_time),
+            tags: a.tags | b.tags,
+        })
+    }
+}
+
+impl Summarizable<TaggedSummary> for VecSeq<TaggedKey> {
+    fn summarize(&self) -> TaggedSummary {
+        TaggedSummary::combine(self.as_ref().iter().map(|k| TaggedSummary {
+            min_time: k.time,
+            max_time: k.time,
+            tags: k.tags,
+        }))
+    }
+}
+
+impl Summarizable<TaggedSummary> for VecSeq<TaggedSummary> {
+    fn summarize(&self) -> TaggedSummary {
+        TaggedSummary::combine(self.as_ref().iter().cloned())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TaggedTT;
+
+impl TreeTypes for TaggedTT {
+    type Key = TaggedKey;
+    type Summary = TaggedSummary;
+    type KeySeq = VecSeq<TaggedKey>;
+    type SummarySeq = VecSeq<TaggedSummary>;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Retention policy camp...";
+}
+
+/// Events at or after a point in time
+#[derive(Debug, Clone)]
+struct SinceQuery(u64);
+
+impl Query<TaggedTT> for SinceQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<TaggedTT>, res: &mut [bool]) {
+        for (i, key) in index.keys.as_ref().iter().enumerate() {
+            res[i] = res[i] && key.time >= self.0;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<TaggedTT>, res: &mut [bool]) {
+        for (i, s) in index.summaries.as_ref().iter().enumerate() {
+            res[i] = res[i] && s.max_time >= self.0;
+        }
+    }
+}
+
+/// Events that have any of the tags
+#[derive(Debug, Clone)]
+struct TagQuery(u64);
+
+impl Query<TaggedTT> for TagQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<TaggedTT>, res: &mut [bool]) {
+        for (i, key) in index.keys.as_ref().iter().enumerate() {
+            res[i] = res[i] && key.tags & self.0 != 0;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<TaggedTT>, res: &mut [bool]) {
+        for (i, s) in index.summaries.as_ref().iter().enumerate() {
+            res[i] = res[i] && s.tags & self.0 != 0;
+        }
+    }
+}
+
+/// A rule that says which events to keep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+    /// the last n events
+    Last(u64),
+    /// all events with a timestamp within the last n days, which is all of them for a huge n
+    Days(u64),
+    /// all events with any of the tags in the mask
+    Tags(u64),
+}
+
+/// A set of rules. An event is kept if any of the rules keeps it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy(pub Vec<Rule>);
+
+impl Policy {
+    /// The query for all events to keep, for a tree with `count` events at time `now`
+    pub fn query(&self, count: u64, now: u64) -> Arc<dyn Query<TaggedTT>> {
+        self.0
+            .iter()
+            .map(|rule| match rule {
+                Rule::Last(n) => OffsetRangeQuery::from(count.saturating_sub(*n)..).boxed(),
+                Rule::Days(n) => SinceQuery(now.saturating_sub(n.saturating_mul(DAY))).boxed(),
+                Rule::Tags(mask) => TagQuery(*mask).boxed(),
+            })
+            .fold(EmptyQuery.boxed(), |a, b| OrQuery(a, b).boxed())
+    }
+}
+
+/// Number of blocks that are still referenced by the tree
+pub fn blocks<V, R: ReadOnlyStore<Sha256Digest>>(
+    forest: &Forest<TaggedTT, R>,
+    tree: &Tree<TaggedTT, V>,
+) -> anyhow::Result<u64> {
+    let mut n = 0;
+    for index in forest.iter_index(tree, AllQuery) {
+        if index?.link().is_some() {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+/// Apply the policy to the tree, returning the number of blocks before and after
+pub fn apply<R, W, V>(
+    txn: &mut Transaction<TaggedTT, R, W>,
+    builder: &mut StreamBuilder<TaggedTT, V>,
+    policy: &Policy,
+    now: u64,
+) -> anyhow::Result<(u64, u64)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest>,
+    V: banyan::store::BanyanValue,
+{
+    let before = blocks(txn, &builder.snapshot())?;
+    let query = policy.query(builder.snapshot().count(), now);
+    txn.retain(builder, &query)?;
+    txn.pack(builder)?;
+    let after = blocks(txn, &builder.snapshot())?;
+    Ok((before, after))
+}
+
+/// Simulate two weeks of events, applying a retention policy after each day
+pub fn retention_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    const ALARM: u64 = 1;
+    const INFO: u64 = 2;
+    let days = 14u64;
+    let per_day = 20000u64;
+    let policy = Policy(vec![Rule::Last(1000), Rule::Days(3), Rule::Tags(ALARM)]);
+    println!(
+        "Example: {} days of {} events per day with retention policy {:?}",
+        days, per_day, policy
+    );
+    let forest = Forest::<TaggedTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<TaggedTT, u64>::new(config.clone(), Secrets::default());
+    let t0 = 1_600_000_000_000u64;
+    println!("day\tevents\tblocks\tafter\treclaimed");
+    for day in 0..days {
+        let xs = (0..per_day).map(|i| {
+            let time = t0 + day * DAY + i * (DAY / per_day);
+            // there was an incident on day 2
+            let tags = if day == 2 && i % 100 == 0 {
+                ALARM
+            } else {
+                INFO
+            };
+            (TaggedKey { time, tags }, day * per_day + i)
+        });
+        txn.extend(&mut builder, xs)?;
+        let now = t0 + (day + 1) * DAY;
+        let (before, after) = apply(&mut txn, &mut builder, &policy, now)?;
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            day,
+            builder.snapshot().count(),
+            before,
+            after,
+            before - after
+        );
+    }
+
+    // the alarms of day 2 survived, even though the day is long out of the retention window
+    let mut alarms = 0;
+    for item in txn.iter_filtered(&builder.snapshot(), TagQuery(ALARM)) {
+        item?;
+        alarms += 1;
+    }
+    anyhow::ensure!(alarms == per_day / 100, "alarms were forgotten");
+    println!("{} alarms retained", alarms);
+    println!();
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! A run-length encoded key sequence, from scratch
+//!
+//! The key is a single "is error" flag per event. Errors are rare and come in bursts, so a leaf
+//! index is a few long runs of the same flag. [RleSeq] stores only the first flag and the run
+//! lengths. The summary of a branch is the number of errors below it, so a query for errors
+//! skips whole subtrees without any, and the total number of errors is in the root.
+//!
+//! zstd compresses long runs of the same byte well, so the stored size is not much smaller than
+//! with a plain [VecSeq]. The gain is in the decoded keys, which are what the branch cache holds.
+//!
+//! A key sequence needs four things: the dag-cbor encoding, [CompactSeq] to get individual keys,
+//! [FromIterator] so banyan can build it from keys, and [Summarizable] for the summary.
+use std::{
+    io::{Read, Seek, Write},
+    iter::FromIterator,
+};
+
+use banyan::{
+    index::{BranchIndex, CompactSeq, Index, LeafIndex, Summarizable, VecSeq},
+    query::{AllQuery, Query},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, Decode, Encode},
+    DagCbor,
+};
+
+/// Number of errors in a subtree
+#[derive(Debug, Clone, Copy, PartialEq, Eq, DagCbor)]
+pub struct ErrorCount(pub u64);
+
+/// A sequence of flags as runs of equal flags
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RleSeq {
+    /// the flag of the first run, the runs after it alternate
+    first: bool,
+    /// end offset of each run, exclusive, so the last one is the length
+    ends: Vec<u64>,
+}
+
+impl RleSeq {
+    /// the run lengths, which is what we store
+    fn runs(&self) -> impl Iterator<Item = u64> + '_ {
+        let starts = std::iter::once(0).chain(self.ends.iter().cloned());
+        self.ends.iter().zip(starts).map(|(end, start)| end - start)
+    }
+}
+
+impl Encode<DagCborCodec> for RleSeq {
+    fn encode<W: Write>(&self, c: DagCborCodec, w: &mut W) -> anyhow::Result<()> {
+        // a cbor array of the first flag and the run lengths
+        (self.first, self.runs().collect::<Vec<_>>()).encode(c, w)
+    }
+}
+
+impl Decode<DagCborCodec> for RleSeq {
+    fn decode<R: Read + Seek>(c: DagCborCodec, r: &mut R) -> anyhow::Result<Self> {
+        let (first, runs) = <(bool, Vec<u64>)>::decode(c, r)?;
+        let mut end = 0u64;
+        let mut ends = Vec::with_capacity(runs.len());
+        for run in runs {
+            // empty runs would make two runs with the same flag look like two different ones
+            anyhow::ensure!(run > 0, "empty run");
+            end = end
+                .checked_add(run)
+                .ok_or_else(|| anyhow::anyhow!("run lengths overflow"))?;
+            ends.push(end);
+        }
+        Ok(Self { first, ends })
+    }
+}
+
+impl CompactSeq for RleSeq {
+    type Item = bool;
+    fn get(&self, index: usize) -> Option<bool> {
+        // the run that contains the index is the first one that ends after it
+        let run = self.ends.partition_point(|end| *end <= index as u64);
+        if run < self.ends.len() {
+            Some(self.first ^ (run % 2 == 1))
+        } else {
+            None
+        }
+    }
+    fn len(&self) -> usize {
+        self.ends.last().cloned().unwrap_or_default() as usize
+    }
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.ends.capacity() * std::mem::size_of::<u64>()
+    }
+}
+
+impl FromIterator<bool> for RleSeq {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut res = Self::default();
+        for flag in iter {
+            let len = res.len() as u64;
+            let current = res.first ^ (res.ends.len() % 2 == 0);
+            match res.ends.last_mut() {
+                // same flag as the current run, make it longer
+                Some(end) if current == flag => *end += 1,
+                Some(_) => res.ends.push(len + 1),
+                None => {
+                    res.first = flag;
+                    res.ends.push(1);
+                }
+            }
+        }
+        res
+    }
+}
+
+impl Summarizable<ErrorCount> for RleSeq {
+    fn summarize(&self) -> ErrorCount {
+        let errors = self
+            .runs()
+            .enumerate()
+            .filter(|(i, _)| self.first ^ (i % 2 == 1))
+            .map(|(_, run)| run)
+            .sum();
+        ErrorCount(errors)
+    }
+}
+
+impl Summarizable<ErrorCount> for VecSeq<bool> {
+    fn summarize(&self) -> ErrorCount {
+        ErrorCount(self.as_ref().iter().filter(|x| **x).count() as u64)
+    }
+}
+
+impl Summarizable<ErrorCount> for VecSeq<ErrorCount> {
+    fn summarize(&self) -> ErrorCount {
+        ErrorCount(self.as_ref().iter().map(|x| x.0).sum())
+    }
+}
+
+/// Tree types with run-length encoded flags
+#[derive(Debug, Clone)]
+pub struct RleTT;
+
+impl TreeTypes for RleTT {
+    type Key = bool;
+    type Summary = ErrorCount;
+    type KeySeq = RleSeq;
+    type SummarySeq = VecSeq<ErrorCount>;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Run length keys camp....";
+}
+
+/// Tree types with one flag per key, for comparison
+#[derive(Debug, Clone)]
+pub struct FlagTT;
+
+impl TreeTypes for FlagTT {
+    type Key = bool;
+    type Summary = ErrorCount;
+    type KeySeq = VecSeq<bool>;
+    type SummarySeq = VecSeq<ErrorCount>;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Plain flag keys camp....";
+}
+
+/// All events that are errors
+#[derive(Debug, Clone)]
+pub struct ErrorQuery;
+
+impl<T: TreeTypes<Key = bool, Summary = ErrorCount>> Query<T> for ErrorQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        for (i, flag) in index.keys().enumerate() {
+            res[i] = res[i] && flag;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        for (i, summary) in index.summaries().enumerate() {
+            res[i] = res[i] && summary.0 > 0;
+        }
+    }
+}
+
+/// Check that a sequence of flags survives the trip through [RleSeq] and its encoding
+fn check(flags: &[bool]) -> anyhow::Result<()> {
+    let seq = flags.iter().cloned().collect::<RleSeq>();
+    anyhow::ensure!(seq.to_vec() == flags, "{:?} changed", flags);
+    anyhow::ensure!(seq.get(flags.len()).is_none(), "{:?} is too long", flags);
+    let errors = flags.iter().filter(|x| **x).count() as u64;
+    let summary: ErrorCount = seq.summarize();
+    anyhow::ensure!(summary == ErrorCount(errors), "{:?} summary", flags);
+    let decoded: RleSeq = DagCborCodec.decode(&DagCborCodec.encode(&seq)?)?;
+    anyhow::ensure!(decoded == seq, "{:?} does not round trip", flags);
+    Ok(())
+}
+
+/// Key bytes of a tree, as stored and as encoded before compression
+#[derive(Debug, Clone, Copy)]
+struct KeyBytes {
+    stored: u64,
+    encoded: u64,
+}
+
+/// Build a tree with the flags and return its key bytes and the offsets of all errors
+fn build<T>(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+    flags: &[bool],
+) -> anyhow::Result<(KeyBytes, Vec<u64>)>
+where
+    T: TreeTypes<Key = bool, Summary = ErrorCount, Link = Sha256Digest>,
+{
+    let forest = Forest::<T, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<T, u64>::new(config.clone(), Secrets::default());
+    txn.extend(
+        &mut builder,
+        flags.iter().enumerate().map(|(i, flag)| (*flag, i as u64)),
+    )?;
+    let tree = builder.snapshot();
+    let index = tree.index().expect("not empty");
+    let errors = txn
+        .iter_filtered(&tree, ErrorQuery)
+        .map(|item| item.map(|(i, _, _)| i))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // no need to look at the errors to count them
+    if let Index::Branch(branch) = index {
+        let total = branch.summaries().map(|s| s.0).sum::<u64>();
+        anyhow::ensure!(total == errors.len() as u64, "summary is off");
+    }
+    let mut encoded = 0;
+    for index in txn.iter_index(&tree, AllQuery) {
+        if let Index::Leaf(leaf) = index? {
+            encoded += DagCborCodec.encode(&leaf.keys)?.len() as u64;
+        }
+    }
+    let bytes = KeyBytes {
+        stored: index.key_bytes(),
+        encoded,
+    };
+    Ok((bytes, errors))
+}
+
+/// Build the same flags with run-length encoded and with plain keys and compare
+pub fn rle_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    for flags in [
+        &[][..],
+        &[true],
+        &[false],
+        &[true, true, false],
+        &[false, true, false, true],
+        &[false, false, false, true, true],
+    ] {
+        check(flags)?;
+    }
+
+    let n = 1000000u64;
+    println!("Example: {} events with a run-length encoded error flag", n);
+    // a burst of 20 errors every 10000 events
+    let flags = (0..n).map(|i| i % 10000 < 20).collect::<Vec<_>>();
+    check(&flags)?;
+    let (plain_bytes, plain_errors) = build::<FlagTT>(store.clone(), config, &flags)?;
+    let (rle_bytes, rle_errors) = build::<RleTT>(store, config, &flags)?;
+    anyhow::ensure!(plain_errors == rle_errors, "query results differ");
+    anyhow::ensure!(
+        rle_errors.len() == flags.iter().filter(|x| **x).count(),
+        "errors are missing"
+    );
+    println!(
+        "encoded leaf keys: plain {}, run-length {} ({:.1}x smaller)",
+        plain_bytes.encoded,
+        rle_bytes.encoded,
+        plain_bytes.encoded as f64 / rle_bytes.encoded as f64,
+    );
+    println!(
+        "stored key bytes: plain {}, run-length {} ({:.1}x smaller), {} errors",
+        plain_bytes.stored,
+        rle_bytes.stored,
+        plain_bytes.stored as f64 / rle_bytes.stored as f64,
+        rle_errors.len()
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! An embedded block store on rocksdb
+//!
+//! Blocks are in one column family, keyed by the bytes of their cid. Named roots, like the newest
+//! root of a stream, are in another. Appending to a stream writes many small blocks, so puts are
+//! collected in memory and written in one batch, either when the batch gets large or together
+//! with the next root. So a root is never stored without the blocks it refers to.
+//!
+//! Only one process can open the database, so a compare-and-swap of a root only has to hold the
+//! lock of the pending blocks while it reads and writes.
+//!
+//! Only available with the `rocksdb` feature.
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid};
+use rocksdb::{ColumnFamilyDescriptor, Options, WriteBatch, DB};
+
+use crate::{
+    error::Error,
+    link::Link,
+    probe::ProbingStore,
+    roots::{self, RootStore},
+    snapshots::LogTT,
+};
+
+const BLOCKS: &str = "blocks";
+const ROOTS: &str = "roots";
+
+/// Write the pending blocks once they are this large
+const BATCH_BYTES: usize = 4 << 20;
+
+#[derive(Debug, Default)]
+struct Pending {
+    blocks: HashMap<Vec<u8>, Vec<u8>>,
+    bytes: usize,
+}
+
+/// A store that keeps blocks and named roots in a rocksdb database
+#[derive(Debug)]
+pub struct RocksStore<L> {
+    db: Arc<DB>,
+    pending: Arc<Mutex<Pending>>,
+    _link: PhantomData<L>,
+}
+
+impl<L> Clone for RocksStore<L> {
+    fn clone(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            pending: self.pending.clone(),
+            _link: PhantomData,
+        }
+    }
+}
+
+impl<L: Link> RocksStore<L> {
+    /// Open the database in the directory, or create it
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let cfs = [BLOCKS, ROOTS]
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        let db = DB::open_cf_descriptors(&options, path, cfs)?;
+        Ok(Self {
+            db: Arc::new(db),
+            pending: Default::default(),
+            _link: PhantomData,
+        })
+    }
+
+    fn key(link: &L) -> Vec<u8> {
+        let cid: Cid = (*link).into();
+        cid.to_bytes()
+    }
+
+    /// Write the pending blocks, and the root if there is one, in one batch
+    fn write(&self, pending: &mut Pending, root: Option<(&str, &L)>) -> anyhow::Result<()> {
+        let blocks = self.db.cf_handle(BLOCKS).expect("blocks column family");
+        let roots = self.db.cf_handle(ROOTS).expect("roots column family");
+        let mut batch = WriteBatch::default();
+        for (key, data) in &pending.blocks {
+            batch.put_cf(blocks, key, data);
+        }
+        if let Some((name, link)) = root {
+            batch.put_cf(roots, name, DagCborCodec.encode(link)?);
+        }
+        self.db.write(batch)?;
+        *pending = Pending::default();
+        Ok(())
+    }
+
+    /// Write all pending blocks
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.write(&mut self.pending.lock().unwrap(), None)
+    }
+}
+
+impl<L: Link> RootStore<L> for RocksStore<L> {
+    fn root(&self, name: &str) -> anyhow::Result<Option<L>> {
+        let roots = self.db.cf_handle(ROOTS).expect("roots column family");
+        match self.db.get_cf(roots, name)? {
+            Some(data) => Ok(Some(DagCborCodec.decode(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the root together with all pending blocks, if it is still the expected one
+    fn compare_and_swap(&self, name: &str, expected: Option<L>, new: L) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        roots::check(name, expected, self.root(name)?)?;
+        self.write(&mut pending, Some((name, &new)))
+    }
+}
+
+impl<L: Link> ReadOnlyStore<L> for RocksStore<L> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let key = Self::key(link);
+        if let Some(data) = self.pending.lock().unwrap().blocks.get(&key) {
+            return Ok(data.clone().into());
+        }
+        let blocks = self.db.cf_handle(BLOCKS).expect("blocks column family");
+        match self.db.get_cf(blocks, &key)? {
+            Some(data) => Ok(data.into()),
+            None => Err(Error::missing(*link).into()),
+        }
+    }
+}
+
+impl<L: Link> ProbingStore<L> for RocksStore<L> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        let key = Self::key(link);
+        if let Some(data) = self.pending.lock().unwrap().blocks.get(&key) {
+            return Ok(Some(data.len() as u64));
+        }
+        // pinned, so the value is not copied just to get its length
+        let blocks = self.db.cf_handle(BLOCKS).expect("blocks column family");
+        let data = self.db.get_pinned_cf(blocks, &key)?;
+        Ok(data.map(|data| data.len() as u64))
+    }
+}
+
+impl<L: Link> BlockWriter<L> for RocksStore<L> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let link = L::digest(&data);
+        let mut pending = self.pending.lock().unwrap();
+        pending.bytes += data.len();
+        pending.blocks.insert(Self::key(&link), data);
+        if pending.bytes >= BATCH_BYTES {
+            self.write(&mut pending, None)?;
+        }
+        Ok(link)
+    }
+}
+
+/// Append to a stream in batches, storing its root after each, and continue where it left off
+pub fn rocks_example(store: RocksStore<Sha256Digest>, config: &Config) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    let name = "events";
+    println!(
+        "Example: appending {} events to the stream {} in rocksdb",
+        n, name
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    // continue the stream from the last run, if there is one
+    let mut builder = match store.root(name)? {
+        Some(root) => txn.load_stream_builder(Secrets::default(), config.clone(), root)?,
+        None => StreamBuilder::new(config.clone(), Secrets::default()),
+    };
+    let offset = builder.snapshot().count();
+    let t0 = Instant::now();
+    for start in (offset..offset + n).step_by(10000) {
+        let base = builder.snapshot().link();
+        txn.extend(&mut builder, (start..start + 10000).map(|i| ((), i)))?;
+        let tree = builder.snapshot();
+        store.compare_and_swap(name, base, tree.link().expect("not empty"))?;
+    }
+    println!("{} events in {}s", n, t0.elapsed().as_secs_f64());
+
+    let root = store.root(name)?.expect("root was stored");
+    let tree = txn.load_tree::<u64>(Secrets::default(), root)?;
+    anyhow::ensure!(tree.count() == offset + n, "events are missing");
+    println!("{:#?}", tree);
+    println!();
+    Ok(())
+}
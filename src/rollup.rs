@@ -0,0 +1,245 @@
+//! One aggregate over a time range, from the summaries where they are enough
+//!
+//! A branch whose summary is entirely within the time range has all its events in it, and its
+//! index knows how many there are. So a count only reads the blocks along the two edges of the
+//! range, however many events are in between. The summaries of [ColumnarTT] are about keys, not
+//! values, so a sum, min or max has to read the leaves of the range, but still only those.
+//!
+//! This walks the tree by hand, decoding the branches like banyan does, because a [Query] can
+//! only skip a child, not say that it was skipped because it is entirely in the range.
+//!
+//! The `rollup` command prints it, and the [server](crate::server) answers
+//! `/trees/<root>/aggregate` with it.
+//!
+//! [Query]: banyan::query::Query
+use std::str::FromStr;
+
+use banyan::{
+    chacha20::XNonce,
+    index::Index,
+    store::{BlockWriter, BranchCache, ReadOnlyStore, ZstdDagCborSeq},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    aggregate::Stats,
+    columnar::{self, ColumnarTT, EventSummary, TimeRangeQuery},
+};
+
+/// What to aggregate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl FromStr for Op {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "count" => Self::Count,
+            "sum" => Self::Sum,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            _ => anyhow::bail!("unknown op {}, not count, sum, min or max", s),
+        })
+    }
+}
+
+/// The result of a [rollup], and what it took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rollup {
+    /// the aggregate, or none for the min or max of no events
+    pub value: Option<u64>,
+    /// blocks read
+    pub blocks: u64,
+}
+
+struct Walk<'a, S> {
+    store: &'a S,
+    secrets: &'a Secrets,
+    range: TimeRangeQuery,
+    op: Op,
+    count: u64,
+    stats: Option<Stats>,
+    blocks: u64,
+}
+
+impl<S: ReadOnlyStore<Sha256Digest>> Walk<'_, S> {
+    fn get(&mut self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        self.blocks += 1;
+        self.store.get(link)
+    }
+
+    fn add(&mut self, value: u64) {
+        match &mut self.stats {
+            Some(stats) => stats.add(value),
+            None => self.stats = Some(Stats::new(value)),
+        }
+    }
+
+    fn visit(&mut self, index: &Index<ColumnarTT>) -> anyhow::Result<()> {
+        let EventSummary {
+            min_time, max_time, ..
+        } = index.summarize();
+        if max_time < self.range.min || min_time > self.range.max {
+            return Ok(());
+        }
+        if self.op == Op::Count && min_time >= self.range.min && max_time <= self.range.max {
+            self.count += index.count();
+            return Ok(());
+        }
+        let nonce = <&XNonce>::from(ColumnarTT::NONCE);
+        match index {
+            Index::Branch(branch) => {
+                let link = branch
+                    .link
+                    .ok_or_else(|| anyhow::anyhow!("a branch in the range is purged"))?;
+                let data = self.get(&link)?;
+                let (children, _) =
+                    ZstdDagCborSeq::decrypt(&data, self.secrets.index_key(), nonce)?;
+                for child in children.items::<Index<ColumnarTT>>()? {
+                    self.visit(&child)?;
+                }
+            }
+            Index::Leaf(leaf) => {
+                let (min, max) = (self.range.min, self.range.max);
+                let in_range = |time: u64| time >= min && time <= max;
+                if self.op == Op::Count {
+                    // the keys are in the index, only the values are in the leaf
+                    let count = leaf.keys().filter(|key| in_range(key.time)).count() as u64;
+                    self.count += count;
+                    return Ok(());
+                }
+                let link = leaf
+                    .link
+                    .ok_or_else(|| anyhow::anyhow!("a leaf in the range is purged"))?;
+                let data = self.get(&link)?;
+                let (values, _) = ZstdDagCborSeq::decrypt(&data, self.secrets.value_key(), nonce)?;
+                for (key, value) in leaf.keys().zip(values.items::<u64>()?) {
+                    if in_range(key.time) {
+                        self.add(value);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Aggregate the values of the events of a tree in a time range
+pub fn rollup<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    tree: &Tree<ColumnarTT, u64>,
+    op: Op,
+    range: TimeRangeQuery,
+) -> anyhow::Result<Rollup> {
+    let secrets = tree.secrets().cloned().unwrap_or_default();
+    let mut walk = Walk {
+        store,
+        secrets: &secrets,
+        range,
+        op,
+        count: 0,
+        stats: None,
+        blocks: 0,
+    };
+    if let Some(index) = tree.as_index_ref() {
+        walk.visit(index)?;
+    }
+    let value = match op {
+        Op::Count => Some(walk.count),
+        Op::Sum => Some(walk.stats.map(|stats| stats.sum).unwrap_or_default()),
+        Op::Min => walk.stats.map(|stats| stats.min),
+        Op::Max => walk.stats.map(|stats| stats.max),
+    };
+    Ok(Rollup {
+        value,
+        blocks: walk.blocks,
+    })
+}
+
+/// Print one aggregate over a time range of a columnar tree in kubo
+pub fn print_rollup<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    op: Op,
+    range: TimeRangeQuery,
+) -> anyhow::Result<()> {
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let result = rollup(store, &tree, op, range)?;
+    match result.value {
+        Some(value) => println!("{:?}\t{}", op, value),
+        None => println!("{:?}\tnone", op),
+    }
+    println!("blocks\t{}", result.blocks);
+    Ok(())
+}
+
+/// Counts and sums over time ranges, compared with a scan
+pub fn rollup_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: one aggregate over a time range of {} events", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, so there are a few levels of summaries
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let secrets = Secrets::new([7u8; 32].into(), [8u8; 32].into());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, secrets);
+    let events = columnar::events(n);
+    txn.extend(&mut builder, events.iter().cloned())?;
+    let tree = builder.snapshot();
+    let ranges = [(1000, 1999), (10, 90000), (0, n as usize - 1)];
+    println!("op\tevents\tvalue\tblocks");
+    for (first, last) in ranges {
+        let range = TimeRangeQuery {
+            min: events[first].0.time,
+            max: events[last].0.time,
+        };
+        let values = events
+            .iter()
+            .filter(|(key, _)| key.time >= range.min && key.time <= range.max)
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>();
+        let expected = [
+            (Op::Count, Some(values.len() as u64)),
+            (Op::Sum, Some(values.iter().sum())),
+            (Op::Min, values.iter().min().copied()),
+            (Op::Max, values.iter().max().copied()),
+        ];
+        for (op, expected) in expected {
+            let result = rollup(&store, &tree, op, range.clone())?;
+            anyhow::ensure!(
+                result.value == expected,
+                "{:?} is {:?}, not {:?}",
+                op,
+                result.value,
+                expected
+            );
+            println!(
+                "{:?}\t{}\t{}\t{}",
+                op,
+                values.len(),
+                expected.unwrap_or_default(),
+                result.blocks
+            );
+        }
+    }
+    let empty = TimeRangeQuery { min: 0, max: 0 };
+    anyhow::ensure!(rollup(&store, &tree, Op::Max, empty)?.value.is_none());
+    println!();
+    Ok(())
+}
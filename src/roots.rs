@@ -0,0 +1,259 @@
+//! Named roots with compare-and-swap
+//!
+//! A store only has immutable blocks, so the one thing that changes is which root is current.
+//! When two writers append to the same stream and both just set the root, the one that writes
+//! last wins, and the events of the other one are silently gone. With compare-and-swap, a writer
+//! says which root it built on, and the update fails with a [RootConflict] if that is no longer
+//! the current one. The writer then has to rebase its events on the current root and try again.
+//!
+//! [rebase] replays the events of a builder since its base onto the current root, so a writer can
+//! append optimistically: build on the latest root, try the swap, and on a conflict rebase and try
+//! again. Events of different writers end up in the order the swaps succeeded.
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{fs_store::sync_dir, link::Link, snapshots::LogTT, sqlite_store::SqliteStore};
+
+/// The root was changed by someone else since it was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootConflict<L> {
+    pub name: String,
+    /// the root the update was based on
+    pub expected: Option<L>,
+    /// the root that is current now
+    pub actual: Option<L>,
+}
+
+impl<L: fmt::Display> fmt::Display for RootConflict<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |link: &Option<L>| match link {
+            Some(link) => link.to_string(),
+            None => "nothing".to_string(),
+        };
+        write!(
+            f,
+            "root {} was expected to be {} but is {}, rebase on the current root and try again",
+            self.name,
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+impl<L: fmt::Debug + fmt::Display> std::error::Error for RootConflict<L> {}
+
+/// Named roots that can only be changed by someone who knows the current value
+pub trait RootStore<L> {
+    /// The current root with this name, if any
+    fn root(&self, name: &str) -> anyhow::Result<Option<L>>;
+
+    /// Set the root to `new` if it is still `expected`, otherwise fail with a [RootConflict]
+    fn compare_and_swap(&self, name: &str, expected: Option<L>, new: L) -> anyhow::Result<()>;
+}
+
+/// Fail with a [RootConflict] unless the current root is the expected one
+pub fn check<L: Link>(name: &str, expected: Option<L>, actual: Option<L>) -> anyhow::Result<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(RootConflict {
+            name: name.to_string(),
+            expected,
+            actual,
+        }
+        .into())
+    }
+}
+
+/// A builder with the events that `builder` added since `base`, on top of `new_base`
+///
+/// The events since the base are the ones after its count, so this assumes that the builder only
+/// appended since then, without retain or pack.
+pub fn rebase<T, V, R, W>(
+    txn: &mut Transaction<T, R, W>,
+    secrets: &Secrets,
+    config: &Config,
+    builder: &StreamBuilder<T, V>,
+    base: Option<T::Link>,
+    new_base: T::Link,
+) -> anyhow::Result<StreamBuilder<T, V>>
+where
+    T: TreeTypes,
+    V: BanyanValue,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+{
+    let offset = match base {
+        Some(base) => txn.load_tree::<V>(secrets.clone(), base)?.count(),
+        None => 0,
+    };
+    anyhow::ensure!(
+        offset <= builder.count(),
+        "builder is not based on {:?}",
+        base
+    );
+    let events = txn
+        .iter_filtered(&builder.snapshot(), OffsetRangeQuery::from(offset..))
+        .map(|item| item.map(|(_, k, v)| (k, v)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut rebased = txn.load_stream_builder(secrets.clone(), config.clone(), new_base)?;
+    txn.extend(&mut rebased, events)?;
+    Ok(rebased)
+}
+
+/// Roots in a text file with a line per name, like `head bafy...`, for writers on the same host
+/// or a shared directory
+///
+/// The file is replaced as a whole with a rename. Updates take a lock file next to it, so two
+/// processes can not both read the same old value and then write. A swap is on disk when it
+/// returns, the new file and the rename both synced.
+#[derive(Debug, Clone)]
+pub struct ManifestFile {
+    path: PathBuf,
+}
+
+impl ManifestFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn read<L: Link + std::str::FromStr<Err = anyhow::Error>>(
+        &self,
+    ) -> anyhow::Result<BTreeMap<String, L>> {
+        let mut roots = BTreeMap::new();
+        if !self.path.exists() {
+            return Ok(roots);
+        }
+        for line in fs::read_to_string(&self.path)?.lines() {
+            let (name, link) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow::anyhow!("invalid manifest line {}", line))?;
+            roots.insert(name.to_string(), link.parse()?);
+        }
+        Ok(roots)
+    }
+
+    /// All roots, by name
+    pub fn roots<L: Link + std::str::FromStr<Err = anyhow::Error>>(
+        &self,
+    ) -> anyhow::Result<BTreeMap<String, L>> {
+        self.read()
+    }
+
+    /// Wait for the lock file, run `f` and remove the lock file again
+    fn locked<T>(&self, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let lock = self.path.with_extension("lock");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while let Err(cause) = OpenOptions::new().write(true).create_new(true).open(&lock) {
+            // a writer that crashed while holding the lock leaves it behind
+            anyhow::ensure!(
+                Instant::now() < deadline,
+                "could not lock {}: {}, remove it if no writer is running",
+                lock.display(),
+                cause
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        let res = f();
+        fs::remove_file(&lock)?;
+        res
+    }
+}
+
+impl<L: Link + std::str::FromStr<Err = anyhow::Error>> RootStore<L> for ManifestFile {
+    fn root(&self, name: &str) -> anyhow::Result<Option<L>> {
+        Ok(self.read()?.remove(name))
+    }
+
+    fn compare_and_swap(&self, name: &str, expected: Option<L>, new: L) -> anyhow::Result<()> {
+        self.locked(|| {
+            let mut roots = self.read::<L>()?;
+            check(name, expected, roots.get(name).cloned())?;
+            roots.insert(name.to_string(), new);
+            let text = roots
+                .iter()
+                .map(|(name, link)| format!("{} {}\n", name, link))
+                .collect::<String>();
+            // write and rename, so a reader never sees a half written file
+            let tmp = self.path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)?;
+            sync_dir(match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            })
+        })
+    }
+}
+
+/// Two writers that append to the same stream, in a sqlite file and in a manifest file. The
+/// second one to swap gets a conflict, rebases and succeeds on the second try
+pub fn roots_example(config: &Config) -> anyhow::Result<()> {
+    println!("Example: two writers updating the same root with compare-and-swap");
+    let dir = std::env::temp_dir().join(format!("banyan-roots-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let store = SqliteStore::<Sha256Digest>::open(dir.join("blocks.sqlite"))?;
+    let manifest = ManifestFile::new(dir.join("manifest"));
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..1000).map(|i| ((), i)))?;
+    let base = builder.snapshot().link().expect("not empty");
+    store.compare_and_swap("events", None, base)?;
+    manifest.compare_and_swap("events", None, base)?;
+
+    // both writers start from the same root
+    let mut a = txn.load_stream_builder::<u64>(Secrets::default(), config.clone(), base)?;
+    let mut b = txn.load_stream_builder::<u64>(Secrets::default(), config.clone(), base)?;
+    txn.extend(&mut a, (1000..2000).map(|i| ((), i)))?;
+    txn.extend(&mut b, (2000..3000).map(|i| ((), i)))?;
+    let a = a.snapshot().link().expect("not empty");
+    let b = b.snapshot().link().expect("not empty");
+    let stores: [(&str, &dyn RootStore<Sha256Digest>); 2] =
+        [("sqlite", &store), ("manifest", &manifest)];
+    for (name, roots) in stores {
+        roots.compare_and_swap("events", Some(base), a)?;
+        let err = roots
+            .compare_and_swap("events", Some(base), b)
+            .expect_err("second writer must fail");
+        let conflict = err
+            .downcast_ref::<RootConflict<Sha256Digest>>()
+            .expect("a conflict");
+        anyhow::ensure!(conflict.actual == Some(a), "wrong current root");
+        anyhow::ensure!(roots.root("events")? == Some(a), "root was overwritten");
+        println!("{}: {}", name, err);
+
+        let b = txn.load_stream_builder::<u64>(Secrets::default(), config.clone(), b)?;
+        let rebased = rebase(&mut txn, &Secrets::default(), config, &b, Some(base), a)?;
+        let ab = rebased.link().expect("not empty");
+        roots.compare_and_swap("events", Some(a), ab)?;
+        let values = txn
+            .iter_from(&rebased.snapshot())
+            .map(|item| item.map(|(_, _, v)| v))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::ensure!(
+            values == (0..3000).collect::<Vec<_>>(),
+            "rebase lost events"
+        );
+        println!("{}: rebased to {} with {} events", name, ab, values.len());
+    }
+    fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! A deterministic sample of a tree
+//!
+//! Previewing a huge tree over a slow gateway should not read all of it. The traversal of a
+//! filtered iteration skips every branch for which the query says nothing can match, without
+//! loading it, and loads a leaf only if at least one of its events matches. So [SampleQuery]
+//! matches evenly spaced offsets, or a pseudo random fraction of the leaves, and
+//! [iter_sampled] reads only the blocks on the paths to them.
+//!
+//! The sample depends only on the offsets, so it is the same on every run, and for every reader
+//! of the same tree.
+use std::time::{Duration, Instant};
+
+use banyan::{
+    index::{BranchIndex, CompactSeq, LeafIndex},
+    query::Query,
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    prefetch::{self, LatencyStore},
+    snapshots::LogTT,
+};
+
+/// Which events to sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sample {
+    /// Whole leaves, each one with this probability. Cheap per event, but the events come in
+    /// runs of neighbours
+    Fraction(f64),
+    /// The events at offsets `0, n, 2n, ...`, each one costing a leaf
+    EveryNth(u64),
+}
+
+/// A query that matches the events of a [Sample]
+#[derive(Debug, Clone)]
+pub struct SampleQuery(pub Sample);
+
+/// A well mixed hash of an offset, see splitmix64
+fn mix(offset: u64) -> u64 {
+    let mut x = offset.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl SampleQuery {
+    /// Whether the leaf starting at `offset` is in a [Sample::Fraction]
+    fn leaf_sampled(fraction: f64, offset: u64) -> bool {
+        (mix(offset) as f64) < fraction * u64::MAX as f64
+    }
+}
+
+impl<T: TreeTypes> Query<T> for SampleQuery {
+    fn containing(&self, offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        match self.0 {
+            Sample::Fraction(fraction) => {
+                if !Self::leaf_sampled(fraction, offset) {
+                    res.iter_mut().for_each(|x| *x = false);
+                }
+            }
+            Sample::EveryNth(n) => {
+                for (i, x) in res.iter_mut().enumerate().take(index.keys.count() as usize) {
+                    *x &= (offset + i as u64).is_multiple_of(n);
+                }
+            }
+        }
+    }
+
+    fn intersecting(&self, offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        // the offsets of the children are not known before the branch is loaded, so only a
+        // branch without a single sampled offset can be skipped
+        if let Sample::EveryNth(n) = self.0 {
+            let next = offset.div_ceil(n) * n;
+            if next >= offset + index.count {
+                res.iter_mut().for_each(|x| *x = false);
+            }
+        }
+    }
+}
+
+/// The events of the sample, in offset order
+pub fn iter_sampled<T, R, V>(
+    forest: &Forest<T, R>,
+    tree: &Tree<T, V>,
+    sample: Sample,
+) -> impl Iterator<Item = anyhow::Result<(u64, T::Key, V)>> + 'static
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    V: BanyanValue,
+{
+    if let Sample::EveryNth(n) = sample {
+        assert!(n > 0, "every 0th event is not a sample");
+    }
+    forest.iter_filtered(tree, SampleQuery(sample))
+}
+
+/// Sample a tree through a store with a latency like a remote store, and compare the time to
+/// reading all of it
+pub fn sample_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let latency = Duration::from_millis(1);
+    println!(
+        "Example: sampling {} events, with {:?} per block get",
+        n, latency
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, like in a tree where each event is large
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config, Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+
+    let slow = LatencyStore::new(store, latency);
+    let forest = Forest::<LogTT, _>::new(slow, BranchCache::new(0));
+    let t0 = Instant::now();
+    let all = forest.iter_from(&tree).count() as u64;
+    anyhow::ensure!(all == n, "events are missing");
+    let leaves = prefetch::leaf_links(&forest, &tree)?.len();
+    println!("sample\tevents\ttime");
+    println!("all\t{}\t{:.3}s", all, t0.elapsed().as_secs_f64());
+
+    for sample in [Sample::EveryNth(n / 100), Sample::Fraction(0.05)] {
+        let t0 = Instant::now();
+        let events = iter_sampled(&forest, &tree, sample).collect::<anyhow::Result<Vec<_>>>()?;
+        let elapsed = t0.elapsed();
+        anyhow::ensure!(!events.is_empty(), "the sample is empty");
+        for (i, _, value) in &events {
+            anyhow::ensure!(*i == *value, "event {} has the wrong value", i);
+        }
+        if let Sample::EveryNth(n) = sample {
+            anyhow::ensure!(
+                events
+                    .iter()
+                    .map(|(i, _, _)| *i)
+                    .eq((0..all).step_by(n as usize)),
+                "wrong offsets"
+            );
+        }
+        let again = iter_sampled(&forest, &tree, sample)
+            .map(|item| item.map(|(i, _, _)| i))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::ensure!(
+            again.iter().eq(events.iter().map(|(i, _, _)| i)),
+            "the sample is not deterministic"
+        );
+        println!(
+            "{:?}\t{}\t{:.3}s",
+            sample,
+            events.len(),
+            elapsed.as_secs_f64()
+        );
+    }
+    println!("{} leaves", leaves);
+    println!();
+    Ok(())
+}
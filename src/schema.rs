@@ -0,0 +1,191 @@
+//! A description of the types of a tree, stored next to its root
+//!
+//! A root is just a link. Opening it with the wrong [TreeTypes] or value type either fails to
+//! decrypt, since the nonce is different, or decodes garbage, or fails somewhere deep in a leaf
+//! with an error about an unexpected cbor code. So when a root is published, the schema of its
+//! key and value types is stored as a block, with the root `<name>.schema` pointing to it. Before
+//! reading, [check] compares it to the types the binary was compiled with, and fails with both
+//! schemas if they differ.
+//!
+//! The schemas are in the IPLD schema language, and written by hand for each type, so they are
+//! only as correct as the [Schema] impls.
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor, Ipld};
+
+use crate::{
+    columnar::EventKey,
+    error::Error,
+    retention::TaggedKey,
+    roots::{ManifestFile, RootStore},
+    schemaless::SchemalessTT,
+    versioned::{Reading, Versioned, VersionedTT},
+};
+
+/// A type with a description in the IPLD schema language
+pub trait Schema {
+    fn schema() -> String;
+}
+
+impl Schema for () {
+    fn schema() -> String {
+        "type Unit null".into()
+    }
+}
+
+impl Schema for u64 {
+    fn schema() -> String {
+        "type Value int".into()
+    }
+}
+
+impl Schema for Ipld {
+    fn schema() -> String {
+        "type Value any".into()
+    }
+}
+
+impl Schema for Versioned {
+    fn schema() -> String {
+        "type Versioned struct { version Int body Any }".into()
+    }
+}
+
+impl Schema for Reading {
+    fn schema() -> String {
+        "type Reading struct { sensor Int celsius Int location nullable String }".into()
+    }
+}
+
+impl Schema for EventKey {
+    fn schema() -> String {
+        "type EventKey struct { time Int device Int }".into()
+    }
+}
+
+impl Schema for TaggedKey {
+    fn schema() -> String {
+        "type TaggedKey struct { time Int tags Int }".into()
+    }
+}
+
+/// The types of a tree
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct TreeSchema {
+    /// the nonce of the [TreeTypes], which tells them apart
+    pub nonce: String,
+    pub key: String,
+    pub value: String,
+}
+
+impl TreeSchema {
+    /// The schema of the types this binary was compiled with
+    pub fn of<T, V>() -> Self
+    where
+        T: TreeTypes,
+        T::Key: Schema,
+        V: Schema,
+    {
+        Self {
+            nonce: String::from_utf8_lossy(T::NONCE).into_owned(),
+            key: T::Key::schema(),
+            value: V::schema(),
+        }
+    }
+}
+
+/// The root that points to the schema of the root `name`
+fn schema_name(name: &str) -> String {
+    format!("{}.schema", name)
+}
+
+/// Store the schema of the tree and point `<name>.schema` to it, unless it is already there
+pub fn record<T, V, R>(
+    store: &mut impl BlockWriter<Sha256Digest>,
+    roots: &R,
+    name: &str,
+) -> anyhow::Result<()>
+where
+    T: TreeTypes,
+    T::Key: Schema,
+    V: Schema,
+    R: RootStore<Sha256Digest>,
+{
+    let link = store.put(DagCborCodec.encode(&TreeSchema::of::<T, V>())?)?;
+    let name = schema_name(name);
+    let current = roots.root(&name)?;
+    if current != Some(link) {
+        roots.compare_and_swap(&name, current, link)?;
+    }
+    Ok(())
+}
+
+/// Fail unless the tree was recorded with the schema of `T` and `V`. Trees from before schemas
+/// were recorded have none, and pass
+pub fn check<T, V, R>(
+    store: &impl ReadOnlyStore<Sha256Digest>,
+    roots: &R,
+    name: &str,
+) -> anyhow::Result<()>
+where
+    T: TreeTypes,
+    T::Key: Schema,
+    V: Schema,
+    R: RootStore<Sha256Digest>,
+{
+    let link = match roots.root(&schema_name(name))? {
+        Some(link) => link,
+        None => return Ok(()),
+    };
+    let stored: TreeSchema = DagCborCodec.decode(&store.get(&link)?)?;
+    let expected = TreeSchema::of::<T, V>();
+    if stored != expected {
+        return Err(Error::SchemaMismatch {
+            name: name.to_string(),
+            expected,
+            actual: stored,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Record the schema next to a root in a manifest, and open it with the right and wrong types
+pub fn schema_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    println!("Example: checking the types of a tree against the schema in the manifest");
+    let path = std::env::temp_dir().join(format!("banyan-schema-{}", std::process::id()));
+    let manifest = ManifestFile::new(&path);
+    let forest = Forest::<VersionedTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder =
+        StreamBuilder::<VersionedTT, Versioned>::new(config.clone(), Secrets::default());
+    let reading = Reading {
+        sensor: 1,
+        celsius: 20,
+        location: None,
+    };
+    let value = Versioned::new(Reading::VERSION, &reading)?;
+    txn.extend(&mut builder, (0..1000).map(|_| ((), value.clone())))?;
+    let root = builder.link().expect("not empty");
+    record::<VersionedTT, Versioned, _>(&mut store.clone(), &manifest, "readings")?;
+    manifest.compare_and_swap("readings", None, root)?;
+    // recording the same schema again changes nothing
+    record::<VersionedTT, Versioned, _>(&mut store.clone(), &manifest, "readings")?;
+
+    check::<VersionedTT, Versioned, _>(&store, &manifest, "readings")?;
+    let err = check::<VersionedTT, Reading, _>(&store, &manifest, "readings")
+        .expect_err("wrong value type must fail");
+    println!("{}", err);
+    let err = check::<SchemalessTT, Ipld, _>(&store, &manifest, "readings")
+        .expect_err("wrong tree types must fail");
+    println!("{}", err);
+    std::fs::remove_file(&path)?;
+    println!();
+    Ok(())
+}
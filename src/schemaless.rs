@@ -0,0 +1,99 @@
+//! Schemaless events
+//!
+//! The value type of a tree only needs to be dag-cbor encodable, and [Ipld] is. So a tree with
+//! [Ipld] values takes any JSON-like event without defining a struct for it. libipld converts
+//! between JSON and [Ipld] with its dag-json codec.
+//!
+//! The dag-json codec of libipld 0.12 predates the current spec: a link is `{"/": "<base64>"}`
+//! with the binary cid instead of its string form, and bytes are written as a list of numbers,
+//! so they come back as a list. Events from JSON never contain bytes, so they round trip.
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{codec::Codec, json::DagJsonCodec, Ipld};
+
+#[derive(Debug, Clone)]
+pub struct SchemalessTT;
+
+impl TreeTypes for SchemalessTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Schemaless events camp..";
+}
+
+/// Parse a JSON document as dag-json
+pub fn from_json(json: &str) -> anyhow::Result<Ipld> {
+    DagJsonCodec.decode(json.as_bytes())
+}
+
+/// Format a value as dag-json
+pub fn to_json(value: &Ipld) -> anyhow::Result<String> {
+    Ok(String::from_utf8(DagJsonCodec.encode(value)?)?)
+}
+
+/// A few events with nothing in common
+const EVENTS: &[&str] = &[
+    r#"{"type":"login","user":"alice","ok":true}"#,
+    r#"{"type":"reading","sensor":17,"values":[1.5,2.25,-3.0]}"#,
+    r#"{"type":"note","text":"hello","tags":["a","b"],"meta":{"edited":null}}"#,
+    r#"{"type":"ref","target":{"/":"AXESICaBo51e5UrymZoWZqOJjP1HG2lp2aWc3nfLUWymX5wt"}}"#,
+    r#"42"#,
+];
+
+/// Append heterogeneous JSON events to a tree with [Ipld] values and read them back as JSON
+pub fn schemaless_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000usize;
+    println!(
+        "Example: {} schemaless events, {} different shapes",
+        n,
+        EVENTS.len()
+    );
+    let events = EVENTS
+        .iter()
+        .map(|json| from_json(json))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let forest = Forest::<SchemalessTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<SchemalessTT, Ipld>::new(config.clone(), Secrets::default());
+    txn.extend(
+        &mut builder,
+        events
+            .iter()
+            .cycle()
+            .take(n)
+            .map(|event| ((), event.clone())),
+    )?;
+    let tree = builder.snapshot();
+    println!("{:#?}", tree);
+
+    // every event comes back as it went in, and formats as the same JSON
+    for item in txn.iter_from(&tree) {
+        let (i, _, value) = item?;
+        anyhow::ensure!(
+            value == events[i as usize % events.len()],
+            "event {} changed",
+            i
+        );
+    }
+    for item in txn.iter_filtered(&tree, OffsetRangeQuery::from(0..EVENTS.len() as u64)) {
+        let (i, _, value) = item?;
+        let json = to_json(&value)?;
+        anyhow::ensure!(
+            from_json(&json)? == value,
+            "event {} does not round trip",
+            i
+        );
+        println!("{}\t{}", i, json);
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Binary search in a tree with non-decreasing keys
+//!
+//! When the keys of a tree never decrease, like the times of a log of events, the summaries of
+//! the children of a branch are sorted as well. So the first event with a key of at least some
+//! value is in the first child whose summary has a maximum of at least that value, and
+//! [find_first_ge] finds it by going down a single path from the root, which is one block read
+//! per level. The keys of a leaf are in the branch above it, so the leaf itself is not read.
+//!
+//! Nothing checks that the keys are actually sorted. For a tree where they are not, the result
+//! is some offset, but not necessarily the first one.
+use std::time::{Duration, Instant};
+
+use banyan::{
+    index::{CompactSeq, Index},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT, EventSummary, TimeRangeQuery},
+    prefetch::LatencyStore,
+    projection,
+};
+
+/// Tree types whose keys are sorted by some part of them
+pub trait Monotonic: TreeTypes {
+    /// The part of the key that never decreases
+    type Ord: Ord;
+
+    fn key_ord(key: &Self::Key) -> Self::Ord;
+
+    /// The largest part of all keys of the summary
+    fn summary_max(summary: &Self::Summary) -> Self::Ord;
+}
+
+/// Events are appended in time order
+impl Monotonic for ColumnarTT {
+    type Ord = u64;
+
+    fn key_ord(key: &Self::Key) -> u64 {
+        key.time
+    }
+
+    fn summary_max(summary: &EventSummary) -> u64 {
+        summary.max_time
+    }
+}
+
+/// The offset and key of the first event whose key is at least `value`
+pub fn find_first_ge<T, R, V>(
+    store: &R,
+    tree: &Tree<T, V>,
+    value: &T::Ord,
+) -> anyhow::Result<Option<(u64, T::Key)>>
+where
+    T: Monotonic,
+    R: ReadOnlyStore<T::Link>,
+{
+    let secrets = tree.secrets().cloned().unwrap_or_default();
+    let mut index = match tree.index() {
+        Some(index) => index.clone(),
+        None => return Ok(None),
+    };
+    let mut offset = 0;
+    loop {
+        match index {
+            Index::Leaf(leaf) => {
+                let keys = leaf.keys.to_vec();
+                let i = keys.partition_point(|key| T::key_ord(key) < *value);
+                return Ok(keys.into_iter().nth(i).map(|key| (offset + i as u64, key)));
+            }
+            Index::Branch(branch) => {
+                let summaries = branch.summaries.to_vec();
+                let i = summaries.partition_point(|summary| T::summary_max(summary) < *value);
+                if i == summaries.len() {
+                    return Ok(None);
+                }
+                let link = branch
+                    .link
+                    .ok_or_else(|| anyhow::anyhow!("branch at {} is purged", offset))?;
+                let mut children = projection::load_children(store, &secrets, &link)?;
+                offset += children[..i].iter().map(|child| child.count()).sum::<u64>();
+                index = children.swap_remove(i);
+            }
+        }
+    }
+}
+
+/// Find events by time with a binary search, a filtered iteration and a scan, through a store
+/// with a latency like a remote store
+pub fn search_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let latency = Duration::from_millis(1);
+    println!(
+        "Example: finding events by time in {} events, with {:?} per block get",
+        n, latency
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    // small leaves, so the tree has a few levels
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, Secrets::default());
+    let events = columnar::events(n);
+    let first = events[0].0.time;
+    let last = events[events.len() - 1].0.time;
+    txn.extend(&mut builder, events)?;
+    let tree = builder.snapshot();
+
+    let slow = LatencyStore::new(store, latency);
+    let forest = Forest::<ColumnarTT, _>::new(slow.clone(), BranchCache::new(0));
+    println!("time\toffset\tsearch\tfiltered\tscan");
+    let times = [0, first, first + (last - first) / 3, last, last + 1];
+    for time in times {
+        let t0 = Instant::now();
+        let found = find_first_ge(&slow, &tree, &time)?;
+        let search = t0.elapsed();
+
+        let t0 = Instant::now();
+        let query = TimeRangeQuery {
+            min: time,
+            max: u64::MAX,
+        };
+        let filtered = forest
+            .iter_filtered(&tree, query)
+            .next()
+            .transpose()?
+            .map(|(i, key, _)| (i, key));
+        let filtered_time = t0.elapsed();
+
+        let t0 = Instant::now();
+        let mut scanned = None;
+        for item in forest.iter_from(&tree) {
+            let (i, key, _) = item?;
+            if key.time >= time {
+                scanned = Some((i, key));
+                break;
+            }
+        }
+        let scan = t0.elapsed();
+        anyhow::ensure!(
+            found == filtered && found == scanned,
+            "search for {} found {:?}",
+            time,
+            found
+        );
+        println!(
+            "{}\t{:?}\t{:.3}s\t{:.3}s\t{:.3}s",
+            time,
+            found.map(|(i, _)| i),
+            search.as_secs_f64(),
+            filtered_time.as_secs_f64(),
+            scan.as_secs_f64()
+        );
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! A secondary index tree next to the primary event sequence
+//!
+//! The primary tree stores the events in the order they were written, so it can only be queried
+//! by offset. To find all events of a sensor, we keep a second tree keyed by sensor id whose
+//! values are offsets into the primary tree. A transaction is per tree type, so we use one for
+//! each tree, but both are always extended with the same batch and snapshotted together, so the
+//! pair of roots is one consistent state.
+use std::time::Instant;
+
+use banyan::{
+    index::{BranchIndex, LeafIndex, Summarizable, VecSeq},
+    query::Query,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::DagCbor;
+
+/// The event payload
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct Reading {
+    pub sensor: u32,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone)]
+struct PrimaryTT;
+
+impl TreeTypes for PrimaryTT {
+    type Key = (); // ordered by offset only
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Primary tree for camp...";
+}
+
+#[derive(Debug, Clone)]
+struct SecondaryTT;
+
+/// Inclusive range of sensor ids
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+struct SensorRange {
+    min: u32,
+    max: u32,
+}
+
+impl TreeTypes for SecondaryTT {
+    type Key = u32; // the sensor id
+    type Summary = SensorRange;
+    type KeySeq = VecSeq<u32>;
+    type SummarySeq = VecSeq<SensorRange>;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Secondary tree for camp.";
+}
+
+impl Summarizable<SensorRange> for VecSeq<u32> {
+    fn summarize(&self) -> SensorRange {
+        let min = self.as_ref().iter().cloned().min().unwrap_or_default();
+        let max = self.as_ref().iter().cloned().max().unwrap_or_default();
+        SensorRange { min, max }
+    }
+}
+
+impl Summarizable<SensorRange> for VecSeq<SensorRange> {
+    fn summarize(&self) -> SensorRange {
+        let min = self
+            .as_ref()
+            .iter()
+            .map(|x| x.min)
+            .min()
+            .unwrap_or_default();
+        let max = self
+            .as_ref()
+            .iter()
+            .map(|x| x.max)
+            .max()
+            .unwrap_or_default();
+        SensorRange { min, max }
+    }
+}
+
+/// All secondary index entries for one sensor
+#[derive(Debug, Clone)]
+struct SensorQuery(u32);
+
+impl Query<SecondaryTT> for SensorQuery {
+    fn containing(&self, _offset: u64, index: &LeafIndex<SecondaryTT>, res: &mut [bool]) {
+        for (i, key) in index.keys.as_ref().iter().enumerate() {
+            res[i] = res[i] && *key == self.0;
+        }
+    }
+
+    fn intersecting(&self, _offset: u64, index: &BranchIndex<SecondaryTT>, res: &mut [bool]) {
+        for (i, s) in index.summaries.as_ref().iter().enumerate() {
+            res[i] = res[i] && s.min <= self.0 && self.0 <= s.max;
+        }
+    }
+}
+
+/// The primary and the secondary tree, always extended together
+struct IndexedLog {
+    primary: StreamBuilder<PrimaryTT, Reading>,
+    secondary: StreamBuilder<SecondaryTT, u64>,
+}
+
+impl IndexedLog {
+    fn new(config: &Config) -> Self {
+        Self {
+            primary: StreamBuilder::new(config.clone(), Secrets::default()),
+            secondary: StreamBuilder::new(config.clone(), Secrets::default()),
+        }
+    }
+
+    /// Append a batch to the primary tree and its index entries to the secondary tree
+    fn append<S>(
+        &mut self,
+        primary: &mut Transaction<PrimaryTT, S, S>,
+        secondary: &mut Transaction<SecondaryTT, S, S>,
+        batch: Vec<Reading>,
+    ) -> anyhow::Result<()>
+    where
+        S: ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    {
+        let offset = self.primary.snapshot().count();
+        // sort the index entries of a batch, so the leaves of the secondary tree cover narrow
+        // ranges of sensor ids and the summaries can prune most of them
+        let mut entries = batch
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.sensor, offset + i as u64))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        primary.extend(&mut self.primary, batch.into_iter().map(|r| ((), r)))?;
+        secondary.extend(&mut self.secondary, entries)?;
+        Ok(())
+    }
+
+    /// The current state, as a pair of trees
+    fn snapshot(&self) -> (Tree<PrimaryTT, Reading>, Tree<SecondaryTT, u64>) {
+        (self.primary.snapshot(), self.secondary.snapshot())
+    }
+}
+
+/// Maintain a secondary index by sensor id and use it to find all readings of one sensor
+pub fn secondary_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 1000000u64;
+    let batch_size = 10000;
+    println!(
+        "Example: {} readings with a secondary index by sensor, in batches of {}",
+        n, batch_size
+    );
+    let mut primary = Transaction::new(
+        Forest::new(store.clone(), BranchCache::new(1024)),
+        store.clone(),
+    );
+    let mut secondary = Transaction::new(Forest::new(store.clone(), BranchCache::new(1024)), store);
+    let mut log = IndexedLog::new(config);
+
+    let readings = (0..n)
+        .map(|i| Reading {
+            sensor: (i.wrapping_mul(0x9e3779b97f4a7c15) >> 54) as u32,
+            value: i,
+        })
+        .collect::<Vec<_>>();
+    let t0 = Instant::now();
+    for batch in readings.chunks(batch_size) {
+        log.append(&mut primary, &mut secondary, batch.to_vec())?;
+    }
+    let (events, index) = log.snapshot();
+    println!(
+        "primary {:#?}\nsecondary {:#?} {}s",
+        events,
+        index,
+        t0.elapsed().as_secs_f64()
+    );
+
+    // look up the offsets in the secondary tree, then the payloads in the primary tree
+    let sensor = readings[0].sensor;
+    let t0 = Instant::now();
+    let mut found = 0;
+    for item in secondary.iter_filtered(&index, SensorQuery(sensor)) {
+        let (_i, _sensor, offset) = item?;
+        let (_, reading) = primary
+            .get(&events, offset)?
+            .ok_or_else(|| anyhow::anyhow!("offset {} not in primary tree", offset))?;
+        anyhow::ensure!(reading.sensor == sensor, "index points to the wrong event");
+        found += 1;
+    }
+    let expected = readings.iter().filter(|r| r.sensor == sensor).count();
+    anyhow::ensure!(found == expected, "index lookup missed events");
+    println!(
+        "sensor {}: {} readings {}s",
+        sensor,
+        found,
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
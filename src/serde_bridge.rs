@@ -0,0 +1,106 @@
+//! dag-cbor encoding for serde types
+//!
+//! Keys and values of a tree need libipld's `Encode` and `Decode` for
+//! [DagCborCodec]. Types that already implement serde's `Serialize` and `Deserialize` can use
+//! [Serde] instead of a `DagCbor` derive, which encodes them with serde_ipld_dagcbor. A blanket
+//! impl for all serde types is not possible, since neither the traits nor the types are ours.
+//!
+//! serde_ipld_dagcbor uses a newer cid crate than libipld 0.12, so values with links can not be
+//! encoded this way. Everything else gives the same dag-cbor as a derive would.
+//!
+//! Only available with the `serde` feature.
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, Decode, Encode},
+    Ipld,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A wrapper that implements dag-cbor `Encode` and `Decode` via serde
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Serde<T>(pub T);
+
+impl<T: Serialize> Encode<DagCborCodec> for Serde<T> {
+    fn encode<W: Write>(&self, _: DagCborCodec, w: &mut W) -> libipld::Result<()> {
+        serde_ipld_dagcbor::to_writer(w, &self.0)?;
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned> Decode<DagCborCodec> for Serde<T> {
+    fn decode<R: Read + Seek>(_: DagCborCodec, r: &mut R) -> libipld::Result<Self> {
+        // serde_ipld_dagcbor wants a BufRead, which may read ahead. The caller expects the
+        // reader to be right after this value, so give back what was not used
+        let mut reader = BufReader::new(r);
+        let value = serde_ipld_dagcbor::de::from_reader_once(&mut reader)?;
+        let unread = reader.fill_buf()?.len() as i64;
+        reader.into_inner().seek(SeekFrom::Current(-unread))?;
+        Ok(Self(value))
+    }
+}
+
+/// A value type that only knows about serde
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Measurement {
+    sensor: String,
+    value: f64,
+    unit: Option<String>,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct SerdeTT;
+
+impl TreeTypes for SerdeTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Serde bridge for camp...";
+}
+
+/// Store serde values in a tree and read them back
+pub fn serde_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: {} serde values", n);
+    let xs = (0..n)
+        .map(|i| Measurement {
+            sensor: format!("sensor-{}", i % 10),
+            value: i as f64 / 4.0,
+            unit: if i % 3 == 0 { Some("°C".into()) } else { None },
+            tags: (0..i % 3).map(|t| format!("tag{}", t)).collect(),
+        })
+        .collect::<Vec<_>>();
+
+    // the encoding is plain dag-cbor, so libipld can read it without knowing the type
+    let ipld: Ipld = DagCborCodec.decode(&DagCborCodec.encode(&Serde(xs[1].clone()))?)?;
+    println!("{:?}", ipld);
+
+    let forest = Forest::<SerdeTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder =
+        StreamBuilder::<SerdeTT, Serde<Measurement>>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, xs.iter().map(|x| ((), Serde(x.clone()))))?;
+    let tree = builder.snapshot();
+    println!("{:#?}", tree);
+    let mut count = 0;
+    for item in txn.iter_from(&tree) {
+        let (i, _, Serde(value)) = item?;
+        anyhow::ensure!(value == xs[i as usize], "value {} changed", i);
+        count += 1;
+    }
+    anyhow::ensure!(count == n, "values are missing");
+    println!();
+    Ok(())
+}
@@ -0,0 +1,1414 @@
+//! An http server for the streams of a manifest file
+//!
+//! The server has a thread per connection, up to a limit, and besides the sync requests of
+//! [crate::sync] it takes new events, and keeps clients up to date over a WebSocket:
+//!
+//! ```text
+//! GET /sync/<name>?have=<cid>     the blocks a reader is missing, see crate::sync
+//! POST /append/<name>             append the JSON values of the body, one per line
+//! GET /tail/<name>?offset=<n>     a WebSocket with every event from offset n on
+//! POST /trees/<root>/select       the events of a columnar tree that match the json query of
+//!                                 the body, see crate::query_json
+//! GET /trees/<root>/aggregate?op=<op>&from=<t>&to=<t>
+//!                                 a count, sum, min or max over a time range of a columnar
+//!                                 tree, see crate::rollup
+//! GET /metrics                    the metrics for prometheus, see crate::metrics
+//! GET /healthz                    the health of the store, see crate::health
+//! ```
+//!
+//! Appended events go to a tree of [schemaless](crate::schemaless) events with the default
+//! secrets, like the export command reads them, and the root in the manifest is swapped after
+//! each append. The answer has the new root, and the offsets of the events from and to,
+//! exclusive. An append to a root that someone else swapped in the meantime fails with a 409.
+//!
+//! With a master key, the trees of the streams of a tenant have the secrets of the tenant
+//! instead, see [crate::tenants], and a select or an aggregate of a tree of a tenant needs the
+//! tenant as the `tenant` parameter of the query.
+//!
+//! A tail sends each event as a text message with its dag-json line of the export command,
+//! `{"offset":..,"key":..,"value":..}`, and then the new events each time the root changes, be it
+//! by an append to this server or by another writer of the manifest. Events only ever go at the
+//! end of a tree and keep their offset, so a client that connects again with the offset after the
+//! last event it got neither misses nor repeats one. A root with fewer events than the offset of
+//! the tail, like after the stream was replaced, closes it with an error.
+//!
+//! A select turns the [JsonQuery] into the queries of banyan here, so only the blocks it does not
+//! prune are read, and sends each event as a line of json, `{"offset":..,"time":..,"device":..,
+//! "value":..}`, as it finds them. The body is chunked, so a client can tell an answer that broke
+//! off from a complete one. An aggregate answers from the summaries of the branches where it can,
+//! so a count over a range reads the blocks along its edges, not the events in between. Its answer
+//! is `{"op":..,"value":..,"blocks":..}`, with a null value for the min or max of no events.
+//!
+//! The health is a probe of the store on every request, a 200 with the [Health] as json, or a 503
+//! if the store does not answer.
+//!
+//! The name of a stream is `stream`, or `tenant/stream` for a stream of a tenant, see
+//! [crate::tenants]. With an access file, every request needs a token that may read or append to
+//! its stream, and a token of a tenant never gets to the streams of another, see [crate::access].
+//! Requests over the rate limit of their client address, or queries over the limit of queries at
+//! the same time, are answered with a 429, see [crate::limits].
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use banyan::{
+    chacha20::Key as SecretKey,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use reqwest::Url;
+
+use crate::{
+    access::{self, Access, Denied, Permission},
+    batch::{self, BatchQuery, BatchStore},
+    cancel::{self, Cancel, CancellableStore},
+    columnar::{ColumnarTT, TimeRangeQuery},
+    drivers::Registry,
+    error::{self, ErrorKind},
+    health::Health,
+    kubo::KuboStore,
+    limits::{Limits, RateLimit, Semaphore},
+    metrics::{BlockCache, CachedStore, Metrics},
+    query_json::JsonQuery,
+    rollup::{self, Op},
+    roots::{ManifestFile, RootStore},
+    schemaless::{self, SchemalessTT},
+    sync, tenants,
+    trace::TracingStore,
+    verify::VerifyingStore,
+    websocket,
+};
+
+/// The longest request line or header line
+const MAX_LINE: u64 = 8 << 10;
+/// The longest body of an append or a query
+pub const MAX_BODY: u64 = 16 << 20;
+/// The longest message a tail client takes, an event as dag-json
+pub const MAX_MESSAGE: u64 = 64 << 20;
+/// How often a tail looks for a root that another writer of the manifest swapped
+const POLL: Duration = Duration::from_secs(1);
+/// How long a read of a request may wait for the client
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a write of an answer may wait for the client
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a read of a request over the rate limit may wait for the client
+const REJECTED_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A status like `200 OK`, and a body
+pub type Response = (&'static str, Vec<u8>);
+
+/// The parts of an http request that the server looks at
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// the target on a made up host, for its path and query
+    pub url: Url,
+    /// the headers, with lower case names
+    headers: Vec<(String, String)>,
+}
+
+fn read_line(reader: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE).read_line(&mut line)?;
+    anyhow::ensure!(line.ends_with('\n'), "request line cut off or too long");
+    Ok(line.trim_end().to_string())
+}
+
+impl Request {
+    /// Read the request line and the headers
+    pub fn read(reader: &mut impl BufRead) -> anyhow::Result<Self> {
+        let line = read_line(reader)?;
+        let (method, target) = match line.split(' ').collect::<Vec<_>>()[..] {
+            [method, target, _] => (method.to_string(), target),
+            _ => anyhow::bail!("invalid request line {}", line),
+        };
+        let url = Url::parse("http://server")?.join(target)?;
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            anyhow::ensure!(headers.len() < 100, "too many headers");
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+        Ok(Self {
+            method,
+            url,
+            headers,
+        })
+    }
+
+    /// The value of a header, by its lower case name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The value of a parameter of the query
+    pub fn query(&self, name: &str) -> Option<String> {
+        self.url
+            .query_pairs()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.into_owned())
+    }
+}
+
+/// Answer with a status and a body, and close the connection
+pub fn respond(stream: &TcpStream, status: &str, body: &[u8]) -> anyhow::Result<()> {
+    respond_with(stream, status, &[], body)
+}
+
+/// [respond] with more headers, like the type of the body
+pub fn respond_with(
+    mut stream: &TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> anyhow::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\n", status)?;
+    for (name, value) in headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    Ok(())
+}
+
+/// A body in chunks, written as they come
+struct Chunked<W>(W);
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would be the end of the body
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Read the body of a request, or answer why not
+fn read_body(request: &Request, body: impl Read) -> anyhow::Result<Result<Vec<u8>, Response>> {
+    let Some(len) = request.header("content-length") else {
+        return Ok(Err(("411 Length Required", Vec::new())));
+    };
+    let len = match len.parse::<u64>() {
+        Ok(len) if len <= MAX_BODY => len,
+        _ => {
+            let message = format!("a body has at most {} bytes", MAX_BODY);
+            return Ok(Err(("413 Payload Too Large", message.into_bytes())));
+        }
+    };
+    let mut data = Vec::new();
+    body.take(len).read_to_end(&mut data)?;
+    anyhow::ensure!(data.len() as u64 == len, "the body is cut off");
+    Ok(Ok(data))
+}
+
+/// Answer a request over a limit, with when to try again in whole seconds
+fn too_many(stream: &TcpStream, wait: Duration, message: &str) -> anyhow::Result<()> {
+    let seconds = wait.as_secs_f64().ceil().max(1.0).to_string();
+    let retry = ("Retry-After", seconds.as_str());
+    respond_with(
+        stream,
+        "429 Too Many Requests",
+        &[retry],
+        message.as_bytes(),
+    )
+}
+
+/// The answer to a request that failed, with a status by the [kind](error::kind) of the error
+fn failed(cause: &anyhow::Error) -> Response {
+    let status = match error::kind(cause) {
+        ErrorKind::Conflict => "409 Conflict",
+        ErrorKind::StoreUnavailable | ErrorKind::Transient => "503 Service Unavailable",
+        ErrorKind::Cancelled => "504 Gateway Timeout",
+        ErrorKind::QuotaExceeded => "507 Insufficient Storage",
+        _ => "500 Internal Server Error",
+    };
+    (status, format!("{:#}", cause).into_bytes())
+}
+
+/// A count of the roots this server swapped, to wake the tails
+#[derive(Debug, Default)]
+struct Changed {
+    count: Mutex<u64>,
+    cond: Condvar,
+}
+
+impl Changed {
+    fn count(&self) -> u64 {
+        *self.count.lock().unwrap()
+    }
+
+    fn notify(&self) {
+        *self.count.lock().unwrap() += 1;
+        self.cond.notify_all();
+    }
+
+    /// Wait until the count is no longer `seen`, or for the timeout, and return the count
+    fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        let count = self.count.lock().unwrap();
+        let (count, _) = self
+            .cond
+            .wait_timeout_while(count, timeout, |count| *count == seen)
+            .unwrap();
+        *count
+    }
+}
+
+/// A writer that sends each line as a text message
+struct Messages<'a> {
+    socket: &'a Mutex<TcpStream>,
+    line: Vec<u8>,
+}
+
+impl Write for Messages<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|byte| *byte == b'\n') {
+            let line = self.line.drain(..=end).collect::<Vec<_>>();
+            let socket = self.socket.lock().unwrap();
+            websocket::write_frame(&*socket, websocket::TEXT, &line[..end])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Answer the control frames of a tail client until it closes the connection
+fn client_frames(mut reader: BufReader<TcpStream>, socket: &Mutex<TcpStream>, closed: &AtomicBool) {
+    // a client only sends control frames, which are at most 125 bytes
+    while let Ok(frame) = websocket::read_frame(&mut reader, 125) {
+        let answer = match frame.opcode {
+            websocket::PING => websocket::PONG,
+            websocket::CLOSE => websocket::CLOSE,
+            _ => continue,
+        };
+        let socket = socket.lock().unwrap();
+        if websocket::write_frame(&*socket, answer, &frame.payload).is_err()
+            || answer == websocket::CLOSE
+        {
+            break;
+        }
+    }
+    closed.store(true, Ordering::SeqCst);
+}
+
+/// Close a tail, with the message of the error if it failed
+fn close(socket: &Mutex<TcpStream>, res: &anyhow::Result<()>) {
+    let mut payload = Vec::new();
+    match res {
+        Ok(()) => payload.extend_from_slice(&1000u16.to_be_bytes()),
+        Err(cause) => {
+            // an internal error, with as much of the message as fits in a control frame
+            payload.extend_from_slice(&1011u16.to_be_bytes());
+            let message = format!("{:#}", cause);
+            let mut end = message.len().min(123);
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&message.as_bytes()[..end]);
+        }
+    }
+    let socket = socket.lock().unwrap();
+    // the client may be gone already
+    websocket::write_frame(&*socket, websocket::CLOSE, &payload).ok();
+    socket.shutdown(Shutdown::Both).ok();
+}
+
+/// The server, shared by the threads of its connections
+pub struct Server<F, M> {
+    /// makes a store per request, so a timeout of the store is per request
+    store: F,
+    roots: M,
+    /// the config of the trees of appended events
+    config: Config,
+    /// one append at a time, so the appends to this server do not conflict with each other
+    appending: Mutex<()>,
+    changed: Changed,
+    /// the blocks of all requests, in front of the stores of the requests
+    cache: Arc<Mutex<BlockCache>>,
+    metrics: Arc<Metrics>,
+    /// who may do what, or everyone everything
+    access: Option<Access>,
+    /// the key the secrets of each tenant are derived from, or the default secrets for all
+    master_key: Option<SecretKey>,
+    rate_limit: Option<RateLimit>,
+    /// the tree traversals, of all requests together
+    queries: Semaphore,
+    /// the connections with a thread, and how many there may be
+    connections: AtomicUsize,
+    max_connections: usize,
+    /// probes the store for `/healthz`
+    health: Box<dyn Fn() -> anyhow::Result<Health> + Send + Sync>,
+}
+
+impl<F, S, M> Server<F, M>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    M: RootStore<Sha256Digest> + Send + Sync + 'static,
+{
+    /// A server with a block cache of at most `cache_bytes`, and the health of the store from the
+    /// probe
+    pub fn new(
+        store: F,
+        roots: M,
+        config: Config,
+        cache_bytes: usize,
+        health: impl Fn() -> anyhow::Result<Health> + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            store,
+            roots,
+            config,
+            appending: Mutex::new(()),
+            changed: Changed::default(),
+            cache: Arc::new(Mutex::new(BlockCache::new(cache_bytes))),
+            metrics: Arc::new(Metrics::new()?),
+            access: None,
+            master_key: None,
+            rate_limit: None,
+            queries: Semaphore::new(usize::MAX),
+            connections: AtomicUsize::new(0),
+            max_connections: usize::MAX,
+            health: Box::new(health),
+        })
+    }
+
+    /// Limit the requests of each client, and the queries of all of them, see [crate::limits]
+    pub fn with_limits(self, limits: Limits) -> anyhow::Result<Self> {
+        Ok(Self {
+            rate_limit: Some(RateLimit::new(limits.rate, limits.burst)?),
+            queries: Semaphore::new(limits.max_queries),
+            max_connections: limits.max_connections,
+            ..self
+        })
+    }
+
+    /// Only answer requests with a token of the access file, see [crate::access]
+    pub fn with_access(self, access: Access) -> Self {
+        Self {
+            access: Some(access),
+            ..self
+        }
+    }
+
+    /// Encrypt the trees of the streams of each tenant with the secrets of the tenant, see
+    /// [crate::tenants]
+    pub fn with_master_key(self, master_key: SecretKey) -> Self {
+        Self {
+            master_key: Some(master_key),
+            ..self
+        }
+    }
+
+    /// The secrets of the trees of a tenant, or of no tenant
+    fn secrets(&self, tenant: Option<&str>) -> anyhow::Result<Secrets> {
+        match (&self.master_key, tenant) {
+            (Some(master), Some(tenant)) => tenants::tenant_secrets(master, tenant),
+            _ => Ok(Secrets::default()),
+        }
+    }
+
+    /// The store for a request
+    fn store(&self) -> CachedStore<S> {
+        CachedStore::new((self.store)(), self.cache.clone(), self.metrics.clone())
+    }
+
+    /// Answer requests forever, each connection on a thread of its own
+    pub fn serve(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            // a client that sends nothing, or a byte now and then, does not keep a thread forever
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            if self.connections.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+                self.metrics
+                    .rejected
+                    .with_label_values(&["connections"])
+                    .inc();
+                // without waiting for the request, which would hold up the accepts
+                too_many(&stream, Duration::from_secs(1), "too many connections").ok();
+                continue;
+            }
+            let server = self.clone();
+            thread::spawn(move || {
+                let res = server.connection(stream);
+                server.connections.fetch_sub(1, Ordering::SeqCst);
+                if let Err(cause) = res {
+                    tracing::warn!("request failed: {:#}", cause);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn connection(&self, stream: TcpStream) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        if let Some(rate_limit) = &self.rate_limit {
+            if let Err(wait) = rate_limit.take(stream.peer_addr()?.ip()) {
+                self.metrics.rejected.with_label_values(&["rate"]).inc();
+                // the request is only read for a moment, so an http client gets the answer
+                stream.set_read_timeout(Some(REJECTED_READ_TIMEOUT))?;
+                Request::read(&mut reader).ok();
+                return too_many(&stream, wait, "too many requests from this address");
+            }
+        }
+        let request = match Request::read(&mut reader) {
+            Ok(request) => request,
+            Err(cause) => return respond(&stream, "400 Bad Request", cause.to_string().as_bytes()),
+        };
+        let path = request.url.path().trim_start_matches('/').to_string();
+        let (route, name) = path.split_once('/').unwrap_or((&path, ""));
+        match self.authorize(&request, route, name) {
+            Ok(()) => {}
+            Err(Denied::Unauthorized) => {
+                let challenge = ("WWW-Authenticate", "Bearer");
+                let body = b"a token of the access file is needed";
+                return respond_with(&stream, "401 Unauthorized", &[challenge], body);
+            }
+            Err(Denied::Forbidden) => {
+                let message = format!("the token may not do this to {}", name);
+                return respond(&stream, "403 Forbidden", message.as_bytes());
+            }
+        }
+        let _query = match route {
+            "sync" | "append" | "trees" => match self.queries.try_acquire() {
+                Some(permit) => Some(permit),
+                None => {
+                    self.metrics.rejected.with_label_values(&["queries"]).inc();
+                    let wait = Duration::from_secs(1);
+                    return too_many(&stream, wait, "too many queries at once");
+                }
+            },
+            _ => None,
+        };
+        let t0 = Instant::now();
+        let res = match (request.method.as_str(), route) {
+            ("GET", "metrics") if name.is_empty() => {
+                let text = self.metrics.text()?;
+                let content_type = ("Content-Type", "text/plain; version=0.0.4");
+                return respond_with(&stream, "200 OK", &[content_type], &text);
+            }
+            ("GET", "healthz") if name.is_empty() => {
+                (self.health)().map(|health| ("200 OK", health.to_json().to_string().into_bytes()))
+            }
+            (_, "sync" | "append" | "tail") if name.is_empty() => {
+                Ok(("404 Not Found", b"no stream name".to_vec()))
+            }
+            (_, "sync" | "append" | "tail") if tenants::check_stream(name).is_err() => {
+                let message = format!("{} is not a stream or tenant/stream", name);
+                Ok(("400 Bad Request", message.into_bytes()))
+            }
+            ("GET", "sync") => self.sync(&request, name),
+            ("POST", "append") => self.append(&request, name, &mut reader),
+            ("GET", "tail") => return self.tail(&request, name, stream, reader),
+            (method, "trees") => match (method, name.split_once('/')) {
+                ("POST", Some((root, "select"))) => {
+                    return self.select(&request, root, &mut reader, &stream)
+                }
+                ("GET", Some((root, "aggregate"))) => self.aggregate(&request, root),
+                (_, Some((_, "select" | "aggregate"))) => {
+                    Ok(("405 Method Not Allowed", Vec::new()))
+                }
+                _ => Ok(("404 Not Found", Vec::new())),
+            },
+            (_, "sync" | "append" | "tail") => Ok(("405 Method Not Allowed", Vec::new())),
+            _ => Ok(("404 Not Found", Vec::new())),
+        };
+        let label = match name.rsplit_once('/') {
+            Some((_, "aggregate")) if route == "trees" => "aggregate",
+            _ => route,
+        };
+        if matches!(label, "sync" | "append" | "aggregate") {
+            let seconds = t0.elapsed().as_secs_f64();
+            self.metrics
+                .query_seconds
+                .with_label_values(&[label])
+                .observe(seconds);
+        }
+        let (status, body) = res.unwrap_or_else(|cause| failed(&cause));
+        respond(&stream, status, &body)
+    }
+
+    /// Check the token of a request, if there is an access file
+    fn authorize(&self, request: &Request, route: &str, name: &str) -> Result<(), Denied> {
+        let Some(access) = &self.access else {
+            return Ok(());
+        };
+        let need = match route {
+            "sync" | "tail" => Some((Permission::Read, name)),
+            // a root can be of any stream
+            "trees" => Some((Permission::Read, "*")),
+            "append" => Some((Permission::Append, name)),
+            _ => None,
+        };
+        let token = request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+            .or_else(|| request.query("access_token"));
+        access.check(token.as_deref(), need)
+    }
+
+    fn sync(&self, request: &Request, name: &str) -> anyhow::Result<Response> {
+        let have = request
+            .query("have")
+            .map(|have| Sha256Digest::from_str(&have));
+        let have = match have.transpose() {
+            Ok(have) => have,
+            Err(cause) => return Ok(("400 Bad Request", cause.to_string().into_bytes())),
+        };
+        sync::answer(&self.store(), &self.roots, name, have)
+    }
+
+    fn append(&self, request: &Request, name: &str, body: impl Read) -> anyhow::Result<Response> {
+        let data = match read_body(request, body)? {
+            Ok(data) => data,
+            Err(response) => return Ok(response),
+        };
+        let values = std::str::from_utf8(&data)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| {
+                text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(schemaless::from_json)
+                    .collect::<anyhow::Result<Vec<_>>>()
+            });
+        let values = match values {
+            Ok(values) => values,
+            Err(cause) => return Ok(("400 Bad Request", format!("{:#}", cause).into_bytes())),
+        };
+
+        let _appending = self.appending.lock().unwrap();
+        let store = self.store();
+        let secrets = self.secrets(tenants::tenant_of(name))?;
+        let base = self.roots.root(name)?;
+        let forest = Forest::<SchemalessTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let mut txn = Transaction::new(forest, store.clone());
+        let mut builder = match base {
+            Some(base) => {
+                let registry = Registry::builtin();
+                let (driver, _) = registry.detect(&store, base, &secrets)?;
+                if driver.name() != "schemaless" {
+                    let message = format!("{} is a {} tree", name, driver.name());
+                    return Ok(("409 Conflict", message.into_bytes()));
+                }
+                txn.load_stream_builder(secrets, self.config.clone(), base)?
+            }
+            None => StreamBuilder::new(self.config.clone(), secrets),
+        };
+        let from = builder.count();
+        txn.extend(&mut builder, values.into_iter().map(|value| ((), value)))?;
+        let root = builder.snapshot().link();
+        if let Some(root) = root.filter(|root| Some(*root) != base) {
+            self.roots.compare_and_swap(name, base, root)?;
+            self.metrics.appended_events.inc_by(builder.count() - from);
+            self.changed.notify();
+        }
+        let answer = serde_json::json!({
+            "root": root.map(|root| root.to_string()),
+            "from": from,
+            "to": builder.count(),
+        });
+        Ok(("200 OK", answer.to_string().into_bytes()))
+    }
+
+    /// The secrets of a tree by its root, of the tenant in the query if there is one
+    fn tree_secrets(&self, request: &Request) -> anyhow::Result<Secrets> {
+        let tenant = request.query("tenant");
+        if let Some(tenant) = &tenant {
+            tenants::check_name("tenant", tenant)?;
+        }
+        self.secrets(tenant.as_deref())
+    }
+
+    /// One aggregate over a time range of a columnar tree
+    fn aggregate(&self, request: &Request, root: &str) -> anyhow::Result<Response> {
+        let bad = |cause: anyhow::Error| ("400 Bad Request", format!("{:#}", cause).into_bytes());
+        let time = |name: &str| {
+            request
+                .query(name)
+                .map(|time| time.parse::<u64>())
+                .transpose()
+        };
+        let parsed = (|| -> anyhow::Result<_> {
+            let root = Sha256Digest::from_str(root)?;
+            let op = request
+                .query("op")
+                .ok_or_else(|| anyhow::anyhow!("no op, count, sum, min or max"))?
+                .parse::<Op>()?;
+            let range = TimeRangeQuery {
+                min: time("from")?.unwrap_or_default(),
+                max: time("to")?.unwrap_or(u64::MAX),
+            };
+            Ok((root, op, range, self.tree_secrets(request)?))
+        })();
+        let (root, op, range, secrets) = match parsed {
+            Ok(parsed) => parsed,
+            Err(cause) => return Ok(bad(cause)),
+        };
+        let store = self.store();
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let tree = forest.load_tree::<u64>(secrets, root)?;
+        let result = rollup::rollup(&store, &tree, op, range)?;
+        let answer = serde_json::json!({
+            "op": format!("{:?}", op).to_lowercase(),
+            "value": result.value,
+            "blocks": result.blocks,
+        });
+        Ok(("200 OK", answer.to_string().into_bytes()))
+    }
+
+    /// Answer with the events of a columnar tree that match the query of the body, as they are
+    /// found
+    fn select(
+        &self,
+        request: &Request,
+        root: &str,
+        body: impl Read,
+        stream: &TcpStream,
+    ) -> anyhow::Result<()> {
+        let t0 = Instant::now();
+        let query = read_body(request, body)?.and_then(|data| {
+            let text = std::str::from_utf8(&data).map_err(anyhow::Error::from);
+            let query = text
+                .and_then(JsonQuery::parse)
+                .map(|query| query.canonical());
+            let root = Sha256Digest::from_str(root);
+            match (root, query, self.tree_secrets(request)) {
+                (Ok(root), Ok(query), Ok(secrets)) => Ok((root, query, secrets)),
+                (Err(cause), _, _) | (_, Err(cause), _) | (_, _, Err(cause)) => {
+                    Err(("400 Bad Request", format!("{:#}", cause).into_bytes()))
+                }
+            }
+        });
+        let (root, query, secrets) = match query {
+            Ok(query) => query,
+            Err((status, body)) => return respond(stream, status, &body),
+        };
+        // the matching children of a branch are fetched concurrently
+        let store = BatchStore::new(self.store());
+        let cache = BranchCache::new(1 << 20);
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), cache.clone());
+        let tree = match forest.load_tree::<u64>(secrets.clone(), root) {
+            Ok(tree) => tree,
+            Err(cause) => {
+                let (status, body) = failed(&cause);
+                return respond(stream, status, &body);
+            }
+        };
+        write!(
+            &*stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\n\
+             Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+        )?;
+        let mut out = io::BufWriter::new(Chunked(stream));
+        let query = BatchQuery::new(query.to_query(), store, cache, secrets, batch::PARALLELISM);
+        for item in forest.iter_filtered(&tree, query) {
+            // a failure breaks the answer off without its last chunk
+            let (offset, key, value) = item?;
+            let event = serde_json::json!({
+                "offset": offset,
+                "time": key.time,
+                "device": key.device,
+                "value": value,
+            });
+            serde_json::to_writer(&mut out, &event)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        (&*stream).write_all(b"0\r\n\r\n")?;
+        let seconds = t0.elapsed().as_secs_f64();
+        self.metrics
+            .query_seconds
+            .with_label_values(&["select"])
+            .observe(seconds);
+        Ok(())
+    }
+
+    fn tail(
+        &self,
+        request: &Request,
+        name: &str,
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+    ) -> anyhow::Result<()> {
+        let Some(key) = request.header("sec-websocket-key") else {
+            return respond(&stream, "426 Upgrade Required", b"a tail is a websocket");
+        };
+        let offset = request.query("offset").map(|offset| offset.parse::<u64>());
+        let offset = match offset.transpose() {
+            Ok(offset) => offset.unwrap_or_default(),
+            Err(cause) => return respond(&stream, "400 Bad Request", cause.to_string().as_bytes()),
+        };
+        websocket::accept(&stream, key)?;
+        // a tail waits for the control frames of the client as long as it is open
+        stream.set_read_timeout(None)?;
+        let socket = Arc::new(Mutex::new(stream));
+        let closed = Arc::new(AtomicBool::new(false));
+        {
+            let (socket, closed) = (socket.clone(), closed.clone());
+            thread::spawn(move || client_frames(reader, &socket, &closed));
+        }
+        let res = self.follow(name, offset, &socket, &closed);
+        close(&socket, &res);
+        res
+    }
+
+    /// Send the events of a stream from offset `next` on, and the new ones whenever the root
+    /// changes, until the client closes the tail
+    fn follow(
+        &self,
+        name: &str,
+        mut next: u64,
+        socket: &Mutex<TcpStream>,
+        closed: &AtomicBool,
+    ) -> anyhow::Result<()> {
+        let mut sent = None;
+        // the count before the root is read, so no swap after the read is missed
+        let mut seen = self.changed.count();
+        let secrets = self.secrets(tenants::tenant_of(name))?;
+        while !closed.load(Ordering::SeqCst) {
+            let root = self.roots.root(name)?;
+            if let Some(root) = root.filter(|root| Some(*root) != sent) {
+                // the client waits instead of getting a 429, since the tail is already open
+                let _query = self.queries.acquire();
+                let t0 = Instant::now();
+                let store = self.store();
+                let registry = Registry::builtin();
+                let (driver, stats) = registry.detect(&store, root, &secrets)?;
+                anyhow::ensure!(
+                    stats.count >= next,
+                    "stream {} has {} events, the tail is at {}",
+                    name,
+                    stats.count,
+                    next
+                );
+                let mut messages = Messages {
+                    socket,
+                    line: Vec::new(),
+                };
+                next += driver.export(&store, root, &secrets, next, &mut messages)?;
+                sent = Some(root);
+                let seconds = t0.elapsed().as_secs_f64();
+                self.metrics
+                    .query_seconds
+                    .with_label_values(&["tail"])
+                    .observe(seconds);
+            }
+            seen = self.changed.wait(seen, POLL);
+        }
+        Ok(())
+    }
+}
+
+/// The store of the server: the kubo at the [endpoint](crate::kubo::endpoint), with a check of
+/// the hash of every block. Each call makes a store whose timeout starts with the call, so the
+/// timeout is per request, or per push of a tail
+pub fn kubo_store(
+    timeout: Option<Duration>,
+) -> anyhow::Result<
+    impl Fn() -> TracingStore<CancellableStore<VerifyingStore<KuboStore>>> + Send + Sync + 'static,
+> {
+    let store = cancel::with_timeout(VerifyingStore::new(KuboStore::from_env()?), None);
+    Ok(move || {
+        let cancel = timeout.map(Cancel::timeout).unwrap_or_default();
+        TracingStore::new(store.with_cancel(cancel))
+    })
+}
+
+/// Serve the streams of a manifest file, with the blocks of a store and a block cache of
+/// `cache_bytes` in front of it, to the tokens of the access file if there is one, and within the
+/// limits. The health of the store is from the probe. The trees of tenants have their secrets
+/// from the master key, if there is one
+#[allow(clippy::too_many_arguments)]
+pub fn print_serve<F, S>(
+    store: F,
+    health: impl Fn() -> anyhow::Result<Health> + Send + Sync + 'static,
+    manifest: &Path,
+    listen: &str,
+    config: &Config,
+    cache_bytes: usize,
+    access: Option<&Path>,
+    limits: Limits,
+    master_key: Option<SecretKey>,
+) -> anyhow::Result<()>
+where
+    F: Fn() -> S + Send + Sync + 'static,
+    S: ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+{
+    let roots = ManifestFile::new(manifest);
+    let mut server =
+        Server::new(store, roots, config.clone(), cache_bytes, health)?.with_limits(limits)?;
+    if let Some(access) = access {
+        server = server.with_access(Access::load(access)?);
+    }
+    if let Some(master_key) = master_key {
+        server = server.with_master_key(master_key);
+    }
+    let listener = TcpListener::bind(listen)?;
+    println!(
+        "serving {} on http://{}",
+        manifest.display(),
+        listener.local_addr()?
+    );
+    Arc::new(server).serve(listener)
+}
+
+/// Print the events of a stream on a server from `offset` on, and the new ones as they come.
+/// When the connection breaks, the tail goes on from the event after the last one it printed
+pub fn print_tail(url: &str, name: &str, mut offset: u64) -> anyhow::Result<()> {
+    let url = Url::parse(url)?;
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "ws"),
+        "{} is not an http or ws url",
+        url
+    );
+    let addr = format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or(80)
+    );
+    let token = access::token_from_env();
+    let mut connected = false;
+    let mut stdout = io::stdout().lock();
+    loop {
+        let path = format!("/tail/{}?offset={}", name, offset);
+        let mut reader = match websocket::connect(&addr, &path, token.as_deref()) {
+            Ok(reader) => reader,
+            // the first connection fails right away, a later one is tried again
+            Err(cause) if connected => {
+                tracing::warn!("tail of {} failed: {:#}", addr, cause);
+                thread::sleep(POLL);
+                continue;
+            }
+            Err(cause) => return Err(cause),
+        };
+        connected = true;
+        loop {
+            let frame = match websocket::read_frame(&mut reader, MAX_MESSAGE) {
+                Ok(frame) => frame,
+                Err(cause) => {
+                    tracing::warn!("tail of {} broke at offset {}: {:#}", addr, offset, cause);
+                    break;
+                }
+            };
+            match frame.opcode {
+                websocket::TEXT => {
+                    let event: serde_json::Value = serde_json::from_slice(&frame.payload)?;
+                    let Some(at) = event["offset"].as_u64() else {
+                        anyhow::bail!("an event without offset");
+                    };
+                    anyhow::ensure!(at == offset, "got event {}, not {}", at, offset);
+                    stdout.write_all(&frame.payload)?;
+                    writeln!(stdout)?;
+                    stdout.flush()?;
+                    offset += 1;
+                }
+                websocket::CLOSE => {
+                    let reason =
+                        String::from_utf8_lossy(frame.payload.get(2..).unwrap_or_default());
+                    anyhow::bail!("the server closed the tail: {}", reason);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use banyan::store::MemStore;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// The next `n` events of a tail, as offset and value
+    fn events(tail: &mut BufReader<TcpStream>, n: usize) -> Vec<(u64, Value)> {
+        (0..n)
+            .map(|_| {
+                let frame = websocket::read_frame(&mut *tail, MAX_MESSAGE).unwrap();
+                assert_eq!(frame.opcode, websocket::TEXT);
+                let event: Value = serde_json::from_slice(&frame.payload).unwrap();
+                (event["offset"].as_u64().unwrap(), event["value"].clone())
+            })
+            .collect()
+    }
+
+    fn tail(addr: &str, path: &str) -> BufReader<TcpStream> {
+        let tail = websocket::connect(addr, path, None).unwrap();
+        tail.get_ref()
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        tail
+    }
+
+    /// The manifest file of a test server, removed when the test is done, even if it fails
+    struct Manifest(std::path::PathBuf);
+
+    impl Drop for Manifest {
+        fn drop(&mut self) {
+            // a test without appends never writes it
+            if self.0.exists() {
+                std::fs::remove_file(&self.0).unwrap();
+            }
+        }
+    }
+
+    /// A server on a memory store, with its address and its manifest
+    fn start(
+        test: &str,
+        access: Option<Access>,
+        limits: Option<Limits>,
+    ) -> (String, ManifestFile, Manifest) {
+        let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+        start_on(store, test, access, limits, None)
+    }
+
+    /// [start] on a store that already has blocks, and with a master key for the tenants
+    fn start_on(
+        store: MemStore<Sha256Digest>,
+        test: &str,
+        access: Option<Access>,
+        limits: Option<Limits>,
+        master_key: Option<SecretKey>,
+    ) -> (String, ManifestFile, Manifest) {
+        let file = format!("banyan-{}-{}.manifest", test, std::process::id());
+        let path = std::env::temp_dir().join(file);
+        let roots = ManifestFile::new(&path);
+        let config = Config::debug_fast();
+        let health = || {
+            Ok(Health {
+                backend: "memory",
+                identity: "test".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                latency: Duration::ZERO,
+                writable: true,
+            })
+        };
+        let mut server = Server::new(
+            move || store.clone(),
+            roots.clone(),
+            config,
+            1 << 20,
+            health,
+        )
+        .unwrap();
+        if let Some(access) = access {
+            server = server.with_access(access);
+        }
+        if let Some(limits) = limits {
+            server = server.with_limits(limits).unwrap();
+        }
+        if let Some(master_key) = master_key {
+            server = server.with_master_key(master_key);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || Arc::new(server).serve(listener));
+        (addr, roots, Manifest(path))
+    }
+
+    #[test]
+    fn append_tail_resume() {
+        let (addr, roots, _manifest) = start("tail", None, None);
+        let client = reqwest::blocking::Client::new();
+        let append = |body: &str| {
+            let url = format!("http://{}/append/events", addr);
+            let response = client.post(url).body(body.to_string()).send().unwrap();
+            (response.status().as_u16(), response.text().unwrap())
+        };
+        let offsets = |(status, body): (u16, String)| {
+            assert_eq!(status, 200, "{}", body);
+            let answer: Value = serde_json::from_str(&body).unwrap();
+            (
+                answer["from"].as_u64().unwrap(),
+                answer["to"].as_u64().unwrap(),
+            )
+        };
+
+        assert_eq!(offsets(append("{\"a\":1}\n2\n\n\"three\"\n")), (0, 3));
+        let mut first = tail(&addr, "/tail/events?offset=1");
+        assert_eq!(
+            events(&mut first, 2),
+            vec![(1, json!(2)), (2, json!("three"))]
+        );
+        assert_eq!(offsets(append("4\n5\n")), (3, 5));
+        assert_eq!(events(&mut first, 2), vec![(3, json!(4)), (4, json!(5))]);
+        drop(first);
+        // a client that comes back with the offset after the last event it got
+        let mut second = tail(&addr, "/tail/events?offset=5");
+        assert_eq!(offsets(append("6\n")), (5, 6));
+        assert_eq!(events(&mut second, 1), vec![(5, json!(6))]);
+
+        // a tail ahead of the stream is closed with the reason
+        let mut ahead = tail(&addr, "/tail/events?offset=7");
+        let frame = websocket::read_frame(&mut ahead, MAX_MESSAGE).unwrap();
+        assert_eq!(frame.opcode, websocket::CLOSE);
+        assert_eq!(frame.payload[..2], 1011u16.to_be_bytes());
+        assert!(websocket::connect(&addr, "/tail/events?offset=x", None).is_err());
+
+        assert_eq!(append("{").0, 400);
+        assert_eq!(offsets(append("")), (6, 6));
+        // the sync requests are answered by the same server
+        let mut reader = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let pulled = sync::pull(
+            &client,
+            &format!("http://{}", addr),
+            "events",
+            None,
+            &mut reader,
+        );
+        assert_eq!(Some(pulled.unwrap().root), roots.root("events").unwrap());
+    }
+
+    #[test]
+    fn metrics() {
+        let (addr, _, _manifest) = start("metrics", None, None);
+        let client = reqwest::blocking::Client::new();
+        for body in ["1\n2\n", "3\n"] {
+            let url = format!("http://{}/append/events", addr);
+            client
+                .post(url)
+                .body(body)
+                .send()
+                .unwrap()
+                .error_for_status()
+                .unwrap();
+        }
+        let mut reader = MemStore::new(usize::MAX, Sha256Digest::digest);
+        sync::pull(
+            &client,
+            &format!("http://{}", addr),
+            "events",
+            None,
+            &mut reader,
+        )
+        .unwrap();
+        let response = client
+            .get(format!("http://{}/metrics", addr))
+            .send()
+            .unwrap();
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let text = response.text().unwrap();
+        let value = |name: &str| {
+            let line = text.lines().find(|line| line.starts_with(name));
+            let line = line.unwrap_or_else(|| panic!("no {} in {}", name, text));
+            line[name.len()..].trim().parse::<f64>().unwrap()
+        };
+        assert_eq!(value("banyan_server_appended_events_total"), 3.0);
+        assert_eq!(
+            value("banyan_server_query_seconds_count{route=\"append\"}"),
+            2.0
+        );
+        assert_eq!(
+            value("banyan_server_query_seconds_count{route=\"sync\"}"),
+            1.0
+        );
+        // every block was put by this server, so the gets are all hits
+        assert!(value("banyan_server_block_cache_hits_total") > 0.0);
+        assert_eq!(value("banyan_server_blocks_fetched_total"), 0.0);
+        assert!(text.contains("banyan_block_put_time"));
+    }
+
+    #[test]
+    fn trees() {
+        let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let mut txn = Transaction::new(forest, store.clone());
+        let mut builder = StreamBuilder::new(Config::debug_fast(), Secrets::default());
+        let events = crate::columnar::events(1000);
+        txn.extend(&mut builder, events.iter().cloned()).unwrap();
+        let tree = builder.snapshot();
+        let root = tree.link().unwrap();
+        let (addr, _, _manifest) = start_on(store, "trees", None, None, None);
+        let client = reqwest::blocking::Client::new();
+        let select = |root: &str, query: &str| {
+            let url = format!("http://{}/trees/{}/select", addr, root);
+            let response = client.post(url).body(query.to_string()).send().unwrap();
+            (response.status().as_u16(), response.text().unwrap())
+        };
+
+        let query = format!(
+            r#"{{"and": [{{"time": {{"from": {}, "to": {}}}}}, {{"not": {{"devices": [3]}}}}]}}"#,
+            events[100].0.time, events[299].0.time
+        );
+        let (status, body) = select(&root.to_string(), &query);
+        assert_eq!(status, 200, "{}", body);
+        let found = body
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let expected =
+            crate::result_cache::run(&txn, &tree, &JsonQuery::parse(&query).unwrap()).unwrap();
+        assert!(!expected.is_empty());
+        assert_eq!(found.len(), expected.len());
+        for (event, (offset, key, value)) in found.iter().zip(expected.iter()) {
+            assert_eq!(event["offset"], *offset);
+            assert_eq!(event["time"], key.time);
+            assert_eq!(event["device"], key.device);
+            assert_eq!(event["value"], *value);
+        }
+
+        assert_eq!(select(&root.to_string(), r#"{"time": 1}"#).0, 400);
+        assert_eq!(select("nonsense", "\"all\"").0, 400);
+        let other = Sha256Digest::digest(b"not in the store");
+        assert_eq!(select(&other.to_string(), "\"all\"").0, 500);
+        let url = format!("http://{}/trees/{}/select", addr, root);
+        assert_eq!(client.get(url).send().unwrap().status().as_u16(), 405);
+
+        let aggregate = |query: &str| {
+            let url = format!("http://{}/trees/{}/aggregate?{}", addr, root, query);
+            let response = client.get(url).send().unwrap();
+            let status = response.status().as_u16();
+            (status, response.json::<Value>().unwrap_or_default())
+        };
+        let (from, to) = (events[100].0.time, events[299].0.time);
+        let in_range = events
+            .iter()
+            .filter(|(key, _)| key.time >= from && key.time <= to)
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>();
+        let range = format!("from={}&to={}", from, to);
+        let (status, count) = aggregate(&format!("op=count&{}", range));
+        assert_eq!(status, 200);
+        assert_eq!(count["op"], "count");
+        assert_eq!(count["value"], in_range.len() as u64);
+        let (_, sum) = aggregate(&format!("op=sum&{}", range));
+        assert_eq!(sum["value"], in_range.iter().sum::<u64>());
+        // a count reads fewer blocks than a sum, which needs the values
+        assert!(count["blocks"].as_u64() < sum["blocks"].as_u64());
+        let (_, max) = aggregate("op=max");
+        assert_eq!(max["value"], events.iter().map(|(_, v)| *v).max().unwrap());
+        assert_eq!(aggregate("op=min&from=1&to=0").1["value"], Value::Null);
+        assert_eq!(aggregate("op=median").0, 400);
+        assert_eq!(aggregate("op=count&from=x").0, 400);
+    }
+
+    #[test]
+    fn tenant_secrets() {
+        let master = SecretKey::from([7u8; 32]);
+        let acme = tenants::tenant_secrets(&master, "acme").unwrap();
+        let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+        let mut txn = Transaction::new(forest, store.clone());
+        let mut builder = StreamBuilder::new(Config::debug_fast(), acme.clone());
+        txn.extend(&mut builder, crate::columnar::events(100))
+            .unwrap();
+        let columnar = builder.link().unwrap();
+        let (addr, roots, _manifest) =
+            start_on(store.clone(), "tenant-secrets", None, None, Some(master));
+        let client = reqwest::blocking::Client::new();
+        for stream in ["acme/events", "events"] {
+            let url = format!("http://{}/append/{}", addr, stream);
+            let response = client.post(url).body("1\n2\n").send().unwrap();
+            assert_eq!(response.status().as_u16(), 200);
+        }
+
+        // the stream of the tenant is only readable with the secrets of the tenant
+        let forest = Forest::<SchemalessTT, _>::new(store, BranchCache::new(0));
+        let read = |root, secrets: Secrets| {
+            forest
+                .load_tree::<libipld::Ipld>(secrets, root)
+                .and_then(|tree| forest.iter_from(&tree).collect::<anyhow::Result<Vec<_>>>())
+                .map(|events| events.len())
+        };
+        let root = roots.root("acme/events").unwrap().unwrap();
+        assert_eq!(read(root, acme.clone()).unwrap(), 2);
+        assert!(read(root, Secrets::default()).is_err());
+        let root = roots.root("events").unwrap().unwrap();
+        assert_eq!(read(root, Secrets::default()).unwrap(), 2);
+        let mut tail = tail(&addr, "/tail/acme/events");
+        assert_eq!(events(&mut tail, 2), vec![(0, json!(1)), (1, json!(2))]);
+
+        // a tree by its root needs its tenant
+        let aggregate = |tenant: &str| {
+            let url = format!(
+                "http://{}/trees/{}/aggregate?op=count{}",
+                addr, columnar, tenant
+            );
+            client.get(url).send().unwrap().status().as_u16()
+        };
+        assert_eq!(aggregate("&tenant=acme"), 200);
+        assert_ne!(aggregate(""), 200);
+        assert_eq!(aggregate("&tenant=a/b"), 400);
+    }
+
+    #[test]
+    fn healthz() {
+        let (addr, _, _manifest) = start("healthz", None, None);
+        let response = reqwest::blocking::get(format!("http://{}/healthz", addr)).unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let health: Value = response.json().unwrap();
+        assert_eq!(health["backend"], "memory");
+        assert_eq!(health["writable"], true);
+
+        // a store that does not answer
+        let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+        let down = || -> anyhow::Result<Health> {
+            Err(crate::error::Error::StoreUnavailable {
+                reason: "no kubo".to_string(),
+            }
+            .into())
+        };
+        let roots = ManifestFile::new(std::env::temp_dir().join("banyan-healthz-down.manifest"));
+        let server = Server::new(move || store.clone(), roots, Config::debug_fast(), 0, down);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || Arc::new(server.unwrap()).serve(listener));
+        let response = reqwest::blocking::get(format!("http://{}/healthz", addr)).unwrap();
+        assert_eq!(response.status().as_u16(), 503);
+        assert!(response.text().unwrap().contains("no kubo"));
+    }
+
+    #[test]
+    fn tokens() {
+        let access = Access::parse(
+            "[[token]]\ntoken = \"reader\"\nread = [\"events\"]\n\
+             [[token]]\ntoken = \"writer\"\nread = [\"*\"]\nappend = [\"events\"]\n\
+             [[token]]\ntoken = \"acme\"\ntenant = \"acme\"\nread = [\"*\"]\nappend = [\"*\"]\n",
+        )
+        .unwrap();
+        let (addr, _, _manifest) = start("tokens", Some(access), None);
+        let client = reqwest::blocking::Client::new();
+        let status = |request: reqwest::blocking::RequestBuilder, token: Option<&str>| {
+            let request = match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            };
+            request.send().unwrap().status().as_u16()
+        };
+        let append = |stream: &str| {
+            client
+                .post(format!("http://{}/append/{}", addr, stream))
+                .body("1\n")
+        };
+        assert_eq!(status(append("events"), None), 401);
+        assert_eq!(status(append("events"), Some("nobody")), 401);
+        assert_eq!(status(append("events"), Some("reader")), 403);
+        assert_eq!(status(append("other"), Some("writer")), 403);
+        assert_eq!(status(append("events"), Some("writer")), 200);
+        let sync = |stream: &str| client.get(format!("http://{}/sync/{}", addr, stream));
+        assert_eq!(status(sync("events"), Some("reader")), 200);
+        assert_eq!(status(sync("other"), Some("reader")), 403);
+        // other is not there, but the writer may read it
+        assert_eq!(status(sync("other"), Some("writer")), 404);
+        // a tenant only reaches its own streams, and no tree by its root
+        assert_eq!(status(append("acme/events"), Some("acme")), 200);
+        assert_eq!(status(sync("acme/events"), Some("acme")), 200);
+        assert_eq!(status(sync("globex/events"), Some("acme")), 403);
+        assert_eq!(status(append("events"), Some("acme")), 403);
+        assert_eq!(status(sync("acme/events"), Some("reader")), 403);
+        let root = Sha256Digest::digest(b"any root");
+        let aggregate = client.get(format!("http://{}/trees/{}/aggregate?op=count", addr, root));
+        assert_eq!(status(aggregate, Some("acme")), 403);
+        let invalid = client.get(format!("http://{}/sync/acme/events/more", addr));
+        assert_eq!(status(invalid, Some("writer")), 400);
+        let metrics = || client.get(format!("http://{}/metrics", addr));
+        assert_eq!(status(metrics(), None), 401);
+        assert_eq!(status(metrics(), Some("reader")), 200);
+
+        // a tail with the token in a header, or in the query like from a browser
+        let mut tail = websocket::connect(&addr, "/tail/events", Some("reader")).unwrap();
+        assert_eq!(events(&mut tail, 1), vec![(0, json!(1))]);
+        let mut query =
+            websocket::connect(&addr, "/tail/events?access_token=reader", None).unwrap();
+        assert_eq!(events(&mut query, 1), vec![(0, json!(1))]);
+        let denied = websocket::connect(&addr, "/tail/events?access_token=writer2", None);
+        assert!(denied.unwrap_err().to_string().contains("401"));
+    }
+
+    #[test]
+    fn limits() {
+        // no queries at all, and three requests in a row
+        let limits = Limits {
+            rate: 0.01,
+            burst: 3.0,
+            max_queries: 0,
+            max_connections: 16,
+        };
+        let (addr, _, _manifest) = start("limits", None, Some(limits));
+        let client = reqwest::blocking::Client::new();
+        let get = |path: &str| {
+            let response = client
+                .get(format!("http://{}{}", addr, path))
+                .send()
+                .unwrap();
+            let retry = response.headers().get("retry-after").cloned();
+            let retry = retry.map(|retry| retry.to_str().unwrap().to_string());
+            (response.status().as_u16(), retry)
+        };
+        assert_eq!(get("/sync/events"), (429, Some("1".to_string())));
+        // the metrics are not a query
+        assert_eq!(get("/metrics").0, 200);
+        assert_eq!(get("/metrics").0, 200);
+        assert_eq!(get("/metrics"), (429, Some("100".to_string())));
+        // a connection takes from the bucket before it sends anything
+        assert!(silent(&addr).starts_with("HTTP/1.1 429"));
+    }
+
+    /// The answer to a connection that sends nothing, or what is there after a few seconds
+    fn silent(addr: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).ok();
+        String::from_utf8_lossy(&answer).to_string()
+    }
+
+    #[test]
+    fn connection_limit() {
+        let limits = Limits {
+            rate: 100.0,
+            burst: 100.0,
+            max_queries: 1,
+            max_connections: 2,
+        };
+        let (addr, _, _manifest) = start("connection-limit", None, Some(limits));
+        // two clients that send nothing hold the connections until they time out
+        let idle = (0..2)
+            .map(|_| TcpStream::connect(&addr).unwrap())
+            .collect::<Vec<_>>();
+        let answer = silent(&addr);
+        assert!(answer.starts_with("HTTP/1.1 429"), "{}", answer);
+        assert!(answer.ends_with("too many connections"), "{}", answer);
+        drop(idle);
+    }
+}
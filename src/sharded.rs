@@ -0,0 +1,209 @@
+//! Spreading the blocks of a store over several stores
+//!
+//! One directory on one disk, or one prefix in one bucket, is a limit on how large a store can
+//! get and how fast it can be written. A [ShardedStore] puts every block in one of several inner
+//! stores, by the first two bytes of the digest of its link. The digest is a hash, so the blocks
+//! spread evenly, and a reader knows which store has a block from its link alone, without an
+//! index.
+//!
+//! The shard of a block depends on the number of shards, so a store has to be opened with the
+//! same stores in the same order every time. There is no way to add a shard to a store with
+//! blocks in it, other than copying all blocks to a new one.
+//!
+//! [ShardedStore::put_all] writes to all shards at once, with a thread per shard, which is faster
+//! than one write after the other when the shards are on different disks.
+use std::{sync::Arc, thread, time::Instant};
+
+use banyan::{
+    query::AllQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+
+use crate::{columnar::ColumnarTT, dedup, fs_store::FsStore, link::Link, probe::ProbingStore};
+
+/// A store over several stores, see the module docs
+#[derive(Debug)]
+pub struct ShardedStore<S> {
+    shards: Arc<Vec<S>>,
+}
+
+impl<S> Clone for ShardedStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<S> ShardedStore<S> {
+    pub fn new(shards: Vec<S>) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (1..=1 << 16).contains(&shards.len()),
+            "{} shards, not between 1 and 65536",
+            shards.len()
+        );
+        Ok(Self {
+            shards: Arc::new(shards),
+        })
+    }
+
+    pub fn shards(&self) -> &[S] {
+        &self.shards
+    }
+
+    /// The index of the shard for a link
+    pub fn shard<L: Link>(&self, link: &L) -> usize {
+        let cid: Cid = (*link).into();
+        let digest = cid.hash().digest();
+        let prefix = u16::from_be_bytes([digest[0], digest[1]]);
+        prefix as usize % self.shards.len()
+    }
+}
+
+impl<S: Clone + Send> ShardedStore<S> {
+    /// Write blocks, with a thread per shard. Returns their links in the order of the blocks
+    pub fn put_all<L: Link>(&self, blocks: Vec<Vec<u8>>) -> anyhow::Result<Vec<L>>
+    where
+        S: BlockWriter<L>,
+    {
+        let links = blocks
+            .iter()
+            .map(|data| L::digest(data))
+            .collect::<Vec<_>>();
+        let mut parts = vec![Vec::new(); self.shards.len()];
+        for (link, data) in links.iter().zip(blocks) {
+            parts[self.shard(link)].push(data);
+        }
+        thread::scope(|scope| {
+            let writers = self
+                .shards
+                .iter()
+                .zip(parts)
+                .filter(|(_, part)| !part.is_empty())
+                .map(|(shard, part)| {
+                    let mut shard = shard.clone();
+                    scope.spawn(move || {
+                        for data in part {
+                            shard.put(data)?;
+                        }
+                        anyhow::Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            for writer in writers {
+                writer.join().expect("shard writer panicked")?;
+            }
+            anyhow::Ok(())
+        })?;
+        Ok(links)
+    }
+}
+
+impl<L: Link, S: ReadOnlyStore<L>> ReadOnlyStore<L> for ShardedStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        self.shards[self.shard(link)].get(link)
+    }
+}
+
+impl<L: Link, S: ProbingStore<L>> ProbingStore<L> for ShardedStore<S> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        self.shards[self.shard(link)].size(link)
+    }
+}
+
+impl<L: Link, S: BlockWriter<L> + Clone> BlockWriter<L> for ShardedStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let link = L::digest(&data);
+        let index = self.shard(&link);
+        // the shards are shared, so this writes through a clone of the shard
+        let mut shard = self.shards[index].clone();
+        let written = shard.put(data)?;
+        anyhow::ensure!(
+            written == link,
+            "shard {} wrote {} as {}",
+            index,
+            link,
+            written
+        );
+        Ok(link)
+    }
+}
+
+/// `shards` file stores in directories `shard-0`, `shard-1` and so on of a directory
+pub fn open_fs(
+    path: impl AsRef<std::path::Path>,
+    shards: usize,
+) -> anyhow::Result<ShardedStore<FsStore<Sha256Digest>>> {
+    let shards = (0..shards)
+        .map(|i| FsStore::open(path.as_ref().join(format!("shard-{}", i))))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    ShardedStore::new(shards)
+}
+
+/// Write a tree to sharded file stores, and copy it to other ones with a thread per shard
+pub fn sharded_example(config: &Config) -> anyhow::Result<()> {
+    let n = 200000u64;
+    let shards = 4;
+    println!(
+        "Example: a tree of {} events in a store of {} shards",
+        n, shards
+    );
+    let path = std::env::temp_dir().join(format!("banyan-sharded-{}", std::process::id()));
+    // small leaves, so there are enough blocks to spread
+    let config = &Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let store = open_fs(path.join("a"), shards)?;
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, crate::columnar::events(n))?;
+    let root = builder.snapshot().link().expect("not empty");
+    let blocks = dedup::blocks(&store, root)?;
+    println!("shard\tblocks\tbytes");
+    for (i, shard) in store.shards().iter().enumerate() {
+        let (count, bytes) = shard.usage();
+        println!("{}\t{}\t{}", i, count, bytes);
+        // each shard has roughly its part of the blocks
+        anyhow::ensure!(
+            count * shards as u64 * 2 > blocks.len() as u64,
+            "shard {} has only {} of {} blocks",
+            i,
+            count,
+            blocks.len()
+        );
+    }
+    for link in blocks.keys() {
+        let shard = &store.shards()[store.shard(link)];
+        anyhow::ensure!(shard.has(link)?, "{} is not in its shard", link);
+    }
+
+    // a copy of all blocks to another sharded store, a shard per thread
+    let data = blocks
+        .keys()
+        .map(|link| Ok(store.get(link)?.into()))
+        .collect::<anyhow::Result<Vec<Vec<u8>>>>()?;
+    let target = open_fs(path.join("b"), shards)?;
+    let t0 = Instant::now();
+    let links = target.put_all(data)?;
+    anyhow::ensure!(links.iter().eq(blocks.keys()), "put_all wrote other links");
+    println!(
+        "copied {} blocks with {} threads in {:.3}s",
+        links.len(),
+        shards,
+        t0.elapsed().as_secs_f64()
+    );
+    let forest = Forest::<ColumnarTT, _>::new(target, BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let count = forest.iter_filtered(&tree, AllQuery).count() as u64;
+    anyhow::ensure!(count == n, "the copy has {} events", count);
+    std::fs::remove_dir_all(&path)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,361 @@
+//! Snapshots signed by their writer
+//!
+//! A reader that gets the newest snapshot from IPNS, a manifest file or a pubsub topic only knows
+//! that someone published that link. The tree behind the link is tamper proof, but nothing says it
+//! is the tree of the writer the reader wants to follow. So the writer signs each snapshot, its
+//! root, number of events and time, with an ed25519 key, and the reader only accepts snapshots
+//! with a valid signature by the public key it was given.
+//!
+//! The signature covers the dag-cbor encoding of the [Snapshot], which is deterministic, so the
+//! reader can check it against the decoded snapshot.
+//!
+//! A valid signature does not mean the snapshot is the newest one. Someone could publish an old
+//! snapshot again, or the writer could have signed two different snapshots after the same one.
+//! So each snapshot also has a sequence number and a link to the previous one, which makes the
+//! snapshots an append-only chain, like a small transparency log. [verify_chain] checks all of it
+//! back to the first snapshot, and [extends] checks that a new head has an older known one in its
+//! chain, so a reader that remembers what it saw notices both rollbacks and forks.
+use std::{fs, io::Write, path::Path};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use libipld::{
+    cbor::DagCborCodec,
+    cid::multibase::{self, Base},
+    codec::Codec,
+    DagCbor,
+};
+
+use crate::snapshots::{self, LogTT};
+
+/// What the writer signs
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct Snapshot {
+    /// root of the tree, `None` for an empty tree
+    pub root: Option<Sha256Digest>,
+    /// the number of events in the tree
+    pub count: u64,
+    /// unix time in milliseconds when the snapshot was taken
+    pub time: u64,
+    /// 0 for the first snapshot, and one more than the previous one for every other
+    pub seq: u64,
+    /// the previous signed snapshot, `None` for the first one
+    pub parent: Option<Sha256Digest>,
+}
+
+/// A snapshot with the signature of its writer
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct SignedSnapshot {
+    pub snapshot: Snapshot,
+    /// the ed25519 signature of the encoded snapshot
+    pub signature: Box<[u8]>,
+}
+
+/// Load the signing key from a file with its 32 bytes, or create a new one there, that only the
+/// user can read
+pub fn load_or_create_key(path: &Path) -> anyhow::Result<SigningKey> {
+    if !path.exists() {
+        let mut secret = [0u8; 32];
+        getrandom::fill(&mut secret)
+            .map_err(|cause| anyhow::anyhow!("no randomness for a key: {}", cause))?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(&secret)?;
+        file.sync_all()?;
+    }
+    let secret = fs::read(path)?;
+    let secret = <[u8; 32]>::try_from(secret.as_slice()).map_err(|_| {
+        anyhow::anyhow!(
+            "{} has {} bytes, not a key of 32",
+            path.display(),
+            secret.len()
+        )
+    })?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// A public key as text, for the reader's command line
+pub fn format_key(key: &VerifyingKey) -> String {
+    multibase::encode(Base::Base58Btc, key.to_bytes())
+}
+
+/// Parse a public key printed by [format_key]
+pub fn parse_key(text: &str) -> anyhow::Result<VerifyingKey> {
+    let (_, bytes) = multibase::decode(text)?;
+    let bytes = <[u8; 32]>::try_from(bytes.as_slice())
+        .map_err(|_| anyhow::anyhow!("a public key has 32 bytes, not {}", bytes.len()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|cause| anyhow::anyhow!("bad public key: {}", cause))
+}
+
+/// Sign a snapshot of the tree after the previous one and store it, returning the link to
+/// publish and the snapshot, which is the previous one for the next call
+pub fn sign<V>(
+    store: &mut impl BlockWriter<Sha256Digest>,
+    key: &SigningKey,
+    time: u64,
+    tree: &Tree<LogTT, V>,
+    previous: Option<&(Sha256Digest, Snapshot)>,
+) -> anyhow::Result<(Sha256Digest, Snapshot)> {
+    let snapshot = Snapshot {
+        root: tree.root().cloned(),
+        count: tree.count(),
+        time,
+        seq: previous.map(|(_, prev)| prev.seq + 1).unwrap_or_default(),
+        parent: previous.map(|(link, _)| *link),
+    };
+    let signature = key.sign(&DagCborCodec.encode(&snapshot)?).to_bytes();
+    let signed = SignedSnapshot {
+        snapshot: snapshot.clone(),
+        signature: signature.to_vec().into(),
+    };
+    Ok((store.put(DagCborCodec.encode(&signed)?)?, snapshot))
+}
+
+/// The snapshot behind the link, if it was signed with the key
+pub fn verify<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    link: Sha256Digest,
+    key: &VerifyingKey,
+) -> anyhow::Result<Snapshot> {
+    let signed: SignedSnapshot = DagCborCodec.decode(&store.get(&link)?)?;
+    let signature = Signature::from_slice(&signed.signature)
+        .map_err(|_| anyhow::anyhow!("{} has no valid signature", link))?;
+    key.verify_strict(&DagCborCodec.encode(&signed.snapshot)?, &signature)
+        .map_err(|_| anyhow::anyhow!("{} is not signed by {}", link, format_key(key)))?;
+    Ok(signed.snapshot)
+}
+
+/// Verify the snapshot behind the link and load its tree
+pub fn open<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    link: Sha256Digest,
+    key: &VerifyingKey,
+) -> anyhow::Result<(Snapshot, Tree<LogTT, u64>)> {
+    let snapshot = verify(store, link, key)?;
+    let tree = match snapshot.root {
+        Some(root) => Forest::<LogTT, _>::new(store.clone(), BranchCache::default())
+            .load_tree(Secrets::default(), root)?,
+        None => Tree::default(),
+    };
+    anyhow::ensure!(
+        tree.count() == snapshot.count,
+        "{} is signed for {} events, but the tree has {}",
+        link,
+        snapshot.count,
+        tree.count()
+    );
+    Ok((snapshot, tree))
+}
+
+/// Check that the snapshot is a valid successor of its parent
+fn check_parent(link: Sha256Digest, child: &Snapshot, parent: &Snapshot) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        child.seq == parent.seq + 1,
+        "{} has seq {} after seq {}",
+        link,
+        child.seq,
+        parent.seq
+    );
+    // the tree only grows, and time does not go back
+    anyhow::ensure!(
+        child.count >= parent.count && child.time >= parent.time,
+        "{} goes back from {} events at {} to {} at {}",
+        link,
+        parent.count,
+        parent.time,
+        child.count,
+        child.time
+    );
+    Ok(())
+}
+
+/// All snapshots from the head back to the first one, newest first, after checking that each is
+/// signed and follows its parent
+pub fn verify_chain<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+    key: &VerifyingKey,
+) -> anyhow::Result<Vec<(Sha256Digest, Snapshot)>> {
+    let mut chain: Vec<(Sha256Digest, Snapshot)> = Vec::new();
+    let mut next = Some(head);
+    while let Some(link) = next {
+        let snapshot = verify(store, link, key)?;
+        if let Some((child_link, child)) = chain.last() {
+            check_parent(*child_link, child, &snapshot)?;
+        }
+        next = snapshot.parent;
+        chain.push((link, snapshot));
+    }
+    let (first, snapshot) = chain.last().expect("at least the head");
+    anyhow::ensure!(
+        snapshot.seq == 0,
+        "{} has no parent, but seq {}",
+        first,
+        snapshot.seq
+    );
+    Ok(chain)
+}
+
+/// Check that the chain of the head contains a snapshot that was seen before
+///
+/// A head with a lower seq than the known snapshot is a rollback, and one with another snapshot
+/// at the seq of the known one is a fork. Only the part of the chain down to the known snapshot is
+/// read.
+pub fn extends<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+    key: &VerifyingKey,
+    known: &(Sha256Digest, Snapshot),
+) -> anyhow::Result<()> {
+    let (known_link, known) = known;
+    let mut link = head;
+    let mut snapshot = verify(store, link, key)?;
+    anyhow::ensure!(
+        snapshot.seq >= known.seq,
+        "rollback: {} has seq {}, but {} with seq {} was seen",
+        head,
+        snapshot.seq,
+        known_link,
+        known.seq
+    );
+    while snapshot.seq > known.seq {
+        let parent_link = snapshot
+            .parent
+            .ok_or_else(|| anyhow::anyhow!("{} has seq {} but no parent", link, snapshot.seq))?;
+        let parent = verify(store, parent_link, key)?;
+        check_parent(link, &snapshot, &parent)?;
+        link = parent_link;
+        snapshot = parent;
+    }
+    anyhow::ensure!(
+        link == *known_link,
+        "fork: {} has {} at seq {}, but {} was seen",
+        head,
+        link,
+        known.seq,
+        known_link
+    );
+    Ok(())
+}
+
+/// Verify the chain of signed snapshots in kubo, and print it
+pub fn print_chain<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+    key: &VerifyingKey,
+    known: Option<Sha256Digest>,
+) -> anyhow::Result<()> {
+    let chain = verify_chain(store, head, key)?;
+    if let Some(known) = known {
+        extends(store, head, key, &(known, verify(store, known, key)?))?;
+    }
+    println!("seq\ttime\tcount\tsnapshot\troot");
+    for (link, snapshot) in &chain {
+        let root = snapshot.root.map(|x| x.to_string()).unwrap_or_default();
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            snapshot.seq, snapshot.time, snapshot.count, link, root
+        );
+    }
+    println!(
+        "{} snapshots, all signed by {}, no fork or rollback",
+        chain.len(),
+        format_key(key)
+    );
+    Ok(())
+}
+
+/// Sign two snapshots, open the second with the writer's key, and try forgeries, a replay and a
+/// fork
+pub fn signed_example(
+    mut store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 10000u64;
+    println!(
+        "Example: a chain of two signed snapshots, {} events apart",
+        n
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let first = builder.snapshot();
+    txn.extend(&mut builder, (n..2 * n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+
+    let dir = std::env::temp_dir();
+    let writer = dir.join(format!("banyan-writer-{}.key", std::process::id()));
+    let other = dir.join(format!("banyan-other-{}.key", std::process::id()));
+    let key = load_or_create_key(&writer)?;
+    // the key file is what makes the writer the same one after a restart
+    anyhow::ensure!(load_or_create_key(&writer)? == key, "the key changed");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&writer)?.permissions().mode();
+        anyhow::ensure!(
+            mode & 0o077 == 0,
+            "others can read the key, mode {:o}",
+            mode
+        );
+    }
+    let public = parse_key(&format_key(&key.verifying_key()))?;
+    let t0 = snapshots::now();
+    let parent = sign(&mut store, &key, t0, &first, None)?;
+    let (link, _) = sign(&mut store, &key, t0 + 1000, &tree, Some(&parent))?;
+    let (snapshot, opened) = open(&store, link, &public)?;
+    anyhow::ensure!(opened.root() == tree.root(), "opened another tree");
+    println!(
+        "{} events at {} signed by {}",
+        snapshot.count,
+        snapshot.time,
+        format_key(&public)
+    );
+
+    // someone else can sign the same snapshot, but not as the writer
+    let (forged, _) = sign(
+        &mut store,
+        &load_or_create_key(&other)?,
+        snapshot.time,
+        &tree,
+        Some(&parent),
+    )?;
+    println!("other key: {}", open(&store, forged, &public).unwrap_err());
+    // and a changed snapshot no longer matches the signature
+    let mut signed: SignedSnapshot = DagCborCodec.decode(&store.get(&link)?)?;
+    signed.snapshot.time += 1;
+    let changed = store.put(DagCborCodec.encode(&signed)?)?;
+    println!(
+        "changed time: {}",
+        open(&store, changed, &public).unwrap_err()
+    );
+
+    // the chain of the head goes back to the first snapshot
+    let chain = verify_chain(&store, link, &public)?;
+    anyhow::ensure!(chain.len() == 2, "the chain has {} snapshots", chain.len());
+    extends(&store, link, &public, &parent)?;
+    // a reader that saw the head rejects the older snapshot when it is published again
+    let head = (link, snapshot);
+    println!(
+        "replayed: {}",
+        extends(&store, parent.0, &public, &head).unwrap_err()
+    );
+    // and another second snapshot, signed by the writer too, after the same first one
+    let (fork, _) = sign(&mut store, &key, t0 + 2000, &first, Some(&parent))?;
+    println!(
+        "forked: {}",
+        extends(&store, fork, &public, &head).unwrap_err()
+    );
+    fs::remove_file(&writer)?;
+    fs::remove_file(&other)?;
+    println!();
+    Ok(())
+}
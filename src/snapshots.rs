@@ -0,0 +1,220 @@
+//! A history of labeled snapshots
+//!
+//! Every snapshot of a builder is a complete, immutable tree, so keeping old versions around costs
+//! nothing but remembering their roots. We record each root together with a label and a link to the
+//! previous record. The records are dag-cbor blocks in the same store as the trees, so the head
+//! record is all you need to get back to any version.
+//!
+//! Each record also has the time the snapshot was taken, so we can query the stream as it was at
+//! any point in time. The old trees share all but their right edge with the newer ones, so this
+//! costs no extra storage.
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+/// One entry in the history
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct SnapshotRecord {
+    pub label: String,
+    /// unix time in milliseconds when the snapshot was taken
+    pub time: u64,
+    /// root of the tree at this point, `None` for an empty tree
+    pub root: Option<Sha256Digest>,
+    /// the previous record, `None` for the first one
+    pub parent: Option<Sha256Digest>,
+}
+
+/// Tree types of the stream whose history we record
+#[derive(Debug, Clone)]
+pub struct LogTT;
+
+impl TreeTypes for LogTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Snapshot history camp...";
+}
+
+/// A point in the history, either by label or by time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsOf {
+    /// the newest snapshot with this label
+    Label(String),
+    /// the newest snapshot taken at or before this unix time in milliseconds
+    Time(u64),
+}
+
+impl FromStr for AsOf {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // labels that are numbers can not be used, but v1, v2, ... are fine
+        Ok(match s.parse() {
+            Ok(time) => Self::Time(time),
+            Err(_) => Self::Label(s.to_string()),
+        })
+    }
+}
+
+impl AsOf {
+    fn matches(&self, record: &SnapshotRecord) -> bool {
+        match self {
+            Self::Label(label) => record.label == *label,
+            Self::Time(time) => record.time <= *time,
+        }
+    }
+}
+
+/// The current unix time in milliseconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Store a record for the tree and return its link, which is the new head
+pub fn record<V>(
+    store: &mut impl BlockWriter<Sha256Digest>,
+    label: &str,
+    time: u64,
+    tree: &Tree<LogTT, V>,
+    parent: Option<Sha256Digest>,
+) -> anyhow::Result<Sha256Digest> {
+    let record = SnapshotRecord {
+        label: label.to_string(),
+        time,
+        root: tree.root().cloned(),
+        parent,
+    };
+    store.put(DagCborCodec.encode(&record)?)
+}
+
+/// All records from the head back to the first one, newest first
+pub fn history<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+) -> impl Iterator<Item = anyhow::Result<(Sha256Digest, SnapshotRecord)>> + '_ {
+    let mut next = Some(head);
+    std::iter::from_fn(move || {
+        let link = next.take()?;
+        let record = store
+            .get(&link)
+            .and_then(|data| DagCborCodec.decode::<SnapshotRecord>(&data));
+        if let Ok(record) = &record {
+            next = record.parent;
+        }
+        Some(record.map(|record| (link, record)))
+    })
+}
+
+/// Load the tree of the newest record that matches
+pub fn checkout<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+    as_of: &AsOf,
+) -> anyhow::Result<Tree<LogTT, u64>> {
+    for item in history(store, head) {
+        let (_, record) = item?;
+        if as_of.matches(&record) {
+            let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::default());
+            return Ok(match record.root {
+                Some(root) => forest.load_tree(Secrets::default(), root)?,
+                None => Tree::default(),
+            });
+        }
+    }
+    Err(anyhow::anyhow!("no snapshot for {:?}", as_of))
+}
+
+/// Print all records from the head back to the first one
+pub fn print_history<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+) -> anyhow::Result<()> {
+    println!("record\tlabel\ttime\troot");
+    for item in history(store, head) {
+        let (link, record) = item?;
+        let root = record.root.map(|x| x.to_string()).unwrap_or_default();
+        println!("{}\t{}\t{}\t{}", link, record.label, record.time, root);
+    }
+    Ok(())
+}
+
+/// Print the values in the offset range of the tree as it was at the given point in the history
+pub fn print_query<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    head: Sha256Digest,
+    as_of: &AsOf,
+    from: u64,
+    to: Option<u64>,
+) -> anyhow::Result<()> {
+    let tree = checkout(store, head, as_of)?;
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::default());
+    let query = OffsetRangeQuery::from(from..to.unwrap_or(u64::MAX));
+    for item in forest.iter_filtered(&tree, query) {
+        let (i, _k, v) = item?;
+        println!("{}\t{}", i, v);
+    }
+    Ok(())
+}
+
+/// Append in a few batches, record a labeled snapshot after each, and go back to an old version
+pub fn snapshots_example(
+    mut store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let batches = 5u64;
+    let batch_size = 10000u64;
+    println!(
+        "Example: {} batches of {} events with a labeled snapshot after each",
+        batches, batch_size
+    );
+    let forest = Forest::<LogTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config.clone(), Secrets::default());
+    let mut head = None;
+    let mut roots = Vec::new();
+    // pretend the batches were written a minute apart
+    let t0 = now();
+    for b in 0..batches {
+        txn.extend(
+            &mut builder,
+            (b * batch_size..(b + 1) * batch_size).map(|i| ((), i)),
+        )?;
+        let label = format!("v{}", b + 1);
+        let time = t0 + b * 60000;
+        head = Some(record(&mut store, &label, time, &builder.snapshot(), head)?);
+        roots.extend(builder.snapshot().root().cloned());
+    }
+    let head = head.expect("at least one batch");
+    print_history(&store, head)?;
+
+    // the old version is still complete, even though the builder has moved on
+    let v2 = checkout(&store, head, &AsOf::Label("v2".into()))?;
+    anyhow::ensure!(v2.count() == 2 * batch_size, "v2 has the wrong size");
+    // half a minute after the third batch, the stream had three batches
+    let past = checkout(&store, head, &AsOf::Time(t0 + 150000))?;
+    anyhow::ensure!(past.count() == 3 * batch_size, "wrong snapshot for time");
+    println!(
+        "v2 has {} events, 2.5 minutes in there were {}, the head has {}",
+        v2.count(),
+        past.count(),
+        builder.snapshot().count()
+    );
+    // all versions together take hardly more space than the newest one
+    crate::dedup::report(&store, &roots)?;
+    println!("history head {}", head);
+    println!();
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Rebuilding a tree in key order
+//!
+//! Events are in the order they were appended. When that is not the key order, like events that
+//! were collected per device and appended device by device, a range query by time matches a few
+//! events in almost every leaf, and has to load all of them. [sort_by_key] builds a new tree with
+//! the same events in key order, so the same query loads only the leaves of its range.
+//!
+//! The tree does not have to fit into memory, the events are sorted with [extsort].
+use std::time::Instant;
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    cache::CacheStats,
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    extsort,
+};
+
+/// A new tree with the events of the tree in key order, using about `budget` bytes of memory.
+/// Events with the same key stay in the order they had
+pub fn sort_by_key<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    config: &Config,
+    tree: &Tree<ColumnarTT, u64>,
+    budget: usize,
+) -> anyhow::Result<Tree<ColumnarTT, u64>>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest>,
+{
+    let events = txn
+        .iter_from(tree)
+        .map(|item| item.map(|(_, key, value)| (key, value)));
+    Ok(extsort::sort(txn, config, events, budget)?.tree)
+}
+
+/// Events appended device by device, sorted by time, and a time range query on both trees
+pub fn sort_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let budget = 1 << 20;
+    println!(
+        "Example: sorting {} events by time, with {} bytes of memory",
+        n, budget
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut events = columnar::events(n);
+    let query = TimeRangeQuery {
+        min: events[(n / 2) as usize].0.time,
+        max: events[(n / 2) as usize + 1000].0.time,
+    };
+    events.sort_by_key(|(key, _)| key.device);
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, events)?;
+    let by_device = builder.snapshot();
+
+    let t0 = Instant::now();
+    let by_time = sort_by_key(&mut txn, config, &by_device, budget)?;
+    let sorting = t0.elapsed();
+    anyhow::ensure!(by_time.count() == n, "events are missing");
+    let mut prev = None;
+    for item in txn.iter_from(&by_time) {
+        let (_, key, _) = item?;
+        anyhow::ensure!(prev <= Some(key), "not sorted at {:?}", key);
+        prev = Some(key);
+    }
+    println!("sorted in {:.3}s", sorting.as_secs_f64());
+
+    println!("tree\tmatches\tleaves");
+    for (name, tree) in [("by device", &by_device), ("by time", &by_time)] {
+        let stats = CacheStats::new();
+        let forest = Forest::<ColumnarTT, _>::new(stats.store(store.clone()), BranchCache::new(0));
+        let mut matches = 0;
+        for item in forest.iter_filtered(tree, stats.query(query.clone())) {
+            item?;
+            matches += 1;
+        }
+        println!("{}\t{}\t{}", name, matches, stats.leaves());
+    }
+    println!();
+    Ok(())
+}
@@ -0,0 +1,162 @@
+//! A block store in a single sqlite file
+//!
+//! Blocks are in one table, keyed by the bytes of their cid. The database is in WAL mode, so
+//! readers don't block the writer, and with `synchronous = NORMAL` a put does not wait for the
+//! disk. A crash can lose the last puts, but never corrupts the file. [DurableStore::sync]
+//! checkpoints the WAL, for roots that are kept outside of the file.
+//!
+//! The statements are prepared once per connection and cached, since a tree is written and read
+//! in many small blocks.
+//!
+//! Named roots are in a second table. A compare-and-swap runs in an immediate transaction, which
+//! takes the write lock before reading, so it is safe with several processes on the same file.
+use std::{
+    marker::PhantomData,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use banyan::store::{BlockWriter, ReadOnlyStore};
+use libipld::Cid;
+use libipld::{cbor::DagCborCodec, codec::Codec};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::{
+    error::Error,
+    fs_store::DurableStore,
+    link::Link,
+    probe::ProbingStore,
+    roots::{self, RootStore},
+};
+
+/// A store that keeps its blocks in a sqlite database
+#[derive(Debug)]
+pub struct SqliteStore<L> {
+    conn: Arc<Mutex<Connection>>,
+    _link: PhantomData<L>,
+}
+
+impl<L> Clone for SqliteStore<L> {
+    fn clone(&self) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            _link: PhantomData,
+        }
+    }
+}
+
+impl<L: Link> SqliteStore<L> {
+    /// Open the database file, or create it
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        // the pragma returns the new mode, which stays the old one if WAL is not possible
+        let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+        anyhow::ensure!(mode == "wal", "journal mode is {} instead of wal", mode);
+        conn.execute_batch(
+            "PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS blocks (link BLOB PRIMARY KEY, data BLOB NOT NULL) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS roots (name TEXT PRIMARY KEY, link BLOB NOT NULL);",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            _link: PhantomData,
+        })
+    }
+
+    /// The number of blocks and their total size in bytes
+    pub fn usage(&self) -> anyhow::Result<(u64, u64)> {
+        let conn = self.conn.lock().unwrap();
+        let (blocks, bytes): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(data)), 0) FROM blocks",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok((blocks as u64, bytes as u64))
+    }
+
+    fn key(link: &L) -> Vec<u8> {
+        let cid: Cid = (*link).into();
+        cid.to_bytes()
+    }
+}
+
+impl<L: Link> ReadOnlyStore<L> for SqliteStore<L> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached("SELECT data FROM blocks WHERE link = ?")?;
+        let data: Option<Vec<u8>> = select
+            .query_row(params![Self::key(link)], |row| row.get(0))
+            .optional()?;
+        match data {
+            Some(data) => Ok(data.into()),
+            None => Err(Error::missing(*link).into()),
+        }
+    }
+}
+
+impl<L: Link> RootStore<L> for SqliteStore<L> {
+    fn root(&self, name: &str) -> anyhow::Result<Option<L>> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached("SELECT link FROM roots WHERE name = ?")?;
+        let link: Option<Vec<u8>> = select
+            .query_row(params![name], |row| row.get(0))
+            .optional()?;
+        link.map(|link| DagCborCodec.decode(&link)).transpose()
+    }
+
+    fn compare_and_swap(&self, name: &str, expected: Option<L>, new: L) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let actual: Option<Vec<u8>> = txn
+            .query_row(
+                "SELECT link FROM roots WHERE name = ?",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let actual = actual.map(|link| DagCborCodec.decode(&link)).transpose()?;
+        roots::check(name, expected, actual)?;
+        txn.execute(
+            "INSERT OR REPLACE INTO roots (name, link) VALUES (?, ?)",
+            params![name, DagCborCodec.encode(&new)?],
+        )?;
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+impl<L: Link> ProbingStore<L> for SqliteStore<L> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut select = conn.prepare_cached("SELECT LENGTH(data) FROM blocks WHERE link = ?")?;
+        let size: Option<i64> = select
+            .query_row(params![Self::key(link)], |row| row.get(0))
+            .optional()?;
+        Ok(size.map(|size| size as u64))
+    }
+}
+
+impl<L: Link> BlockWriter<L> for SqliteStore<L> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let link = L::digest(&data);
+        let conn = self.conn.lock().unwrap();
+        let mut insert =
+            conn.prepare_cached("INSERT OR IGNORE INTO blocks (link, data) VALUES (?, ?)")?;
+        insert.execute(params![Self::key(&link), data])?;
+        Ok(link)
+    }
+}
+
+impl<L: Link> DurableStore<L> for SqliteStore<L> {
+    /// A root swapped in the same file after the puts is lost along with them, but not a root in
+    /// a manifest file. So this checkpoints the WAL into the database, which syncs both
+    fn sync(&self) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(FULL)", [], |row| row.get(0))?;
+        anyhow::ensure!(
+            busy == 0,
+            "the wal checkpoint is blocked by another connection"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! Sweep of the leaf size options
+//!
+//! `max_leaf_count` and `target_leaf_size` decide how many blocks a tree has, and so how deep it
+//! is, how much it costs to write, and how much a range query has to read and decompress. Which
+//! values are best depends on the data and the queries, so this builds the same events with every
+//! combination of a grid of values and prints one CSV line per combination, to be compared in a
+//! spreadsheet or a plot. The leaf closes at whichever of the two limits is reached first.
+use std::time::Instant;
+
+use banyan::{
+    store::{BranchCache, MemStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    progress::CountingStore,
+};
+
+/// Build `n` events with each combination of `leaf_counts` and `leaf_sizes`, run `queries` time
+/// range queries of about 1000 events each against every tree, and print the results as CSV
+pub fn report(
+    config: &Config,
+    n: u64,
+    queries: u64,
+    leaf_counts: &[usize],
+    leaf_sizes: &[usize],
+) -> anyhow::Result<()> {
+    anyhow::ensure!(n >= 1000, "need at least 1000 events");
+    let configs = leaf_counts
+        .iter()
+        .flat_map(|&max_leaf_count| {
+            leaf_sizes.iter().map(move |&target_leaf_size| Config {
+                max_leaf_count,
+                target_leaf_size,
+                ..config.clone()
+            })
+        })
+        .collect::<Vec<_>>();
+    for config in &configs {
+        anyhow::ensure!(config.max_leaf_count > 0, "a max leaf count of 0");
+        config.validate()?;
+    }
+    let xs = columnar::events(n);
+    let queries = (0..queries)
+        .map(|i| {
+            // pseudo random windows, spread over the whole range, the same for every config
+            let start = (i.wrapping_mul(0x9e3779b97f4a7c15) % (n - 999)) as usize;
+            TimeRangeQuery {
+                min: xs[start].0.time,
+                max: xs[start + 999].0.time,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    println!("max_leaf_count,target_leaf_size,write_s,level,blocks,bytes,query_ms,matches");
+    let mut expected = None;
+    for config in &configs {
+        // a fresh store per config, so the counters only see the blocks of this tree
+        let store = CountingStore::new(MemStore::new(usize::MAX, Sha256Digest::digest));
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::default());
+        let mut txn = Transaction::new(forest, store.clone());
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+        let t0 = Instant::now();
+        txn.extend(&mut builder, xs.iter().cloned())?;
+        let write = t0.elapsed().as_secs_f64();
+        let tree = builder.snapshot();
+
+        let t0 = Instant::now();
+        let mut matches = 0u64;
+        for query in &queries {
+            for item in txn.iter_filtered(&tree, query.clone()) {
+                item?;
+                matches += 1;
+            }
+        }
+        let query_ms = t0.elapsed().as_secs_f64() * 1000.0 / queries.len().max(1) as f64;
+        anyhow::ensure!(
+            *expected.get_or_insert(matches) == matches,
+            "max leaf count {} and target leaf size {} found {} events",
+            config.max_leaf_count,
+            config.target_leaf_size,
+            matches
+        );
+        println!(
+            "{},{},{:.3},{},{},{},{:.3},{}",
+            config.max_leaf_count,
+            config.target_leaf_size,
+            write,
+            tree.level(),
+            store.counters().blocks(),
+            store.counters().bytes(),
+            query_ms,
+            matches
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Sending a reader just the blocks it is missing
+//!
+//! A reader that follows a stream through kubo finds the new root, and then asks bitswap for
+//! every block it does not have, a round trip per level of the tree, each with a search for who
+//! has the block. For a tail that gets a new root every second, that is most of the work. But
+//! the reader knows the root it has, and a new root of the same stream shares almost all of its
+//! blocks with it, so the writer can tell which blocks the reader is missing without asking.
+//!
+//! The protocol is a single http request. The reader asks for a stream by the name of its root,
+//! with the root it has, if any:
+//!
+//! ```text
+//! GET /sync/<name>?have=<cid>
+//! ```
+//!
+//! and the writer answers with a CAR whose root is the latest root, and whose blocks are all
+//! blocks reachable from it that are not reachable from the root the reader has. If the writer
+//! does not have that root, e.g. because it was collected, the answer has all blocks. A reader
+//! that is up to date gets a CAR without blocks.
+//!
+//! To find the blocks of the old root, [missing] walks all of them, which is a read of every
+//! block in the store of the writer, but no round trips over the network. The reader checks the
+//! hash of every block it gets, see [crate::verify].
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    convert::TryFrom,
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    str::FromStr,
+    thread,
+};
+
+use banyan::{
+    query::AllQuery,
+    store::{BlockWriter, BranchCache, MemStore, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use indicatif::HumanBytes;
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld};
+
+use crate::{
+    access, car,
+    columnar::{self, ColumnarTT},
+    dedup,
+    error::Error,
+    progress,
+    roots::{ManifestFile, RootStore},
+    server::{respond, Request, Response},
+};
+
+/// What a reader got from one [pull]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pulled {
+    /// the latest root, which the reader now has all blocks of
+    pub root: Sha256Digest,
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// The blocks reachable from `latest` that are not reachable from `have`, parents before their
+/// children
+pub fn missing<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    have: Option<Sha256Digest>,
+    latest: Sha256Digest,
+) -> anyhow::Result<Vec<Sha256Digest>> {
+    let known = match have {
+        // a root that is gone is as good as none
+        Some(have) => dedup::blocks(store, have).unwrap_or_default(),
+        None => BTreeMap::new(),
+    };
+    let mut result = Vec::new();
+    let mut seen = HashSet::new();
+    let mut todo = vec![latest];
+    while let Some(link) = todo.pop() {
+        // the reader has everything below a block it has
+        if known.contains_key(&link) || !seen.insert(link) {
+            continue;
+        }
+        let data = store.get(&link)?;
+        if let Ok(ipld) = DagCborCodec.decode::<Ipld>(&data) {
+            let mut links = BTreeSet::<Cid>::new();
+            ipld.references(&mut links);
+            for cid in links {
+                todo.push(Sha256Digest::try_from(cid)?);
+            }
+        }
+        result.push(link);
+    }
+    Ok(result)
+}
+
+/// The answer to a reader of stream `name` that has the root `have`: a CAR with the blocks it is
+/// missing, or a 404 if there is no such stream
+pub fn answer<S: ReadOnlyStore<Sha256Digest>, M: RootStore<Sha256Digest>>(
+    store: &S,
+    roots: &M,
+    name: &str,
+    have: Option<Sha256Digest>,
+) -> anyhow::Result<Response> {
+    let Some(latest) = roots.root(name)? else {
+        return Ok(("404 Not Found", format!("no root {}", name).into_bytes()));
+    };
+    let blocks = missing(store, have, latest)?;
+    let mut body = Vec::new();
+    car::write_car_stream(store, &[latest], &blocks, &mut body)?;
+    Ok(("200 OK", body))
+}
+
+/// Answer one request of a reader, with the roots of the root store. The [server](crate::server)
+/// answers these along with its other requests
+pub fn handle<S: ReadOnlyStore<Sha256Digest>, M: RootStore<Sha256Digest>>(
+    stream: TcpStream,
+    store: &S,
+    roots: &M,
+) -> anyhow::Result<()> {
+    let request = Request::read(&mut BufReader::new(stream.try_clone()?))?;
+    if request.method != "GET" {
+        return respond(&stream, "405 Method Not Allowed", b"");
+    }
+    let Some(name) = request.url.path().strip_prefix("/sync/") else {
+        return respond(&stream, "404 Not Found", b"");
+    };
+    let have = match request
+        .query("have")
+        .map(|have| Sha256Digest::from_str(&have))
+    {
+        Some(Ok(have)) => Some(have),
+        Some(Err(cause)) => {
+            return respond(&stream, "400 Bad Request", cause.to_string().as_bytes())
+        }
+        None => None,
+    };
+    let (status, body) = answer(store, roots, name, have)?;
+    respond(&stream, status, &body)
+}
+
+/// Get the blocks of the latest root of a stream that the reader is missing from a writer at
+/// `url`, and write them to the store of the reader
+pub fn pull<W: BlockWriter<Sha256Digest>>(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    name: &str,
+    have: Option<Sha256Digest>,
+    writer: &mut W,
+) -> anyhow::Result<Pulled> {
+    let url = match have {
+        Some(have) => format!("{}/sync/{}?have={}", url, name, have),
+        None => format!("{}/sync/{}", url, name),
+    };
+    let response = client.get(&url).send()?.error_for_status()?;
+    let (roots, blocks) = car::read_car_stream(&mut response.bytes()?.as_ref())?;
+    let root = match roots[..] {
+        [root] => root,
+        _ => anyhow::bail!("{} sent {} roots", url, roots.len()),
+    };
+    let bar = progress::block_bar(Some(blocks.len() as u64))?;
+    let mut bytes = 0;
+    for (link, data) in &blocks {
+        let actual = Sha256Digest::digest(data);
+        if actual != *link {
+            return Err(Error::Corrupt {
+                cid: (*link).into(),
+                actual: actual.into(),
+            }
+            .into());
+        }
+        bytes += data.len() as u64;
+        writer.put(data.clone())?;
+        bar.inc(1);
+        bar.set_message(HumanBytes(bytes).to_string());
+    }
+    // the caller reports the blocks and bytes
+    bar.finish_and_clear();
+    Ok(Pulled {
+        root,
+        blocks: blocks.len() as u64,
+        bytes,
+    })
+}
+
+/// Pull the latest root of a stream into a store, and print it with what was sent
+pub fn print_pull<W: BlockWriter<Sha256Digest>>(
+    writer: &mut W,
+    url: &str,
+    name: &str,
+    have: Option<Sha256Digest>,
+) -> anyhow::Result<()> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(token) = access::token_from_env() {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))?;
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    let client = reqwest::blocking::Client::builder()
+        .default_headers(headers)
+        .build()?;
+    let pulled = pull(&client, url, name, have, writer)?;
+    println!("root\tblocks\tbytes");
+    println!("{}\t{}\t{}", pulled.root, pulled.blocks, pulled.bytes);
+    Ok(())
+}
+
+/// A writer that appends to a stream, and a reader that follows it with a store of its own
+pub fn sync_example(config: &Config) -> anyhow::Result<()> {
+    let batches = 10u64;
+    let batch = 10000u64;
+    println!(
+        "Example: following a stream of {} batches of {} events over http",
+        batches, batch
+    );
+    // leaves that fill up many times over, like the leaves of a long stream
+    let config = &Config {
+        target_leaf_size: 1 << 12,
+        max_leaf_count: 1 << 10,
+        ..config.clone()
+    };
+    config.validate()?;
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let path = std::env::temp_dir().join(format!("banyan-sync-{}.manifest", std::process::id()));
+    let roots = ManifestFile::new(&path);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    // a pull per batch, one more when up to date, and one of a stream that is not there
+    let requests = batches as usize + 2;
+    let server = {
+        let (store, roots) = (store.clone(), roots.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                handle(stream?, &store, &roots)?;
+            }
+            anyhow::Ok(())
+        })
+    };
+
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let events = columnar::events(batches * batch);
+    let client = reqwest::blocking::Client::new();
+    let mut reader = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let mut have = None;
+    let (mut sent, mut full) = (0, 0);
+    println!("events\tblocks\tbytes\tall bytes");
+    for part in events.chunks(batch as usize) {
+        txn.extend(&mut builder, part.iter().cloned())?;
+        let root = builder.snapshot().link().expect("not empty");
+        roots.compare_and_swap("events", have, root)?;
+        let pulled = pull(&client, &url, "events", have, &mut reader)?;
+        anyhow::ensure!(pulled.root == root, "pulled {}, not {}", pulled.root, root);
+        let all = dedup::blocks(&store, root)?.values().sum::<u64>();
+        println!(
+            "{}\t{}\t{}\t{}",
+            builder.snapshot().count(),
+            pulled.blocks,
+            pulled.bytes,
+            all
+        );
+        sent += pulled.bytes;
+        full += all;
+        have = Some(root);
+    }
+    let root = have.expect("not empty");
+    let pulled = pull(&client, &url, "events", have, &mut reader)?;
+    anyhow::ensure!(
+        pulled.root == root && pulled.blocks == 0,
+        "an up to date reader got {:?}",
+        pulled
+    );
+    anyhow::ensure!(
+        pull(&client, &url, "other", None, &mut reader).is_err(),
+        "got a stream that is not there"
+    );
+    server.join().expect("server panicked")?;
+
+    // the reader has the whole tree, without ever asking for a block
+    let forest = Forest::<ColumnarTT, _>::new(reader, BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let count = forest.iter_filtered(&tree, AllQuery).count() as u64;
+    anyhow::ensure!(count == batches * batch, "the reader has {} events", count);
+    anyhow::ensure!(sent < full, "the pulls sent everything every time");
+    println!(
+        "sent {} bytes instead of {}, {:.1}%",
+        sent,
+        full,
+        sent as f64 * 100.0 / full as f64
+    );
+    let _ = std::fs::remove_file(&path);
+    println!();
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Several tenants in one manifest
+//!
+//! One deployment can host the streams of several applications. Each application is a tenant,
+//! with its own namespace of stream names and its own secrets. A [Namespace] is a view of a
+//! [RootStore] that only sees the roots of one tenant, stored as `tenant/stream`, so two tenants
+//! can both have a stream called `events` without seeing each other's roots.
+//!
+//! The roots are not secret, and the blocks are all in the same store. What keeps the event logs
+//! apart is that each tenant gets its own index and value key, derived from a master key and the
+//! tenant name with [tenant_secrets]. Without them, another tenant can see that a tree exists, but
+//! can not read its keys, summaries or values.
+//!
+//! The master key is the `master_key` of the profile. The writer and the reader of a tenant use
+//! the secrets of the tenant, and so does the [server](crate::server) for the trees of the streams
+//! of a tenant, `tenant/stream`. A token of its access file can be for one tenant, which only
+//! reaches the streams of that tenant, see [crate::access]. Without a master key, the server
+//! appends to trees with the default secrets, and it is only the tokens that keep tenants apart
+//! there.
+use std::{fs, marker::PhantomData};
+
+use banyan::{
+    chacha20::Key as SecretKey, store::BranchCache, Config, Forest, Secrets, StreamBuilder,
+    Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use multihash::{Code, MultihashDigest};
+
+use crate::{
+    columnar::{self, ColumnarTT, TimeRangeQuery},
+    roots::{ManifestFile, RootStore},
+    sqlite_store::SqliteStore,
+};
+
+/// Check that a tenant or stream name can be used in a manifest, which separates names and links
+/// with a space and tenants and streams with a slash
+pub fn check_name(kind: &str, name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !name.is_empty() && !name.contains(|c: char| c == '/' || c.is_whitespace()),
+        "invalid {} name {:?}, it must not be empty or contain slashes or spaces",
+        kind,
+        name
+    );
+    Ok(())
+}
+
+/// The name of the root of a stream of a tenant
+pub fn scoped(tenant: &str, stream: &str) -> anyhow::Result<String> {
+    check_name("tenant", tenant)?;
+    check_name("stream", stream)?;
+    Ok(format!("{}/{}", tenant, stream))
+}
+
+/// Check the name of a stream, `stream` or `tenant/stream`
+pub fn check_stream(name: &str) -> anyhow::Result<()> {
+    match name.split_once('/') {
+        Some((tenant, stream)) => scoped(tenant, stream).map(drop),
+        None => check_name("stream", name),
+    }
+}
+
+/// The tenant of a stream name `tenant/stream`, or none for a plain `stream`
+pub fn tenant_of(name: &str) -> Option<&str> {
+    name.split_once('/').map(|(tenant, _)| tenant)
+}
+
+/// The roots of one tenant
+#[derive(Debug, Clone)]
+pub struct Namespace<R, L> {
+    roots: R,
+    tenant: String,
+    _link: PhantomData<L>,
+}
+
+impl<R: RootStore<L>, L> Namespace<R, L> {
+    pub fn new(roots: R, tenant: &str) -> anyhow::Result<Self> {
+        check_name("tenant", tenant)?;
+        Ok(Self {
+            roots,
+            tenant: tenant.to_string(),
+            _link: PhantomData,
+        })
+    }
+}
+
+impl<R: RootStore<L>, L> RootStore<L> for Namespace<R, L> {
+    fn root(&self, name: &str) -> anyhow::Result<Option<L>> {
+        self.roots.root(&scoped(&self.tenant, name)?)
+    }
+
+    fn compare_and_swap(&self, name: &str, expected: Option<L>, new: L) -> anyhow::Result<()> {
+        self.roots
+            .compare_and_swap(&scoped(&self.tenant, name)?, expected, new)
+    }
+}
+
+/// The secrets of a tenant. Each key is a blake3 hash of the master key, its purpose and the tenant
+/// name, so no key of one tenant says anything about the keys of another
+pub fn tenant_secrets(master: &SecretKey, tenant: &str) -> anyhow::Result<Secrets> {
+    check_name("tenant", tenant)?;
+    let derive = |purpose: &str| -> anyhow::Result<SecretKey> {
+        let mut input = master.to_vec();
+        input.extend_from_slice(format!("banyan tenant {} key {}", purpose, tenant).as_bytes());
+        let key = <[u8; 32]>::try_from(Code::Blake3_256.digest(&input).digest())?;
+        Ok(SecretKey::from(key))
+    };
+    Ok(Secrets::new(derive("index")?, derive("value")?))
+}
+
+/// Two tenants with a stream of the same name in one manifest and one store, each only able to
+/// read its own
+pub fn tenants_example(config: &Config) -> anyhow::Result<()> {
+    let n = 10000u64;
+    println!("Example: two tenants with an events stream of {} each", n);
+    let dir = std::env::temp_dir().join(format!("banyan-tenants-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let store = SqliteStore::<Sha256Digest>::open(dir.join("blocks.sqlite"))?;
+    let manifest = ManifestFile::new(dir.join("manifest"));
+    let master = SecretKey::from([7u8; 32]);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let events = columnar::events(n);
+    let query = TimeRangeQuery {
+        min: events[100].0.time,
+        max: events[199].0.time,
+    };
+
+    let tenants = ["acme", "globex"];
+    for tenant in tenants {
+        let roots = Namespace::new(manifest.clone(), tenant)?;
+        let secrets = tenant_secrets(&master, tenant)?;
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets);
+        txn.extend(&mut builder, events.iter().cloned())?;
+        roots.compare_and_swap("events", None, builder.link().expect("not empty"))?;
+    }
+    print!("{}", fs::read_to_string(dir.join("manifest"))?);
+
+    for tenant in tenants {
+        let roots = Namespace::<_, Sha256Digest>::new(manifest.clone(), tenant)?;
+        let root = roots.root("events")?.expect("written above");
+        let own = txn.load_tree::<u64>(tenant_secrets(&master, tenant)?, root)?;
+        let matches = txn.iter_filtered(&own, query.clone()).count();
+        anyhow::ensure!(matches >= 100, "{} can not read its own events", tenant);
+        // the root is no secret, but the tree is encrypted with the keys of its tenant
+        let others = tenants.iter().filter(|other| **other != tenant);
+        for other in others {
+            let secrets = tenant_secrets(&master, other)?;
+            let read = txn.load_tree::<u64>(secrets, root).and_then(|tree| {
+                txn.iter_filtered(&tree, query.clone())
+                    .collect::<anyhow::Result<Vec<_>>>()
+            });
+            anyhow::ensure!(read.is_err(), "{} can read the events of {}", other, tenant);
+        }
+        println!(
+            "{}: {} events in the query, not readable by the others",
+            tenant, matches
+        );
+    }
+    anyhow::ensure!(
+        scoped("acme/evil", "events").is_err(),
+        "a tenant escaped its namespace"
+    );
+    fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,245 @@
+//! Hot blocks on local disk, cold blocks only in ipfs
+//!
+//! An edge device has little disk, but reads mostly recent events. A [TieredStore] writes every
+//! block to a local [FsStore] and to a cold store like kubo, and remembers when each block was
+//! last read or written. [TieredStore::demote] removes the local copy of every block that was
+//! not touched since a cutoff, except for the branches of the trees to keep, so a query can
+//! still find its way through the tree without going to the network. A block that is read
+//! after it was demoted comes from the cold store, and is local again from then on.
+//!
+//! The access times are only in memory. After a restart every block counts as touched at start,
+//! so the blocks that were local before are demoted like the others once they are not read.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use banyan::{
+    index::Index,
+    query::{AllQuery, OffsetRangeQuery},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    fs_store::FsStore,
+    link::Link,
+    snapshots::{self, LogTT},
+};
+
+/// What a [TieredStore::demote] did, and what it costs on reads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TierStats {
+    /// blocks and bytes removed from the local store
+    pub demoted: u64,
+    pub demoted_bytes: u64,
+    /// reads that had to go to the cold store
+    pub refetched: u64,
+}
+
+#[derive(Debug, Default)]
+struct State<L> {
+    /// last access per block, in unix milliseconds
+    touched: HashMap<L, u64>,
+    stats: TierStats,
+}
+
+/// A local store in front of a cold store, see the module docs
+#[derive(Debug)]
+pub struct TieredStore<L, C> {
+    local: FsStore<L>,
+    cold: C,
+    state: Arc<Mutex<State<L>>>,
+}
+
+impl<L, C: Clone> Clone for TieredStore<L, C> {
+    fn clone(&self) -> Self {
+        Self {
+            local: self.local.clone(),
+            cold: self.cold.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<L: Link, C> TieredStore<L, C> {
+    /// A tiered store, with every block that is already local touched now
+    pub fn new(local: FsStore<L>, cold: C) -> anyhow::Result<Self> {
+        let now = snapshots::now();
+        let touched = local.links()?.into_iter().map(|link| (link, now)).collect();
+        Ok(Self {
+            local,
+            cold,
+            state: Arc::new(Mutex::new(State {
+                touched,
+                stats: TierStats::default(),
+            })),
+        })
+    }
+
+    pub fn local(&self) -> &FsStore<L> {
+        &self.local
+    }
+
+    pub fn stats(&self) -> TierStats {
+        self.state.lock().unwrap().stats
+    }
+
+    fn touch(&self, link: L) {
+        let now = snapshots::now();
+        self.state.lock().unwrap().touched.insert(link, now);
+    }
+
+    /// Remove the local copy of every block not touched since `cutoff`, in unix milliseconds,
+    /// except for those in `keep`
+    pub fn demote(&self, cutoff: u64, keep: &HashSet<L>) -> anyhow::Result<TierStats> {
+        let mut state = self.state.lock().unwrap();
+        let cold = state
+            .touched
+            .iter()
+            .filter(|(link, touched)| **touched < cutoff && !keep.contains(link))
+            .map(|(link, _)| *link)
+            .collect::<Vec<_>>();
+        let mut result = TierStats::default();
+        for link in cold {
+            state.touched.remove(&link);
+            if let Some(size) = self.local.remove(&link)? {
+                result.demoted += 1;
+                result.demoted_bytes += size;
+            }
+        }
+        state.stats.demoted += result.demoted;
+        state.stats.demoted_bytes += result.demoted_bytes;
+        Ok(result)
+    }
+}
+
+impl<L: Link, C: ReadOnlyStore<L>> ReadOnlyStore<L> for TieredStore<L, C> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let data = match self.local.get(link) {
+            Ok(data) => data,
+            Err(_) => {
+                let data = self.cold.get(link)?;
+                self.local.clone().put(data.to_vec())?;
+                self.state.lock().unwrap().stats.refetched += 1;
+                data
+            }
+        };
+        self.touch(*link);
+        Ok(data)
+    }
+}
+
+impl<L: Link, C: BlockWriter<L>> BlockWriter<L> for TieredStore<L, C> {
+    /// Write to the cold store first, so a block is never only local
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let link = self.cold.put(data.clone())?;
+        self.local.put(data)?;
+        self.touch(link);
+        Ok(link)
+    }
+}
+
+/// The links of all branches of a tree, which are what queries need to find the leaves they read
+pub fn spine<T, R, V>(forest: &Forest<T, R>, tree: &Tree<T, V>) -> anyhow::Result<HashSet<T::Link>>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+{
+    let mut links = HashSet::new();
+    for index in forest.iter_index(tree, AllQuery) {
+        if let Index::Branch(branch) = index? {
+            links.extend(branch.link);
+        }
+    }
+    Ok(links)
+}
+
+/// Demote the old part of a tree, and read old and new events from it
+pub fn tiering_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let recent = 1000u64;
+    println!(
+        "Example: demoting the blocks of {} events that were not read recently",
+        n
+    );
+    let dir = std::env::temp_dir().join(format!("banyan-tiering-{}", std::process::id()));
+    let tiered = TieredStore::new(FsStore::open(&dir)?, store)?;
+    let forest = Forest::<LogTT, _>::new(tiered.clone(), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, tiered.clone());
+    // small leaves, so there are more than a few blocks to demote
+    let config = Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let mut builder = StreamBuilder::<LogTT, u64>::new(config, Secrets::default());
+    txn.extend(&mut builder, (0..n).map(|i| ((), i)))?;
+    let tree = builder.snapshot();
+    let (blocks, bytes) = tiered.local().usage();
+
+    // days pass, and only the newest events are read
+    thread::sleep(Duration::from_millis(2));
+    let cutoff = snapshots::now();
+    thread::sleep(Duration::from_millis(2));
+    let newest = OffsetRangeQuery::from(n - recent..);
+    let read = txn.iter_filtered(&tree, newest.clone()).count() as u64;
+    anyhow::ensure!(read == recent, "read {} recent events", read);
+
+    let keep = spine(&txn, &tree)?;
+    let demoted = tiered.demote(cutoff, &keep)?;
+    let (local_blocks, local_bytes) = tiered.local().usage();
+    println!("local\tblocks\tbytes");
+    println!("before\t{}\t{}", blocks, bytes);
+    println!("after\t{}\t{}", local_blocks, local_bytes);
+    anyhow::ensure!(
+        demoted.demoted > 0 && local_blocks == blocks - demoted.demoted,
+        "nothing was demoted"
+    );
+
+    // recent events and the spine are local, old events come from the cold store again
+    let before = tiered.stats().refetched;
+    txn.iter_filtered(&tree, newest)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        tiered.stats().refetched == before,
+        "recent events were demoted"
+    );
+    let old = txn
+        .iter_filtered(&tree, OffsetRangeQuery::from(0..recent))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        old.iter()
+            .map(|(offset, _, value)| (*offset, *value))
+            .eq((0..recent).map(|i| (i, i))),
+        "old events changed"
+    );
+    let refetched = tiered.stats().refetched - before;
+    anyhow::ensure!(refetched > 0, "old events were still local");
+    println!(
+        "{} blocks demoted, {} of them fetched again by reading {} old events",
+        demoted.demoted, refetched, recent
+    );
+    let reopened = FsStore::<Sha256Digest>::open(&dir)?;
+    anyhow::ensure!(
+        reopened.usage() == tiered.local().usage(),
+        "the index forgot the removals"
+    );
+    // after a restart, the blocks that were local before are demoted like the others
+    let restarted = TieredStore::new(reopened, tiered.cold.clone())?;
+    let demoted = restarted.demote(snapshots::now() + 1, &keep)?;
+    anyhow::ensure!(
+        demoted.demoted > 0 && restarted.local().usage().0 <= keep.len() as u64,
+        "blocks from before the restart were not demoted"
+    );
+    std::fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
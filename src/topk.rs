@@ -0,0 +1,190 @@
+//! The most frequent items of a query, in bounded memory
+//!
+//! Counting every distinct item of a large query, like the device of each event, needs memory for
+//! all of them. [SpaceSaving] keeps a fixed number of counters instead. When an item without a
+//! counter comes in and all counters are taken, the counter with the smallest count is given to
+//! the new item, keeping its count as the possible error. Every item that occurs more than
+//! `n / capacity` times is guaranteed to have a counter in the end, and its count is at most
+//! `error` too high.
+//!
+//! The device is part of the key, so [top_devices] only reads the branches, via [KeyIter].
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Instant,
+};
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey, TimeRangeQuery},
+    projection::KeyIter,
+};
+
+/// The estimated count of an item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter<G> {
+    pub item: G,
+    /// at least the real count, and at most `error` more
+    pub count: u64,
+    pub error: u64,
+}
+
+/// The space-saving algorithm, with a fixed number of counters
+pub struct SpaceSaving<G> {
+    capacity: usize,
+    /// count and error per item
+    counters: BTreeMap<G, (u64, u64)>,
+}
+
+impl<G: Ord + Clone> SpaceSaving<G> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "at least one counter is needed");
+        Self {
+            capacity,
+            counters: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, item: G) {
+        if let Some((count, _)) = self.counters.get_mut(&item) {
+            *count += 1;
+        } else if self.counters.len() < self.capacity {
+            self.counters.insert(item, (1, 0));
+        } else {
+            // a linear scan, since the number of counters is small
+            let (min, (count, _)) = self
+                .counters
+                .iter()
+                .min_by_key(|(_, (count, _))| *count)
+                .map(|(item, counter)| (item.clone(), *counter))
+                .expect("capacity is not 0");
+            self.counters.remove(&min);
+            self.counters.insert(item, (count + 1, count));
+        }
+    }
+
+    /// The `k` items with the highest counts, highest first
+    pub fn top(&self, k: usize) -> Vec<Counter<G>> {
+        let mut res = self
+            .counters
+            .iter()
+            .map(|(item, (count, error))| Counter {
+                item: item.clone(),
+                count: *count,
+                error: *error,
+            })
+            .collect::<Vec<_>>();
+        res.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.item.cmp(&b.item)));
+        res.truncate(k);
+        res
+    }
+}
+
+/// The `k` most frequent items, using `capacity` counters
+pub fn top_k<G: Ord + Clone>(
+    items: impl IntoIterator<Item = anyhow::Result<G>>,
+    k: usize,
+    capacity: usize,
+) -> anyhow::Result<Vec<Counter<G>>> {
+    let mut counters = SpaceSaving::new(capacity.max(k));
+    for item in items {
+        counters.add(item?);
+    }
+    Ok(counters.top(k))
+}
+
+/// The `k` devices with the most events matching the query, from the branches alone
+pub fn top_devices<V>(
+    store: impl ReadOnlyStore<Sha256Digest>,
+    tree: &Tree<ColumnarTT, V>,
+    query: TimeRangeQuery,
+    k: usize,
+) -> anyhow::Result<Vec<Counter<u32>>> {
+    let devices = KeyIter::new(store, tree, query).map(|item| item.map(|(_, key)| key.device));
+    // more counters than results, so the counts of the last few results are still good
+    top_k(devices, k, k * 10)
+}
+
+/// Print the devices with the most events of a columnar tree in kubo
+pub fn print_top<S: ReadOnlyStore<Sha256Digest>>(
+    store: &S,
+    root: Sha256Digest,
+    k: usize,
+    query: TimeRangeQuery,
+) -> anyhow::Result<()> {
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    println!("device\tcount\terror");
+    for counter in top_devices(store.clone(), &tree, query, k)? {
+        println!("{}\t{}\t{}", counter.item, counter.count, counter.error);
+    }
+    Ok(())
+}
+
+/// Find the busiest devices in events where a few devices send much more than the others, and
+/// compare the estimates to exact counts
+pub fn topk_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let k = 5;
+    println!("Example: the top {} of 1000 devices in {} events", k, n);
+    // every third event comes from one of five busy devices
+    let events = columnar::events(n)
+        .into_iter()
+        .map(|(key, i)| {
+            let device = if i % 3 == 0 {
+                (i % 5) as u32 * 7
+            } else {
+                key.device * 10 + (i % 10) as u32
+            };
+            (EventKey { device, ..key }, i)
+        })
+        .collect::<Vec<_>>();
+    let mut exact = BTreeMap::<u32, u64>::new();
+    for (key, _) in &events {
+        *exact.entry(key.device).or_default() += 1;
+    }
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, events)?;
+    let tree = builder.snapshot();
+    let all = TimeRangeQuery {
+        min: 0,
+        max: u64::MAX,
+    };
+
+    let t0 = Instant::now();
+    let top = top_devices(store, &tree, all, k)?;
+    println!("device\tcount\terror\texact");
+    for counter in &top {
+        let real = exact[&counter.item];
+        println!(
+            "{}\t{}\t{}\t{}",
+            counter.item, counter.count, counter.error, real
+        );
+        anyhow::ensure!(
+            real <= counter.count && counter.count <= real + counter.error,
+            "count of {} is off",
+            counter.item
+        );
+    }
+    let busy = top.iter().map(|c| c.item).collect::<BTreeSet<_>>();
+    anyhow::ensure!(
+        busy == (0..5).map(|i| i * 7).collect(),
+        "busy devices are missing"
+    );
+    println!(
+        "{} distinct devices, {}s",
+        exact.len(),
+        t0.elapsed().as_secs_f64()
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Tracing instrumentation for the store and query paths
+//!
+//! Branches are cached in the forest's BranchCache, so a `get` span for a link that was just
+//! seen in an `intersecting` span is a cache miss. A branch that is visited without a following
+//! `get` was served from the cache.
+use banyan::{
+    index::{BranchIndex, CompactSeq, LeafIndex},
+    query::Query,
+    store::{BlockWriter, ReadOnlyStore},
+    TreeTypes,
+};
+use std::fmt::Display;
+use tracing::{debug_span, trace_span};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+use crate::probe::ProbingStore;
+
+/// Install a fmt subscriber that logs all spans with their timings to stderr when they close
+pub fn init() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// A store wrapper that creates a span for every block get and put
+#[derive(Clone)]
+pub struct TracingStore<S>(S);
+
+impl<S> TracingStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self(inner)
+    }
+}
+
+impl<L: Display, S: ReadOnlyStore<L>> ReadOnlyStore<L> for TracingStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let span = debug_span!("get", %link, size = tracing::field::Empty);
+        let _enter = span.enter();
+        let data = self.0.get(link)?;
+        span.record("size", data.len());
+        Ok(data)
+    }
+}
+
+impl<L: Display, S: ProbingStore<L>> ProbingStore<L> for TracingStore<S> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        let span = debug_span!("size", %link);
+        let _enter = span.enter();
+        self.0.size(link)
+    }
+}
+
+impl<L: Display, S: BlockWriter<L>> BlockWriter<L> for TracingStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        let span = debug_span!("put", size = data.len(), link = tracing::field::Empty);
+        let _enter = span.enter();
+        let link = self.0.put(data)?;
+        span.record("link", tracing::field::display(&link));
+        Ok(link)
+    }
+}
+
+/// A query wrapper that creates a span for every branch and leaf the traversal looks at
+#[derive(Debug, Clone)]
+pub struct TracedQuery<Q>(pub Q);
+
+impl<T: TreeTypes, Q: Query<T>> Query<T> for TracedQuery<Q> {
+    fn containing(&self, offset: u64, index: &LeafIndex<T>, res: &mut [bool]) {
+        let span = trace_span!("containing", offset, count = index.keys.count());
+        let _enter = span.enter();
+        self.0.containing(offset, index, res);
+    }
+
+    fn intersecting(&self, offset: u64, index: &BranchIndex<T>, res: &mut [bool]) {
+        let span = trace_span!(
+            "intersecting",
+            offset,
+            level = index.level,
+            link = ?index.link,
+        );
+        let _enter = span.enter();
+        self.0.intersecting(offset, index, res);
+    }
+}
@@ -0,0 +1,245 @@
+//! Dropping duplicate events when appending
+//!
+//! A source that delivers at least once sends some events again after a reconnect or a retry,
+//! and once a duplicate is in the log it stays there. Each event has an id, and [RecentIds]
+//! remembers the ids of the last appended events. [extend_unique] drops every event whose id is
+//! among them, so a duplicate is caught as long as it comes within that many events of the
+//! original, which is what redeliveries do.
+//!
+//! The ids are only in memory. After a restart, [RecentIds::seed] reads them back from the tail
+//! of the tree, which is one reverse iteration over the last leaves.
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::Hash,
+};
+
+use banyan::{
+    query::OffsetRangeQuery,
+    store::{BanyanValue, BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+
+use crate::columnar::{self, ColumnarTT, EventKey};
+
+/// The ids of the last `capacity` appended events
+#[derive(Debug, Clone)]
+pub struct RecentIds<I> {
+    capacity: usize,
+    /// the ids in append order, the oldest first
+    order: VecDeque<I>,
+    ids: HashSet<I>,
+}
+
+impl<I: Hash + Eq + Clone> RecentIds<I> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            ids: HashSet::new(),
+        }
+    }
+
+    /// The ids of the last `capacity` events of the tree
+    pub fn seed<T, R, V>(
+        forest: &Forest<T, R>,
+        tree: &Tree<T, V>,
+        capacity: usize,
+        id: impl Fn(&T::Key, &V) -> I,
+    ) -> anyhow::Result<Self>
+    where
+        T: TreeTypes,
+        R: ReadOnlyStore<T::Link>,
+        V: BanyanValue,
+    {
+        let query = OffsetRangeQuery::from(tree.count().saturating_sub(capacity as u64)..);
+        let mut tail = forest
+            .iter_filtered_reverse(tree, query)
+            .map(|item| item.map(|(_, key, value)| id(&key, &value)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        tail.reverse();
+        let mut res = Self::new(capacity);
+        for id in tail {
+            res.insert(id);
+        }
+        Ok(res)
+    }
+
+    /// Whether the id is among the recent ones
+    pub fn contains(&self, id: &I) -> bool {
+        self.ids.contains(id)
+    }
+
+    /// Add the id, and forget the oldest one if there are too many. False if it is already there
+    pub fn insert(&mut self, id: I) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().expect("not empty");
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
+/// Append the events whose ids were not seen recently, and return the number of dropped
+/// duplicates. The ids are only remembered once the events are written, so a batch whose extend
+/// failed is appended when it is retried
+pub fn extend_unique<T, R, W, V, I>(
+    txn: &mut Transaction<T, R, W>,
+    builder: &mut StreamBuilder<T, V>,
+    recent: &mut RecentIds<I>,
+    xs: impl IntoIterator<Item = (T::Key, V)>,
+    id: impl Fn(&T::Key, &V) -> I,
+) -> anyhow::Result<u64>
+where
+    T: TreeTypes,
+    R: ReadOnlyStore<T::Link>,
+    W: BlockWriter<T::Link>,
+    V: BanyanValue,
+    I: Hash + Eq + Clone,
+{
+    let mut dropped = 0;
+    // the ids of the batch, for duplicates within it
+    let mut batch = HashSet::new();
+    let mut ids = Vec::new();
+    let unique = xs
+        .into_iter()
+        .filter(|(key, value)| {
+            let id = id(key, value);
+            let new = !recent.contains(&id) && batch.insert(id.clone());
+            if new {
+                ids.push(id);
+            } else {
+                dropped += 1;
+            }
+            new
+        })
+        .collect::<Vec<_>>();
+    txn.extend(builder, unique)?;
+    for id in ids {
+        recent.insert(id);
+    }
+    Ok(dropped)
+}
+
+/// A source that sends some batches twice, before and after a restart of the writer
+pub fn unique_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let batch = 1000;
+    let window = 10000;
+    println!(
+        "Example: appending {} events from a source that redelivers batches",
+        n
+    );
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    // the value of an event is its sequence number at the source, which is also its id
+    let id = |_: &EventKey, value: &u64| *value;
+    let events = columnar::events(n);
+    let batches = events.chunks(batch).collect::<Vec<_>>();
+    let mut recent = RecentIds::new(window);
+    let mut dropped = 0;
+    for (i, chunk) in batches.iter().enumerate() {
+        if i == batches.len() / 2 {
+            // the writer restarts, and the source sends the last few batches again
+            let tree = builder.snapshot();
+            recent = RecentIds::seed(&txn, &tree, window, id)?;
+            for chunk in &batches[i - 3..i] {
+                dropped += extend_unique(&mut txn, &mut builder, &mut recent, chunk.to_vec(), id)?;
+            }
+        }
+        dropped += extend_unique(&mut txn, &mut builder, &mut recent, chunk.to_vec(), id)?;
+        if i % 10 == 9 {
+            // a retry after a timeout, for a batch that did arrive
+            dropped += extend_unique(&mut txn, &mut builder, &mut recent, chunk.to_vec(), id)?;
+        }
+    }
+    let tree = builder.snapshot();
+    anyhow::ensure!(
+        tree.count() == n,
+        "{} events instead of {}",
+        tree.count(),
+        n
+    );
+    for item in txn.iter_from(&tree) {
+        let (i, _, value) = item?;
+        anyhow::ensure!(i == value, "event {} is {}", i, value);
+    }
+    println!("{} events, {} duplicates dropped", tree.count(), dropped);
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use banyan::store::MemStore;
+
+    use super::*;
+
+    /// A store whose writes fail while `down` is set
+    #[derive(Clone)]
+    struct Flaky {
+        inner: MemStore<Sha256Digest>,
+        down: Arc<AtomicBool>,
+    }
+
+    impl ReadOnlyStore<Sha256Digest> for Flaky {
+        fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+            self.inner.get(link)
+        }
+    }
+
+    impl BlockWriter<Sha256Digest> for Flaky {
+        fn put(&mut self, data: Vec<u8>) -> anyhow::Result<Sha256Digest> {
+            anyhow::ensure!(!self.down.load(Ordering::Relaxed), "the store is down");
+            self.inner.put(data)
+        }
+    }
+
+    #[test]
+    fn retry_after_a_failed_extend() {
+        let store = Flaky {
+            inner: MemStore::new(usize::MAX, Sha256Digest::digest),
+            down: Default::default(),
+        };
+        let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1024));
+        let mut txn = Transaction::new(forest, store.clone());
+        let config = Config {
+            zstd_level: 3,
+            ..Config::debug_fast()
+        };
+        let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config, Secrets::default());
+        let id = |_: &EventKey, value: &u64| *value;
+        let mut recent = RecentIds::new(100);
+        let events = columnar::events(10);
+        store.down.store(true, Ordering::Relaxed);
+        assert!(extend_unique(&mut txn, &mut builder, &mut recent, events.clone(), id).is_err());
+        store.down.store(false, Ordering::Relaxed);
+        // the retry is not mistaken for a redelivery
+        let dropped = extend_unique(&mut txn, &mut builder, &mut recent, events.clone(), id);
+        assert_eq!(dropped.unwrap(), 0);
+        let tree = builder.snapshot();
+        let values = txn
+            .iter_from(&tree)
+            .map(|item| item.map(|(_, _, value)| value))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(values, events.iter().map(|(_, v)| *v).collect::<Vec<_>>());
+        // and a redelivery after it is
+        let dropped = extend_unique(&mut txn, &mut builder, &mut recent, events, id);
+        assert_eq!(dropped.unwrap(), 10);
+    }
+}
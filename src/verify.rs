@@ -0,0 +1,163 @@
+//! Checking that a block is the block its link says
+//!
+//! A link is the hash of the block, so a reader can tell whether a store gave it the right bytes.
+//! banyan does not check it, and a local store has no reason to get it wrong, but a kubo
+//! elsewhere, a proxy in front of it or the network in between might. A corrupt leaf can
+//! decrypt and decompress into wrong values instead of failing. [VerifyingStore] hashes every
+//! block it gets, and fails with [Error::Corrupt] unless the hash is the link. The query
+//! commands use it, since their kubo can be anywhere.
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use banyan::{
+    query::AllQuery,
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+
+use crate::{
+    columnar::{self, ColumnarTT},
+    dedup,
+    error::{self, Error, ErrorKind},
+    link::Link,
+    probe::ProbingStore,
+};
+
+/// What a [VerifyingStore] checked so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Verified {
+    pub blocks: u64,
+    pub bytes: u64,
+    /// blocks whose hash was not their link
+    pub rejected: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    blocks: AtomicU64,
+    bytes: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// A store wrapper that checks the hash of every block it gets, see the module docs
+#[derive(Debug, Clone)]
+pub struct VerifyingStore<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+impl<S> VerifyingStore<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn verified(&self) -> Verified {
+        Verified {
+            blocks: self.counters.blocks.load(Ordering::Relaxed),
+            bytes: self.counters.bytes.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<L: Link, S: ReadOnlyStore<L>> ReadOnlyStore<L> for VerifyingStore<S> {
+    fn get(&self, link: &L) -> anyhow::Result<Box<[u8]>> {
+        let data = self.inner.get(link)?;
+        let actual = L::digest(&data);
+        if actual != *link {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::Corrupt {
+                cid: (*link).into(),
+                actual: actual.into(),
+            }
+            .into());
+        }
+        self.counters.blocks.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(data)
+    }
+}
+
+impl<L: Link, S: ProbingStore<L>> ProbingStore<L> for VerifyingStore<S> {
+    fn size(&self, link: &L) -> anyhow::Result<Option<u64>> {
+        self.inner.size(link)
+    }
+}
+
+impl<L, S: BlockWriter<L>> BlockWriter<L> for VerifyingStore<S> {
+    fn put(&mut self, data: Vec<u8>) -> anyhow::Result<L> {
+        self.inner.put(data)
+    }
+}
+
+/// A store that flips a bit in every block whose link starts with an even byte, like a broken
+/// proxy
+#[derive(Debug, Clone)]
+struct Tampering<S>(S);
+
+impl<S: ReadOnlyStore<Sha256Digest>> ReadOnlyStore<Sha256Digest> for Tampering<S> {
+    fn get(&self, link: &Sha256Digest) -> anyhow::Result<Box<[u8]>> {
+        let mut data = self.0.get(link)?;
+        if Cid::from(*link).hash().digest()[0] % 2 == 0 {
+            let last = data.len() - 1;
+            data[last] ^= 1;
+        }
+        Ok(data)
+    }
+}
+
+/// Read a tree through a verifying store, from a good store and from one that corrupts blocks
+pub fn verify_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!("Example: verifying the blocks of a tree of {} events", n);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    txn.extend(&mut builder, columnar::events(n))?;
+    let root = builder.snapshot().link().expect("not empty");
+    let blocks = dedup::blocks(&store, root)?;
+
+    let verifying = VerifyingStore::new(store.clone());
+    let forest = Forest::<ColumnarTT, _>::new(verifying.clone(), BranchCache::new(1 << 20));
+    let tree = forest.load_tree::<u64>(Secrets::default(), root)?;
+    let count = forest.iter_filtered(&tree, AllQuery).count() as u64;
+    anyhow::ensure!(count == n, "read {} events", count);
+    let verified = verifying.verified();
+    println!("good store\t{:?}", verified);
+    // a block can be read more than once, but each of them at least once
+    anyhow::ensure!(
+        verified.rejected == 0
+            && verified.blocks >= blocks.len() as u64
+            && verified.bytes >= blocks.values().sum::<u64>(),
+        "not every block was verified"
+    );
+
+    let verifying = VerifyingStore::new(Tampering(store));
+    let forest = Forest::<ColumnarTT, _>::new(verifying.clone(), BranchCache::new(0));
+    let err = forest
+        .iter_filtered(&tree, AllQuery)
+        .find_map(|item| item.err())
+        .ok_or_else(|| anyhow::anyhow!("the corrupt blocks were not noticed"))?;
+    anyhow::ensure!(
+        error::kind(&err) == ErrorKind::Corrupt && verifying.verified().rejected == 1,
+        "{} is {:?}",
+        err,
+        error::kind(&err)
+    );
+    println!("tampering store\t{:?}", verifying.verified());
+    println!("{}", err);
+    println!();
+    Ok(())
+}
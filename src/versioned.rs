@@ -0,0 +1,206 @@
+//! Values whose type changes over time
+//!
+//! A tree lives longer than the struct of its events. Each value is stored in an envelope with
+//! the version of the struct it was written with, and the body as [Ipld]. To read an old value,
+//! the registered upgrades are applied one version at a time, from the version of the value to
+//! the version the binary knows, and the result is decoded as the current struct.
+//!
+//! Upgrades work on [Ipld] instead of the old structs, so only the current struct has to be kept
+//! in the code.
+use std::collections::BTreeMap;
+
+use banyan::{
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, TreeTypes,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, Decode, Encode},
+    DagCbor, Ipld,
+};
+
+#[derive(Debug, Clone)]
+pub struct VersionedTT;
+
+impl TreeTypes for VersionedTT {
+    type Key = ();
+    type Summary = ();
+    type KeySeq = banyan::index::UnitSeq;
+    type SummarySeq = banyan::index::UnitSeq;
+    type Link = Sha256Digest;
+    const NONCE: &'static [u8; 24] = b"Versioned values camp...";
+}
+
+/// A value with the version of the struct it was written with
+#[derive(Debug, Clone, PartialEq, DagCbor)]
+pub struct Versioned {
+    pub version: u32,
+    pub body: Ipld,
+}
+
+impl Versioned {
+    pub fn new<T: Encode<DagCborCodec>>(version: u32, value: &T) -> anyhow::Result<Self> {
+        let body = DagCborCodec.decode(&DagCborCodec.encode(value)?)?;
+        Ok(Self { version, body })
+    }
+}
+
+/// A function that turns the body of a version into the body of the next version
+pub type Upgrade = fn(Ipld) -> anyhow::Result<Ipld>;
+
+/// The upgrades from each version to the next
+#[derive(Debug, Clone, Default)]
+pub struct Upgrades {
+    steps: BTreeMap<u32, Upgrade>,
+}
+
+impl Upgrades {
+    /// Register the upgrade from `version` to `version + 1`
+    pub fn register(&mut self, version: u32, upgrade: Upgrade) {
+        self.steps.insert(version, upgrade);
+    }
+
+    /// Upgrade the value to `version` and decode it
+    pub fn decode<T: Decode<DagCborCodec>>(
+        &self,
+        value: Versioned,
+        version: u32,
+    ) -> anyhow::Result<T> {
+        anyhow::ensure!(
+            value.version <= version,
+            "version {} is newer than {}",
+            value.version,
+            version
+        );
+        let mut body = value.body;
+        for v in value.version..version {
+            let upgrade = self
+                .steps
+                .get(&v)
+                .ok_or_else(|| anyhow::anyhow!("no upgrade from version {}", v))?;
+            body = upgrade(body)?;
+        }
+        DagCborCodec.decode(&DagCborCodec.encode(&body)?)
+    }
+}
+
+/// A reading as it was first written
+#[derive(Debug, Clone, PartialEq, DagCbor)]
+struct ReadingV1 {
+    sensor: u64,
+    temp: i64,
+}
+
+/// The current reading, with a clearer name for the temperature and an optional location
+#[derive(Debug, Clone, PartialEq, DagCbor)]
+pub struct Reading {
+    pub sensor: u64,
+    pub celsius: i64,
+    pub location: Option<String>,
+}
+
+impl Reading {
+    pub const VERSION: u32 = 2;
+}
+
+/// Rename `temp` to `celsius`, and add `location`, which old readings don't have
+fn upgrade_v1(body: Ipld) -> anyhow::Result<Ipld> {
+    let mut map = match body {
+        Ipld::StringMap(map) => map,
+        other => anyhow::bail!("expected a map, got {:?}", other),
+    };
+    let temp = map
+        .remove("temp")
+        .ok_or_else(|| anyhow::anyhow!("temp is missing"))?;
+    map.insert("celsius".into(), temp);
+    map.insert("location".into(), Ipld::Null);
+    Ok(Ipld::StringMap(map))
+}
+
+/// The upgrades for [Reading]
+pub fn upgrades() -> Upgrades {
+    let mut upgrades = Upgrades::default();
+    upgrades.register(1, upgrade_v1);
+    upgrades
+}
+
+/// A stream that was written with the first version of the reading for a while, and with the
+/// second after that, read back entirely as the second
+pub fn versioned_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    println!(
+        "Example: {} readings, the first half written as version 1",
+        n
+    );
+    let forest = Forest::<VersionedTT, _>::new(store.clone(), BranchCache::new(1024));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder =
+        StreamBuilder::<VersionedTT, Versioned>::new(config.clone(), Secrets::default());
+    let old = (0..n / 2)
+        .map(|i| {
+            let reading = ReadingV1 {
+                sensor: i % 10,
+                temp: (i % 40) as i64 - 10,
+            };
+            Ok(((), Versioned::new(1, &reading)?))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    txn.extend(&mut builder, old)?;
+    let new = (n / 2..n)
+        .map(|i| {
+            let reading = Reading {
+                sensor: i % 10,
+                celsius: (i % 40) as i64 - 10,
+                location: Some(format!("room {}", i % 3)),
+            };
+            Ok(((), Versioned::new(Reading::VERSION, &reading)?))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    txn.extend(&mut builder, new)?;
+
+    let tree = builder.snapshot();
+    let upgrades = upgrades();
+    let mut upgraded = 0;
+    for item in txn.iter_from(&tree) {
+        let (i, _, value) = item?;
+        let version = value.version;
+        let reading: Reading = upgrades.decode(value, Reading::VERSION)?;
+        anyhow::ensure!(
+            reading.celsius == (i % 40) as i64 - 10,
+            "reading {} changed",
+            i
+        );
+        anyhow::ensure!(
+            (version == 1) == reading.location.is_none(),
+            "wrong location"
+        );
+        if version < Reading::VERSION {
+            upgraded += 1;
+        }
+    }
+    println!(
+        "read {} readings, {} of them upgraded",
+        tree.count(),
+        upgraded
+    );
+
+    // a binary that only knows version 1 can not read what a newer one wrote
+    let newer = Versioned::new(
+        Reading::VERSION,
+        &Reading {
+            sensor: 0,
+            celsius: 0,
+            location: None,
+        },
+    )?;
+    anyhow::ensure!(
+        upgrades.decode::<ReadingV1>(newer, 1).is_err(),
+        "a newer version must not decode"
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! Materialized views: trees with the events of a query on another tree
+//!
+//! A query that is run often, like the events of a few devices, reads fewer blocks when its
+//! events are in a tree of their own. [materialize] writes that tree, and a [View] block that
+//! records where it came from: the root and count of the source, and the
+//! [canonical](JsonQuery::canonical) query. The link of the view block is all a reader needs.
+//!
+//! Streams only grow, so when the source advances, [refresh] runs the query on the events after
+//! the recorded count, and appends what it finds to the view. That is a continuous query, one
+//! refresh at a time. The offset range prunes everything before the count by the index, so a
+//! refresh reads the leaves of the new events and the branches above them, however large the
+//! source got, and the view is extended rather than written again.
+//!
+//! The new source has to be an extension of the old one. Checking that would mean reading both
+//! trees, so a refresh only checks that the last event of the old source is at the same offset in
+//! the new one, which catches a source from another stream.
+//!
+//! The events keep their keys and values, but not their offsets, so an `offset` query on the view
+//! is not the same as on the source.
+use banyan::{
+    query::{AndQuery, OffsetRangeQuery},
+    store::{BlockWriter, BranchCache, ReadOnlyStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use libipld::{cbor::DagCborCodec, codec::Codec, DagCbor};
+
+use crate::{
+    cache::CacheStats,
+    columnar::{self, ColumnarTT},
+    kubo::KuboStore,
+    query_json::JsonQuery,
+};
+
+/// Where a materialized view came from, and where it is
+#[derive(Debug, Clone, PartialEq, Eq, DagCbor)]
+pub struct View {
+    /// the source tree
+    pub source: Sha256Digest,
+    /// the high-water mark: the source events up to here are in the view
+    pub source_count: u64,
+    /// the canonical json of the query
+    pub query: String,
+    /// the view tree, `None` while the query found nothing
+    pub root: Option<Sha256Digest>,
+    /// the number of events in the view tree
+    pub count: u64,
+}
+
+impl View {
+    pub fn load<S: ReadOnlyStore<Sha256Digest>>(
+        store: &S,
+        link: Sha256Digest,
+    ) -> anyhow::Result<Self> {
+        DagCborCodec
+            .decode(&store.get(&link)?)
+            .map_err(|cause| anyhow::anyhow!("{} is not a view: {}", link, cause))
+    }
+}
+
+/// Append the events of the query on the source after `from` to the view, and write the view
+/// block. Returns its link
+fn update<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    builder: &mut StreamBuilder<ColumnarTT, u64>,
+    source: &Tree<ColumnarTT, u64>,
+    from: u64,
+    query: &JsonQuery,
+) -> anyhow::Result<(Sha256Digest, View)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest> + Clone,
+{
+    let root = source
+        .link()
+        .ok_or_else(|| anyhow::anyhow!("the source is empty"))?;
+    let new = AndQuery(OffsetRangeQuery::from(from..), query.to_query());
+    let events = txn
+        .iter_filtered(source, new)
+        .map(|item| item.map(|(_, key, value)| (key, value)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    txn.extend(builder, events)?;
+    let tree = builder.snapshot();
+    let view = View {
+        source: root,
+        source_count: source.count(),
+        query: query.canonical().to_json().to_string(),
+        root: tree.link(),
+        count: tree.count(),
+    };
+    let link = txn.writer().clone().put(DagCborCodec.encode(&view)?)?;
+    Ok((link, view))
+}
+
+/// Write the events of a query on a tree into a tree of their own. Returns the link of the
+/// [View] block
+pub fn materialize<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    config: &Config,
+    secrets: &Secrets,
+    source: Sha256Digest,
+    query: &JsonQuery,
+) -> anyhow::Result<(Sha256Digest, View)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest> + Clone,
+{
+    let source = txn.load_tree::<u64>(secrets.clone(), source)?;
+    let mut builder = StreamBuilder::new(config.clone(), secrets.clone());
+    update(txn, &mut builder, &source, 0, query)
+}
+
+/// Check that the last of the `count` events of the old tree is at the same offset in the new
+/// one, see the module docs
+pub fn check_extends<R, W>(
+    txn: &Transaction<ColumnarTT, R, W>,
+    secrets: &Secrets,
+    old: Sha256Digest,
+    count: u64,
+    new: &Tree<ColumnarTT, u64>,
+) -> anyhow::Result<()>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+{
+    let old_tree = txn.load_tree::<u64>(secrets.clone(), old)?;
+    let last = OffsetRangeQuery::from(count - 1..count);
+    let before = txn
+        .iter_filtered(&old_tree, last.clone())
+        .next()
+        .transpose()?;
+    let after = txn.iter_filtered(new, last).next().transpose()?;
+    anyhow::ensure!(
+        before == after,
+        "{} does not extend {}",
+        new.link().map(|x| x.to_string()).unwrap_or_default(),
+        old
+    );
+    Ok(())
+}
+
+/// Bring a view up to date with a newer version of its source. Returns the link of the new
+/// [View] block, which is the old one if the source did not change
+pub fn refresh<R, W>(
+    txn: &mut Transaction<ColumnarTT, R, W>,
+    config: &Config,
+    secrets: &Secrets,
+    view: Sha256Digest,
+    source: Sha256Digest,
+) -> anyhow::Result<(Sha256Digest, View)>
+where
+    R: ReadOnlyStore<Sha256Digest>,
+    W: BlockWriter<Sha256Digest> + Clone,
+{
+    let old = View::load(txn.store(), view)?;
+    if old.source == source {
+        return Ok((view, old));
+    }
+    let query = JsonQuery::parse(&old.query)?;
+    let new_source = txn.load_tree::<u64>(secrets.clone(), source)?;
+    anyhow::ensure!(
+        new_source.count() >= old.source_count,
+        "{} has {} events, fewer than the {} the view was made from",
+        source,
+        new_source.count(),
+        old.source_count
+    );
+    check_extends(txn, secrets, old.source, old.source_count, &new_source)?;
+    let mut builder = match old.root {
+        Some(root) => txn.load_stream_builder(secrets.clone(), config.clone(), root)?,
+        None => StreamBuilder::new(config.clone(), secrets.clone()),
+    };
+    update(txn, &mut builder, &new_source, old.source_count, &query)
+}
+
+fn print_view(link: Sha256Digest, view: &View) {
+    let root = view
+        .root
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| "-".into());
+    println!("view\t{}", link);
+    println!("root\t{}\t{} events", root, view.count);
+    println!("source\t{}\t{} events", view.source, view.source_count);
+    println!("query\t{}", view.query);
+}
+
+/// Materialize a query on a columnar tree in kubo, and print the view
+pub fn print_materialize(
+    config: &Config,
+    secrets: &Secrets,
+    source: Sha256Digest,
+    query: &JsonQuery,
+) -> anyhow::Result<()> {
+    let store = KuboStore::from_env()?;
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let (link, view) = materialize(&mut txn, config, secrets, source, query)?;
+    print_view(link, &view);
+    Ok(())
+}
+
+/// Refresh a view in kubo to a newer source, and print it
+pub fn print_refresh(
+    config: &Config,
+    secrets: &Secrets,
+    view: Sha256Digest,
+    source: Sha256Digest,
+) -> anyhow::Result<()> {
+    let store = KuboStore::from_env()?;
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let (link, view) = refresh(&mut txn, config, secrets, view, source)?;
+    print_view(link, &view);
+    Ok(())
+}
+
+/// Materialize a query, let the source grow, refresh the view, and compare it with a query
+pub fn views_example(
+    store: impl ReadOnlyStore<Sha256Digest> + BlockWriter<Sha256Digest>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let n = 100000u64;
+    let steps = 4;
+    println!(
+        "Example: a view of two devices on {} events, kept up to date in {} steps",
+        n, steps
+    );
+    // small leaves, so a scan of the whole source is more than a few blocks
+    let config = &Config {
+        target_leaf_size: 1 << 10,
+        max_leaf_count: 1 << 8,
+        ..config.clone()
+    };
+    config.validate()?;
+    let secrets = Secrets::default();
+    // no branch cache, so every block a refresh reads is counted
+    let stats = CacheStats::new();
+    let forest = Forest::<ColumnarTT, _>::new(stats.store(store.clone()), BranchCache::new(0));
+    let mut txn = Transaction::new(forest, store);
+    let reads = || stats.misses() + stats.leaves();
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets.clone());
+    let events = columnar::events(n);
+    let query = JsonQuery::parse(r#"{"devices": [3, 17]}"#)?;
+    let chunk = (n / steps) as usize;
+    let mut view = None;
+    println!("source\tview\tblocks read\tfrom scratch");
+    for part in events.chunks(chunk) {
+        txn.extend(&mut builder, part.iter().cloned())?;
+        let source = builder.snapshot().link().expect("not empty");
+        let before = reads();
+        let (scratch, _) = materialize(&mut txn, config, &secrets, source, &query)?;
+        let from_scratch = reads() - before;
+        let link = match view {
+            None => scratch,
+            Some(link) => {
+                let before = reads();
+                let refreshed = refresh(&mut txn, config, &secrets, link, source)?;
+                let read = reads() - before;
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    refreshed.1.source_count, refreshed.1.count, read, from_scratch
+                );
+                anyhow::ensure!(read < from_scratch, "the refresh read the whole source");
+                anyhow::ensure!(
+                    refreshed.1.count == View::load(txn.store(), scratch)?.count,
+                    "the refreshed view is not the view from scratch"
+                );
+                refreshed.0
+            }
+        };
+        view = Some(link);
+    }
+    let view = View::load(txn.store(), view.expect("refreshed"))?;
+    let tree = txn.load_tree::<u64>(secrets.clone(), view.root.expect("not empty"))?;
+    let found = txn
+        .iter_from(&tree)
+        .map(|item| item.map(|(_, key, value)| (key, value)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let expected = events
+        .iter()
+        .filter(|(key, _)| key.device == 3 || key.device == 17)
+        .cloned()
+        .collect::<Vec<_>>();
+    anyhow::ensure!(found == expected, "the view differs from the query");
+    // a tree from another stream is not a newer version of the source
+    let mut other = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), secrets.clone());
+    txn.extend(&mut other, columnar::events(2 * n).into_iter().rev())?;
+    let other = other.snapshot().link().expect("not empty");
+    let latest = builder.snapshot().link().expect("not empty");
+    let (link, _) = materialize(&mut txn, config, &secrets, latest, &query)?;
+    anyhow::ensure!(
+        refresh(&mut txn, config, &secrets, link, other).is_err(),
+        "refreshed from another stream"
+    );
+    println!();
+    Ok(())
+}
@@ -0,0 +1,250 @@
+//! A write-ahead log for events that are not in a snapshot yet
+//!
+//! An ingest loop acknowledges events long before they are in a persisted snapshot, since a
+//! snapshot per event would be far too slow. If it crashes in between, those events are gone. So
+//! a [Wal] appends each batch to a file, and syncs it, before the batch goes into the tree. On
+//! startup, [Wal::open] reads back all batches, and the loop appends those that are not in the
+//! newest snapshot again. After a snapshot is persisted, [Wal::checkpoint] empties the file.
+//! Persisted means on disk: the blocks, the root that points to them and the directories they are
+//! in all synced, like with [FsStore::sync] and a [ManifestFile] swap, or the checkpoint throws
+//! away the only copy of the events that a crash loses.
+//!
+//! A record is `[len u32][checksum 4 bytes][entry]`, little endian. A record that was only partly
+//! written when the process died fails its length or checksum, and is cut off along with
+//! everything after it. What an entry is depends on the loop:
+//!
+//! - a [Batch] of columnar events is `[offset u64][events]`, each event `time u64, device u32,
+//!   value u64`, see [encode_batch]. The offset is the one of its first event in the stream, so
+//!   replaying is idempotent: a crash after the snapshot but before the checkpoint only replays
+//!   events that are skipped
+//! - the [bridge](crate::bridge) logs the same, but with the sequence of the newest record of the
+//!   source instead of the offset, which is also the id of the batch for
+//!   [append_batch](crate::idempotent::append_batch)
+//! - the [mqtt](crate::mqtt) subscriber logs the messages as dag-cbor, with their offset
+//!
+//! The bridge and the mqtt subscriber ack what they got once it is in the log, and persist a
+//! snapshot every few batches. On startup, they append what the log has on top of the snapshot.
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use banyan::{store::BranchCache, Config, Forest, Secrets, StreamBuilder, Transaction};
+use banyan_utils::tags::Sha256Digest;
+use libipld::Cid;
+
+use crate::{
+    columnar::{self, ColumnarTT, EventKey},
+    fs_store::FsStore,
+    roots::{ManifestFile, RootStore},
+};
+
+const EVENT_BYTES: usize = 8 + 4 + 8;
+
+/// A batch of events, and the offset of the first one
+pub type Batch<E = (EventKey, u64)> = (u64, Vec<E>);
+
+/// An append-only log of batches, see the module docs
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+}
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let cid = Cid::from(Sha256Digest::digest(data));
+    let mut res = [0u8; 4];
+    res.copy_from_slice(&cid.hash().digest()[..4]);
+    res
+}
+
+fn encode(entry: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(8 + entry.len());
+    record.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(entry));
+    record.extend_from_slice(entry);
+    record
+}
+
+/// Decode the record at the start of data, and return its entry and its length
+fn decode(data: &[u8]) -> Option<(&[u8], usize)> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let entry = data.get(8..8 + len)?;
+    if data[4..8] != checksum(entry) {
+        return None;
+    }
+    Some((entry, 8 + len))
+}
+
+/// The entry of a batch of columnar events
+pub fn encode_batch(offset: u64, events: &[(EventKey, u64)]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(8 + events.len() * EVENT_BYTES);
+    entry.extend_from_slice(&offset.to_le_bytes());
+    for (key, value) in events {
+        entry.extend_from_slice(&key.time.to_le_bytes());
+        entry.extend_from_slice(&key.device.to_le_bytes());
+        entry.extend_from_slice(&value.to_le_bytes());
+    }
+    entry
+}
+
+/// A batch of columnar events from its entry
+pub fn decode_batch(entry: &[u8]) -> anyhow::Result<Batch> {
+    anyhow::ensure!(
+        entry.len() >= 8 && (entry.len() - 8).is_multiple_of(EVENT_BYTES),
+        "a batch of {} bytes",
+        entry.len()
+    );
+    let u64_at = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().expect("8 bytes"));
+    let events = entry[8..]
+        .chunks(EVENT_BYTES)
+        .map(|event| {
+            let key = EventKey {
+                time: u64_at(&event[..8]),
+                device: u32::from_le_bytes(event[8..12].try_into().expect("4 bytes")),
+            };
+            (key, u64_at(&event[12..]))
+        })
+        .collect();
+    Ok((u64_at(&entry[..8]), events))
+}
+
+impl Wal {
+    /// Open the log, or create it, and return the entries in it. A partly written record at the
+    /// end is removed
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<(Self, Vec<Vec<u8>>)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while let Some((entry, len)) = decode(&data[pos..]) {
+            entries.push(entry.to_vec());
+            pos += len;
+        }
+        if pos < data.len() {
+            tracing::warn!(
+                "cutting off {} bytes of a torn record from {}",
+                data.len() - pos,
+                path.display()
+            );
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok((Self { file }, entries))
+    }
+
+    /// Append an entry. When this returns, it is on disk, and what is in it can be acknowledged
+    pub fn append(&mut self, entry: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(&encode(entry))?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Forget all batches, once a snapshot that has all of them is persisted, see the module docs
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// The events of the batches that are not in a tree of `count` events yet, in order
+pub fn replay<E>(batches: Vec<Batch<E>>, count: u64) -> anyhow::Result<Vec<E>> {
+    let mut events = Vec::new();
+    for (offset, batch) in batches {
+        // the offset of the next event that is neither in the tree nor replayed
+        let next = count + events.len() as u64;
+        anyhow::ensure!(
+            offset <= next,
+            "batch at offset {} leaves a gap after offset {}",
+            offset,
+            next
+        );
+        events.extend(batch.into_iter().skip((next - offset) as usize));
+    }
+    Ok(events)
+}
+
+/// Ingest batches with a snapshot every few of them, crash in between, and recover from the log
+pub fn wal_example(config: &Config) -> anyhow::Result<()> {
+    let batches = 50usize;
+    let batch = 200usize;
+    let every = 8usize;
+    println!(
+        "Example: {} batches of {} events, a snapshot every {}, and a crash",
+        batches, batch, every
+    );
+    let dir = std::env::temp_dir().join(format!("banyan-wal-{}", std::process::id()));
+    let events = columnar::events((batches * batch) as u64);
+    let store = FsStore::<Sha256Digest>::open(dir.join("store"))?;
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store.clone());
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let manifest = ManifestFile::new(dir.join("manifest"));
+    let (mut wal, old) = Wal::open(dir.join("wal"))?;
+    anyhow::ensure!(old.is_empty(), "a new log is not empty");
+    // the root of the last persisted snapshot
+    let mut persisted = None;
+    let crash = 45;
+    for (i, chunk) in events.chunks(batch).enumerate().take(crash) {
+        wal.append(&encode_batch(builder.snapshot().count(), chunk))?;
+        txn.extend(&mut builder, chunk.iter().cloned())?;
+        if (i + 1) % every == 0 {
+            let root = builder.snapshot().link().expect("not empty");
+            store.sync()?;
+            manifest.compare_and_swap("events", persisted, root)?;
+            persisted = Some(root);
+            wal.checkpoint()?;
+        }
+    }
+    // the process dies in the middle of writing the next batch
+    drop(wal);
+    let torn = &encode(&encode_batch(0, &events[..batch]))[..100];
+    OpenOptions::new()
+        .append(true)
+        .open(dir.join("wal"))?
+        .write_all(torn)?;
+    let acknowledged = crash * batch;
+
+    // recover: the newest snapshot, and what the log has on top of it
+    let root = RootStore::<Sha256Digest>::root(&manifest, "events")?
+        .ok_or_else(|| anyhow::anyhow!("no snapshot"))?;
+    let mut builder = txn.load_stream_builder(Secrets::default(), config.clone(), root)?;
+    let in_snapshot = builder.snapshot().count();
+    let (mut wal, entries) = Wal::open(dir.join("wal"))?;
+    let batches = entries.iter().map(|entry| decode_batch(entry));
+    let missing = replay(batches.collect::<anyhow::Result<_>>()?, in_snapshot)?;
+    txn.extend(&mut builder, missing.iter().cloned())?;
+    let tree = builder.snapshot();
+    let found = txn.iter_from(&tree).collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(
+        found.len() == acknowledged
+            && found
+                .iter()
+                .map(|(_, k, v)| (*k, *v))
+                .eq(events[..acknowledged].iter().cloned()),
+        "lost acknowledged events"
+    );
+    // replaying again after a crash before the checkpoint adds nothing
+    let (_, entries) = Wal::open(dir.join("wal"))?;
+    let batches = entries.iter().map(|entry| decode_batch(entry));
+    let batches = batches.collect::<anyhow::Result<_>>()?;
+    anyhow::ensure!(replay(batches, tree.count())?.is_empty(), "replayed twice");
+    wal.checkpoint()?;
+    println!(
+        "{} events acknowledged, {} in the snapshot, {} replayed from the log",
+        acknowledged,
+        in_snapshot,
+        missing.len()
+    );
+    std::fs::remove_dir_all(&dir)?;
+    println!();
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Webhooks for new snapshots
+//!
+//! A system that wants to react to new data would have to poll for the newest root. Instead, a
+//! [Notifier] posts a json record of every snapshot to a list of urls:
+//!
+//! ```json
+//! {"stream": "ingest", "root": "bafy...", "count": 1000, "time": 1660000000000,
+//!  "keys": {"min_time": 1660000000000, "max_time": 1660000001000, "min_device": 0, "max_device": 99}}
+//! ```
+//!
+//! `root` and `keys` are null for an empty tree. The posts happen on a thread of their own, so a
+//! slow or dead endpoint does not hold up ingest. A record that is still waiting when the next
+//! snapshot comes is replaced by the newer one, which has everything the older tree had. A post
+//! that fails is tried a few more times, and then given up.
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use banyan::{
+    store::{BranchCache, MemStore},
+    Config, Forest, Secrets, StreamBuilder, Transaction, Tree,
+};
+use banyan_utils::tags::Sha256Digest;
+use serde_json::{json, Value};
+
+use crate::{columnar::ColumnarTT, snapshots};
+
+/// Number of tries per record and url
+const TRIES: u32 = 3;
+
+/// What a [Notifier] did so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NotifyStats {
+    /// posts that were accepted, one per record and url
+    pub sent: u64,
+    /// posts that failed every try
+    pub failed: u64,
+    /// records that were replaced by a newer one before they were sent
+    pub replaced: u64,
+}
+
+/// The record of a snapshot of a columnar tree
+pub fn record<V>(stream: &str, tree: &Tree<ColumnarTT, V>) -> Value {
+    let keys = tree.as_index_ref().map(|index| {
+        let summary = index.summarize();
+        json!({
+            "min_time": summary.min_time,
+            "max_time": summary.max_time,
+            "min_device": summary.min_device,
+            "max_device": summary.max_device,
+        })
+    });
+    json!({
+        "stream": stream,
+        "root": tree.link().map(|link| link.to_string()),
+        "count": tree.count(),
+        "time": snapshots::now(),
+        "keys": keys,
+    })
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: Option<Value>,
+    /// whether the worker is posting a record right now
+    busy: bool,
+    closed: bool,
+    stats: NotifyStats,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+/// Posts snapshot records to webhooks in the background, see the module docs
+#[derive(Debug)]
+pub struct Notifier {
+    shared: Shared,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    /// A notifier for the urls, with a timeout for each post
+    pub fn new(urls: Vec<String>, timeout: Duration) -> anyhow::Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .build()?;
+        let shared: Shared = Arc::default();
+        let worker = thread::spawn({
+            let shared = shared.clone();
+            move || post_loop(&client, &urls, &shared)
+        });
+        Ok(Self {
+            shared,
+            worker: Some(worker),
+        })
+    }
+
+    /// Post the record of a new snapshot, as soon as the posts of the last one are done
+    pub fn notify(&self, record: Value) {
+        let (state, wakeup) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        if state.pending.replace(record).is_some() {
+            state.stats.replaced += 1;
+        }
+        wakeup.notify_all();
+    }
+
+    pub fn stats(&self) -> NotifyStats {
+        self.shared.0.lock().unwrap().stats
+    }
+
+    /// Wait until every record so far is posted
+    pub fn flush(&self) {
+        let (state, wakeup) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        while state.pending.is_some() || state.busy {
+            state = wakeup.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Notifier {
+    /// Post what is still waiting, and stop the worker
+    fn drop(&mut self) {
+        let (state, wakeup) = &*self.shared;
+        state.lock().unwrap().closed = true;
+        wakeup.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn post(client: &reqwest::blocking::Client, url: &str, record: &Value) -> anyhow::Result<()> {
+    let response = client.post(url).json(record).send()?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "webhook {} answered {}",
+        url,
+        response.status()
+    );
+    Ok(())
+}
+
+fn post_loop(client: &reqwest::blocking::Client, urls: &[String], shared: &Shared) {
+    let (state, wakeup) = &**shared;
+    loop {
+        let record = {
+            let mut state = state.lock().unwrap();
+            loop {
+                if let Some(record) = state.pending.take() {
+                    state.busy = true;
+                    break record;
+                }
+                if state.closed {
+                    return;
+                }
+                state = wakeup.wait(state).unwrap();
+            }
+        };
+        for url in urls {
+            let mut result = Ok(());
+            for attempt in 0..TRIES {
+                if attempt > 0 {
+                    thread::sleep(Duration::from_millis(100 << attempt));
+                }
+                result = post(client, url, &record);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            let mut state = state.lock().unwrap();
+            match result {
+                Ok(()) => state.stats.sent += 1,
+                Err(cause) => {
+                    eprintln!("giving up on webhook: {}", cause);
+                    state.stats.failed += 1;
+                }
+            }
+        }
+        state.lock().unwrap().busy = false;
+        wakeup.notify_all();
+    }
+}
+
+/// A webhook endpoint that fails its first request, and returns the records it accepted
+fn fake_endpoint(listener: TcpListener, accept: usize) -> anyhow::Result<Vec<Value>> {
+    let mut records = Vec::new();
+    let mut requests = 0;
+    while records.len() < accept {
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("content-length:") {
+                length = value.trim().parse()?;
+            }
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+        requests += 1;
+        let status = match requests {
+            1 => "500 Internal Server Error",
+            _ => {
+                records.push(serde_json::from_slice(&body)?);
+                "200 OK"
+            }
+        };
+        write!(
+            &stream,
+            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        )?;
+    }
+    Ok(records)
+}
+
+/// Snapshot a tree a few times, and check what a webhook got
+pub fn webhooks_example(config: &Config) -> anyhow::Result<()> {
+    let snapshots = 5;
+    println!(
+        "Example: posting {} snapshot records to a webhook that fails once",
+        snapshots
+    );
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}/banyan", listener.local_addr()?);
+    let endpoint = thread::spawn(move || fake_endpoint(listener, snapshots));
+    let notifier = Notifier::new(vec![url], Duration::from_secs(5))?;
+
+    let store = MemStore::new(usize::MAX, Sha256Digest::digest);
+    let forest = Forest::<ColumnarTT, _>::new(store.clone(), BranchCache::new(1 << 20));
+    let mut txn = Transaction::new(forest, store);
+    let mut builder = StreamBuilder::<ColumnarTT, u64>::new(config.clone(), Secrets::default());
+    let events = crate::columnar::events(snapshots as u64 * 1000);
+    let mut roots = Vec::new();
+    for chunk in events.chunks(1000) {
+        txn.extend(&mut builder, chunk.iter().cloned())?;
+        let tree = builder.snapshot();
+        roots.push(tree.link().map(|link| link.to_string()));
+        notifier.notify(record("example", &tree));
+        // wait, so no record is replaced and the endpoint gets all of them
+        notifier.flush();
+    }
+    let stats = notifier.stats();
+    drop(notifier);
+    let records = endpoint.join().expect("endpoint panicked")?;
+    anyhow::ensure!(
+        stats.sent == snapshots as u64 && stats.failed == 0,
+        "{:?}",
+        stats
+    );
+    anyhow::ensure!(
+        records
+            .iter()
+            .map(|record| record["root"].as_str().map(String::from))
+            .eq(roots),
+        "the webhook got other roots"
+    );
+    let last = &records[snapshots - 1];
+    anyhow::ensure!(
+        last["count"] == events.len()
+            && last["keys"]["max_time"] == events[events.len() - 1].0.time,
+        "wrong count or key range {}",
+        last
+    );
+    println!("{}", last);
+    println!();
+    Ok(())
+}
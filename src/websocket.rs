@@ -0,0 +1,201 @@
+//! Just enough of WebSocket (RFC 6455) for the tail of the server
+//!
+//! The server only sends text messages, each in a single frame, and only reads the control frames
+//! of the client: a close, answered with a close, and a ping, answered with a pong. The client of
+//! the tail command only reads. So there is no fragmentation, no extensions and no subprotocols.
+//!
+//! A message is a frame with a two byte header, an extended length for payloads of 126 bytes or
+//! more, and the payload. Frames of a client are masked with four random bytes, frames of a server
+//! are not.
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+use sha1_smol::Sha1;
+
+pub const TEXT: u8 = 0x1;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xa;
+
+/// What the key of the client is hashed with, from the RFC
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The `Sec-WebSocket-Accept` for the `Sec-WebSocket-Key` of a client
+pub fn accept_key(key: &str) -> String {
+    use base64::Engine;
+    let digest = Sha1::from(format!("{}{}", key, GUID)).digest().bytes();
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// Answer the upgrade request of a client with the `Sec-WebSocket-Key` of its request
+pub fn accept(mut stream: &TcpStream, key: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )
+}
+
+/// A frame, with the payload unmasked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Write a frame with the whole payload, unmasked, like a server does
+pub fn write_frame(mut w: impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => header.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            header.push(126);
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            header.push(127);
+            header.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    w.write_all(&header)?;
+    w.write_all(payload)?;
+    w.flush()
+}
+
+/// Read a frame, masked or not, and fail if its payload is longer than `max` bytes
+pub fn read_frame(mut r: impl Read, max: u64) -> anyhow::Result<Frame> {
+    let mut header = [0u8; 2];
+    r.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0f;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            r.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            r.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    anyhow::ensure!(
+        len <= max,
+        "websocket frame of {} bytes, longer than {}",
+        len,
+        max
+    );
+    let mut mask = [0u8; 4];
+    let masked = header[1] & 0x80 != 0;
+    if masked {
+        r.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    Ok(Frame { opcode, payload })
+}
+
+/// Open a WebSocket to `path` on the server at `addr`, like `127.0.0.1:8435`, with a bearer token
+/// if there is one. The reader is buffered, and may already have the first frames
+pub fn connect(
+    addr: &str,
+    path: &str,
+    token: Option<&str>,
+) -> anyhow::Result<BufReader<TcpStream>> {
+    use base64::Engine;
+    let mut nonce = [0u8; 16];
+    getrandom::fill(&mut nonce).map_err(|cause| anyhow::anyhow!("no randomness: {}", cause))?;
+    let key = base64::engine::general_purpose::STANDARD.encode(nonce);
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+        path, addr, key
+    )?;
+    if let Some(token) = token {
+        write!(stream, "Authorization: Bearer {}\r\n", token)?;
+    }
+    write!(stream, "\r\n")?;
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let mut accepted = None;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("sec-websocket-accept") {
+                accepted = Some(value.trim().to_string());
+            }
+        }
+    }
+    if !status.starts_with("HTTP/1.1 101") {
+        // the body says why, and the server closes the connection after it
+        let mut body = String::new();
+        reader.read_to_string(&mut body).ok();
+        anyhow::bail!("{} answered {} {}", addr, status.trim_end(), body);
+    }
+    anyhow::ensure!(
+        accepted == Some(accept_key(&key)),
+        "{} accepted another key",
+        addr
+    );
+    Ok(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_from_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let mut buf = Vec::new();
+            write_frame(&mut buf, TEXT, &payload).unwrap();
+            let frame = read_frame(buf.as_slice(), 1 << 20).unwrap();
+            assert_eq!(
+                frame,
+                Frame {
+                    opcode: TEXT,
+                    payload
+                }
+            );
+        }
+        let mut buf = Vec::new();
+        write_frame(&mut buf, TEXT, &[0; 200]).unwrap();
+        assert!(read_frame(buf.as_slice(), 100).is_err());
+    }
+
+    #[test]
+    fn masked_frame() {
+        // a masked "Hello" from the RFC
+        let frame = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = read_frame(frame.as_slice(), 125).unwrap();
+        assert_eq!(frame.payload, b"Hello");
+    }
+}